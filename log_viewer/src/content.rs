@@ -2,13 +2,25 @@ use std::collections::BTreeSet;
 
 use egui::{CentralPanel, Color32, Grid, RichText, ScrollArea, Separator, Window};
 use egui_phosphor::fill;
-use gdrollback::SentInput;
+use gdrollback::{
+    logging::{EventSeverity, SyncState},
+    SentInput,
+};
 use itertools::Itertools;
 
-use crate::{entries::SyncState, util::trim_path, window_button::UiExt, App};
+use crate::{
+    entries::ContainsState,
+    run::FrameRow,
+    util::{hex_rows, trim_path},
+    window_button::UiExt,
+    App,
+};
 
 pub fn show_content(app: &mut App, ctx: &egui::Context) {
     CentralPanel::default().show(ctx, |ui| {
+        let input_decoder = &app.input_decoder;
+        let collapse_quiet_frames = app.collapse_quiet_frames;
+        let min_event_severity = app.min_event_severity;
         let Some(run) = app.runs.get_mut(app.focused_run_index) else {
             ui.centered_and_justified(|ui| {
                 ui.heading("No runs found");
@@ -27,7 +39,8 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
 
         let text_style = egui::TextStyle::Heading;
         let row_height = ui.text_style_height(&text_style);
-        let total_rows = run.frames.len() + 1;
+        let rows = run.rows(collapse_quiet_frames);
+        let total_rows = rows.len() + 1;
 
         ScrollArea::vertical().auto_shrink(false).show_rows(
             ui,
@@ -55,13 +68,46 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
                                 continue;
                             }
 
-                            let frame = row - 1;
+                            let (frame, expanded_segment) = match rows[row - 1] {
+                                FrameRow::Collapsed { start, end } => {
+                                    ui.heading(format!("{}-{}", start, end));
+                                    ui.add(Separator::default().vertical());
+                                    for _ in &run.players {
+                                        ui.label(format!("{} synced frames", end - start + 1));
+                                        ui.add(Separator::default().vertical());
+                                    }
+                                    if ui
+                                        .button(fill::CARET_DOWN)
+                                        .on_hover_text("Expand")
+                                        .clicked()
+                                    {
+                                        run.expanded_segments.insert(start);
+                                    }
+                                    ui.end_row();
+                                    continue;
+                                }
+                                FrameRow::Frame {
+                                    frame,
+                                    expanded_segment,
+                                } => (frame as usize, expanded_segment),
+                            };
 
                             let Some(frame_entries) = run.frames.get(&(frame as u64)).cloned()
                             else {
                                 continue;
                             };
-                            ui.heading(frame.to_string());
+                            ui.horizontal(|ui| {
+                                ui.heading(frame.to_string());
+                                if let Some(start) = expanded_segment {
+                                    if ui
+                                        .small_button(fill::CARET_UP)
+                                        .on_hover_text("Collapse")
+                                        .clicked()
+                                    {
+                                        run.expanded_segments.remove(&start);
+                                    }
+                                }
+                            });
                             ui.add(Separator::default().vertical());
 
                             for logger in run.players.clone().iter() {
@@ -73,7 +119,7 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
                                         if let Some(SentInput { input, .. }) =
                                             &player_entries.sent_input
                                         {
-                                            let color = if input == &Default::default() {
+                                            let color = if input.iter().all(|byte| *byte == 0) {
                                                 Color32::GRAY
                                             } else {
                                                 Color32::WHITE
@@ -87,27 +133,21 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
                                                 |ui| {
                                                     ui.vertical(|ui| {
                                                         ui.heading("Sent Input");
-                                                        ui.label(format!(
-                                                            "Movement: {:?}",
-                                                            input.movement
-                                                        ));
-                                                        ui.label(format!("Look: {:?}", input.look));
-                                                        ui.label(format!("Jump: {:?}", input.jump));
-                                                        ui.label(format!(
-                                                            "Punch: {:?}",
-                                                            input.punch
-                                                        ));
-                                                        if input.events.is_empty() {
-                                                            ui.label("Events: []");
+                                                        let decoded = input_decoder
+                                                            .as_ref()
+                                                            .and_then(|decoder| decoder(input));
+                                                        if let Some(lines) = decoded {
+                                                            for line in lines {
+                                                                ui.label(line);
+                                                            }
                                                         } else {
-                                                            ui.label("Events: [");
-                                                            for event in &input.events {
-                                                                ui.label(format!(
-                                                                    "    {:?}",
-                                                                    event
-                                                                ));
+                                                            ui.label(format!(
+                                                                "{} bytes",
+                                                                input.len()
+                                                            ));
+                                                            for row in hex_rows(input) {
+                                                                ui.monospace(row);
                                                             }
-                                                            ui.label("]");
                                                         }
                                                     });
                                                 },
@@ -156,10 +196,15 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
                                             start = rollback.rolled_back_to as usize;
                                         }
 
-                                        if player_entries.dropped_frame.is_some() {
+                                        if let Some(dropped_frame) = &player_entries.dropped_frame {
                                             ui.label(
                                                 RichText::new(fill::HAND_PALM).color(Color32::RED),
-                                            );
+                                            )
+                                            .on_hover_text(format!(
+                                                "Stalled {} ticks waiting on frame {}",
+                                                dropped_frame.stall_ticks,
+                                                dropped_frame.frame_missing_input
+                                            ));
                                         } else {
                                             let frames = if start == frame {
                                                 frame.to_string()
@@ -228,16 +273,27 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
                                             );
                                         }
 
-                                        if !player_entries.events.is_empty() {
-                                            let event_count = player_entries
-                                                .events
-                                                .values()
-                                                .map(|events| events.len())
-                                                .sum::<usize>();
+                                        let shown_events = player_entries
+                                            .events
+                                            .values()
+                                            .flatten()
+                                            .filter(|event| event.severity >= min_event_severity)
+                                            .collect::<Vec<_>>();
+                                        if !shown_events.is_empty() {
+                                            let max_severity = shown_events
+                                                .iter()
+                                                .map(|event| event.severity)
+                                                .max()
+                                                .unwrap_or_default();
                                             ui.window_button(
                                                 &(frame, "events"),
                                                 false,
-                                                format!("{}{}", fill::ARTICLE, event_count,),
+                                                RichText::new(format!(
+                                                    "{}{}",
+                                                    fill::ARTICLE,
+                                                    shown_events.len()
+                                                ))
+                                                .color(severity_color(max_severity)),
                                                 format!("P{} Events", player_number),
                                                 |ui| {
                                                     ui.vertical(|ui| {
@@ -246,18 +302,39 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
                                                                 .events
                                                                 .get(&(frame as u64))
                                                                 .cloned()
-                                                                .unwrap_or_else(|| BTreeSet::new());
+                                                                .unwrap_or_else(|| BTreeSet::new())
+                                                                .into_iter()
+                                                                .filter(|event| {
+                                                                    event.severity
+                                                                        >= min_event_severity
+                                                                })
+                                                                .collect::<Vec<_>>();
                                                             if !events.is_empty() {
                                                                 ui.heading(format!(
                                                                     "Frame {}",
                                                                     frame
                                                                 ));
                                                                 for node in events {
-                                                                    ui.label(format!(
-                                                                        "{}: {}",
-                                                                        node.event.clone(),
-                                                                        node.data.clone()
-                                                                    ));
+                                                                    let category = node
+                                                                        .category
+                                                                        .map(|category| {
+                                                                            format!(
+                                                                                "[{}] ",
+                                                                                category.name()
+                                                                            )
+                                                                        })
+                                                                        .unwrap_or_default();
+                                                                    ui.label(
+                                                                        RichText::new(format!(
+                                                                            "{}{}: {}",
+                                                                            category,
+                                                                            node.event,
+                                                                            node.data
+                                                                        ))
+                                                                        .color(severity_color(
+                                                                            node.severity,
+                                                                        )),
+                                                                    );
                                                                 }
                                                             }
                                                         }
@@ -297,6 +374,28 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
                                         format!("Frame {} State Disagreements", frame),
                                         |ui| {
                                             ui.vertical(|ui| {
+                                                if ui.button("Save Report").clicked() {
+                                                    let saved = run
+                                                        .save_desync_report(frame as u64)
+                                                        .map_err(|error| error.to_string());
+                                                    run.saved_reports.insert(frame as u64, saved);
+                                                }
+                                                match run.saved_reports.get(&(frame as u64)) {
+                                                    Some(Ok(report_path)) => {
+                                                        ui.label(format!(
+                                                            "Saved to {}",
+                                                            report_path.display()
+                                                        ));
+                                                    }
+                                                    Some(Err(error)) => {
+                                                        ui.colored_label(
+                                                            Color32::RED,
+                                                            format!("Could not save: {error}"),
+                                                        );
+                                                    }
+                                                    None => {}
+                                                }
+
                                                 for ((path, key), values) in disagreements {
                                                     let path = trim_path(path);
                                                     ui.heading(format!("{path}::{key}"));
@@ -327,3 +426,11 @@ pub fn show_content(app: &mut App, ctx: &egui::Context) {
         );
     });
 }
+
+fn severity_color(severity: EventSeverity) -> Color32 {
+    match severity {
+        EventSeverity::Info => Color32::GRAY,
+        EventSeverity::Warning => Color32::YELLOW,
+        EventSeverity::Error => Color32::RED,
+    }
+}
@@ -1,61 +1,13 @@
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use gdrollback::logging::{FrameState, SyncState};
 
-use gdrollback::{
-    logging::{
-        DroppedFrame, Event, FrameState, ReceivedInput, Rollback, RunInfo, SpawnedNodeAlive,
-    },
-    SentInput,
-};
-use uuid::Uuid;
-
-#[derive(Clone)]
-pub struct PlayerEntries {
-    pub run_info: Option<RunInfo>,
-    pub sent_input: Option<SentInput>,
-    pub received_inputs: Vec<ReceivedInput>,
-    pub rollback: Option<Rollback>,
-    pub dropped_frame: Option<DroppedFrame>,
-    pub frame_states: Vec<FrameState>,
-    pub spawned_nodes_alive: HashMap<u64, Vec<SpawnedNodeAlive>>,
-    pub events: HashMap<u64, BTreeSet<Event>>,
-}
-
-impl PlayerEntries {
-    pub fn contains_state(&self, highlighted_state: &Option<(String, String, u64)>) -> bool {
-        let Some((expected_path, expected_key, expected_hash)) = highlighted_state.as_ref() else {
-            return false;
-        };
-
-        for state in self.frame_states.iter() {
-            if &state.path == expected_path
-                && &state.key == expected_key
-                && &state.value_hash == expected_hash
-            {
-                return true;
-            }
-        }
-        false
-    }
-}
-
-#[derive(Clone, Debug, Hash)]
-pub struct Argument {
-    pub player: Uuid,
-    pub state: Option<FrameState>,
+/// Checks whether the highlighted state is present. A trait since SyncState is
+/// defined in gdrollback alongside the disagreement computation
+pub trait ContainsState {
+    fn contains_state(&self, highlighted_state: &Option<(String, String, u64)>) -> bool;
 }
 
-#[derive(Clone, Debug, Hash)]
-pub enum SyncState {
-    Synced {
-        consensus: Vec<FrameState>,
-    },
-    Desynced {
-        disagreements: BTreeMap<(String, String), Vec<Argument>>,
-    },
-}
-
-impl SyncState {
-    pub fn contains_state(&self, highlighted_state: &Option<(String, String, u64)>) -> bool {
+impl ContainsState for SyncState {
+    fn contains_state(&self, highlighted_state: &Option<(String, String, u64)>) -> bool {
         let Some((expected_path, expected_key, expected_hash)) = highlighted_state.as_ref() else {
             return false;
         };
@@ -92,9 +44,3 @@ impl SyncState {
         }
     }
 }
-
-#[derive(Clone)]
-pub struct FrameEntries {
-    pub player_entries: HashMap<Uuid, PlayerEntries>,
-    pub sync_state: SyncState,
-}
@@ -1,18 +1,23 @@
 mod content;
 mod entries;
 mod menu_bar;
+mod pacing;
 mod run;
 mod side_bar;
+mod timeline;
 mod util;
 mod window_button;
 
+use std::path::PathBuf;
+
 use content::show_content;
 use eframe::egui;
 use menu_bar::show_menu_bar;
+use pacing::show_pacing;
 use run::Run;
 use side_bar::show_side_bar;
 
-use gdrollback::logging::LogReader;
+use gdrollback::logging::{EventSeverity, LogReader};
 
 fn main() -> eframe::Result<()> {
     eframe::run_native(
@@ -22,9 +27,22 @@ fn main() -> eframe::Result<()> {
     )
 }
 
+/// Decodes the raw bytes of a sent input into lines of text for display.
+/// Returns None if the bytes are not in a format the decoder understands.
+pub type InputDecoder = Box<dyn Fn(&[u8]) -> Option<Vec<String>>>;
+
 pub struct App {
     pub focused_run_index: usize,
     pub runs: Vec<Run>,
+    pub input_decoder: Option<InputDecoder>,
+    /// Fold runs of synced frames without rollbacks into single rows
+    pub collapse_quiet_frames: bool,
+    /// Events below this severity are hidden
+    pub min_event_severity: EventSeverity,
+    /// Show the window charting each player's frame pacing
+    pub show_pacing: bool,
+    /// Where the last exported timeline was saved, or why saving it failed
+    pub saved_timeline: Option<Result<PathBuf, String>>,
 }
 
 impl App {
@@ -33,6 +51,11 @@ impl App {
         let mut app: App = App {
             focused_run_index: 0,
             runs: Vec::new(),
+            input_decoder: None,
+            collapse_quiet_frames: false,
+            min_event_severity: EventSeverity::Info,
+            show_pacing: false,
+            saved_timeline: None,
         };
 
         app.update_data();
@@ -46,6 +69,17 @@ impl App {
         app
     }
 
+    /// Registers a decoder used to display sent inputs. Inputs the decoder
+    /// can't handle fall back to a raw hex view.
+    #[allow(dead_code)]
+    pub fn with_input_decoder(
+        mut self,
+        decoder: impl Fn(&[u8]) -> Option<Vec<String>> + 'static,
+    ) -> Self {
+        self.input_decoder = Some(Box::new(decoder));
+        self
+    }
+
     pub fn update_data(&mut self) {
         self.runs.clear();
         for (edited, run_id) in LogReader::list_runs().unwrap() {
@@ -79,5 +113,6 @@ impl eframe::App for App {
         show_menu_bar(self, ctx);
         show_side_bar(self, ctx);
         show_content(self, ctx);
+        show_pacing(self, ctx);
     }
 }
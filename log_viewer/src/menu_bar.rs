@@ -1,5 +1,5 @@
-use egui::{Button, TopBottomPanel};
-use gdrollback::logging::{log_file_directory, LogReader};
+use egui::{Button, Color32, ComboBox, TopBottomPanel};
+use gdrollback::logging::{log_file_directory, EventSeverity, LogReader};
 
 use crate::App;
 
@@ -45,6 +45,40 @@ pub fn show_menu_bar(app: &mut App, ctx: &egui::Context) {
 
                 app.update_data();
             }
+
+            if ui
+                .add_enabled(
+                    app.runs.len() > app.focused_run_index,
+                    Button::new("Export Timeline"),
+                )
+                .clicked()
+            {
+                app.saved_timeline = Some(
+                    app.runs[app.focused_run_index]
+                        .save_timeline()
+                        .map_err(|error| error.to_string()),
+                );
+            }
+            match &app.saved_timeline {
+                Some(Ok(timeline_path)) => {
+                    ui.label(format!("Saved to {}", timeline_path.display()));
+                }
+                Some(Err(error)) => {
+                    ui.colored_label(Color32::RED, format!("Could not save timeline: {error}"));
+                }
+                None => {}
+            }
+
+            ui.checkbox(&mut app.collapse_quiet_frames, "Collapse Synced Frames");
+            ui.checkbox(&mut app.show_pacing, "Show Pacing");
+
+            ComboBox::from_label("Minimum Event Severity")
+                .selected_text(app.min_event_severity.name())
+                .show_ui(ui, |ui| {
+                    for severity in EventSeverity::ALL {
+                        ui.selectable_value(&mut app.min_event_severity, severity, severity.name());
+                    }
+                });
         });
     });
 }
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use egui::{pos2, Align2, Color32, FontId, Pos2, Sense, Shape, Stroke, Vec2, Window};
+use gdrollback::logging::FramePacing;
+
+use crate::App;
+
+/// Height in pixels of each player's pacing chart
+const CHART_HEIGHT: f32 = 80.0;
+/// Number of tick periods the height of a chart covers. Longer frames, such
+/// as stalls, are clipped to the top
+const CHART_PERIODS: u32 = 4;
+
+/// Charts the time between each frame and the one before it for every player
+/// in the focused run, with the ideal tick period as a gray line and frames
+/// which overran it marked in red
+pub fn show_pacing(app: &mut App, ctx: &egui::Context) {
+    let Some(run) = app.runs.get(app.focused_run_index) else {
+        return;
+    };
+
+    Window::new("Pacing")
+        .open(&mut app.show_pacing)
+        .default_width(600.0)
+        .show(ctx, |ui| {
+            for player in &run.players {
+                let pacing = run.pacing.get(player).map_or(&[][..], |pacing| pacing);
+                let overruns = pacing.iter().filter(|pacing| pacing.overran()).count();
+                ui.horizontal(|ui| {
+                    ui.label("Player ");
+                    ui.label(run.player_label(*player));
+                    ui.label(format!("{overruns} frames overran"));
+                });
+                pacing_chart(ui, pacing);
+            }
+        });
+}
+
+fn pacing_chart(ui: &mut egui::Ui, pacing: &[FramePacing]) {
+    let size = Vec2::new(ui.available_width(), CHART_HEIGHT);
+    let (response, painter) = ui.allocate_painter(size, Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+
+    let (Some(first), Some(last)) = (pacing.first(), pacing.last()) else {
+        painter.text(
+            rect.center(),
+            Align2::CENTER_CENTER,
+            "No tick times logged",
+            FontId::default(),
+            ui.visuals().text_color(),
+        );
+        return;
+    };
+
+    let frame_span = (last.frame - first.frame).max(1) as f32;
+    let x = |frame: u64| rect.left() + (frame - first.frame) as f32 / frame_span * rect.width();
+    let y = |delta: Duration, ideal: Duration| {
+        let fraction = delta.as_secs_f32() / (ideal * CHART_PERIODS).as_secs_f32();
+        rect.bottom() - fraction.min(1.0) * rect.height()
+    };
+    let point = |pacing: &FramePacing| pos2(x(pacing.frame), y(pacing.delta, pacing.ideal));
+
+    let ideal_y = y(first.ideal, first.ideal);
+    painter.line_segment(
+        [pos2(rect.left(), ideal_y), pos2(rect.right(), ideal_y)],
+        Stroke::new(1.0, Color32::GRAY),
+    );
+    painter.add(Shape::line(
+        pacing.iter().map(point).collect::<Vec<Pos2>>(),
+        Stroke::new(1.0, Color32::GREEN),
+    ));
+    for overran in pacing.iter().filter(|pacing| pacing.overran()) {
+        painter.circle_filled(point(overran), 2.0, Color32::RED);
+    }
+
+    if let Some(hovered) = response
+        .hover_pos()
+        .and_then(|position| hovered_frame(pacing, (position.x - rect.left()) / rect.width()))
+    {
+        response.on_hover_text_at_pointer(format!(
+            "Frame {}\n{:.1}ms (ideal {:.1}ms)",
+            hovered.frame,
+            hovered.delta.as_secs_f64() * 1000.0,
+            hovered.ideal.as_secs_f64() * 1000.0,
+        ));
+    }
+}
+
+/// The frame closest to the given fraction of the way across the chart
+fn hovered_frame(pacing: &[FramePacing], fraction: f32) -> Option<&FramePacing> {
+    let first = pacing.first()?.frame;
+    let last = pacing.last()?.frame;
+    let frame = first + (fraction.clamp(0.0, 1.0) * (last - first) as f32) as u64;
+    pacing
+        .iter()
+        .min_by_key(|pacing| pacing.frame.abs_diff(frame))
+}
@@ -1,17 +1,19 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    path::PathBuf,
     time::SystemTime,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use egui::{Color32, Label, RichText, Sense};
 use itertools::Itertools;
 use uuid::Uuid;
 
-use gdrollback::logging::{FrameState, LogEntry, LogReader};
+use gdrollback::logging::{log_file_directory, FrameBundle, FramePacing, FrameState, LogReader};
 
 use crate::{
-    entries::{Argument, FrameEntries, PlayerEntries, SyncState},
+    timeline::timeline_svg,
     util::{small_text, trim_path},
 };
 
@@ -26,13 +28,54 @@ const PLAYER_COLORS: &[Color32] = &[
     Color32::LIGHT_YELLOW,
 ];
 
+/// A row of the frame grid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameRow {
+    /// A single frame. Holds the start of its segment if the frame is the
+    /// first of an expanded segment so that it can be collapsed again
+    Frame {
+        frame: u64,
+        expanded_segment: Option<u64>,
+    },
+    /// A segment of quiet frames folded into a single row
+    Collapsed { start: u64, end: u64 },
+}
+
+/// Splits the frames into segments of consecutive frames which are either all
+/// quiet or all not. Returns each segment's range and whether it is quiet.
+pub fn frame_segments(frames: &HashMap<u64, FrameBundle>) -> Vec<(RangeInclusive<u64>, bool)> {
+    let mut segments: Vec<(RangeInclusive<u64>, bool)> = Vec::new();
+    for frame in frames.keys().copied().sorted() {
+        let quiet = frames[&frame].is_quiet();
+        match segments.last_mut() {
+            Some((range, segment_quiet))
+                if *segment_quiet == quiet && *range.end() + 1 == frame =>
+            {
+                *range = *range.start()..=frame;
+            }
+            _ => segments.push((frame..=frame, quiet)),
+        }
+    }
+    segments
+}
+
 pub struct Run {
     pub log_reader: Option<LogReader>,
     pub id: Uuid,
     pub players: Vec<Uuid>,
-    pub frames: HashMap<u64, FrameEntries>,
+    pub frames: HashMap<u64, FrameBundle>,
     pub edited: SystemTime,
     pub highlighted_state: Option<(String, String, u64)>,
+    /// Starts of the quiet segments the user expanded while collapsing
+    pub expanded_segments: HashSet<u64>,
+    /// Every distinct value logged for each state hash shared by more than
+    /// one value
+    pub hash_collisions: HashMap<u64, Vec<String>>,
+    /// Each player's frame intervals compared with the ideal tick period
+    pub pacing: HashMap<Uuid, Vec<FramePacing>>,
+    /// Where the desync report for each frame was saved, or why saving it
+    /// failed
+    pub saved_reports: HashMap<u64, Result<PathBuf, String>>,
 }
 
 impl Default for Run {
@@ -44,6 +87,10 @@ impl Default for Run {
             frames: HashMap::new(),
             edited: SystemTime::UNIX_EPOCH,
             highlighted_state: None,
+            expanded_segments: HashSet::new(),
+            hash_collisions: HashMap::new(),
+            pacing: HashMap::new(),
+            saved_reports: HashMap::new(),
         }
     }
 }
@@ -97,10 +144,21 @@ impl Run {
         }: &FrameState,
     ) {
         let hash_text = small_text(*value_hash);
-        if ui
-            .add(Label::new(format!("{value_text}#{hash_text}")).sense(Sense::click()))
-            .clicked()
-        {
+        // Colliding values would compare as synced even though they differ
+        let collision = self.hash_collisions.get(value_hash);
+        let text = match collision {
+            Some(_) => RichText::new(format!("{value_text}#{hash_text} (hash collision)"))
+                .color(Color32::YELLOW),
+            None => RichText::new(format!("{value_text}#{hash_text}")),
+        };
+        let mut response = ui.add(Label::new(text).sense(Sense::click()));
+        if let Some(value_texts) = collision {
+            response = response.on_hover_text(format!(
+                "Hash shared by different values:\n{}",
+                value_texts.join("\n")
+            ));
+        }
+        if response.clicked() {
             let clicked_state = Some((path.clone(), key.clone(), *value_hash));
             if self.highlighted_state == clicked_state {
                 self.highlighted_state = None;
@@ -110,6 +168,59 @@ impl Run {
         }
     }
 
+    /// The rows to show in the frame grid. When collapsing, quiet segments
+    /// longer than one frame are folded into a single row unless expanded.
+    pub fn rows(&self, collapse_quiet: bool) -> Vec<FrameRow> {
+        let mut rows = Vec::new();
+        for (segment, quiet) in frame_segments(&self.frames) {
+            let (start, end) = (*segment.start(), *segment.end());
+            if collapse_quiet && quiet && start != end {
+                if self.expanded_segments.contains(&start) {
+                    rows.extend(segment.map(|frame| FrameRow::Frame {
+                        frame,
+                        expanded_segment: (frame == start).then_some(start),
+                    }));
+                } else {
+                    rows.push(FrameRow::Collapsed { start, end });
+                }
+            } else {
+                rows.extend(segment.map(|frame| FrameRow::Frame {
+                    frame,
+                    expanded_segment: None,
+                }));
+            }
+        }
+        rows
+    }
+
+    /// Writes the desync report for the frame to the reports folder in the
+    /// log directory and returns the path written to.
+    pub fn save_desync_report(&self, frame: u64) -> Result<PathBuf> {
+        let report = self
+            .log_reader
+            .as_ref()
+            .ok_or(anyhow!("Run has not been loaded"))?
+            .desync_report(frame)?;
+
+        let report_directory = log_file_directory()?.join("reports");
+        std::fs::create_dir_all(&report_directory)?;
+        let report_path = report_directory.join(format!("{}_frame_{frame}_desync.txt", self.id));
+        std::fs::write(&report_path, report)?;
+
+        Ok(report_path)
+    }
+
+    /// Writes an svg of the whole run's frame timeline to the reports folder
+    /// in the log directory and returns the path written to.
+    pub fn save_timeline(&self) -> Result<PathBuf> {
+        let report_directory = log_file_directory()?.join("reports");
+        std::fs::create_dir_all(&report_directory)?;
+        let timeline_path = report_directory.join(format!("{}_timeline.svg", self.id));
+        std::fs::write(&timeline_path, timeline_svg(&self.players, &self.frames))?;
+
+        Ok(timeline_path)
+    }
+
     pub fn update_data(&mut self) -> Result<()> {
         let log_reader = if let Some(log_reader) = self.log_reader.as_ref() {
             log_reader
@@ -119,147 +230,128 @@ impl Run {
         };
 
         self.players = log_reader.players()?;
-        let entries_by_frame = log_reader
-            .log_entries()?
+        self.hash_collisions = log_reader
+            .hash_collisions()?
             .into_iter()
-            .into_group_map_by(|entry| entry.frame());
-        let frame_count = log_reader.frame_count()?;
-
-        self.frames.clear();
-        for frame in 0..frame_count {
-            let Some(entries) = entries_by_frame.get(&(frame as u64)) else {
-                continue;
-            };
-
-            let mut frame_entries = FrameEntries {
-                player_entries: HashMap::new(),
-                sync_state: SyncState::Synced {
-                    consensus: Default::default(),
-                },
-            };
-            let entries_by_player: HashMap<Uuid, Vec<LogEntry>> = entries
-                .into_iter()
-                .into_group_map_by(|entry| entry.logger())
-                .into_iter()
-                .map(|(logger, entries)| {
-                    (
-                        logger,
-                        entries.into_iter().cloned().collect::<Vec<LogEntry>>(),
-                    )
-                })
-                .collect();
-
-            for logger in self.players.iter() {
-                let mut player_entries = PlayerEntries {
-                    run_info: None,
-                    sent_input: None,
-                    received_inputs: Vec::new(),
-                    rollback: None,
-                    dropped_frame: None,
-                    frame_states: Vec::new(),
-                    spawned_nodes_alive: HashMap::new(),
-                    events: HashMap::new(),
-                };
+            .map(|collision| (collision.value_hash, collision.value_texts))
+            .collect();
+        self.frames = log_reader.frames()?.collect();
+        self.pacing = self
+            .players
+            .iter()
+            .map(|player| Ok((*player, log_reader.pacing(*player)?)))
+            .collect::<Result<_>>()?;
 
-                for entry in entries_by_player.get(logger).unwrap_or(&Vec::new()) {
-                    match entry {
-                        LogEntry::RunInfo(entry) => {
-                            player_entries.run_info = Some(entry.clone());
-                        }
-                        LogEntry::SentInput(entry) => {
-                            player_entries.sent_input = Some(entry.clone());
-                        }
-                        LogEntry::ReceivedInput(entry) => {
-                            player_entries.received_inputs.push(entry.clone());
-                        }
-                        LogEntry::Rollback(entry) => {
-                            player_entries.rollback = Some(entry.clone());
-                        }
-                        LogEntry::DroppedFrame(entry) => {
-                            player_entries.dropped_frame = Some(entry.clone());
-                        }
-                        LogEntry::FrameState(entry) => {
-                            player_entries.frame_states.push(entry.clone());
-                        }
-                        LogEntry::SpawnedNodeAlive(entry) => {
-                            player_entries
-                                .spawned_nodes_alive
-                                .entry(entry.frame)
-                                .or_insert_with(Vec::new)
-                                .push(entry.clone());
-                        }
-                        LogEntry::Event(entry) => {
-                            player_entries
-                                .events
-                                .entry(entry.frame)
-                                .or_insert_with(BTreeSet::new)
-                                .insert(entry.clone());
-                        }
-                    }
-                }
+        Ok(())
+    }
+}
 
-                frame_entries.player_entries.insert(*logger, player_entries);
-            }
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
 
-            // Compute desyncs
-            let latest_states = self
-                .players
-                .iter()
-                .filter_map(|player| {
-                    log_reader
-                        .latest_states_for_frame(*player, frame)
-                        .ok()
-                        .map(|states| (*player, states))
-                })
-                .collect::<Vec<_>>();
-
-            if let Some((_, sentinel)) = latest_states.get(0) {
-                if latest_states.iter().all(|(_, states)| {
-                    states
-                        .iter()
-                        .zip(sentinel.iter())
-                        .all(|(a, b)| a.value_hash == b.value_hash)
-                }) {
-                    frame_entries.sync_state = SyncState::Synced {
-                        consensus: sentinel.clone(),
-                    };
-                } else {
-                    let mut disagreements = BTreeMap::new();
-                    let keys: HashSet<(String, String)> = latest_states
-                        .iter()
-                        .flat_map(|(_, states)| {
-                            states
-                                .iter()
-                                .map(|state| (state.path.clone(), state.key.clone()))
-                        })
-                        .collect();
-                    for (path, key) in keys.into_iter() {
-                        for (player, player_states) in latest_states.iter() {
-                            let state = player_states
-                                .iter()
-                                .find(|state| state.path == path && state.key == key)
-                                .cloned();
-                            let arguments = disagreements
-                                .entry((path.clone(), key.clone()))
-                                .or_insert_with(|| Vec::new());
-                            arguments.push(Argument {
-                                player: *player,
-                                state,
-                            });
-                        }
-                    }
-                    disagreements.retain(|_, arguments| {
-                        let first = arguments[0].state.as_ref().map(|state| state.value_hash);
-                        arguments.iter().any(|argument| {
-                            argument.state.as_ref().map(|state| state.value_hash) != first
-                        })
-                    });
-                    frame_entries.sync_state = SyncState::Desynced { disagreements };
-                }
-            }
-            self.frames.insert(frame, frame_entries);
+    use gdrollback::logging::{FrameBundle, PlayerEntries, Rollback, SyncState};
+    use uuid::Uuid;
+
+    use super::{frame_segments, FrameRow, Run};
+
+    enum Status {
+        Quiet,
+        RolledBack,
+        Desynced,
+    }
+
+    fn frame_bundle(player: Uuid, status: Status) -> FrameBundle {
+        let rollback = matches!(status, Status::RolledBack).then(|| Rollback {
+            frame: 0,
+            rolled_back_to: 0,
+            updater: player,
+        });
+        let sync_state = match status {
+            Status::Desynced => SyncState::Desynced {
+                disagreements: BTreeMap::new(),
+            },
+            _ => SyncState::Synced {
+                consensus: Vec::new(),
+            },
+        };
+        FrameBundle {
+            player_entries: HashMap::from([(
+                player,
+                PlayerEntries {
+                    rollback,
+                    ..Default::default()
+                },
+            )]),
+            sync_state,
         }
+    }
 
-        Ok(())
+    /// Quiet frames 0 through 3, a rollback on 4, a desync on 5, quiet frames
+    /// 6 through 8, nothing logged for 9, and quiet frames 10 and 11
+    fn synthetic_frames() -> HashMap<u64, FrameBundle> {
+        let player = Uuid::new_v4();
+        (0..12)
+            .filter(|frame| *frame != 9)
+            .map(|frame| {
+                let status = match frame {
+                    4 => Status::RolledBack,
+                    5 => Status::Desynced,
+                    _ => Status::Quiet,
+                };
+                (frame, frame_bundle(player, status))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn segments_split_on_status_changes_and_gaps() {
+        assert_eq!(
+            frame_segments(&synthetic_frames()),
+            vec![
+                (0..=3, true),
+                (4..=5, false),
+                (6..=8, true),
+                (10..=11, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn quiet_segments_collapse_unless_expanded() {
+        let mut run = Run {
+            frames: synthetic_frames(),
+            ..Default::default()
+        };
+        run.expanded_segments.insert(6);
+
+        assert_eq!(
+            run.rows(true),
+            vec![
+                FrameRow::Collapsed { start: 0, end: 3 },
+                FrameRow::Frame {
+                    frame: 4,
+                    expanded_segment: None
+                },
+                FrameRow::Frame {
+                    frame: 5,
+                    expanded_segment: None
+                },
+                FrameRow::Frame {
+                    frame: 6,
+                    expanded_segment: Some(6)
+                },
+                FrameRow::Frame {
+                    frame: 7,
+                    expanded_segment: None
+                },
+                FrameRow::Frame {
+                    frame: 8,
+                    expanded_segment: None
+                },
+                FrameRow::Collapsed { start: 10, end: 11 },
+            ]
+        );
+        assert_eq!(run.rows(false).len(), 11);
     }
 }
@@ -0,0 +1,156 @@
+use std::{collections::HashMap, fmt::Write};
+
+use egui::Color32;
+use gdrollback::logging::{FrameBundle, SyncState};
+use itertools::Itertools;
+use uuid::Uuid;
+
+/// Width in pixels of a single frame in the exported timeline
+const FRAME_WIDTH: u64 = 2;
+/// Height in pixels of each player's row in the exported timeline
+const PLAYER_HEIGHT: u64 = 16;
+
+/// What happened to a player on a single frame, worst first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameStatus {
+    Dropped,
+    Desynced,
+    Rollback,
+    Synced,
+}
+
+impl FrameStatus {
+    fn of(frame_entries: &FrameBundle, player: &Uuid) -> Option<FrameStatus> {
+        let player_entries = frame_entries.player_entries.get(player)?;
+        Some(if player_entries.dropped_frame.is_some() {
+            FrameStatus::Dropped
+        } else if matches!(frame_entries.sync_state, SyncState::Desynced { .. }) {
+            FrameStatus::Desynced
+        } else if player_entries.rollback.is_some() {
+            FrameStatus::Rollback
+        } else {
+            FrameStatus::Synced
+        })
+    }
+
+    fn color(self) -> Color32 {
+        match self {
+            FrameStatus::Dropped => Color32::RED,
+            FrameStatus::Desynced => Color32::from_rgb(200, 0, 200),
+            FrameStatus::Rollback => Color32::YELLOW,
+            FrameStatus::Synced => Color32::GREEN,
+        }
+    }
+}
+
+/// Renders a run's frames as an svg with a row per player and a column per
+/// frame, colored by what happened to the player on that frame. Frames a
+/// player didn't log are left blank.
+pub fn timeline_svg(players: &[Uuid], frames: &HashMap<u64, FrameBundle>) -> String {
+    let frame_count = frames.keys().max().map_or(0, |frame| frame + 1);
+    let width = frame_count * FRAME_WIDTH;
+    let height = players.len() as u64 * PLAYER_HEIGHT;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n"
+    );
+    for (row, player) in players.iter().enumerate() {
+        let y = row as u64 * PLAYER_HEIGHT;
+        // Neighboring frames with the same status are merged into one rect to
+        // keep long runs small
+        let statuses = (0..frame_count).map(|frame| {
+            frames
+                .get(&frame)
+                .and_then(|frame_entries| FrameStatus::of(frame_entries, player))
+        });
+        let mut start = 0;
+        for (status, frames) in &statuses.group_by(|status| *status) {
+            let length = frames.count() as u64;
+            if let Some(status) = status {
+                let color = status.color();
+                writeln!(
+                    svg,
+                    "  <rect x=\"{}\" y=\"{y}\" width=\"{}\" height=\"{PLAYER_HEIGHT}\" fill=\"#{:02x}{:02x}{:02x}\"/>",
+                    start * FRAME_WIDTH,
+                    length * FRAME_WIDTH,
+                    color.r(),
+                    color.g(),
+                    color.b(),
+                )
+                .expect("Writing to a string can't fail");
+            }
+            start += length;
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use gdrollback::logging::{FrameBundle, PlayerEntries, Rollback, SyncState};
+    use uuid::Uuid;
+
+    use super::{timeline_svg, FRAME_WIDTH, PLAYER_HEIGHT};
+
+    fn frame_bundle(players: &[Uuid], rolled_back: Option<Uuid>) -> FrameBundle {
+        let player_entries = players
+            .iter()
+            .map(|player| {
+                let rollback = (rolled_back == Some(*player)).then(|| Rollback {
+                    frame: 0,
+                    rolled_back_to: 0,
+                    updater: *player,
+                });
+                (
+                    *player,
+                    PlayerEntries {
+                        rollback,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        FrameBundle {
+            player_entries,
+            sync_state: SyncState::Synced {
+                consensus: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn timeline_covers_every_frame_and_player() {
+        let players = [Uuid::new_v4(), Uuid::new_v4()];
+        // The second player rolls back on frame 5 and frame 3 wasn't logged
+        let frames = (0..10)
+            .filter(|frame| *frame != 3)
+            .map(|frame| {
+                let rolled_back = (frame == 5).then_some(players[1]);
+                (frame, frame_bundle(&players, rolled_back))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let svg = timeline_svg(&players, &frames);
+
+        let (width, height) = (10 * FRAME_WIDTH, 2 * PLAYER_HEIGHT);
+        assert!(svg.starts_with(&format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\""
+        )));
+        assert!(svg.ends_with("</svg>\n"));
+        // Each player's row is split by the missing frame, and the second
+        // player's again around the rollback
+        assert_eq!(svg.matches("<rect").count(), 2 + 4);
+        assert_eq!(svg.matches("fill=\"#ffff00\"").count(), 1);
+    }
+
+    #[test]
+    fn empty_run_renders_an_empty_image() {
+        let svg = timeline_svg(&[], &HashMap::new());
+
+        assert!(svg.contains("width=\"0\" height=\"0\""));
+        assert!(!svg.contains("<rect"));
+    }
+}
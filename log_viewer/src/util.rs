@@ -17,3 +17,40 @@ pub fn small_text(n: u64) -> String {
 pub fn trim_path(path: &str) -> &str {
     path.trim_start_matches("/root/World/")
 }
+
+// Format bytes as rows of space separated hex pairs so that inputs
+// with an unknown encoding can still be inspected.
+pub fn hex_rows(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(16)
+        .map(|row| {
+            row.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::hex_rows;
+
+    #[test]
+    fn hex_rows_split_every_sixteen_bytes() {
+        let bytes: Vec<u8> = (0..20).chain([0xab, 0xff]).collect();
+
+        assert_eq!(
+            hex_rows(&bytes),
+            vec![
+                "00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f",
+                "10 11 12 13 ab ff",
+            ]
+        );
+    }
+
+    #[test]
+    fn hex_rows_of_nothing_is_empty() {
+        assert!(hex_rows(&[]).is_empty());
+    }
+}
@@ -1,16 +1,24 @@
 use std::{
-    net::{SocketAddr, ToSocketAddrs},
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
     time::Duration,
 };
 
 use anyhow::Result;
+use godot::builtin::{Callable, Variant};
 use uuid::Uuid;
 
-use udp_ext::persistent::{PersistentEvent, PersistentSocket, PersistentSocketSender};
+use udp_ext::{
+    frame::FrameId,
+    persistent::{PersistentEvent, PersistentSocket, PersistentSocketSender},
+    reliable::ReceiveError,
+    trace::PacketTraceEvent,
+};
 
 use crate::{
     logging::{LogWriter, RunInfo},
     message::Message,
+    play_stage::{DisconnectPolicy, InputPrediction, DEFAULT_MAX_REWIND, MIN_MAX_REWIND},
 };
 
 pub struct Context {
@@ -21,6 +29,90 @@ pub struct Context {
     socket: PersistentSocket<Uuid>,
 
     replay_overrides: Option<RunInfo>,
+    /// Frames carrying local inputs which each peer may not have received yet,
+    /// keyed by the input tick
+    unacked_inputs: HashMap<Uuid, BTreeMap<u64, FrameId>>,
+    /// Connected peers which watch the match without playing. They are sent
+    /// inputs and state hashes but are never waited on
+    spectators: HashSet<Uuid>,
+
+    /// Ticks simulated per second. Every peer must use the same rate
+    simulation_rate: u32,
+    /// Ticks after the first two before local input is recorded. Every peer
+    /// must use the same offset
+    start_offset: u32,
+    /// Minimum number of frames between advantage based stalls
+    min_stall_interval: u64,
+    /// Whether frames are dropped to let peers which are behind catch up
+    advantage_stalling: bool,
+    /// Called with each remote input and the sender's id before the input is
+    /// applied. Returns the input to apply or null to reject it
+    input_validator: Option<Callable>,
+    /// Drive spawns and despawns in replays from the logged events rather
+    /// than from game code
+    faithful_replay: bool,
+    /// Replays deliver every logged message on the tick it was applied
+    /// rather than only inputs
+    network_replay: bool,
+    /// Pass the local input events buffered since the last tick to
+    /// networked_input instead of sampling input once per tick
+    buffered_input: bool,
+    input_buffer: Vec<Variant>,
+    /// Hold received inputs for a delay sized from the variation in response
+    /// times before applying them
+    jitter_buffer: bool,
+    /// Size each peer's rewind window from its round trip time instead of
+    /// using the same window for every peer
+    adaptive_rewind: bool,
+    /// Keep despawned nodes out of the tree and reuse them for later spawns
+    /// of the same scene instead of freeing them
+    node_pooling: bool,
+
+    disconnect_policy: DisconnectPolicy,
+    input_prediction: InputPrediction,
+    /// Rewind window used by matches started from now on
+    max_rewind: u64,
+    /// Ticks between sampling the local input and applying it
+    input_delay: u64,
+    /// Raise the input delay while rollbacks are frequent and lower it back
+    /// once they settle
+    adaptive_input_delay: bool,
+    /// Consecutive ticks the last local input is repeated for when the input
+    /// source returns nothing. 0 disables repeating
+    input_repeat_limit: u32,
+    /// Floats in logged states are rounded to a multiple of this before
+    /// hashing, if set, so that tiny rounding differences don't desync
+    state_hash_epsilon: Option<f64>,
+    /// States are only written to the log on frames which are a multiple of
+    /// this. They are still hashed every frame
+    state_log_interval: u64,
+    /// Frames on which a serialized snapshot of the node states is saved for
+    /// rollbacks to start from. 0 disables checkpoints
+    checkpoint_interval: u64,
+    /// Ticks a peer's estimated clock may drift from its baseline before a
+    /// warning is raised. 0 disables the check
+    clock_drift_threshold: f64,
+    /// Spawns game code may make while simulating a single frame before
+    /// further spawns are refused. None allows any number
+    spawn_cap: Option<usize>,
+    /// Number of packet events kept by the socket's trace, if tracing is
+    /// enabled. Kept here so that rebinding the socket keeps tracing
+    packet_trace_capacity: Option<usize>,
+    /// Number of unacknowledged packets a peer may have before broadcasts
+    /// skip it
+    max_in_flight: Option<usize>,
+    /// Set once the socket stops receiving because of a socket error. Cleared
+    /// by rebinding
+    socket_failed: bool,
+
+    /// Set while ticks are stepped manually rather than by physics_process.
+    /// The play stage then requests execute_tick instead of deferring it
+    stepping: bool,
+    execute_tick_requested: bool,
+
+    /// Signals raised by stages. Emitted deferred by the sync manager so that
+    /// handlers can safely call back into it
+    queued_signals: Vec<(&'static str, Vec<Variant>)>,
 }
 
 impl Context {
@@ -30,20 +122,59 @@ impl Context {
             current_tick: 0,
             latest_tick: 0,
             logger: LogWriter::new(),
-            socket: PersistentSocket::bind(0).expect("Could not bind random port"),
+            socket: bind_socket(Ipv4Addr::UNSPECIFIED.into(), 0)
+                .expect("Could not bind random port"),
 
             replay_overrides: None,
+            unacked_inputs: HashMap::new(),
+            spectators: HashSet::new(),
+
+            simulation_rate: 60,
+            start_offset: 0,
+            min_stall_interval: 0,
+            advantage_stalling: true,
+            input_validator: None,
+            faithful_replay: false,
+            network_replay: false,
+            buffered_input: false,
+            input_buffer: Vec::new(),
+            jitter_buffer: false,
+            adaptive_rewind: false,
+            node_pooling: false,
+            disconnect_policy: DisconnectPolicy::default(),
+            input_prediction: InputPrediction::default(),
+            max_rewind: DEFAULT_MAX_REWIND,
+            input_delay: 0,
+            adaptive_input_delay: false,
+            input_repeat_limit: 0,
+            state_hash_epsilon: None,
+            state_log_interval: 1,
+            checkpoint_interval: 0,
+            clock_drift_threshold: 10.0,
+            spawn_cap: None,
+            packet_trace_capacity: None,
+            max_in_flight: None,
+            socket_failed: false,
+
+            stepping: false,
+            execute_tick_requested: false,
+
+            queued_signals: Vec::new(),
         }
     }
 
     pub fn set_replay(&mut self, overrides: RunInfo) {
-        self.logger.disable();
+        self.logger
+            .disable(self)
+            .expect("Could not log logging paused");
         self.replay_overrides = Some(overrides);
     }
 
     pub fn clear_replay(&mut self) {
-        self.logger.enable();
         self.replay_overrides = None;
+        self.logger
+            .enable(self)
+            .expect("Could not log logging resumed");
     }
 
     pub fn local_id(&self) -> Uuid {
@@ -53,11 +184,30 @@ impl Context {
             .unwrap_or_else(|| self.local_id)
     }
 
+    /// The connected players, not counting spectators
     pub fn peers(&self) -> Vec<Uuid> {
         self.replay_overrides
             .as_ref()
             .map(|overrides| overrides.peers.clone())
-            .unwrap_or_else(|| self.socket.peers())
+            .unwrap_or_else(|| {
+                let mut peers = self.socket.peers();
+                peers.retain(|peer| !self.spectators.contains(peer));
+                peers
+            })
+    }
+
+    pub fn spectators(&self) -> Vec<Uuid> {
+        self.spectators.iter().copied().collect()
+    }
+
+    pub fn is_spectator(&self, peer: Uuid) -> bool {
+        self.spectators.contains(&peer)
+    }
+
+    /// Connects to a peer which watches the match without playing
+    pub fn connect_spectator(&mut self, peer: Uuid, address: SocketAddr) {
+        self.connect(peer, address);
+        self.spectators.insert(peer);
     }
 
     /// The leader is the peer with the lowest Uuid in the group. This is an arbitrary
@@ -87,6 +237,261 @@ impl Context {
         self.current_tick = tick;
     }
 
+    /// Moves both ticks to the given tick, for joining a match in progress
+    pub fn start_at_tick(&mut self, tick: u64) {
+        self.current_tick = tick;
+        self.latest_tick = tick;
+    }
+
+    pub fn reset_ticks(&mut self) {
+        self.current_tick = 0;
+        self.latest_tick = 0;
+    }
+
+    pub fn simulation_rate(&self) -> u32 {
+        self.simulation_rate
+    }
+
+    pub fn set_simulation_rate(&mut self, simulation_rate: u32) {
+        self.simulation_rate = simulation_rate;
+    }
+
+    pub fn start_offset(&self) -> u32 {
+        self.start_offset
+    }
+
+    pub fn set_start_offset(&mut self, start_offset: u32) {
+        self.start_offset = start_offset;
+    }
+
+    /// Number of whole ticks which fit in the duration at the simulation rate
+    pub fn duration_ticks(&self, duration: Duration) -> u32 {
+        duration_ticks(duration, self.simulation_rate)
+    }
+
+    pub fn min_stall_interval(&self) -> u64 {
+        self.min_stall_interval
+    }
+
+    /// Limits advantage based stalls to at most one every `frames` frames. On
+    /// a persistently asymmetric connection this keeps the faster peer from
+    /// hitching constantly at the cost of more rollbacks.
+    pub fn set_min_stall_interval(&mut self, frames: u64) {
+        self.min_stall_interval = frames;
+    }
+
+    pub fn advantage_stalling(&self) -> bool {
+        self.advantage_stalling
+    }
+
+    /// Disabling advantage based stalls leaves catching up entirely to
+    /// rollback. Stalls waiting on input older than the rewind max still
+    /// happen since those frames can't be rolled back to.
+    pub fn set_advantage_stalling(&mut self, advantage_stalling: bool) {
+        self.advantage_stalling = advantage_stalling;
+    }
+
+    pub fn input_validator(&self) -> Option<&Callable> {
+        self.input_validator.as_ref()
+    }
+
+    pub fn set_input_validator(&mut self, validator: Option<Callable>) {
+        self.input_validator = validator;
+    }
+
+    pub fn faithful_replay(&self) -> bool {
+        self.faithful_replay
+    }
+
+    pub fn set_faithful_replay(&mut self, faithful_replay: bool) {
+        self.faithful_replay = faithful_replay;
+    }
+
+    pub fn network_replay(&self) -> bool {
+        self.network_replay
+    }
+
+    pub fn set_network_replay(&mut self, network_replay: bool) {
+        self.network_replay = network_replay;
+    }
+
+    pub fn buffered_input(&self) -> bool {
+        self.buffered_input
+    }
+
+    pub fn set_buffered_input(&mut self, buffered_input: bool) {
+        self.buffered_input = buffered_input;
+        self.input_buffer.clear();
+    }
+
+    pub fn buffer_input(&mut self, event: Variant) {
+        self.input_buffer.push(event);
+    }
+
+    /// The events buffered since the last tick, or None if input isn't
+    /// buffered
+    pub fn drain_input_buffer(&mut self) -> Option<Vec<Variant>> {
+        drain_buffered_events(self.buffered_input, &mut self.input_buffer)
+    }
+
+    pub fn jitter_buffer(&self) -> bool {
+        self.jitter_buffer
+    }
+
+    pub fn set_jitter_buffer(&mut self, jitter_buffer: bool) {
+        self.jitter_buffer = jitter_buffer;
+    }
+
+    pub fn adaptive_rewind(&self) -> bool {
+        self.adaptive_rewind
+    }
+
+    pub fn set_adaptive_rewind(&mut self, adaptive_rewind: bool) {
+        self.adaptive_rewind = adaptive_rewind;
+    }
+
+    pub fn node_pooling(&self) -> bool {
+        self.node_pooling
+    }
+
+    pub fn set_node_pooling(&mut self, node_pooling: bool) {
+        self.node_pooling = node_pooling;
+    }
+
+    pub fn max_rewind(&self) -> u64 {
+        self.max_rewind
+    }
+
+    pub fn set_max_rewind(&mut self, max_rewind: u64) {
+        self.max_rewind = max_rewind.max(MIN_MAX_REWIND);
+    }
+
+    pub fn disconnect_policy(&self) -> DisconnectPolicy {
+        self.disconnect_policy
+    }
+
+    pub fn set_disconnect_policy(&mut self, disconnect_policy: DisconnectPolicy) {
+        self.disconnect_policy = disconnect_policy;
+    }
+
+    pub fn input_prediction(&self) -> InputPrediction {
+        self.input_prediction
+    }
+
+    pub fn set_input_prediction(&mut self, input_prediction: InputPrediction) {
+        self.input_prediction = input_prediction;
+    }
+
+    pub fn input_delay(&self) -> u64 {
+        self.input_delay
+    }
+
+    pub fn set_input_delay(&mut self, input_delay: u64) {
+        self.input_delay = input_delay;
+    }
+
+    pub fn adaptive_input_delay(&self) -> bool {
+        self.adaptive_input_delay
+    }
+
+    pub fn set_adaptive_input_delay(&mut self, adaptive_input_delay: bool) {
+        self.adaptive_input_delay = adaptive_input_delay;
+    }
+
+    pub fn input_repeat_limit(&self) -> u32 {
+        self.input_repeat_limit
+    }
+
+    pub fn set_input_repeat_limit(&mut self, input_repeat_limit: u32) {
+        self.input_repeat_limit = input_repeat_limit;
+    }
+
+    pub fn state_hash_epsilon(&self) -> Option<f64> {
+        self.state_hash_epsilon
+    }
+
+    pub fn set_state_hash_epsilon(&mut self, state_hash_epsilon: Option<f64>) {
+        self.state_hash_epsilon = state_hash_epsilon;
+    }
+
+    pub fn state_log_interval(&self) -> u64 {
+        self.state_log_interval
+    }
+
+    pub fn set_state_log_interval(&mut self, state_log_interval: u64) {
+        self.state_log_interval = state_log_interval.max(1);
+    }
+
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.checkpoint_interval
+    }
+
+    pub fn set_checkpoint_interval(&mut self, checkpoint_interval: u64) {
+        self.checkpoint_interval = checkpoint_interval;
+    }
+
+    pub fn spawn_cap(&self) -> Option<usize> {
+        self.spawn_cap
+    }
+
+    pub fn set_spawn_cap(&mut self, spawn_cap: Option<usize>) {
+        self.spawn_cap = spawn_cap;
+    }
+
+    pub fn clock_drift_threshold(&self) -> f64 {
+        self.clock_drift_threshold
+    }
+
+    pub fn set_clock_drift_threshold(&mut self, clock_drift_threshold: f64) {
+        self.clock_drift_threshold = clock_drift_threshold;
+    }
+
+    /// Records the most recent `capacity` packets sent and received by the
+    /// socket, or stops recording if `capacity` is None
+    pub fn set_packet_tracing(&mut self, capacity: Option<usize>) {
+        self.packet_trace_capacity = capacity;
+        match capacity {
+            Some(capacity) => self.socket.enable_tracing(capacity),
+            None => self.socket.disable_tracing(),
+        }
+    }
+
+    pub fn packet_trace(&self) -> Vec<PacketTraceEvent> {
+        self.socket.trace()
+    }
+
+    /// Skips peers with at least `max_in_flight` unacknowledged packets when
+    /// broadcasting, or never skips if None
+    pub fn set_max_in_flight(&mut self, max_in_flight: Option<usize>) {
+        self.max_in_flight = max_in_flight;
+        self.socket.set_max_in_flight(max_in_flight);
+    }
+
+    pub fn stepping(&self) -> bool {
+        self.stepping
+    }
+
+    pub fn set_stepping(&mut self, stepping: bool) {
+        self.stepping = stepping;
+    }
+
+    pub fn request_execute_tick(&mut self) {
+        self.execute_tick_requested = true;
+    }
+
+    /// Returns whether execute_tick was requested since the last call
+    pub fn take_execute_tick_request(&mut self) -> bool {
+        std::mem::take(&mut self.execute_tick_requested)
+    }
+
+    pub fn queue_signal(&mut self, signal: &'static str, args: Vec<Variant>) {
+        self.queued_signals.push((signal, args));
+    }
+
+    pub fn take_queued_signals(&mut self) -> Vec<(&'static str, Vec<Variant>)> {
+        std::mem::take(&mut self.queued_signals)
+    }
+
     pub fn set_run(&self, run: Uuid) -> Result<()> {
         if self.replay_overrides.is_some() {
             panic!("Can't set run during a replay");
@@ -110,6 +515,33 @@ impl Context {
         Ok(())
     }
 
+    /// Sends an input message for the given tick to the peer, remembering the
+    /// frame it was sent in so that it can be canceled once superseded
+    pub fn send_input(&mut self, peer: Uuid, tick: u64, message: Message) -> Result<()> {
+        if self.replay_overrides.is_none() {
+            let frame_id = self.socket.send_to(peer, message)?;
+            self.unacked_inputs
+                .entry(peer)
+                .or_default()
+                .insert(tick, frame_id);
+        }
+        Ok(())
+    }
+
+    /// Stops resending inputs for ticks up to and including the given tick to
+    /// the peer. The tick must be one the peer has received every input up
+    /// to, so that no canceled input is still missing on its end.
+    pub fn cancel_inputs_through(&mut self, peer: Uuid, tick: u64) -> Result<()> {
+        let Some(unacked_inputs) = self.unacked_inputs.get_mut(&peer) else {
+            return Ok(());
+        };
+
+        for frame_id in take_inputs_through(unacked_inputs, tick) {
+            self.socket.cancel(peer, frame_id)?;
+        }
+        Ok(())
+    }
+
     pub fn send_to_address(&mut self, address: impl ToSocketAddrs, message: Message) -> Result<()> {
         if self.replay_overrides.is_none() {
             self.socket.send_to_address(address, message)?;
@@ -125,11 +557,26 @@ impl Context {
         self.socket.connect(peer, address)
     }
 
-    pub fn broadcast(&mut self, message: Message) -> Result<()> {
-        if self.replay_overrides.is_none() {
-            self.socket.broadcast(message)?;
+    /// Forgets the peer so that it is no longer sent messages or waited on
+    /// for input
+    pub fn disconnect(&mut self, peer: Uuid) {
+        if self.replay_overrides.is_some() {
+            panic!("Can't disconnect during a replay");
         }
-        Ok(())
+
+        self.unacked_inputs.remove(&peer);
+        self.spectators.remove(&peer);
+        self.socket.disconnect(peer)
+    }
+
+    /// Sends the message to every connected peer. Returns the peers skipped
+    /// for having too many packets in flight
+    pub fn broadcast(&mut self, message: Message) -> Result<Vec<Uuid>> {
+        if self.replay_overrides.is_some() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self.socket.broadcast(message)?.throttled)
     }
 
     pub fn average_lobby_response_time(&self) -> Duration {
@@ -147,20 +594,62 @@ impl Context {
         self.socket.average_response_time(peer)
     }
 
+    pub fn packet_loss(&self, peer: Uuid) -> f32 {
+        if self.replay_overrides.is_some() {
+            return 0.0;
+        }
+
+        self.socket.packet_loss(peer)
+    }
+
+    pub fn response_time_deviation(&self, peer: Uuid) -> Option<Duration> {
+        if self.replay_overrides.is_some() {
+            panic!("Can't call response_time_deviation during a replay");
+        }
+
+        self.socket.response_time_deviation(peer)
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.replay_overrides.is_some()
+    }
+
+    /// Returns the events received since the last pump. Once the socket stops
+    /// receiving the error is returned once and nothing is received until it
+    /// is rebound. Other errors only fail the pump they happen in
     pub fn pump_socket(&mut self) -> Result<Vec<(PersistentEvent, PersistentSocketSender<Uuid>)>> {
-        self.socket.pump()
+        if self.socket_failed {
+            return Ok(Vec::new());
+        }
+
+        let results = self.socket.pump();
+        self.socket_failed = results
+            .as_ref()
+            .is_err_and(|error| error.is::<ReceiveError>());
+        results
     }
 
     pub fn set_port(&mut self, port: u16) -> Result<()> {
+        self.bind(Ipv4Addr::UNSPECIFIED.into(), port)
+    }
+
+    /// Rebinds the socket to the given local address and port unless it is
+    /// already bound to them
+    pub fn bind(&mut self, address: IpAddr, port: u16) -> Result<()> {
         if self.replay_overrides.is_some() {
-            panic!("Can't set port  during a replay");
+            panic!("Can't bind during a replay");
         }
 
-        if self.socket.local_addr()?.port() == port as u16 {
+        if !self.socket_failed && self.socket.local_addr()? == SocketAddr::new(address, port) {
             return Ok(());
         }
 
-        self.socket = PersistentSocket::bind(port as u16)?;
+        self.socket = bind_socket(address, port)?;
+        self.socket_failed = false;
+        if let Some(capacity) = self.packet_trace_capacity {
+            self.socket.enable_tracing(capacity);
+        }
+        self.socket.set_max_in_flight(self.max_in_flight);
 
         Ok(())
     }
@@ -169,3 +658,98 @@ impl Context {
         &self.logger
     }
 }
+
+/// Binds a socket which leaves timed out peers connected. The disconnect
+/// policy decides whether they are forgotten
+fn bind_socket(address: IpAddr, port: u16) -> Result<PersistentSocket<Uuid>> {
+    let mut socket = PersistentSocket::bind_to(address, port)?;
+    socket.set_auto_disconnect(false);
+    Ok(socket)
+}
+
+/// Removes the unacked inputs for ticks up to and including the given tick,
+/// returning the frames they were sent in
+fn take_inputs_through(unacked_inputs: &mut BTreeMap<u64, FrameId>, tick: u64) -> Vec<FrameId> {
+    let remaining = unacked_inputs.split_off(&(tick + 1));
+    std::mem::replace(unacked_inputs, remaining)
+        .into_values()
+        .collect()
+}
+
+/// Takes every event buffered since the last tick if input is buffered, so
+/// that taps between ticks all reach the next input
+fn drain_buffered_events<T>(buffered_input: bool, buffer: &mut Vec<T>) -> Option<Vec<T>> {
+    buffered_input.then(|| std::mem::take(buffer))
+}
+
+/// Number of whole ticks which fit in the duration at the simulation rate
+fn duration_ticks(duration: Duration, simulation_rate: u32) -> u32 {
+    (duration.as_secs_f64() * simulation_rate as f64) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use udp_ext::frame::FrameId;
+
+    use super::{drain_buffered_events, duration_ticks, take_inputs_through};
+
+    #[test]
+    fn superseded_input_resends_are_canceled() {
+        let mut unacked_inputs = BTreeMap::from([
+            (4, FrameId(10)),
+            (5, FrameId(11)),
+            (6, FrameId(12)),
+            (7, FrameId(13)),
+        ]);
+
+        let canceled = take_inputs_through(&mut unacked_inputs, 5);
+        assert_eq!(canceled, vec![FrameId(10), FrameId(11)]);
+        assert_eq!(unacked_inputs.into_keys().collect::<Vec<_>>(), vec![6, 7]);
+    }
+
+    #[test]
+    fn nothing_is_canceled_before_the_first_unacked_input() {
+        let mut unacked_inputs = BTreeMap::from([(4, FrameId(10))]);
+
+        assert!(take_inputs_through(&mut unacked_inputs, 3).is_empty());
+        assert_eq!(unacked_inputs.len(), 1);
+    }
+
+    #[test]
+    fn events_between_ticks_all_reach_the_next_input() {
+        let mut buffer = Vec::new();
+        // Two quick taps land between ticks
+        buffer.push("jump_pressed");
+        buffer.push("jump_released");
+        assert_eq!(
+            drain_buffered_events(true, &mut buffer),
+            Some(vec!["jump_pressed", "jump_released"])
+        );
+
+        // The next tick only gets events since the last one
+        buffer.push("fire_pressed");
+        assert_eq!(
+            drain_buffered_events(true, &mut buffer),
+            Some(vec!["fire_pressed"])
+        );
+        assert_eq!(drain_buffered_events(true, &mut buffer), Some(vec![]));
+    }
+
+    #[test]
+    fn unbuffered_input_leaves_the_buffer_alone() {
+        let mut buffer = vec!["jump_pressed"];
+        assert_eq!(drain_buffered_events(false, &mut buffer), None);
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn durations_count_whole_ticks_at_the_simulation_rate() {
+        let one_way = Duration::from_millis(50);
+        assert_eq!(duration_ticks(one_way, 30), 1);
+        assert_eq!(duration_ticks(one_way, 60), 3);
+        assert_eq!(duration_ticks(one_way, 120), 6);
+        assert_eq!(duration_ticks(Duration::ZERO, 60), 0);
+    }
+}
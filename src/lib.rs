@@ -2,8 +2,10 @@ mod context;
 mod lobby_stage;
 pub mod logging;
 mod message;
+mod peer_set_check;
 mod play_stage;
 mod replay_stage;
+mod stable_hasher;
 pub mod sync_manager;
 mod sync_stage;
 
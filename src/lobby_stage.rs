@@ -1,22 +1,128 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{SocketAddr, ToSocketAddrs},
+};
 
-use anyhow::Result;
-use godot::prelude::*;
+use anyhow::{anyhow, Result};
+use godot::{
+    engine::utilities::{bytes_to_var, var_to_bytes},
+    prelude::*,
+};
 use udp_ext::persistent::PersistentSocketSender;
 use uuid::Uuid;
 
 use crate::{
-    message::Message, play_stage::PlayStage, sync_manager::RollbackSyncManager,
-    sync_stage::SyncStage, Context,
+    message::{Message, StateSnapshot},
+    play_stage::PlayStage,
+    sync_manager::RollbackSyncManager,
+    sync_stage::SyncStage,
+    Context,
 };
 
-const SCHEDULE_TICKS: u32 = 1 * 60;
+/// Seconds between scheduling a start and the match starting
+const SCHEDULE_SECONDS: u32 = 1;
+
+/// Ticks between scheduling a start and the match starting at the current
+/// simulation rate
+fn schedule_ticks(simulation_rate: u32) -> u32 {
+    SCHEDULE_SECONDS * simulation_rate
+}
+
+/// Ticks a join waits for a reply before timing out, rounded up so that a
+/// short timeout still waits at least a tick
+fn join_timeout_ticks(timeout_seconds: f64, simulation_rate: u32) -> u32 {
+    (timeout_seconds.max(0.0) * simulation_rate as f64).ceil() as u32
+}
+
+/// Stores a setting received from a peer. Returns true if it changed the
+/// local settings
+fn apply_setting(settings: &mut BTreeMap<String, Vec<u8>>, key: String, value: Vec<u8>) -> bool {
+    if settings.get(&key) == Some(&value) {
+        return false;
+    }
+
+    settings.insert(key, value);
+    true
+}
+
+/// Whether each of the peers has reported the same settings as the local
+/// ones. Peers which haven't reported are treated as having none
+fn settings_agree(
+    settings: &BTreeMap<String, Vec<u8>>,
+    peer_settings: &HashMap<Uuid, BTreeMap<String, Vec<u8>>>,
+    peers: &[Uuid],
+) -> bool {
+    peers.iter().all(|peer| {
+        peer_settings
+            .get(peer)
+            .map(|peer_settings| peer_settings == settings)
+            .unwrap_or_else(|| settings.is_empty())
+    })
+}
+
+/// Whether every player, local peer included, has reported its response
+/// times to the others
+fn connectivity_complete(
+    connectivity: &HashMap<Uuid, Vec<(Uuid, Option<u64>)>>,
+    mut players: impl Iterator<Item = Uuid>,
+) -> bool {
+    players.all(|id| connectivity.contains_key(&id))
+}
+
+/// True if a start scheduled by the scheduler takes the place of the start
+/// already scheduled, which only happens if the scheduler's id is lower
+fn replaces_scheduled_start(scheduled_start: Option<&ScheduledStart>, scheduler: Uuid) -> bool {
+    scheduled_start.is_none_or(|scheduled_start| scheduler < scheduled_start.scheduler)
+}
+
+/// Describes the first peer found simulating at a different rate than the
+/// local peer. Peers which haven't reported a rate yet are assumed to match
+/// since the rate is sent as soon as peers connect.
+fn simulation_rate_mismatch(
+    peers: &[Uuid],
+    peer_simulation_rates: &HashMap<Uuid, u32>,
+    simulation_rate: u32,
+) -> Option<String> {
+    peers.iter().find_map(|peer| {
+        let rate = *peer_simulation_rates.get(peer)?;
+        (rate != simulation_rate).then(|| {
+            format!(
+                "peer {peer} simulates at {rate} ticks per second but this peer simulates at {simulation_rate}"
+            )
+        })
+    })
+}
+
+/// A join started by `connect_and_wait` which emits `connect_finished` once
+/// the joined peer replies or the timeout runs out.
+struct PendingJoin {
+    address: SocketAddr,
+    ticks_remaining: u32,
+}
+
+/// A start scheduled by a peer along with the run it picked. Only the start
+/// from the lowest id is kept so that peers which briefly disagree about who
+/// the lowest id is still converge on a single run.
+struct ScheduledStart {
+    scheduler: Uuid,
+    run: Uuid,
+    ticks_till_start: u32,
+}
 
 pub struct LobbyStage {
     ready: bool,
-    scheduled_start: Option<u32>,
+    scheduled_start: Option<ScheduledStart>,
     early_inputs: Vec<Message>,
     peers_ready: HashMap<Uuid, bool>,
+    connectivity: HashMap<Uuid, Vec<(Uuid, Option<u64>)>>,
+    settings: BTreeMap<String, Vec<u8>>,
+    peer_settings: HashMap<Uuid, BTreeMap<String, Vec<u8>>>,
+    pending_join: Option<PendingJoin>,
+    peer_simulation_rates: HashMap<Uuid, u32>,
+    peer_start_offsets: HashMap<Uuid, u32>,
+    /// Snapshot received after asking to join a match as a spectator. The
+    /// match is joined on the next tick
+    snapshot: Option<StateSnapshot>,
 }
 
 impl LobbyStage {
@@ -26,12 +132,45 @@ impl LobbyStage {
             scheduled_start: None,
             early_inputs: Vec::new(),
             peers_ready: HashMap::new(),
+            connectivity: HashMap::new(),
+            settings: BTreeMap::new(),
+            peer_settings: HashMap::new(),
+            pending_join: None,
+            peer_simulation_rates: HashMap::new(),
+            peer_start_offsets: HashMap::new(),
+            snapshot: None,
         }
     }
 
-    pub fn tick(&mut self, node: &mut Gd<Node>, cx: &Context) -> Result<Option<SyncStage>> {
-        if let Some(ticks_till_start) = self.scheduled_start.as_mut() {
-            if *ticks_till_start == 0 {
+    pub fn tick(&mut self, node: &mut Gd<Node>, cx: &mut Context) -> Result<Option<SyncStage>> {
+        if let Some(pending_join) = self.pending_join.as_mut() {
+            if pending_join.ticks_remaining == 0 {
+                godot_print!("Timed out connecting to {}", pending_join.address);
+                self.pending_join = None;
+                cx.queue_signal("connect_finished", vec![Variant::from(false)]);
+            } else {
+                pending_join.ticks_remaining -= 1;
+            }
+        }
+
+        if let Some(snapshot) = self.snapshot.take() {
+            // Spectators don't log to the players' run, so they start their own
+            cx.set_run(Uuid::new_v4())
+                .expect("Could not set run on logger");
+            let node = (*node).clone();
+            let mut this = node.cast::<RollbackSyncManager>();
+            this.call_deferred("start_game".into(), &[]);
+            return Ok(Some(SyncStage::Play(PlayStage::spectate(
+                snapshot,
+                self.early_inputs.clone(),
+                cx,
+            )?)));
+        }
+
+        if let Some(scheduled_start) = self.scheduled_start.as_mut() {
+            if scheduled_start.ticks_till_start == 0 {
+                cx.set_run(scheduled_start.run)
+                    .expect("Could not set run on logger");
                 self.scheduled_start = None;
                 let node = (*node).clone();
                 let mut this = node.cast::<RollbackSyncManager>();
@@ -42,14 +181,13 @@ impl LobbyStage {
                 ))));
             }
 
-            *ticks_till_start -= 1;
+            scheduled_start.ticks_till_start -= 1;
         }
         Ok(None)
     }
 
     pub fn handle_message(
         &mut self,
-        node: &mut Gd<Node>,
         message: Message,
         sender: PersistentSocketSender<Uuid>,
         cx: &mut Context,
@@ -63,12 +201,37 @@ impl LobbyStage {
                     return Ok(());
                 };
 
-                node.emit_signal("connected".into(), &[Variant::from(id.to_string())]);
+                cx.queue_signal("connected", vec![Variant::from(id.to_string())]);
+                if self
+                    .pending_join
+                    .as_ref()
+                    .is_some_and(|pending_join| pending_join.address == address)
+                {
+                    self.pending_join = None;
+                    cx.queue_signal("connect_finished", vec![Variant::from(true)]);
+                }
 
                 cx.send_to_address(address, Message::Connect(cx.local_id()))?;
 
                 cx.broadcast(Message::GossipPeer(id, address.to_string()))?;
-                self.update_ready(node, false, cx)?;
+                cx.send_to_address(
+                    address,
+                    Message::SimulationRate(cx.local_id(), cx.simulation_rate()),
+                )?;
+                cx.send_to_address(
+                    address,
+                    Message::StartOffset(cx.local_id(), cx.start_offset()),
+                )?;
+                for (key, value) in self.settings.iter() {
+                    cx.send_to_address(
+                        address,
+                        Message::LobbySetting {
+                            key: key.clone(),
+                            value: value.clone(),
+                        },
+                    )?;
+                }
+                self.update_ready(false, cx)?;
                 for peer in cx.peers() {
                     let peer_address = cx.address(peer).unwrap();
                     cx.send_to_address(
@@ -93,19 +256,70 @@ impl LobbyStage {
                 };
                 self.peers_ready.insert(id, ready);
                 dbg!(id);
-                self.try_schedule_start(node, cx)?;
+                self.try_schedule_start(cx)?;
             }
             Message::ScheduleStart(run) => {
                 let PersistentSocketSender::Connected(id) = sender else {
                     panic!("ScheduleStart message from unconnected sender");
                 };
 
-                let start_adjustment = (cx.average_response_time(id).unwrap() / 2).as_millis() / 16;
+                let start_adjustment = cx.duration_ticks(cx.average_response_time(id).unwrap() / 2);
                 godot_print!("Start adjustment: {}", start_adjustment);
-                self.scheduled_start = Some(SCHEDULE_TICKS.saturating_sub(start_adjustment as u32));
-                cx.set_run(run).expect("Could not set run on logger");
-                godot_print!("Scheduled start");
-                node.emit_signal("start_scheduled".into(), &[]);
+                self.schedule_start(
+                    id,
+                    run,
+                    schedule_ticks(cx.simulation_rate()).saturating_sub(start_adjustment),
+                    cx,
+                );
+            }
+            Message::CheckConnectivity => {
+                self.connectivity.clear();
+                self.report_connectivity(cx)?;
+            }
+            Message::ConnectivityReport(response_times) => {
+                let PersistentSocketSender::Connected(id) = sender else {
+                    return Ok(());
+                };
+
+                self.record_connectivity(id, response_times, cx);
+            }
+            Message::LobbySetting { key, value } => {
+                if apply_setting(&mut self.settings, key, value) {
+                    cx.broadcast(Message::LobbySettings(self.settings.clone()))?;
+                }
+            }
+            Message::LobbySettings(settings) => {
+                let PersistentSocketSender::Connected(id) = sender else {
+                    return Ok(());
+                };
+
+                self.peer_settings.insert(id, settings);
+                self.try_schedule_start(cx)?;
+            }
+            Message::SimulationRate(id, rate) => {
+                self.peer_simulation_rates.insert(id, rate);
+                self.try_schedule_start(cx)?;
+            }
+            Message::StartOffset(id, offset) => {
+                self.peer_start_offsets.insert(id, offset);
+                self.try_schedule_start(cx)?;
+            }
+            Message::StateSnapshot(snapshot) => {
+                let PersistentSocketSender::Unconnected(address) = sender else {
+                    return Ok(());
+                };
+                if self.pending_join.is_some() || self.scheduled_start.is_some() {
+                    return Ok(());
+                }
+
+                cx.connect(snapshot.sender, address);
+                for (player, player_address) in snapshot.players.iter() {
+                    match player_address.parse() {
+                        Ok(player_address) => cx.connect(*player, player_address),
+                        Err(_) => godot_warn!("Invalid address {player_address} for {player}"),
+                    }
+                }
+                self.snapshot = Some(snapshot);
             }
             message @ Message::Input { .. } => {
                 self.early_inputs.push(message);
@@ -116,50 +330,391 @@ impl LobbyStage {
         Ok(())
     }
 
-    pub fn update_ready(
+    /// Sends a connect message to the given address and waits up to the
+    /// timeout for the peer to reply. Returns an error if the address could not
+    /// be resolved or the message could not be sent.
+    pub fn connect_and_wait(
         &mut self,
-        node: &mut Gd<Node>,
-        value: bool,
+        address: String,
+        timeout_seconds: f64,
         cx: &mut Context,
     ) -> Result<()> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Could not resolve {address}"))?;
+        cx.send_to_address(address, Message::Connect(cx.local_id()))?;
+        self.pending_join = Some(PendingJoin {
+            address,
+            ticks_remaining: join_timeout_ticks(timeout_seconds, cx.simulation_rate()),
+        });
+
+        Ok(())
+    }
+
+    /// Asks the peer at the given address to let the local peer watch the
+    /// match it is playing. The match starts once the peer replies with a
+    /// snapshot of its state.
+    pub fn join_as_spectator(&mut self, address: String, cx: &mut Context) -> Result<()> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Could not resolve {address}"))?;
+        cx.send_to_address(address, Message::JoinAsSpectator(cx.local_id()))
+    }
+
+    pub fn update_ready(&mut self, value: bool, cx: &mut Context) -> Result<()> {
         self.ready = value;
         cx.broadcast(Message::UpdateReady(self.ready))?;
-        self.try_schedule_start(node, cx)?;
+        self.try_schedule_start(cx)?;
+
+        Ok(())
+    }
+
+    /// Sets a lobby setting locally and on every peer. The game will not start
+    /// until every peer agrees on every setting.
+    pub fn set_setting(&mut self, key: String, value: Variant, cx: &mut Context) -> Result<()> {
+        let value = var_to_bytes(value).to_vec();
+        self.settings.insert(key.clone(), value.clone());
+        cx.broadcast(Message::LobbySetting { key, value })?;
+        cx.broadcast(Message::LobbySettings(self.settings.clone()))?;
 
         Ok(())
     }
 
-    pub fn try_schedule_start(&mut self, node: &mut Gd<Node>, cx: &mut Context) -> Result<()> {
+    pub fn settings(&self) -> Dictionary {
+        let mut settings = Dictionary::new();
+        for (key, value) in self.settings.iter() {
+            settings.set(key.clone(), bytes_to_var(PackedByteArray::from(&value[..])));
+        }
+        settings
+    }
+
+    /// Returns true if every peer has reported the same settings as the local
+    /// settings. Peers which haven't reported are treated as having none.
+    fn settings_agreed(&self, cx: &Context) -> bool {
+        settings_agree(&self.settings, &self.peer_settings, &cx.peers())
+    }
+
+    /// Sets the local simulation rate and reports it to every peer
+    pub fn set_simulation_rate(&mut self, rate: u32, cx: &mut Context) -> Result<()> {
+        cx.set_simulation_rate(rate);
+        cx.broadcast(Message::SimulationRate(cx.local_id(), rate))?;
+        self.try_schedule_start(cx)
+    }
+
+    fn simulation_rate_mismatch(&self, cx: &Context) -> Option<String> {
+        simulation_rate_mismatch(
+            &cx.peers(),
+            &self.peer_simulation_rates,
+            cx.simulation_rate(),
+        )
+    }
+
+    /// Sets the local start offset and reports it to every peer
+    pub fn set_start_offset(&mut self, offset: u32, cx: &mut Context) -> Result<()> {
+        cx.set_start_offset(offset);
+        cx.broadcast(Message::StartOffset(cx.local_id(), offset))?;
+        self.try_schedule_start(cx)
+    }
+
+    /// Describes the first peer found using a different start offset than the
+    /// local peer. Like simulation rates, peers which haven't reported an
+    /// offset yet are assumed to match.
+    fn start_offset_mismatch(&self, cx: &Context) -> Option<String> {
+        cx.peers().into_iter().find_map(|peer| {
+            let offset = *self.peer_start_offsets.get(&peer)?;
+            (offset != cx.start_offset()).then(|| {
+                format!(
+                    "peer {peer} starts recording input after {offset} ticks but this peer starts after {}",
+                    cx.start_offset()
+                )
+            })
+        })
+    }
+
+    pub fn try_schedule_start(&mut self, cx: &mut Context) -> Result<()> {
         if self.ready
             && cx
                 .peers()
                 .iter()
                 .all(|peer| self.peers_ready.get(peer).copied().unwrap_or_default())
         {
+            let mismatch = self
+                .simulation_rate_mismatch(cx)
+                .or_else(|| self.start_offset_mismatch(cx));
+            if let Some(reason) = mismatch {
+                godot_warn!("Refusing to start: {reason}");
+                cx.queue_signal("start_refused", vec![Variant::from(reason)]);
+                return Ok(());
+            }
+
+            if !self.settings_agreed(cx) {
+                godot_print!("Waiting for peers to agree on lobby settings");
+                return Ok(());
+            }
+
             let lowest_id = cx
                 .peers()
                 .into_iter()
                 .chain(std::iter::once(cx.local_id()))
                 .min()
                 .expect("Could not find lowest id");
-            if lowest_id == cx.local_id() {
+            if lowest_id == cx.local_id() && self.scheduled_start.is_none() {
                 let run = Uuid::new_v4();
-                cx.set_run(run).expect("Could not set run on logger");
                 cx.broadcast(Message::ScheduleStart(run))?;
 
-                let average_lobby_response_millis = cx.average_lobby_response_time().as_millis();
-                let start_adjustment = if average_lobby_response_millis > 0 {
-                    average_lobby_response_millis / 32
-                } else {
-                    0
-                };
+                let start_adjustment = cx.duration_ticks(cx.average_lobby_response_time() / 2);
                 godot_print!("Start adjustment: {}", start_adjustment);
-                self.scheduled_start = Some(SCHEDULE_TICKS + start_adjustment as u32);
+                self.schedule_start(
+                    cx.local_id(),
+                    run,
+                    schedule_ticks(cx.simulation_rate()) + start_adjustment,
+                    cx,
+                );
                 godot_print!("Broadcast scheduled start");
-                node.emit_signal("start_scheduled".into(), &[]);
             }
         }
 
         Ok(())
     }
+
+    /// Records a start scheduled by the given peer. If another peer already
+    /// scheduled a start, whichever scheduler has the lowest id wins so that
+    /// every peer ends up with the same run. The run is only handed to the
+    /// logger once the game actually starts since it can't be changed after.
+    fn schedule_start(
+        &mut self,
+        scheduler: Uuid,
+        run: Uuid,
+        ticks_till_start: u32,
+        cx: &mut Context,
+    ) {
+        if !replaces_scheduled_start(self.scheduled_start.as_ref(), scheduler) {
+            godot_print!("Ignoring start scheduled by {scheduler}");
+            return;
+        }
+
+        if let Some(scheduled_start) = &self.scheduled_start {
+            godot_print!("Replacing start scheduled by {}", scheduled_start.scheduler);
+        } else {
+            godot_print!("Scheduled start");
+            cx.queue_signal("start_scheduled", Vec::new());
+        }
+
+        self.scheduled_start = Some(ScheduledStart {
+            scheduler,
+            run,
+            ticks_till_start,
+        });
+    }
+
+    /// Asks every peer to report their response times to every other peer.
+    /// Once a report has been received from every peer, the
+    /// `connectivity_checked` signal is emitted with the full matrix.
+    pub fn check_connectivity(&mut self, cx: &mut Context) -> Result<()> {
+        self.connectivity.clear();
+        cx.broadcast(Message::CheckConnectivity)?;
+        self.report_connectivity(cx)
+    }
+
+    fn report_connectivity(&mut self, cx: &mut Context) -> Result<()> {
+        let response_times: Vec<(Uuid, Option<u64>)> = cx
+            .peers()
+            .into_iter()
+            .map(|peer| {
+                let response_millis = cx
+                    .average_response_time(peer)
+                    .map(|response_time| response_time.as_millis() as u64);
+                (peer, response_millis)
+            })
+            .collect();
+
+        cx.broadcast(Message::ConnectivityReport(response_times.clone()))?;
+        self.record_connectivity(cx.local_id(), response_times, cx);
+
+        Ok(())
+    }
+
+    fn record_connectivity(
+        &mut self,
+        reporter: Uuid,
+        response_times: Vec<(Uuid, Option<u64>)>,
+        cx: &mut Context,
+    ) {
+        self.connectivity.insert(reporter, response_times);
+
+        let players = cx.peers().into_iter().chain(std::iter::once(cx.local_id()));
+        if connectivity_complete(&self.connectivity, players) {
+            cx.queue_signal(
+                "connectivity_checked",
+                vec![Variant::from(self.connectivity_report())],
+            );
+        }
+    }
+
+    /// Returns a dictionary from each reporting peer's id to a dictionary of
+    /// that peer's response time in milliseconds to each of its peers. Peers
+    /// which have not responded are reported as -1.
+    pub fn connectivity_report(&self) -> Dictionary {
+        let mut report = Dictionary::new();
+        for (reporter, response_times) in self.connectivity.iter() {
+            let mut row = Dictionary::new();
+            for (peer, response_millis) in response_times {
+                row.set(
+                    peer.to_string(),
+                    response_millis.map(|millis| millis as i64).unwrap_or(-1),
+                );
+            }
+            report.set(reporter.to_string(), row);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+
+    use uuid::Uuid;
+
+    use super::{
+        apply_setting, connectivity_complete, join_timeout_ticks, replaces_scheduled_start,
+        schedule_ticks, settings_agree, simulation_rate_mismatch, ScheduledStart,
+    };
+
+    #[test]
+    fn connectivity_completes_once_every_player_reports() {
+        let players = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let mut connectivity = HashMap::new();
+        for reporter in players {
+            assert!(!connectivity_complete(&connectivity, players.into_iter()));
+
+            // Each player reports a response time to each of the others
+            let response_times = players
+                .into_iter()
+                .filter(|peer| *peer != reporter)
+                .map(|peer| (peer, Some(20)))
+                .collect::<Vec<_>>();
+            connectivity.insert(reporter, response_times);
+        }
+        assert!(connectivity_complete(&connectivity, players.into_iter()));
+
+        // The full matrix has a row for each player with every other player
+        for reporter in players {
+            assert_eq!(connectivity[&reporter].len(), players.len() - 1);
+        }
+    }
+
+    #[test]
+    fn unanswered_peers_still_count_as_reported() {
+        let players = [Uuid::new_v4(), Uuid::new_v4()];
+        let connectivity = HashMap::from([
+            (players[0], vec![(players[1], None)]),
+            (players[1], vec![(players[0], None)]),
+        ]);
+        assert!(connectivity_complete(&connectivity, players.into_iter()));
+    }
+
+    #[test]
+    fn start_waits_until_a_setting_has_propagated() {
+        let (host, guest) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut host_settings = BTreeMap::new();
+        let mut guest_settings = BTreeMap::new();
+        let mut host_view = HashMap::new();
+        let mut guest_view = HashMap::new();
+        // Nobody has set anything yet
+        assert!(settings_agree(&host_settings, &host_view, &[guest]));
+
+        // The host picks a map, which the guest hasn't heard about
+        assert!(apply_setting(&mut host_settings, "map".into(), vec![1]));
+        assert!(!settings_agree(&host_settings, &host_view, &[guest]));
+
+        // The guest receives the setting and reports its settings back
+        assert!(apply_setting(&mut guest_settings, "map".into(), vec![1]));
+        host_view.insert(guest, guest_settings.clone());
+        guest_view.insert(host, host_settings.clone());
+        assert!(settings_agree(&host_settings, &host_view, &[guest]));
+        assert!(settings_agree(&guest_settings, &guest_view, &[host]));
+
+        // Receiving the same value again changes nothing
+        assert!(!apply_setting(&mut guest_settings, "map".into(), vec![1]));
+    }
+
+    #[test]
+    fn stale_peer_settings_block_the_start() {
+        let guest = Uuid::new_v4();
+        let settings = BTreeMap::from([("map".to_string(), vec![2])]);
+        let peer_settings =
+            HashMap::from([(guest, BTreeMap::from([("map".to_string(), vec![1])]))]);
+        assert!(!settings_agree(&settings, &peer_settings, &[guest]));
+    }
+
+    #[test]
+    fn join_timeouts_round_up_to_whole_ticks() {
+        assert_eq!(join_timeout_ticks(1.0, 60), 60);
+        assert_eq!(join_timeout_ticks(0.01, 60), 1);
+        assert_eq!(join_timeout_ticks(0.5, 30), 15);
+        // Negative timeouts time out on the next tick
+        assert_eq!(join_timeout_ticks(-1.0, 60), 0);
+    }
+
+    #[test]
+    fn mismatched_simulation_rates_refuse_to_start() {
+        let peers = [Uuid::new_v4(), Uuid::new_v4()];
+        let mut peer_simulation_rates = HashMap::from([(peers[0], 60)]);
+        // Peers which haven't reported yet don't block the start
+        assert_eq!(
+            simulation_rate_mismatch(&peers, &peer_simulation_rates, 60),
+            None
+        );
+
+        peer_simulation_rates.insert(peers[1], 30);
+        let reason = simulation_rate_mismatch(&peers, &peer_simulation_rates, 60)
+            .expect("Mismatched rates were accepted");
+        assert!(reason.contains(&peers[1].to_string()));
+        assert!(reason.contains("30 ticks per second"));
+        assert!(reason.contains("simulates at 60"));
+    }
+
+    #[test]
+    fn competing_starts_converge_on_the_lowest_scheduler() {
+        let mut ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        ids.sort();
+        let [lowest, second, third] = ids;
+        // The two lowest ids both believe they lead and schedule a start
+        let starts = [(second, Uuid::new_v4()), (lowest, Uuid::new_v4())];
+
+        // Each peer receives the starts in a different order, schedulers
+        // seeing their own first
+        for order in [[0, 1], [1, 0]] {
+            let mut scheduled_start: Option<ScheduledStart> = None;
+            for (scheduler, run) in order.map(|index| starts[index]) {
+                if replaces_scheduled_start(scheduled_start.as_ref(), scheduler) {
+                    scheduled_start = Some(ScheduledStart {
+                        scheduler,
+                        run,
+                        ticks_till_start: 60,
+                    });
+                }
+            }
+            assert_eq!(scheduled_start.map(|start| start.run), Some(starts[1].1));
+        }
+
+        // The same start delivered again doesn't replace itself
+        let scheduled_start = ScheduledStart {
+            scheduler: lowest,
+            run: starts[1].1,
+            ticks_till_start: 10,
+        };
+        assert!(!replaces_scheduled_start(Some(&scheduled_start), lowest));
+        assert!(!replaces_scheduled_start(Some(&scheduled_start), third));
+    }
+
+    #[test]
+    fn scheduled_starts_count_down_one_second_at_any_rate() {
+        for simulation_rate in [30, 60, 120] {
+            assert_eq!(schedule_ticks(simulation_rate), simulation_rate);
+        }
+    }
 }
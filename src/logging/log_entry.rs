@@ -1,22 +1,26 @@
-use std::{collections::HashMap, hash::Hash};
+use std::{cmp::Ordering, collections::HashMap, hash::Hash};
 
 use anyhow::Result;
 use indoc::indoc;
-use rusqlite::{named_params, Connection};
+use rusqlite::{named_params, types::ValueRef, Connection, Row};
 use uuid::Uuid;
 
 use crate::message::SentInput;
 
+use super::LogConfig;
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum LogEntry {
     RunInfo(RunInfo),
     SentInput(SentInput),
     ReceivedInput(ReceivedInput),
+    ReceivedMessage(ReceivedMessage),
     DroppedFrame(DroppedFrame),
     Rollback(Rollback),
     FrameState(FrameState),
     SpawnedNodeAlive(SpawnedNodeAlive),
     Event(Event),
+    TickTime(TickTime),
 }
 
 impl LogEntry {
@@ -24,11 +28,13 @@ impl LogEntry {
         RunInfo::setup_table(connection)?;
         SentInput::setup_table(connection)?;
         ReceivedInput::setup_table(connection)?;
+        ReceivedMessage::setup_table(connection)?;
         DroppedFrame::setup_table(connection)?;
         Rollback::setup_table(connection)?;
         FrameState::setup_table(connection)?;
         SpawnedNodeAlive::setup_table(connection)?;
         Event::setup_table(connection)?;
+        TickTime::setup_table(connection)?;
         Ok(())
     }
 
@@ -37,11 +43,13 @@ impl LogEntry {
         table_names.append(&mut RunInfo::table_names());
         table_names.append(&mut SentInput::table_names());
         table_names.append(&mut ReceivedInput::table_names());
+        table_names.append(&mut ReceivedMessage::table_names());
         table_names.append(&mut DroppedFrame::table_names());
         table_names.append(&mut Rollback::table_names());
         table_names.append(&mut FrameState::table_names());
         table_names.append(&mut SpawnedNodeAlive::table_names());
         table_names.append(&mut Event::table_names());
+        table_names.append(&mut TickTime::table_names());
         table_names
     }
 
@@ -50,11 +58,13 @@ impl LogEntry {
             LogEntry::RunInfo(_) => 0,
             LogEntry::SentInput(SentInput { frame, .. }) => *frame,
             LogEntry::ReceivedInput(ReceivedInput { received_frame, .. }) => *received_frame,
+            LogEntry::ReceivedMessage(ReceivedMessage { received_frame, .. }) => *received_frame,
             LogEntry::DroppedFrame(DroppedFrame { frame, .. }) => *frame,
             LogEntry::Rollback(Rollback { frame, .. }) => *frame,
             LogEntry::FrameState(FrameState { latest_frame, .. }) => *latest_frame,
             LogEntry::SpawnedNodeAlive(SpawnedNodeAlive { latest_frame, .. }) => *latest_frame,
             LogEntry::Event(Event { latest_frame, .. }) => *latest_frame,
+            LogEntry::TickTime(TickTime { frame, .. }) => *frame,
         }
     }
 
@@ -63,25 +73,29 @@ impl LogEntry {
             LogEntry::RunInfo(RunInfo { local_id, .. }) => *local_id,
             LogEntry::SentInput(SentInput { sender, .. }) => *sender,
             LogEntry::ReceivedInput(ReceivedInput { receiver, .. }) => *receiver,
+            LogEntry::ReceivedMessage(ReceivedMessage { receiver, .. }) => *receiver,
             LogEntry::DroppedFrame(DroppedFrame { lagger, .. }) => *lagger,
             LogEntry::Rollback(Rollback { updater, .. }) => *updater,
             LogEntry::FrameState(FrameState { player, .. }) => *player,
             LogEntry::SpawnedNodeAlive(SpawnedNodeAlive { player, .. }) => *player,
             LogEntry::Event(Event { player, .. }) => *player,
+            LogEntry::TickTime(TickTime { player, .. }) => *player,
         }
     }
 
     /// Writes the log entry to the given database connection.
-    pub fn write(&self, connection: &Connection) -> Result<()> {
+    pub fn write(&self, connection: &Connection, config: &LogConfig) -> Result<()> {
         match self {
             LogEntry::RunInfo(entry) => entry.write(connection),
             LogEntry::SentInput(entry) => entry.write(connection),
             LogEntry::ReceivedInput(entry) => entry.write(connection),
+            LogEntry::ReceivedMessage(entry) => entry.write(connection),
             LogEntry::DroppedFrame(entry) => entry.write(connection),
             LogEntry::Rollback(entry) => entry.write(connection),
-            LogEntry::FrameState(entry) => entry.write(connection),
+            LogEntry::FrameState(entry) => entry.write(connection, config),
             LogEntry::SpawnedNodeAlive(entry) => entry.write(connection),
             LogEntry::Event(entry) => entry.write(connection),
+            LogEntry::TickTime(entry) => entry.write(connection),
         }
     }
 
@@ -107,6 +121,12 @@ impl LogEntry {
                 .map(LogEntry::ReceivedInput)
                 .collect(),
         );
+        log_entries.append(
+            &mut ReceivedMessage::read(connection)?
+                .into_iter()
+                .map(LogEntry::ReceivedMessage)
+                .collect(),
+        );
         log_entries.append(
             &mut DroppedFrame::read(connection)?
                 .into_iter()
@@ -137,6 +157,12 @@ impl LogEntry {
                 .map(LogEntry::Event)
                 .collect(),
         );
+        log_entries.append(
+            &mut TickTime::read(connection)?
+                .into_iter()
+                .map(LogEntry::TickTime)
+                .collect(),
+        );
 
         log_entries.sort_by_key(|entry| entry.frame());
 
@@ -321,6 +347,75 @@ impl ReceivedInput {
     }
 }
 
+/// A message applied by the play stage, recorded so that network replays can
+/// deliver the same messages on the same ticks
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Hash)]
+pub struct ReceivedMessage {
+    pub id: usize,
+    /// Number of play stage ticks processed before the message was applied.
+    /// Unlike frames, this keeps counting while the simulation is stalled
+    pub processed_tick: u64,
+    pub received_frame: u64,
+    pub receiver: Uuid,
+    /// The bincode encoded message
+    pub message: Vec<u8>,
+}
+
+impl ReceivedMessage {
+    pub fn setup_table(connection: &Connection) -> Result<()> {
+        connection.execute_batch(indoc! {"
+            CREATE TABLE IF NOT EXISTS received_messages (
+                id INTEGER NOT NULL,             -- Monotonically increasing id by logger
+                receiver BLOB NOT NULL,          -- The id of the receiver of this message
+                processed_tick INTEGER NOT NULL, -- The play stage tick the message was applied on
+                received_frame INTEGER NOT NULL, -- The latest frame when the message was applied
+                message BLOB NOT NULL,           -- The bincode encoded message
+                PRIMARY KEY (id, receiver)
+            );
+        "})?;
+        Ok(())
+    }
+
+    fn table_names() -> Vec<&'static str> {
+        vec!["received_messages"]
+    }
+
+    pub fn write(&self, connection: &Connection) -> Result<()> {
+        let mut statement = connection.prepare_cached(indoc! {"
+                INSERT INTO received_messages (id, receiver, processed_tick, received_frame, message)
+                VALUES (:id, :receiver, :processed_tick, :received_frame, :message)
+            "})?;
+
+        statement.execute(named_params! {
+            ":id": self.id,
+            ":receiver": self.receiver.as_bytes(),
+            ":processed_tick": self.processed_tick,
+            ":received_frame": self.received_frame,
+            ":message": self.message,
+        })?;
+
+        Ok(())
+    }
+
+    pub fn read(connection: &Connection) -> Result<Vec<Self>> {
+        let mut statement = connection.prepare_cached(
+            "SELECT id, receiver, processed_tick, received_frame, message FROM received_messages",
+        )?;
+
+        let messages = statement.query_and_then([], |row| {
+            Ok(ReceivedMessage {
+                id: row.get::<_, usize>(0)?,
+                receiver: Uuid::from_slice(&row.get::<_, Vec<u8>>(1)?)?,
+                processed_tick: row.get::<_, u64>(2)?,
+                received_frame: row.get::<_, u64>(3)?,
+                message: row.get::<_, Vec<u8>>(4)?,
+            })
+        })?;
+
+        messages.collect()
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Hash)]
 pub struct DroppedFrame {
     pub id: usize,
@@ -328,6 +423,7 @@ pub struct DroppedFrame {
     pub frame_missing_input: u64,
     pub lagger: Uuid,
     pub dropper: Uuid,
+    pub stall_ticks: u64,
 }
 
 impl DroppedFrame {
@@ -339,6 +435,7 @@ impl DroppedFrame {
                 frame_missing_input INTEGER NOT NULL, -- The frame that was missing input
                 lagger BLOB NOT NULL,                 -- The id of the player that was lagging
                 dropper BLOB NOT NULL,                -- The id of the peer that dropped the frame
+                stall_ticks INTEGER NOT NULL,         -- Consecutive ticks stalled on the missing input so far
                 PRIMARY KEY (id, dropper)
             );
         "})?;
@@ -351,8 +448,8 @@ impl DroppedFrame {
 
     pub fn write(&self, connection: &Connection) -> Result<()> {
         let mut statement = connection.prepare_cached(indoc! {"
-                INSERT INTO dropped_frames (id, frame, frame_missing_input, lagger, dropper)
-                VALUES (:id, :frame, :frame_missing_input, :lagger, :dropper)
+                INSERT INTO dropped_frames (id, frame, frame_missing_input, lagger, dropper, stall_ticks)
+                VALUES (:id, :frame, :frame_missing_input, :lagger, :dropper, :stall_ticks)
             "})?;
 
         statement.execute(named_params! {
@@ -361,6 +458,7 @@ impl DroppedFrame {
             ":frame_missing_input": self.frame_missing_input,
             ":lagger": self.lagger.as_bytes(),
             ":dropper": self.dropper.as_bytes(),
+            ":stall_ticks": self.stall_ticks,
         })?;
 
         Ok(())
@@ -368,7 +466,7 @@ impl DroppedFrame {
 
     pub fn read(connection: &Connection) -> Result<Vec<Self>> {
         let mut statement = connection.prepare_cached(
-            "SELECT id, frame, frame_missing_input, lagger, dropper FROM dropped_frames",
+            "SELECT id, frame, frame_missing_input, lagger, dropper, stall_ticks FROM dropped_frames",
         )?;
 
         let frames = statement.query_and_then([], |row| {
@@ -377,12 +475,14 @@ impl DroppedFrame {
             let frame_missing_input = row.get::<_, u64>(2)?;
             let lagger = Uuid::from_slice(&row.get::<_, Vec<u8>>(3)?)?;
             let dropper = Uuid::from_slice(&row.get::<_, Vec<u8>>(4)?)?;
+            let stall_ticks = row.get::<_, u64>(5)?;
             Ok(DroppedFrame {
                 id,
                 frame,
                 frame_missing_input,
                 lagger,
                 dropper,
+                stall_ticks,
             })
         })?;
 
@@ -480,7 +580,7 @@ impl FrameState {
         vec!["frame_states"]
     }
 
-    pub fn write(&self, connection: &Connection) -> Result<()> {
+    pub fn write(&self, connection: &Connection, config: &LogConfig) -> Result<()> {
         let mut statement = connection.prepare_cached(indoc! {"
                 INSERT OR REPLACE INTO frame_states (frame, latest_frame, player, path, key, value_text, value_hash)
                 VALUES (:frame, :latest_frame, :player, :path, :key, :value_text, :value_hash)
@@ -488,19 +588,44 @@ impl FrameState {
 
         let value_hash_bytes = self.value_hash.to_be_bytes();
 
-        statement.execute(named_params! {
-            ":frame": self.frame,
-            ":latest_frame": self.latest_frame,
-            ":player": self.player.as_bytes(),
-            ":path": self.path,
-            ":key": self.key,
-            ":value_text": self.value_text,
-            ":value_hash": value_hash_bytes,
-        })?;
+        // Compressed values are stored as blobs so that they can be told apart
+        // from plain text when read
+        if config.compress_states {
+            let value_blob =
+                zstd::encode_all(self.value_text.as_bytes(), LogConfig::COMPRESSION_LEVEL)?;
+            statement.execute(named_params! {
+                ":frame": self.frame,
+                ":latest_frame": self.latest_frame,
+                ":player": self.player.as_bytes(),
+                ":path": self.path,
+                ":key": self.key,
+                ":value_text": value_blob,
+                ":value_hash": value_hash_bytes,
+            })?;
+        } else {
+            statement.execute(named_params! {
+                ":frame": self.frame,
+                ":latest_frame": self.latest_frame,
+                ":player": self.player.as_bytes(),
+                ":path": self.path,
+                ":key": self.key,
+                ":value_text": self.value_text,
+                ":value_hash": value_hash_bytes,
+            })?;
+        }
 
         Ok(())
     }
 
+    /// Reads a value_text column, decompressing it if it was written with
+    /// state compression enabled.
+    pub fn read_value_text(row: &Row, index: usize) -> Result<String> {
+        Ok(match row.get_ref(index)? {
+            ValueRef::Blob(compressed) => String::from_utf8(zstd::decode_all(compressed)?)?,
+            _ => row.get::<_, String>(index)?,
+        })
+    }
+
     pub fn read(connection: &Connection) -> Result<Vec<Self>> {
         let mut statement = connection.prepare_cached(
             "SELECT frame, latest_frame, player, path, key, value_text, value_hash FROM frame_states",
@@ -512,7 +637,7 @@ impl FrameState {
             let player = Uuid::from_slice(&row.get::<_, Vec<u8>>(2)?)?;
             let path = row.get::<_, String>(3)?;
             let key = row.get::<_, String>(4)?;
-            let value_text = row.get::<_, String>(5)?;
+            let value_text = Self::read_value_text(row, 5)?;
             let value_hash_bytes: [u8; 8] = row.get::<_, Vec<u8>>(6)?.try_into().unwrap();
             let value_hash = u64::from_be_bytes(value_hash_bytes);
             Ok(Self {
@@ -593,7 +718,89 @@ impl SpawnedNodeAlive {
     }
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialOrd, Ord, PartialEq, Eq)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+pub enum EventSeverity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+}
+
+impl EventSeverity {
+    pub const ALL: [EventSeverity; 3] = [
+        EventSeverity::Info,
+        EventSeverity::Warning,
+        EventSeverity::Error,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventSeverity::Info => "info",
+            EventSeverity::Warning => "warning",
+            EventSeverity::Error => "error",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        EventSeverity::ALL
+            .into_iter()
+            .find(|severity| severity.name() == name)
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum EventCategory {
+    Spawn,
+    Desync,
+    Stall,
+    Input,
+    Network,
+    Match,
+}
+
+impl EventCategory {
+    pub const ALL: [EventCategory; 6] = [
+        EventCategory::Spawn,
+        EventCategory::Desync,
+        EventCategory::Stall,
+        EventCategory::Input,
+        EventCategory::Network,
+        EventCategory::Match,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventCategory::Spawn => "spawn",
+            EventCategory::Desync => "desync",
+            EventCategory::Stall => "stall",
+            EventCategory::Input => "input",
+            EventCategory::Network => "network",
+            EventCategory::Match => "match",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        EventCategory::ALL
+            .into_iter()
+            .find(|category| category.name() == name)
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
 pub struct Event {
     pub id: usize,
     pub frame: u64,
@@ -601,6 +808,42 @@ pub struct Event {
     pub player: Uuid,
     pub event: String,
     pub data: String,
+    pub severity: EventSeverity,
+    pub category: Option<EventCategory>,
+}
+
+/// Events are ordered by frame, then by the player who logged them, then by
+/// simulation and the order the player logged them in. Ids are only unique per
+/// player, so ordering by id first would interleave peers arbitrarily.
+impl Ord for Event {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (
+            self.frame,
+            self.player,
+            self.latest_frame,
+            self.id,
+            &self.event,
+            &self.data,
+            self.severity,
+            self.category,
+        )
+            .cmp(&(
+                other.frame,
+                other.player,
+                other.latest_frame,
+                other.id,
+                &other.event,
+                &other.data,
+                other.severity,
+                other.category,
+            ))
+    }
+}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl Event {
@@ -613,6 +856,8 @@ impl Event {
                 player BLOB NOT NULL,
                 event TEXT NOT NULL,
                 data TEXT NOT NULL,
+                severity TEXT NOT NULL,
+                category TEXT,
                 PRIMARY KEY (id, player)
             );
         "})?;
@@ -625,8 +870,8 @@ impl Event {
 
     pub fn write(&self, connection: &Connection) -> Result<()> {
         let mut statement = connection.prepare_cached(indoc! {"
-                INSERT INTO events (id, frame, latest_frame, player, event, data)
-                VALUES (:id, :frame, :latest_frame, :player, :event, :data)
+                INSERT INTO events (id, frame, latest_frame, player, event, data, severity, category)
+                VALUES (:id, :frame, :latest_frame, :player, :event, :data, :severity, :category)
             "})?;
 
         statement.execute(named_params! {
@@ -636,14 +881,19 @@ impl Event {
             ":player": self.player.as_bytes(),
             ":event": self.event,
             ":data": self.data,
+            ":severity": self.severity.name(),
+            ":category": self.category.map(|category| category.name()),
         })?;
 
         Ok(())
     }
 
     pub fn read(connection: &Connection) -> Result<Vec<Self>> {
-        let mut statement = connection
-            .prepare_cached("SELECT id, frame, latest_frame, player, event, data FROM events")?;
+        let mut statement = connection.prepare_cached(indoc! {"
+                SELECT id, frame, latest_frame, player, event, data, severity, category
+                FROM events
+                ORDER BY frame, player, latest_frame, id
+            "})?;
 
         let states = statement.query_and_then([], |row| {
             let id = row.get::<_, usize>(0)?;
@@ -652,6 +902,10 @@ impl Event {
             let player = Uuid::from_slice(&row.get::<_, Vec<u8>>(3)?)?;
             let event = row.get::<_, String>(4)?;
             let data = row.get::<_, String>(5)?;
+            let severity = EventSeverity::from_name(&row.get::<_, String>(6)?).unwrap_or_default();
+            let category = row
+                .get::<_, Option<String>>(7)?
+                .and_then(|category| EventCategory::from_name(&category));
             Ok(Self {
                 id,
                 frame,
@@ -659,9 +913,232 @@ impl Event {
                 player,
                 event,
                 data,
+                severity,
+                category,
             })
         })?;
 
         states.collect()
     }
 }
+
+/// The wall clock time a player started simulating a frame for the first
+/// time. Consecutive frames' timestamps show how evenly ticks were paced
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, Hash, PartialEq, Eq)]
+pub struct TickTime {
+    pub frame: u64,
+    pub player: Uuid,
+    /// Microseconds since the unix epoch
+    pub timestamp: u64,
+    /// Ticks per second the player was simulating at
+    pub simulation_rate: u32,
+}
+
+impl TickTime {
+    pub fn setup_table(connection: &Connection) -> Result<()> {
+        connection.execute_batch(indoc! {"
+            CREATE TABLE IF NOT EXISTS tick_times (
+                frame INTEGER NOT NULL,
+                player BLOB NOT NULL,
+                timestamp INTEGER NOT NULL,       -- Microseconds since the unix epoch
+                simulation_rate INTEGER NOT NULL, -- Ticks per second when the frame started
+                PRIMARY KEY (frame, player)
+            );
+        "})?;
+        Ok(())
+    }
+
+    fn table_names() -> Vec<&'static str> {
+        vec!["tick_times"]
+    }
+
+    pub fn write(&self, connection: &Connection) -> Result<()> {
+        let mut statement = connection.prepare_cached(indoc! {"
+                INSERT OR REPLACE INTO tick_times (frame, player, timestamp, simulation_rate)
+                VALUES (:frame, :player, :timestamp, :simulation_rate)
+            "})?;
+
+        statement.execute(named_params! {
+            ":frame": self.frame,
+            ":player": self.player.as_bytes(),
+            ":timestamp": self.timestamp,
+            ":simulation_rate": self.simulation_rate,
+        })?;
+
+        Ok(())
+    }
+
+    pub fn read(connection: &Connection) -> Result<Vec<Self>> {
+        let mut statement = connection
+            .prepare_cached("SELECT frame, player, timestamp, simulation_rate FROM tick_times")?;
+
+        let tick_times = statement.query_and_then([], |row| {
+            Ok(Self {
+                frame: row.get::<_, u64>(0)?,
+                player: Uuid::from_slice(&row.get::<_, Vec<u8>>(1)?)?,
+                timestamp: row.get::<_, u64>(2)?,
+                simulation_rate: row.get::<_, u32>(3)?,
+            })
+        })?;
+
+        tick_times.collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::Connection;
+    use uuid::Uuid;
+
+    use super::{Event, EventCategory, EventSeverity, FrameState};
+    use crate::logging::{setup_connection, LogConfig};
+
+    /// Writes the states to a fresh in memory log and returns the log along
+    /// with the number of bytes their values take up in it
+    fn write_states(states: &[FrameState], config: &LogConfig) -> (Connection, i64) {
+        let connection = Connection::open_in_memory().unwrap();
+        setup_connection(&connection).unwrap();
+        for state in states {
+            state.write(&connection, config).unwrap();
+        }
+        let value_bytes = connection
+            .query_row(
+                "SELECT SUM(LENGTH(CAST(value_text AS BLOB))) FROM frame_states",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap();
+        (connection, value_bytes)
+    }
+
+    #[test]
+    fn compressed_states_read_back_identically_and_smaller() {
+        let player = Uuid::new_v4();
+        // Node states tend to be long and repetitive, such as arrays of
+        // positions which barely change
+        let states = (0..20)
+            .map(|frame| FrameState {
+                frame,
+                latest_frame: frame,
+                player,
+                path: "/root/World/Level".to_string(),
+                key: "tiles".to_string(),
+                value_text: format!("[{}{frame}]", "(0, 0, 1), ".repeat(200)),
+                value_hash: frame,
+            })
+            .collect::<Vec<_>>();
+
+        let (plain, plain_bytes) = write_states(&states, &LogConfig::default());
+        let compressed_config = LogConfig {
+            compress_states: true,
+            ..Default::default()
+        };
+        let (compressed, compressed_bytes) = write_states(&states, &compressed_config);
+
+        let sorted = |mut states: Vec<FrameState>| {
+            states.sort_by_key(|state| state.frame);
+            states
+        };
+        assert_eq!(sorted(FrameState::read(&plain).unwrap()), states);
+        assert_eq!(sorted(FrameState::read(&compressed).unwrap()), states);
+        assert!(
+            compressed_bytes * 10 < plain_bytes,
+            "{compressed_bytes} compressed bytes vs {plain_bytes} plain"
+        );
+    }
+
+    #[test]
+    fn events_read_in_a_stable_cross_peer_order() {
+        let (first, second) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let event = |id, frame, latest_frame, player| Event {
+            id,
+            frame,
+            latest_frame,
+            player,
+            event: format!("event {id}"),
+            data: String::new(),
+            severity: EventSeverity::Info,
+            category: None,
+        };
+        // Ids count per process, so they overlap between peers and say
+        // nothing about the order across them
+        let written = [
+            event(0, 5, 5, second),
+            event(7, 5, 5, first),
+            event(9, 4, 4, first),
+            event(3, 5, 6, second),
+            event(2, 5, 5, first),
+        ];
+        let connection = Connection::open_in_memory().unwrap();
+        setup_connection(&connection).unwrap();
+        for event in &written {
+            event.write(&connection).unwrap();
+        }
+
+        let read = Event::read(&connection).unwrap();
+
+        let order = |events: &[Event]| {
+            events
+                .iter()
+                .map(|event| (event.frame, event.player, event.latest_frame, event.id))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            (4, first, 4, 9),
+            (5, first, 5, 2),
+            (5, first, 5, 7),
+            (5, second, 5, 0),
+            (5, second, 6, 3),
+        ];
+        assert_eq!(order(&read), expected);
+        // Sorting by Ord agrees with the order the reader returns
+        let mut sorted = written.to_vec();
+        sorted.sort();
+        assert_eq!(order(&sorted), expected);
+    }
+
+    #[test]
+    fn events_keep_their_severity_and_category() {
+        for severity in EventSeverity::ALL {
+            assert_eq!(EventSeverity::from_name(severity.name()), Some(severity));
+        }
+        for category in EventCategory::ALL {
+            assert_eq!(EventCategory::from_name(category.name()), Some(category));
+        }
+        assert_eq!(EventSeverity::from_name("fatal"), None);
+
+        let player = Uuid::from_u128(1);
+        let event = |id, event: &str, severity, category| Event {
+            id,
+            frame: 10,
+            latest_frame: 10,
+            player,
+            event: event.to_string(),
+            data: String::new(),
+            severity,
+            category,
+        };
+        let written = vec![
+            event(
+                0,
+                "desync",
+                EventSeverity::Error,
+                Some(EventCategory::Desync),
+            ),
+            event(
+                1,
+                "stall",
+                EventSeverity::Warning,
+                Some(EventCategory::Stall),
+            ),
+            event(2, "GODOT", EventSeverity::Info, None),
+        ];
+        let connection = Connection::open_in_memory().unwrap();
+        setup_connection(&connection).unwrap();
+        for event in &written {
+            event.write(&connection).unwrap();
+        }
+
+        assert_eq!(Event::read(&connection).unwrap(), written);
+    }
+}
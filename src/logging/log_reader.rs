@@ -1,13 +1,187 @@
-use std::{fs::DirEntry, path::Path, time::SystemTime};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Write,
+    fs::DirEntry,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
 use indoc::indoc;
-use rusqlite::{named_params, params, Connection};
+use rusqlite::{named_params, params, Connection, OpenFlags};
 use uuid::Uuid;
 
-use crate::message::SentInput;
+use crate::message::{Message, SentInput};
 
-use super::{FrameState, LogEntry, RunInfo};
+use super::{
+    DroppedFrame, Event, EventCategory, EventSeverity, FrameState, JournalMode, LogEntry,
+    ReceivedInput, Rollback, RunInfo, SpawnedNodeAlive, TickTime,
+};
+
+/// One player's value for a path and key which players disagree on
+#[derive(Clone, Debug, Hash)]
+pub struct Argument {
+    pub player: Uuid,
+    pub state: Option<FrameState>,
+}
+
+/// Whether every player agreed on the latest states for a frame
+#[derive(Clone, Debug, Hash)]
+pub enum SyncState {
+    Synced {
+        consensus: Vec<FrameState>,
+    },
+    Desynced {
+        disagreements: BTreeMap<(String, String), Vec<Argument>>,
+    },
+}
+
+/// A path and key whose agreed value differs between two runs at a frame.
+/// The value is None in a run which didn't log the key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateDifference {
+    pub path: String,
+    pub key: String,
+    pub value_text: Option<String>,
+    pub other_value_text: Option<String>,
+}
+
+/// A state hash logged for more than one distinct value. Differing values
+/// with the same hash compare as synced, hiding a desync
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashCollision {
+    pub value_hash: u64,
+    pub value_texts: Vec<String>,
+}
+
+/// How long after the previous frame a player started simulating a frame,
+/// compared with the tick period it was aiming for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramePacing {
+    pub frame: u64,
+    pub delta: Duration,
+    pub ideal: Duration,
+}
+
+impl FramePacing {
+    /// Godot runs physics ticks late and then catches up, so a frame only
+    /// counts as overrunning once it is half a tick period late
+    pub fn overran(&self) -> bool {
+        self.delta > self.ideal * 3 / 2
+    }
+
+    /// Computes the pacing of every frame whose previous frame also has a
+    /// tick time. Frames missing from the log, such as while logging was
+    /// disabled, leave a gap rather than one long frame.
+    pub fn from_tick_times(tick_times: &[TickTime]) -> Vec<FramePacing> {
+        let mut tick_times = tick_times.iter().collect::<Vec<_>>();
+        tick_times.sort_by_key(|tick_time| tick_time.frame);
+        tick_times
+            .windows(2)
+            .filter(|pair| pair[0].frame + 1 == pair[1].frame)
+            .map(|pair| FramePacing {
+                frame: pair[1].frame,
+                delta: Duration::from_micros(pair[1].timestamp.saturating_sub(pair[0].timestamp)),
+                ideal: Duration::from_secs(1) / pair[1].simulation_rate.max(1),
+            })
+            .collect()
+    }
+}
+
+/// The entries a player logged on a frame, grouped by kind
+#[derive(Clone, Debug, Default)]
+pub struct PlayerEntries {
+    pub run_info: Option<RunInfo>,
+    pub sent_input: Option<SentInput>,
+    pub received_inputs: Vec<ReceivedInput>,
+    pub rollback: Option<Rollback>,
+    pub dropped_frame: Option<DroppedFrame>,
+    pub frame_states: Vec<FrameState>,
+    pub spawned_nodes_alive: HashMap<u64, Vec<SpawnedNodeAlive>>,
+    pub events: HashMap<u64, BTreeSet<Event>>,
+    pub tick_time: Option<TickTime>,
+}
+
+impl PlayerEntries {
+    fn add(&mut self, entry: LogEntry) {
+        match entry {
+            LogEntry::RunInfo(entry) => {
+                self.run_info = Some(entry);
+            }
+            LogEntry::SentInput(entry) => {
+                self.sent_input = Some(entry);
+            }
+            LogEntry::ReceivedInput(entry) => {
+                self.received_inputs.push(entry);
+            }
+            // Only used by network replays
+            LogEntry::ReceivedMessage(_) => {}
+            LogEntry::Rollback(entry) => {
+                self.rollback = Some(entry);
+            }
+            LogEntry::DroppedFrame(entry) => {
+                self.dropped_frame = Some(entry);
+            }
+            LogEntry::FrameState(entry) => {
+                self.frame_states.push(entry);
+            }
+            LogEntry::SpawnedNodeAlive(entry) => {
+                self.spawned_nodes_alive
+                    .entry(entry.frame)
+                    .or_default()
+                    .push(entry);
+            }
+            LogEntry::Event(entry) => {
+                self.events.entry(entry.frame).or_default().insert(entry);
+            }
+            LogEntry::TickTime(entry) => {
+                self.tick_time = Some(entry);
+            }
+        }
+    }
+
+    pub fn contains_state(&self, highlighted_state: &Option<(String, String, u64)>) -> bool {
+        let Some((expected_path, expected_key, expected_hash)) = highlighted_state.as_ref() else {
+            return false;
+        };
+
+        for state in self.frame_states.iter() {
+            if &state.path == expected_path
+                && &state.key == expected_key
+                && &state.value_hash == expected_hash
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Every entry logged on a frame grouped by the player which logged it, along
+/// with whether the players agreed on the frame's states
+#[derive(Clone, Debug)]
+pub struct FrameBundle {
+    pub player_entries: HashMap<Uuid, PlayerEntries>,
+    pub sync_state: SyncState,
+}
+
+impl FrameBundle {
+    /// A frame is quiet if every player agreed on its state and no player
+    /// rolled back or dropped it. Runs of quiet frames can be folded away.
+    pub fn is_quiet(&self) -> bool {
+        matches!(self.sync_state, SyncState::Synced { .. })
+            && self.player_entries.values().all(|player_entries| {
+                player_entries.rollback.is_none()
+                    && player_entries.dropped_frame.is_none()
+                    && player_entries
+                        .events
+                        .values()
+                        .flatten()
+                        .all(|event| event.severity == EventSeverity::Info)
+            })
+    }
+}
 
 pub struct LogReader {
     pub run: Uuid,
@@ -96,23 +270,58 @@ impl LogReader {
                 .ok_or(anyhow!("File name not a standard string"))?
                 .starts_with(&run_string)
             {
-                let file_path = entry
-                    .path()
-                    .to_str()
-                    .ok_or(anyhow!("File path not a standard string"))?
-                    .to_string();
-                let mut sql = format!("ATTACH DATABASE '{file_path}' AS run;\n");
-                for table in LogEntry::table_names() {
-                    sql.push_str(&format!("INSERT INTO {table} SELECT * FROM run.{table};\n"));
-                }
-                sql.push_str("DETACH DATABASE run;");
-                connection.execute_batch(&sql)?;
+                Self::copy_log_file(&connection, &entry.path())?;
             }
         }
 
         Ok(Self { run, connection })
     }
 
+    /// Copies every table of the log file into the connection. The file is
+    /// attached read only so a game still writing the log can't be disturbed,
+    /// and copied in a single transaction so every table comes from the same
+    /// snapshot of the log.
+    fn copy_log_file(connection: &Connection, path: &Path) -> Result<()> {
+        let readable_copy = Self::readable_copy(path)?;
+        let file_path = readable_copy
+            .as_deref()
+            .unwrap_or(path)
+            .to_str()
+            .ok_or(anyhow!("File path not a standard string"))?
+            .to_string();
+        connection.execute("ATTACH DATABASE ? AS run", [read_only_uri(&file_path)])?;
+        let mut sql = "BEGIN;\n".to_string();
+        for table in LogEntry::table_names() {
+            sql.push_str(&format!("INSERT INTO {table} SELECT * FROM run.{table};\n"));
+        }
+        sql.push_str("COMMIT;\nDETACH DATABASE run;");
+        connection.execute_batch(&sql)?;
+
+        if let Some(readable_copy) = readable_copy {
+            std::fs::remove_file(readable_copy)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the log file to the temp directory if it can't be read while it is
+    /// being written to. Without a WAL journal the writer locks the whole file
+    /// for every transaction, so reading a live log would fail with busy errors.
+    fn readable_copy(path: &Path) -> Result<Option<PathBuf>> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        connection.busy_timeout(Duration::from_millis(100))?;
+        if JournalMode::query(&connection)?.allows_concurrent_reads() {
+            return Ok(None);
+        }
+
+        let file_name = path
+            .file_name()
+            .ok_or(anyhow!("log path does not have a file name"))?;
+        let copy_path = std::env::temp_dir().join(file_name);
+        std::fs::copy(path, &copy_path)?;
+        Ok(Some(copy_path))
+    }
+
     pub fn load_log_file(file_path: &str) -> Result<Self> {
         let run = Self::parse_log_run_id(file_path)?;
         let connection = Connection::open(file_path)?;
@@ -140,8 +349,19 @@ impl LogReader {
         Ok(statement.query_row([], |row| row.get::<_, u64>(0))?)
     }
 
-    /// Finds the last frame where the given player rolledback past the given frame
-    pub fn last_update_for_frame(&self, player: Uuid, frame: u64) -> Result<u64> {
+    /// The first frame local input was sent for, or None if none was sent
+    pub fn first_input_frame(&self) -> Result<Option<u64>> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT MIN(frame) FROM sent_inputs")?;
+
+        Ok(statement.query_row([], |row| row.get::<_, Option<u64>>(0))?)
+    }
+
+    /// Finds the last frame where the given player rolledback past the given
+    /// frame. None if the player didn't log any states for the frame, such as
+    /// when states are only logged every few frames
+    pub fn last_update_for_frame(&self, player: Uuid, frame: u64) -> Result<Option<u64>> {
         let mut statement = self.connection.prepare_cached(indoc! {"
                 SELECT MAX(latest_frame)
                 FROM (SELECT latest_frame, player, frame
@@ -151,13 +371,17 @@ impl LogReader {
 
         Ok(
             statement.query_row(params![player.as_bytes(), &frame], |row| {
-                row.get::<_, u64>(0)
+                row.get::<_, Option<u64>>(0)
             })?,
         )
     }
 
+    /// The states the player logged for the frame the last time it simulated
+    /// it. Empty if the player didn't log states for the frame
     pub fn latest_states_for_frame(&self, player: Uuid, frame: u64) -> Result<Vec<FrameState>> {
-        let last_update_frame = self.last_update_for_frame(player, frame)?;
+        let Some(last_update_frame) = self.last_update_for_frame(player, frame)? else {
+            return Ok(Vec::new());
+        };
         let mut statement = self
             .connection
             .prepare_cached(indoc! {"
@@ -172,7 +396,7 @@ impl LogReader {
         while let Some(row) = rows.next()? {
             let path = row.get::<_, String>(0)?;
             let key = row.get::<_, String>(1)?;
-            let value_text = row.get::<_, String>(2)?;
+            let value_text = FrameState::read_value_text(row, 2)?;
             let value_hash_bytes: [u8; 8] = row.get::<_, Vec<u8>>(3)?.try_into().unwrap();
             let value_hash = u64::from_be_bytes(value_hash_bytes);
             states.push(FrameState {
@@ -213,6 +437,31 @@ impl LogReader {
         inputs.collect()
     }
 
+    /// Returns the messages the receiver applied on the given processed tick
+    /// in the order they were applied
+    pub fn received_messages_for_tick(
+        &self,
+        receiver: Uuid,
+        processed_tick: u64,
+    ) -> Result<Vec<Message>> {
+        let mut statement = self.connection.prepare_cached(indoc! {"
+            SELECT message
+            FROM received_messages
+            WHERE receiver = :receiver AND processed_tick = :processed_tick
+            ORDER BY id
+        "})?;
+
+        let messages = statement.query_and_then(
+            named_params! {
+                ":receiver": receiver.as_bytes(),
+                ":processed_tick": processed_tick,
+            },
+            |row| Ok(bincode::deserialize::<Message>(&row.get::<_, Vec<u8>>(0)?)?),
+        )?;
+
+        messages.collect()
+    }
+
     pub fn sent_input_for_tick(&self, tick: u64) -> Result<Vec<u8>> {
         let mut statement = self.connection.prepare_cached(indoc! {"
             SELECT input
@@ -228,7 +477,873 @@ impl LogReader {
         )?)
     }
 
+    /// Returns the events with the given name logged by the player for the
+    /// given frame. If the frame was simulated multiple times, only the events
+    /// from the latest simulation are returned.
+    pub fn events_for_frame(&self, player: Uuid, frame: u64, event: &str) -> Result<Vec<Event>> {
+        let mut statement = self.connection.prepare_cached(indoc! {"
+            SELECT id, latest_frame, data, severity, category
+            FROM events
+            WHERE player = :player AND frame = :frame AND event = :event AND latest_frame = (
+                SELECT MAX(latest_frame)
+                FROM events
+                WHERE player = :player AND frame = :frame
+            )
+            ORDER BY id
+        "})?;
+
+        let events = statement.query_and_then(
+            named_params! {
+                ":player": player.as_bytes(),
+                ":frame": frame,
+                ":event": event,
+            },
+            |row| {
+                Ok(Event {
+                    id: row.get::<_, usize>(0)?,
+                    frame,
+                    latest_frame: row.get::<_, u64>(1)?,
+                    player,
+                    event: event.to_string(),
+                    data: row.get::<_, String>(2)?,
+                    severity: EventSeverity::from_name(&row.get::<_, String>(3)?)
+                        .unwrap_or_default(),
+                    category: row
+                        .get::<_, Option<String>>(4)?
+                        .and_then(|category| EventCategory::from_name(&category)),
+                })
+            },
+        )?;
+
+        events.collect()
+    }
+
+    /// Returns the markers logged by the player as (frame, label) pairs
+    /// ordered by frame. Markers logged again when a frame was re-simulated
+    /// are only returned for the first frame each label was logged on.
+    pub fn markers(&self, player: Uuid) -> Result<Vec<(u64, String)>> {
+        let mut statement = self.connection.prepare_cached(indoc! {"
+            SELECT MIN(frame), data
+            FROM events
+            WHERE player = :player AND event = 'marker'
+            GROUP BY data
+            ORDER BY MIN(frame), MIN(id)
+        "})?;
+
+        let markers = statement.query_and_then(
+            named_params! {
+                ":player": player.as_bytes(),
+            },
+            |row| Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?)),
+        )?;
+
+        markers.collect()
+    }
+
+    /// The frames the player didn't log because logging was paused, from
+    /// each logging_paused marker up to the following logging_resumed
+    /// marker. A gap still open when the log ends runs through the last frame
+    /// input was sent for.
+    pub fn logging_gaps(&self, player: Uuid) -> Result<Vec<Range<u64>>> {
+        let mut statement = self.connection.prepare_cached(indoc! {"
+            SELECT frame, event
+            FROM events
+            WHERE player = :player AND event IN ('logging_paused', 'logging_resumed')
+            ORDER BY frame, id
+        "})?;
+
+        let markers = statement
+            .query_and_then(
+                named_params! {
+                    ":player": player.as_bytes(),
+                },
+                |row| -> Result<(u64, bool)> {
+                    Ok((
+                        row.get::<_, u64>(0)?,
+                        row.get::<_, String>(1)? == "logging_paused",
+                    ))
+                },
+            )?
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut gaps = Vec::new();
+        let mut paused_at = None;
+        for (frame, paused) in markers {
+            match (paused, paused_at) {
+                (true, None) => paused_at = Some(frame),
+                (false, Some(start)) => {
+                    gaps.push(start..frame);
+                    paused_at = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = paused_at {
+            gaps.push(start..self.frame_count()? + 1);
+        }
+
+        Ok(gaps)
+    }
+
+    /// Compares the latest states each player logged for the frame. Returns
+    /// None if no player logged states for the frame.
+    pub fn sync_state(&self, frame: u64) -> Result<Option<SyncState>> {
+        let latest_states = self
+            .players()?
+            .into_iter()
+            .filter_map(|player| {
+                self.latest_states_for_frame(player, frame)
+                    .ok()
+                    .filter(|states| !states.is_empty())
+                    .map(|states| (player, states))
+            })
+            .collect::<Vec<_>>();
+
+        let Some((_, sentinel)) = latest_states.get(0) else {
+            return Ok(None);
+        };
+
+        if latest_states.iter().all(|(_, states)| {
+            states
+                .iter()
+                .zip(sentinel.iter())
+                .all(|(a, b)| a.value_hash == b.value_hash)
+        }) {
+            return Ok(Some(SyncState::Synced {
+                consensus: sentinel.clone(),
+            }));
+        }
+
+        let mut disagreements = BTreeMap::new();
+        let keys: HashSet<(String, String)> = latest_states
+            .iter()
+            .flat_map(|(_, states)| {
+                states
+                    .iter()
+                    .map(|state| (state.path.clone(), state.key.clone()))
+            })
+            .collect();
+        for (path, key) in keys.into_iter() {
+            for (player, player_states) in latest_states.iter() {
+                let state = player_states
+                    .iter()
+                    .find(|state| state.path == path && state.key == key)
+                    .cloned();
+                let arguments = disagreements
+                    .entry((path.clone(), key.clone()))
+                    .or_insert_with(|| Vec::new());
+                arguments.push(Argument {
+                    player: *player,
+                    state,
+                });
+            }
+        }
+        disagreements.retain(|_, arguments| {
+            let first = arguments[0].state.as_ref().map(|state| state.value_hash);
+            arguments
+                .iter()
+                .any(|argument| argument.state.as_ref().map(|state| state.value_hash) != first)
+        });
+
+        Ok(Some(SyncState::Desynced { disagreements }))
+    }
+
+    /// The states every player agreed on at the frame. Empty if no states were
+    /// logged for the frame, and an error if the players desynced there since
+    /// the run then has no single state to compare.
+    pub fn consensus_states(&self, frame: u64) -> Result<Vec<FrameState>> {
+        match self.sync_state(frame)? {
+            None => Ok(Vec::new()),
+            Some(SyncState::Synced { consensus }) => Ok(consensus),
+            Some(SyncState::Desynced { .. }) => Err(anyhow!(
+                "Run {} desynced at frame {frame} so it has no consensus state",
+                self.run
+            )),
+        }
+    }
+
+    /// Compares the states the players of this run and the other run agreed
+    /// on at the frame, returning every path and key whose value differs.
+    /// Useful for checking that a change didn't alter the simulation by
+    /// replaying the same inputs with both builds.
+    pub fn diff_runs(&self, other: &LogReader, frame: u64) -> Result<Vec<StateDifference>> {
+        let states = self.consensus_states(frame)?;
+        let other_states = other.consensus_states(frame)?;
+        let by_key = |states: Vec<FrameState>| {
+            states
+                .into_iter()
+                .map(|state| ((state.path.clone(), state.key.clone()), state))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let mut states = by_key(states);
+        let mut other_states = by_key(other_states);
+
+        let keys = states
+            .keys()
+            .chain(other_states.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                let state = states.remove(&key);
+                let other_state = other_states.remove(&key);
+                let value_hash = state.as_ref().map(|state| state.value_hash);
+                let other_value_hash = other_state.as_ref().map(|state| state.value_hash);
+                (value_hash != other_value_hash).then(|| {
+                    let (path, key) = key;
+                    StateDifference {
+                        path,
+                        key,
+                        value_text: state.map(|state| state.value_text),
+                        other_value_text: other_state.map(|state| state.value_text),
+                    }
+                })
+            })
+            .collect())
+    }
+
+    /// Finds every state hash in the run which was logged with more than one
+    /// distinct value. Values which a state hash epsilon rounds together
+    /// share a hash on purpose, so they are reported here as well.
+    pub fn hash_collisions(&self) -> Result<Vec<HashCollision>> {
+        // Compressed and uncompressed texts of the same value differ in the
+        // database, so candidates are only confirmed once decompressed
+        let mut statement = self.connection.prepare_cached(indoc! {"
+            SELECT DISTINCT value_hash, value_text
+            FROM frame_states
+            WHERE value_hash IN (
+                SELECT value_hash
+                FROM frame_states
+                GROUP BY value_hash
+                HAVING COUNT(DISTINCT value_text) > 1
+            )
+        "})?;
+        let mut rows = statement.query([])?;
+
+        let mut value_texts_by_hash = BTreeMap::<u64, BTreeSet<String>>::new();
+        while let Some(row) = rows.next()? {
+            let value_hash_bytes: [u8; 8] = row.get::<_, Vec<u8>>(0)?.try_into().unwrap();
+            let value_hash = u64::from_be_bytes(value_hash_bytes);
+            let value_text = FrameState::read_value_text(row, 1)?;
+            value_texts_by_hash
+                .entry(value_hash)
+                .or_default()
+                .insert(value_text);
+        }
+
+        Ok(value_texts_by_hash
+            .into_iter()
+            .filter(|(_, value_texts)| value_texts.len() > 1)
+            .map(|(value_hash, value_texts)| HashCollision {
+                value_hash,
+                value_texts: value_texts.into_iter().collect(),
+            })
+            .collect())
+    }
+
+    /// Produces a plain text report of every path and key the players
+    /// disagree on at the given frame along with each player's value.
+    pub fn desync_report(&self, frame: u64) -> Result<String> {
+        let mut report = format!("Run {} frame {frame}\n", self.run);
+        match self.sync_state(frame)? {
+            None => writeln!(report, "No states logged")?,
+            Some(SyncState::Synced { .. }) => writeln!(report, "All players agree")?,
+            Some(SyncState::Desynced { disagreements }) => {
+                for ((path, key), arguments) in disagreements {
+                    writeln!(report, "\n{path}::{key}")?;
+                    for Argument { player, state } in arguments {
+                        match state {
+                            Some(FrameState {
+                                value_text,
+                                value_hash,
+                                ..
+                            }) => writeln!(report, "  {player}: {value_text} #{value_hash:016x}")?,
+                            None => writeln!(report, "  {player}: None")?,
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// The pacing of every frame the player logged a tick time for, ordered by
+    /// frame
+    pub fn pacing(&self, player: Uuid) -> Result<Vec<FramePacing>> {
+        let tick_times = TickTime::read(&self.connection)?
+            .into_iter()
+            .filter(|tick_time| tick_time.player == player)
+            .collect::<Vec<_>>();
+        Ok(FramePacing::from_tick_times(&tick_times))
+    }
+
     pub fn log_entries(&self) -> Result<Vec<LogEntry>> {
         LogEntry::read(&self.connection)
     }
+
+    /// Groups the run's entries by the frame they were logged on, in frame
+    /// order. Frames without any entries are skipped, and every player in the
+    /// run has entries in each bundle even if it logged nothing on the frame.
+    pub fn frames(&self) -> Result<impl Iterator<Item = (u64, FrameBundle)>> {
+        let players = self.players()?;
+        let frame_count = self.frame_count()?;
+        let mut entries_by_frame: BTreeMap<u64, Vec<LogEntry>> = BTreeMap::new();
+        for entry in self.log_entries()? {
+            entries_by_frame
+                .entry(entry.frame())
+                .or_default()
+                .push(entry);
+        }
+
+        // Only frames before the last frame input was sent for are bundled
+        entries_by_frame.split_off(&frame_count);
+
+        let mut frames = Vec::new();
+        for (frame, entries) in entries_by_frame {
+            let mut player_entries: HashMap<Uuid, PlayerEntries> = players
+                .iter()
+                .map(|player| (*player, PlayerEntries::default()))
+                .collect();
+            for entry in entries {
+                if let Some(entries) = player_entries.get_mut(&entry.logger()) {
+                    entries.add(entry);
+                }
+            }
+
+            let sync_state = self
+                .sync_state(frame)?
+                .unwrap_or_else(|| SyncState::Synced {
+                    consensus: Default::default(),
+                });
+            frames.push((
+                frame,
+                FrameBundle {
+                    player_entries,
+                    sync_state,
+                },
+            ));
+        }
+
+        Ok(frames.into_iter())
+    }
+}
+
+/// Builds a sqlite URI which opens the log file at the given path read only
+fn read_only_uri(path: &str) -> String {
+    let path = path
+        .replace('\\', "/")
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    // Windows paths start with a drive letter instead of a slash
+    let separator = if path.starts_with('/') { "" } else { "/" };
+    format!("file://{separator}{path}?mode=ro")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        hash::{Hash, Hasher},
+        time::Duration,
+    };
+
+    use rusqlite::Connection;
+    use uuid::Uuid;
+
+    use super::{read_only_uri, FramePacing, HashCollision, LogReader, StateDifference, SyncState};
+    use crate::{
+        logging::{
+            setup_connection, Event, EventCategory, EventSeverity, FrameState, LogConfig, LogEntry,
+            ReceivedInput, Rollback, TickTime,
+        },
+        message::SentInput,
+        stable_hasher::StableHasher,
+    };
+
+    const PATH: &str = "/root/World/Player";
+
+    /// A reader over an in memory log holding the entries
+    fn reader_with(entries: impl IntoIterator<Item = LogEntry>) -> LogReader {
+        let connection = Connection::open_in_memory().unwrap();
+        setup_connection(&connection).unwrap();
+        for entry in entries {
+            entry.write(&connection, &LogConfig::default()).unwrap();
+        }
+        LogReader {
+            run: Uuid::new_v4(),
+            connection,
+        }
+    }
+
+    fn value_hash(value_text: &str) -> u64 {
+        let mut hasher = StableHasher::new();
+        value_text.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn sent_input(frame: u64, sender: Uuid) -> LogEntry {
+        LogEntry::SentInput(SentInput {
+            frame,
+            sender,
+            input: vec![frame as u8],
+        })
+    }
+
+    /// A state the player logged for the frame while simulating latest_frame
+    fn state(frame: u64, latest_frame: u64, player: Uuid, key: &str, value_text: &str) -> LogEntry {
+        LogEntry::FrameState(FrameState {
+            frame,
+            latest_frame,
+            player,
+            path: PATH.to_string(),
+            key: key.to_string(),
+            value_text: value_text.to_string(),
+            value_hash: value_hash(value_text),
+        })
+    }
+
+    #[test]
+    fn frames_are_bundled_in_tick_order() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut entries = Vec::new();
+        // Logged newest first so the order has to come from the reader
+        for frame in (1..=4).rev() {
+            for player in [a, b] {
+                entries.push(sent_input(frame, player));
+                entries.push(state(frame, frame, player, "tick", &frame.to_string()));
+            }
+        }
+        entries.push(LogEntry::ReceivedInput(ReceivedInput {
+            received_frame: 2,
+            receiver: a,
+            sent_input: SentInput {
+                frame: 1,
+                sender: b,
+                input: vec![1],
+            },
+        }));
+        // Player a rolls back on frame 3 and simulates frame 2 again
+        entries.push(LogEntry::Rollback(Rollback {
+            frame: 3,
+            rolled_back_to: 2,
+            updater: a,
+        }));
+        entries.push(state(2, 3, a, "tick", "2"));
+        let reader = reader_with(entries);
+
+        let frames = reader.frames().unwrap().collect::<Vec<_>>();
+
+        // Frame 4 is the last frame input was sent for, so it's still open
+        let ticks = frames.iter().map(|(frame, _)| *frame).collect::<Vec<_>>();
+        assert_eq!(ticks, vec![1, 2, 3]);
+        for (frame, bundle) in &frames {
+            assert_eq!(bundle.player_entries.len(), 2);
+            assert!(matches!(bundle.sync_state, SyncState::Synced { .. }));
+            for player in [a, b] {
+                let player_entries = &bundle.player_entries[&player];
+                let sent_input = player_entries.sent_input.as_ref().unwrap();
+                assert_eq!((sent_input.frame, sent_input.sender), (*frame, player));
+                assert!(player_entries
+                    .frame_states
+                    .iter()
+                    .all(|state| state.latest_frame == *frame && state.player == player));
+            }
+        }
+
+        let received_inputs = &frames[1].1.player_entries[&a].received_inputs;
+        assert_eq!(received_inputs.len(), 1);
+        assert_eq!(received_inputs[0].sent_input.sender, b);
+        assert!(frames[1].1.player_entries[&b].received_inputs.is_empty());
+
+        let frame_3 = &frames[2].1.player_entries[&a];
+        assert_eq!(frame_3.rollback.as_ref().unwrap().rolled_back_to, 2);
+        let mut simulated = frame_3
+            .frame_states
+            .iter()
+            .map(|state| state.frame)
+            .collect::<Vec<_>>();
+        simulated.sort();
+        assert_eq!(simulated, vec![2, 3]);
+        assert!(frames[2].1.player_entries[&b].rollback.is_none());
+    }
+
+    #[test]
+    fn sparse_state_rows_still_reveal_desyncs() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut entries = Vec::new();
+        // States are logged every fifth frame, and the players diverge from
+        // frame 8 onward
+        for frame in 0..=20 {
+            for player in [a, b] {
+                entries.push(sent_input(frame, player));
+                if frame % 5 == 0 {
+                    let diverged = player == b && frame >= 8;
+                    let value_text = if diverged { "moved" } else { "idle" };
+                    entries.push(state(frame, frame, player, "status", value_text));
+                }
+            }
+        }
+        let reader = reader_with(entries);
+
+        let logged_frames = (0..=20)
+            .filter(|frame| {
+                !reader
+                    .latest_states_for_frame(a, *frame)
+                    .unwrap()
+                    .is_empty()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(logged_frames, vec![0, 5, 10, 15, 20]);
+        assert_eq!(reader.last_update_for_frame(a, 3).unwrap(), None);
+
+        assert!(reader.sync_state(3).unwrap().is_none());
+        assert!(matches!(
+            reader.sync_state(5).unwrap(),
+            Some(SyncState::Synced { .. })
+        ));
+        assert!(matches!(
+            reader.sync_state(10).unwrap(),
+            Some(SyncState::Desynced { .. })
+        ));
+
+        // Frames between the logged ones are bundled without states rather
+        // than failing
+        let frames = reader.frames().unwrap().collect::<Vec<_>>();
+        assert_eq!(frames.len(), 20);
+        let desynced = frames
+            .iter()
+            .filter(|(_, bundle)| matches!(bundle.sync_state, SyncState::Desynced { .. }))
+            .map(|(frame, _)| *frame)
+            .collect::<Vec<_>>();
+        assert_eq!(desynced, vec![10, 15]);
+    }
+
+    #[test]
+    fn distinct_values_forced_into_one_hash_are_reported() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let forced = |player, value_text: &str| {
+            LogEntry::FrameState(FrameState {
+                frame: 1,
+                latest_frame: 1,
+                player,
+                path: PATH.to_string(),
+                key: "position".to_string(),
+                value_text: value_text.to_string(),
+                value_hash: 42,
+            })
+        };
+        let reader = reader_with([
+            sent_input(1, a),
+            sent_input(1, b),
+            forced(a, "(1, 2)"),
+            forced(b, "(1, 3)"),
+            state(1, 1, a, "health", "100"),
+            state(1, 1, b, "health", "100"),
+        ]);
+
+        assert_eq!(
+            reader.hash_collisions().unwrap(),
+            vec![HashCollision {
+                value_hash: 42,
+                value_texts: vec!["(1, 2)".to_string(), "(1, 3)".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn compressed_and_plain_copies_of_a_value_do_not_collide() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let reader = reader_with([state(1, 1, a, "health", "100")]);
+        let compressed = LogConfig {
+            compress_states: true,
+            ..Default::default()
+        };
+        state(1, 1, b, "health", "100")
+            .write(&reader.connection, &compressed)
+            .unwrap();
+
+        assert!(reader.hash_collisions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn logging_gaps_span_from_pause_to_resume() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let marker = |id, frame, event: &str| {
+            LogEntry::Event(Event {
+                id,
+                frame,
+                latest_frame: frame,
+                player: a,
+                event: event.to_string(),
+                data: String::new(),
+                severity: EventSeverity::Info,
+                category: Some(EventCategory::Match),
+            })
+        };
+        let mut entries = (0..=40)
+            .flat_map(|frame| [sent_input(frame, a), sent_input(frame, b)])
+            .collect::<Vec<_>>();
+        // The second pause is never resumed before the run ends, and pausing
+        // again while paused doesn't start a new gap
+        entries.extend([
+            marker(0, 10, "logging_paused"),
+            marker(1, 12, "logging_paused"),
+            marker(2, 20, "logging_resumed"),
+            marker(3, 30, "logging_paused"),
+        ]);
+        let reader = reader_with(entries);
+
+        assert_eq!(reader.logging_gaps(a).unwrap(), vec![10..20, 30..41]);
+        assert!(reader.logging_gaps(b).unwrap().is_empty());
+    }
+
+    #[test]
+    fn live_logs_are_read_from_their_committed_snapshot_without_writing() {
+        let path = std::env::temp_dir().join(format!("{}_{}.db", Uuid::new_v4(), Uuid::new_v4()));
+        let player = Uuid::new_v4();
+        let writer = Connection::open(&path).unwrap();
+        assert!(setup_connection(&writer).unwrap().allows_concurrent_reads());
+        for frame in 1..=2 {
+            state(frame, frame, player, "tick", &frame.to_string())
+                .write(&writer, &LogConfig::default())
+                .unwrap();
+        }
+        // The writer is partway through committing the next frame
+        writer.execute_batch("BEGIN;").unwrap();
+        state(3, 3, player, "tick", "3")
+            .write(&writer, &LogConfig::default())
+            .unwrap();
+
+        let connection = Connection::open_in_memory().unwrap();
+        setup_connection(&connection).unwrap();
+        LogReader::copy_log_file(&connection, &path).unwrap();
+        let reader = LogReader {
+            run: Uuid::new_v4(),
+            connection,
+        };
+        for frame in 1..=2 {
+            assert_eq!(
+                reader.latest_states_for_frame(player, frame).unwrap().len(),
+                1
+            );
+        }
+        assert!(reader
+            .latest_states_for_frame(player, 3)
+            .unwrap()
+            .is_empty());
+
+        // The log is attached read only, so the reader can't disturb it
+        reader
+            .connection
+            .execute(
+                "ATTACH DATABASE ? AS run",
+                [read_only_uri(path.to_str().unwrap())],
+            )
+            .unwrap();
+        assert!(reader
+            .connection
+            .execute("DELETE FROM run.frame_states", [])
+            .is_err());
+        reader
+            .connection
+            .execute("DETACH DATABASE run", [])
+            .unwrap();
+
+        writer.execute_batch("COMMIT;").unwrap();
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn desync_report_lists_each_players_disagreeing_value() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let reader = reader_with([
+            sent_input(1, a),
+            sent_input(1, b),
+            state(1, 1, a, "health", "100"),
+            state(1, 1, b, "health", "100"),
+            state(1, 1, a, "position", "(1, 2)"),
+            state(1, 1, b, "position", "(1, 3)"),
+        ]);
+
+        let report = reader.desync_report(1).unwrap();
+
+        assert!(report.starts_with(&format!("Run {} frame 1\n", reader.run)));
+        assert!(report.contains(&format!("\n{PATH}::position\n")));
+        assert!(report.contains(&format!("  {a}: (1, 2) #{:016x}\n", value_hash("(1, 2)"))));
+        assert!(report.contains(&format!("  {b}: (1, 3) #{:016x}\n", value_hash("(1, 3)"))));
+        // Values every player agrees on are left out
+        assert!(!report.contains("health"));
+    }
+
+    #[test]
+    fn diffing_runs_finds_the_single_differing_state() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let run = |position: &str| {
+            reader_with([
+                sent_input(1, a),
+                sent_input(1, b),
+                state(1, 1, a, "health", "100"),
+                state(1, 1, b, "health", "100"),
+                state(1, 1, a, "position", position),
+                state(1, 1, b, "position", position),
+            ])
+        };
+        let reader = run("(1, 2)");
+        let other = run("(1, 3)");
+
+        assert_eq!(
+            reader.diff_runs(&other, 1).unwrap(),
+            vec![StateDifference {
+                path: PATH.to_string(),
+                key: "position".to_string(),
+                value_text: Some("(1, 2)".to_string()),
+                other_value_text: Some("(1, 3)".to_string()),
+            }]
+        );
+        assert!(reader.diff_runs(&run("(1, 2)"), 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diffing_runs_reports_states_only_one_run_logged() {
+        let player = Uuid::new_v4();
+        let reader = reader_with([sent_input(1, player), state(1, 1, player, "health", "100")]);
+        let other = reader_with([
+            sent_input(1, player),
+            state(1, 1, player, "health", "100"),
+            state(1, 1, player, "ammo", "3"),
+        ]);
+
+        assert_eq!(
+            reader.diff_runs(&other, 1).unwrap(),
+            vec![StateDifference {
+                path: PATH.to_string(),
+                key: "ammo".to_string(),
+                value_text: None,
+                other_value_text: Some("3".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn desync_report_notes_agreement_and_missing_states() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let reader = reader_with([
+            sent_input(1, a),
+            sent_input(1, b),
+            state(1, 1, a, "health", "100"),
+            state(1, 1, b, "health", "100"),
+        ]);
+
+        assert!(reader
+            .desync_report(1)
+            .unwrap()
+            .ends_with("All players agree\n"));
+        assert!(reader
+            .desync_report(2)
+            .unwrap()
+            .ends_with("No states logged\n"));
+    }
+
+    fn tick_time(frame: u64, player: Uuid, timestamp: u64) -> TickTime {
+        TickTime {
+            frame,
+            player,
+            timestamp,
+            simulation_rate: 50,
+        }
+    }
+
+    #[test]
+    fn pacing_deltas_come_from_consecutive_tick_times() {
+        let player = Uuid::new_v4();
+        // Logged out of order, with frame 4 missing while logging was paused
+        let tick_times = [
+            tick_time(2, player, 1_020_000),
+            tick_time(1, player, 1_000_000),
+            tick_time(3, player, 1_060_000),
+            tick_time(5, player, 2_000_000),
+            tick_time(6, player, 2_019_000),
+        ];
+
+        let pacing = FramePacing::from_tick_times(&tick_times);
+
+        let ideal = Duration::from_millis(20);
+        assert_eq!(
+            pacing,
+            vec![
+                FramePacing {
+                    frame: 2,
+                    delta: Duration::from_millis(20),
+                    ideal,
+                },
+                FramePacing {
+                    frame: 3,
+                    delta: Duration::from_millis(40),
+                    ideal,
+                },
+                FramePacing {
+                    frame: 6,
+                    delta: Duration::from_millis(19),
+                    ideal,
+                },
+            ]
+        );
+        let overran = pacing
+            .iter()
+            .filter(|pacing| pacing.overran())
+            .map(|pacing| pacing.frame)
+            .collect::<Vec<_>>();
+        assert_eq!(overran, vec![3]);
+    }
+
+    #[test]
+    fn pacing_tolerates_clocks_stepping_backwards() {
+        let player = Uuid::new_v4();
+        let tick_times = [tick_time(1, player, 1_000), tick_time(2, player, 500)];
+
+        let pacing = FramePacing::from_tick_times(&tick_times);
+
+        assert_eq!(pacing[0].delta, Duration::ZERO);
+        assert!(!pacing[0].overran());
+    }
+
+    #[test]
+    fn markers_are_listed_by_the_first_frame_they_were_logged_on() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let event = |id, frame, latest_frame, player, event: &str, data: &str| {
+            LogEntry::Event(Event {
+                id,
+                frame,
+                latest_frame,
+                player,
+                event: event.to_string(),
+                data: data.to_string(),
+                severity: EventSeverity::Info,
+                category: Some(EventCategory::Match),
+            })
+        };
+        let reader = reader_with([
+            event(0, 120, 120, a, "marker", "round 2 start"),
+            event(1, 10, 10, a, "marker", "round 1 start"),
+            // Re-simulating the frame after a rollback logs the marker again
+            event(2, 120, 123, a, "marker", "round 2 start"),
+            event(3, 60, 60, a, "spawned", "round 1 start"),
+            event(0, 90, 90, b, "marker", "halftime"),
+        ]);
+
+        assert_eq!(
+            reader.markers(a).unwrap(),
+            vec![
+                (10, "round 1 start".to_string()),
+                (120, "round 2 start".to_string()),
+            ]
+        );
+        assert_eq!(
+            reader.markers(b).unwrap(),
+            vec![(90, "halftime".to_string())]
+        );
+    }
 }
@@ -5,62 +5,155 @@ use std::{
         Arc,
     },
     thread,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use parking_lot::RwLock;
 use rusqlite::Connection;
 use uuid::Uuid;
 
-use crate::{message::SentInput, Context};
+use crate::{
+    message::{Message, SentInput},
+    Context,
+};
 
 use super::{
-    log_file_directory, setup_connection, DroppedFrame, Event, FrameState, LogEntry, ReceivedInput,
-    Rollback, RunInfo, SpawnedNodeAlive,
+    log_file_directory, setup_connection, DroppedFrame, Event, EventCategory, EventSeverity,
+    FrameState, JournalMode, LogEntry, ReceivedInput, ReceivedMessage, Rollback, RunInfo,
+    SpawnedNodeAlive, TickTime,
 };
 
+/// Options controlling how log entries are written to disk
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogConfig {
+    /// Compress the value of each frame state with zstd. Frame states are by
+    /// far the largest table, so this shrinks logs considerably at the cost
+    /// of some CPU time on the writer thread. Readers decompress transparently.
+    pub compress_states: bool,
+    /// Once this many entries are waiting to be written, frame states are
+    /// logged with only their hash and without their value text. Keeps memory
+    /// bounded when the writer can't keep up, such as on a slow disk, while
+    /// still allowing desyncs to be detected. None never drops anything.
+    pub max_backlog: Option<usize>,
+}
+
+impl LogConfig {
+    pub const COMPRESSION_LEVEL: i32 = 3;
+}
+
+/// Counts the entries waiting on the writer thread and drops the values of
+/// frame states logged while too many are waiting
+#[derive(Debug, Default)]
+struct Backlog {
+    pending: AtomicUsize,
+    dropped_states: AtomicUsize,
+}
+
+impl Backlog {
+    fn queued(&self) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn written(&self, count: usize) {
+        self.pending.fetch_sub(count, Ordering::SeqCst);
+    }
+
+    fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    fn dropped_states(&self) -> usize {
+        self.dropped_states.load(Ordering::SeqCst)
+    }
+
+    /// The value text to log for a frame state. Once the backlog reaches the
+    /// maximum the text is dropped, keeping only the hash, and the drop is
+    /// counted.
+    fn state_value_text(&self, value_text: String, max_backlog: Option<usize>) -> String {
+        if max_backlog.map_or(false, |max_backlog| self.pending() >= max_backlog) {
+            self.dropped_states.fetch_add(1, Ordering::SeqCst);
+            String::new()
+        } else {
+            value_text
+        }
+    }
+}
+
+enum WriterMessage {
+    Entry(LogEntry),
+    // Replies once every entry sent before it has been committed
+    Flush(Sender<()>),
+}
+
 pub struct LogWriter {
     run_sender: Sender<(Uuid, Uuid)>,
-    log_sender: Sender<LogEntry>,
+    log_sender: Sender<WriterMessage>,
     id_counter: AtomicUsize,
     enabled: Arc<AtomicBool>,
+    config: Arc<RwLock<LogConfig>>,
+    journal_mode: Arc<RwLock<Option<JournalMode>>>,
+    /// Entries sent but not yet processed by the writer thread
+    backlog: Arc<Backlog>,
+    run_set: AtomicBool,
 }
 
 impl LogWriter {
+    const FLUSH_TIMEOUT_SECONDS: u64 = 5;
+
     pub fn new() -> Self {
         let (run_sender, run_receiver) = channel::<(Uuid, Uuid)>();
-        let (log_sender, log_receiver) = channel::<LogEntry>();
+        let (log_sender, log_receiver) = channel::<WriterMessage>();
         let enabled = Arc::new(AtomicBool::new(true));
+        let config = Arc::new(RwLock::new(LogConfig::default()));
+        let journal_mode = Arc::new(RwLock::new(None));
+        let backlog = Arc::new(Backlog::default());
         let directory = log_file_directory().unwrap();
 
         thread::spawn({
-            let enabled = enabled.clone();
+            let config = config.clone();
+            let journal_mode = journal_mode.clone();
+            let backlog = backlog.clone();
             move || {
                 let (run, id) = run_receiver.recv().expect("Failed to receive run id");
 
                 let file_path = directory.join(format!("{run}_{}.db", id.to_string()));
 
                 let mut connection = Connection::open(file_path).unwrap();
-                setup_connection(&connection).unwrap();
+                *journal_mode.write() = Some(setup_connection(&connection).unwrap());
 
-                while let Ok(entry) = log_receiver.recv() {
-                    let mut entries = vec![entry];
-                    while let Ok(entry) = log_receiver.try_recv() {
-                        entries.push(entry);
+                while let Ok(message) = log_receiver.recv() {
+                    let mut messages = vec![message];
+                    while let Ok(message) = log_receiver.try_recv() {
+                        messages.push(message);
                     }
 
-                    if !enabled.load(Ordering::SeqCst) {
-                        continue;
+                    let mut entries = Vec::new();
+                    let mut flushes = Vec::new();
+                    for message in messages {
+                        match message {
+                            WriterMessage::Entry(entry) => entries.push(entry),
+                            WriterMessage::Flush(reply) => flushes.push(reply),
+                        }
                     }
 
+                    let entry_count = entries.len();
+                    let config = *config.read();
                     let transaction = connection.transaction().unwrap();
                     for entry in entries {
                         entry
-                            .write(&transaction)
+                            .write(&transaction, &config)
                             .expect(&format!("Failed to write {entry:?} to database"));
                     }
                     transaction
                         .commit()
                         .expect("Failed to commit transaction to db");
+
+                    backlog.written(entry_count);
+
+                    for reply in flushes {
+                        reply.send(()).ok();
+                    }
                 }
             }
         });
@@ -70,24 +163,107 @@ impl LogWriter {
             log_sender,
             id_counter: AtomicUsize::new(0),
             enabled,
+            config,
+            journal_mode,
+            backlog,
+            run_set: AtomicBool::new(false),
         }
     }
 
     pub fn set_run(&self, run: Uuid, id: Uuid) -> Result<()> {
         self.run_sender.send((run, id))?;
+        self.run_set.store(true, Ordering::SeqCst);
         Ok(())
     }
 
-    pub fn enable(&self) {
-        self.enabled.store(true, Ordering::SeqCst);
+    /// The journal mode the log database ended up in. None until the database
+    /// has been opened, which happens once the run is set.
+    pub fn journal_mode(&self) -> Option<JournalMode> {
+        self.journal_mode.read().clone()
     }
 
-    pub fn disable(&self) {
+    /// Blocks until every entry logged so far has been committed to the
+    /// database. Returns immediately if no run has been set since nothing
+    /// will be written until one is.
+    pub fn flush(&self) -> Result<()> {
+        if !self.run_set.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let (reply_sender, reply_receiver) = channel();
+        self.log_sender.send(WriterMessage::Flush(reply_sender))?;
+        reply_receiver
+            .recv_timeout(Duration::from_secs(LogWriter::FLUSH_TIMEOUT_SECONDS))
+            .map_err(|_| anyhow!("Timed out waiting for log writer to flush"))
+    }
+
+    /// Queues the entry for the writer thread. Entries logged while disabled
+    /// are dropped here so the writer thread sits idle until enabled again
+    fn send(&self, entry: LogEntry) -> Result<()> {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        self.backlog.queued();
+        self.log_sender.send(WriterMessage::Entry(entry))?;
+        Ok(())
+    }
+
+    /// Approximate number of entries waiting to be written
+    pub fn backlog(&self) -> usize {
+        self.backlog.pending()
+    }
+
+    /// Number of frame states logged without their value text because the
+    /// backlog was over the configured maximum
+    pub fn dropped_states(&self) -> usize {
+        self.backlog.dropped_states()
+    }
+
+    /// Resumes logging, marking the end of the gap with a logging_resumed
+    /// event so readers can tell why frames are missing
+    pub fn enable(&self, cx: &Context) -> Result<()> {
+        if self.enabled.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        self.event(
+            EventSeverity::Info,
+            Some(EventCategory::Match),
+            "logging_resumed".into(),
+            String::new(),
+            cx,
+        )
+    }
+
+    /// Stops logging until enabled again, marking the start of the gap with a
+    /// logging_paused event. Entries already logged are still written.
+    pub fn disable(&self, cx: &Context) -> Result<()> {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        self.event(
+            EventSeverity::Info,
+            Some(EventCategory::Match),
+            "logging_paused".into(),
+            String::new(),
+            cx,
+        )?;
         self.enabled.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn config(&self) -> LogConfig {
+        *self.config.read()
+    }
+
+    pub fn set_config(&self, config: LogConfig) {
+        *self.config.write() = config;
     }
 
     pub fn run_info(&self, cx: &Context) -> Result<()> {
-        self.log_sender.send(LogEntry::RunInfo(RunInfo {
+        self.send(LogEntry::RunInfo(RunInfo {
             local_id: cx.local_id(),
             peers: cx.peers(),
         }))?;
@@ -95,7 +271,7 @@ impl LogWriter {
     }
 
     pub fn sent_input(&self, sent_input: SentInput) -> Result<()> {
-        self.log_sender.send(LogEntry::SentInput(sent_input))?;
+        self.send(LogEntry::SentInput(sent_input))?;
         Ok(())
     }
 
@@ -105,12 +281,11 @@ impl LogWriter {
         sent_input: SentInput,
         cx: &Context,
     ) -> Result<()> {
-        self.log_sender
-            .send(LogEntry::ReceivedInput(ReceivedInput {
-                received_frame,
-                receiver: cx.local_id(),
-                sent_input,
-            }))?;
+        self.send(LogEntry::ReceivedInput(ReceivedInput {
+            received_frame,
+            receiver: cx.local_id(),
+            sent_input,
+        }))?;
         Ok(())
     }
 
@@ -120,12 +295,27 @@ impl LogWriter {
         receiver: Uuid,
         sent_input: SentInput,
     ) -> Result<()> {
-        self.log_sender
-            .send(LogEntry::ReceivedInput(ReceivedInput {
-                received_frame,
-                receiver,
-                sent_input,
-            }))?;
+        self.send(LogEntry::ReceivedInput(ReceivedInput {
+            received_frame,
+            receiver,
+            sent_input,
+        }))?;
+        Ok(())
+    }
+
+    pub fn received_message(
+        &self,
+        processed_tick: u64,
+        message: &Message,
+        cx: &Context,
+    ) -> Result<()> {
+        self.send(LogEntry::ReceivedMessage(ReceivedMessage {
+            id: self.id_counter.fetch_add(1, Ordering::SeqCst),
+            processed_tick,
+            received_frame: cx.latest_tick(),
+            receiver: cx.local_id(),
+            message: bincode::serialize(message)?,
+        }))?;
         Ok(())
     }
 
@@ -134,21 +324,23 @@ impl LogWriter {
         frame: u64,
         frame_missing_input: u64,
         lagger: Uuid,
+        stall_ticks: u64,
         cx: &Context,
     ) -> Result<()> {
-        self.log_sender.send(LogEntry::DroppedFrame(DroppedFrame {
+        self.send(LogEntry::DroppedFrame(DroppedFrame {
             id: self.id_counter.fetch_add(1, Ordering::SeqCst),
             frame,
             frame_missing_input,
             lagger,
             dropper: cx.local_id(),
+            stall_ticks,
         }))?;
 
         Ok(())
     }
 
     pub fn rollback(&self, frame: u64, rolled_back_to: u64, cx: &Context) -> Result<()> {
-        self.log_sender.send(LogEntry::Rollback(Rollback {
+        self.send(LogEntry::Rollback(Rollback {
             frame,
             rolled_back_to,
             updater: cx.local_id(),
@@ -165,7 +357,11 @@ impl LogWriter {
         value_hash: u64,
         cx: &Context,
     ) -> Result<()> {
-        self.log_sender.send(LogEntry::FrameState(FrameState {
+        let value_text = self
+            .backlog
+            .state_value_text(value_text, self.config.read().max_backlog);
+
+        self.send(LogEntry::FrameState(FrameState {
             frame: cx.current_tick(),
             latest_frame: cx.latest_tick(),
             player: cx.local_id(),
@@ -179,37 +375,103 @@ impl LogWriter {
     }
 
     pub fn spawned_node_alive(&self, node_path: String, cx: &Context) -> Result<()> {
-        self.log_sender
-            .send(LogEntry::SpawnedNodeAlive(SpawnedNodeAlive {
-                frame: cx.current_tick(),
-                latest_frame: cx.latest_tick(),
-                player: cx.local_id(),
-                node_path,
-            }))?;
+        self.send(LogEntry::SpawnedNodeAlive(SpawnedNodeAlive {
+            frame: cx.current_tick(),
+            latest_frame: cx.latest_tick(),
+            player: cx.local_id(),
+            node_path,
+        }))?;
 
         Ok(())
     }
 
-    pub fn event(&self, event: String, data: String, cx: &Context) -> Result<()> {
-        self.event_for_frame(cx.current_tick(), event, data, cx)
+    /// Records the wall clock time the latest tick started simulating
+    pub fn tick_time(&self, cx: &Context) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        self.send(LogEntry::TickTime(TickTime {
+            frame: cx.latest_tick(),
+            player: cx.local_id(),
+            timestamp: timestamp.as_micros() as u64,
+            simulation_rate: cx.simulation_rate(),
+        }))?;
+
+        Ok(())
+    }
+
+    pub fn event(
+        &self,
+        severity: EventSeverity,
+        category: Option<EventCategory>,
+        event: String,
+        data: String,
+        cx: &Context,
+    ) -> Result<()> {
+        self.event_for_frame(cx.current_tick(), severity, category, event, data, cx)
     }
 
     pub fn event_for_frame(
         &self,
         frame: u64,
+        severity: EventSeverity,
+        category: Option<EventCategory>,
         event: String,
         data: String,
         cx: &Context,
     ) -> Result<()> {
-        self.log_sender.send(LogEntry::Event(Event {
+        self.send(LogEntry::Event(Event {
             id: self.id_counter.fetch_add(1, Ordering::SeqCst),
             frame,
             latest_frame: cx.latest_tick(),
             player: cx.local_id(),
             event,
             data,
+            severity,
+            category,
         }))?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Backlog;
+
+    #[test]
+    fn state_values_are_dropped_past_the_max_backlog() {
+        let backlog = Backlog::default();
+        let mut value_texts = Vec::new();
+        // The writer thread falls behind while six states are logged
+        for frame in 0..6 {
+            value_texts.push(backlog.state_value_text(format!("value {frame}"), Some(3)));
+            backlog.queued();
+        }
+
+        assert_eq!(
+            value_texts,
+            vec!["value 0", "value 1", "value 2", "", "", ""]
+        );
+        assert_eq!(backlog.pending(), 6);
+        assert_eq!(backlog.dropped_states(), 3);
+
+        // Once the writer catches up values are kept again
+        backlog.written(6);
+        assert_eq!(backlog.pending(), 0);
+        assert_eq!(
+            backlog.state_value_text("value 6".into(), Some(3)),
+            "value 6"
+        );
+        assert_eq!(backlog.dropped_states(), 3);
+    }
+
+    #[test]
+    fn nothing_is_dropped_without_a_max_backlog() {
+        let backlog = Backlog::default();
+        for _ in 0..1000 {
+            backlog.queued();
+        }
+
+        assert_eq!(backlog.state_value_text("value".into(), None), "value");
+        assert_eq!(backlog.dropped_states(), 0);
+    }
+}
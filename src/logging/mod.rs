@@ -6,7 +6,10 @@ use anyhow::Result;
 use godot::engine::ProjectSettings;
 use indoc::indoc;
 use rusqlite::Connection;
-use std::path::PathBuf;
+use std::{
+    fmt::{Display, Formatter},
+    path::PathBuf,
+};
 
 pub use log_entry::*;
 pub use log_reader::*;
@@ -20,9 +23,57 @@ pub fn log_file_directory() -> Result<PathBuf> {
     Ok(directory_path.to_owned())
 }
 
-pub fn setup_connection(connection: &Connection) -> Result<()> {
+/// The journal mode sqlite actually applied to a connection. Some platforms
+/// ship sqlite builds which silently ignore unsupported journal modes, so the
+/// mode has to be queried after setting it rather than assumed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Wal2,
+    Wal,
+    Other(String),
+}
+
+impl JournalMode {
+    pub fn query(connection: &Connection) -> Result<Self> {
+        let mode: String = connection.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        Ok(match mode.to_lowercase().as_str() {
+            "wal2" => JournalMode::Wal2,
+            "wal" => JournalMode::Wal,
+            _ => JournalMode::Other(mode),
+        })
+    }
+
+    /// Whether the database can be read while a writer is writing to it
+    pub fn allows_concurrent_reads(&self) -> bool {
+        matches!(self, JournalMode::Wal2 | JournalMode::Wal)
+    }
+}
+
+impl Display for JournalMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalMode::Wal2 => write!(f, "wal2"),
+            JournalMode::Wal => write!(f, "wal"),
+            JournalMode::Other(mode) => write!(f, "{}", mode.to_lowercase()),
+        }
+    }
+}
+
+fn set_journal_mode(connection: &Connection, mode: &str) -> Result<JournalMode> {
+    connection.execute_batch(&format!("PRAGMA journal_mode={mode};"))?;
+    JournalMode::query(connection)
+}
+
+/// Configures the connection and creates the log tables. Returns the journal
+/// mode which took effect, falling back from WAL2 to WAL when WAL2 isn't
+/// supported.
+pub fn setup_connection(connection: &Connection) -> Result<JournalMode> {
+    let mut journal_mode = set_journal_mode(connection, "WAL2")?;
+    if !journal_mode.allows_concurrent_reads() {
+        journal_mode = set_journal_mode(connection, "WAL")?;
+    }
+
     connection.execute_batch(indoc! {"
-            PRAGMA journal_mode=WAL2;
             PRAGMA synchronous=NORMAL;
             PRAGMA foreign_keys=ON;
             PRAGMA busy_timeout=100;
@@ -30,5 +81,53 @@ pub fn setup_connection(connection: &Connection) -> Result<()> {
 
     LogEntry::setup_tables(connection)?;
 
-    Ok(())
+    Ok(journal_mode)
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::Connection;
+    use uuid::Uuid;
+
+    use super::{set_journal_mode, setup_connection, JournalMode};
+
+    #[test]
+    fn file_logs_end_up_in_a_wal_mode() {
+        let path = std::env::temp_dir().join(format!("{}_{}.db", Uuid::new_v4(), Uuid::new_v4()));
+        let connection = Connection::open(&path).unwrap();
+
+        let journal_mode = setup_connection(&connection).unwrap();
+
+        // Builds without WAL2 fall back to WAL
+        assert!(matches!(journal_mode, JournalMode::Wal2 | JournalMode::Wal));
+        assert_eq!(JournalMode::query(&connection).unwrap(), journal_mode);
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn non_wal_modes_are_detected() {
+        let path = std::env::temp_dir().join(format!("{}_{}.db", Uuid::new_v4(), Uuid::new_v4()));
+        let connection = Connection::open(&path).unwrap();
+
+        let journal_mode = set_journal_mode(&connection, "DELETE").unwrap();
+
+        assert_eq!(journal_mode, JournalMode::Other("delete".to_string()));
+        assert_eq!(journal_mode.to_string(), "delete");
+        assert!(!journal_mode.allows_concurrent_reads());
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_mode_switch_which_did_not_take_is_detected() {
+        // In memory databases silently ignore requests for a WAL journal, the
+        // same way some platforms' sqlite builds ignore WAL2
+        let connection = Connection::open_in_memory().unwrap();
+
+        let journal_mode = setup_connection(&connection).unwrap();
+
+        assert_eq!(journal_mode, JournalMode::Other("memory".to_string()));
+        assert!(!journal_mode.allows_concurrent_reads());
+    }
 }
@@ -1,4 +1,7 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -17,6 +20,31 @@ impl Hash for SentInput {
     }
 }
 
+/// A value returned by log_state along with the hash peers compare
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct LoggedState {
+    pub path: String,
+    pub key: String,
+    pub value_text: String,
+    pub value_hash: u64,
+}
+
+/// A frame sent to a spectator joining a match in progress along with what
+/// it needs to follow the match from that frame
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct StateSnapshot {
+    pub sender: Uuid,
+    pub frame: u64,
+    /// var_to_bytes encoded node states of the frame
+    pub states: BTreeMap<String, Vec<u8>>,
+    /// The frame's spawn records in the format they are logged in
+    pub spawns: Vec<String>,
+    /// Every input the sender has for the frame and later frames
+    pub inputs: Vec<SentInput>,
+    /// The id and address of every other player
+    pub players: Vec<(Uuid, String)>,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum Message {
     // If uuid is not in peers, add it, send a connect in reply and gossip the address to all
@@ -33,6 +61,9 @@ pub enum Message {
     Input {
         sent_input: SentInput,
         last_received_frame: u64,
+        // The tick through which every input from the recipient has been
+        // received, so resends up to it can be canceled
+        received_through: u64,
     },
     // Compare the given hash with the stored state hash for the given frame
     // If they do not mash, there has been a desync
@@ -40,4 +71,59 @@ pub enum Message {
         frame: u64,
         hash: u64,
     },
+    // Clear any previous connectivity results and reply with a connectivity
+    // report
+    CheckConnectivity,
+    // The average response time in milliseconds from the sender to each of
+    // its peers. None if the peer has not responded yet
+    ConnectivityReport(Vec<(Uuid, Option<u64>)>),
+    // Set the lobby setting to the var_to_bytes encoded value and reply with
+    // a LobbySettings message containing the updated settings
+    LobbySetting {
+        key: String,
+        value: Vec<u8>,
+    },
+    // The full set of lobby settings the sender currently has. The game can't
+    // start until every peer reports the same settings
+    LobbySettings(BTreeMap<String, Vec<u8>>),
+    // Hash of the sorted ids of every peer in the game including the sender.
+    // Compare with the local peer set hash. If they do not match, the peers
+    // disagree on who is in the game
+    PeerSetHash(u64),
+    // The sender paused or resumed the match. Pause or resume along with it
+    // so no peer's ticks advance while another's are stopped
+    PauseRequest(bool),
+    // The number of ticks per second the given peer simulates at. The game
+    // can't start until every peer reports the same rate
+    SimulationRate(Uuid, u32),
+    // The number of ticks the given peer waits before recording input. The
+    // game can't start until every peer reports the same offset
+    StartOffset(Uuid, u32),
+    // The leader's var_to_bytes encoded node states for the given frame.
+    // Replace the local states for the frame and re-simulate every frame
+    // after it
+    Resync {
+        frame: u64,
+        states: BTreeMap<String, Vec<u8>>,
+    },
+    // Ask a peer in a match to let the sender watch it. The peer replies with
+    // a StateSnapshot and tells the other players about the spectator
+    JoinAsSpectator(Uuid),
+    // Sent by a player to a joining spectator. Connect to the players and
+    // follow the match from the snapshot's frame
+    StateSnapshot(StateSnapshot),
+    // The states the sender logged for a frame its hash disagreed on.
+    // Compare them with the local states to find which values diverged
+    DesyncStates {
+        sender: Uuid,
+        frame: u64,
+        states: Vec<LoggedState>,
+    },
+    // A spectator joined from the given frame. Connect to it and send it
+    // every local input after the frame along with every later input
+    SpectatorJoined {
+        id: Uuid,
+        address: String,
+        frame: u64,
+    },
 }
@@ -0,0 +1,120 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use anyhow::Result;
+use godot::prelude::*;
+use uuid::Uuid;
+
+use crate::{message::Message, stable_hasher::StableHasher, Context};
+
+/// Ticks between each peer set hash broadcast
+const CHECK_INTERVAL_TICKS: u32 = 60;
+/// Number of consecutive mismatched hashes from a peer before it is flagged.
+/// Gossip takes a moment to propagate, so a single mismatch is expected while
+/// peers are joining.
+const MISMATCHES_BEFORE_FLAGGING: u32 = 2;
+/// Peer sets are only compared for this many ticks into play. Peers can't
+/// join after the game starts, so any disagreement will have shown up by then.
+pub const EARLY_PLAY_TICKS: u64 = 10 * 60;
+
+/// Periodically exchanges a hash of the peer set with every peer so that
+/// disagreements about who is in the game are caught early instead of
+/// showing up later as phantom desyncs.
+pub struct PeerSetCheck {
+    ticks_until_check: u32,
+    mismatches: HashMap<Uuid, u32>,
+    flagged: HashSet<Uuid>,
+}
+
+impl PeerSetCheck {
+    pub fn new() -> Self {
+        Self {
+            ticks_until_check: CHECK_INTERVAL_TICKS,
+            mismatches: HashMap::new(),
+            flagged: HashSet::new(),
+        }
+    }
+
+    pub fn tick(&mut self, cx: &mut Context) -> Result<()> {
+        if self.ticks_until_check > 0 {
+            self.ticks_until_check -= 1;
+            return Ok(());
+        }
+
+        self.ticks_until_check = CHECK_INTERVAL_TICKS;
+        cx.broadcast(Message::PeerSetHash(Self::peer_set_hash(cx)))?;
+        Ok(())
+    }
+
+    /// Compares a peer's hash against the local peer set, queueing the
+    /// `peer_set_mismatch` signal the first time a peer repeatedly disagrees.
+    pub fn record_hash(&mut self, peer: Uuid, remote_hash: u64, cx: &mut Context) {
+        if self.record_match(peer, remote_hash == Self::peer_set_hash(cx)) {
+            godot_warn!("Peer {peer} disagrees on the set of peers in the game");
+            cx.queue_signal("peer_set_mismatch", vec![Variant::from(peer.to_string())]);
+        }
+    }
+
+    /// Tracks whether the peer's latest hash matched. Returns true the first
+    /// time the peer has mismatched enough times in a row to be flagged
+    fn record_match(&mut self, peer: Uuid, matched: bool) -> bool {
+        if matched {
+            self.mismatches.remove(&peer);
+            self.flagged.remove(&peer);
+            return false;
+        }
+
+        let mismatches = self.mismatches.entry(peer).or_insert(0);
+        *mismatches += 1;
+        *mismatches >= MISMATCHES_BEFORE_FLAGGING && self.flagged.insert(peer)
+    }
+
+    fn peer_set_hash(cx: &Context) -> u64 {
+        let mut ids = cx.peers();
+        ids.push(cx.local_id());
+        hash_peer_set(ids)
+    }
+}
+
+/// Hashes the ids in sorted order, so every peer with the same set agrees
+fn hash_peer_set(mut ids: Vec<Uuid>) -> u64 {
+    ids.sort();
+
+    let mut hasher = StableHasher::new();
+    ids.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::{hash_peer_set, PeerSetCheck, MISMATCHES_BEFORE_FLAGGING};
+
+    #[test]
+    fn a_peer_which_missed_a_gossip_is_flagged() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        // b joined through a, but the gossip telling c about b was lost
+        let full_set = hash_peer_set(vec![a, b, c]);
+        let b_view = hash_peer_set(vec![b, c, a]);
+        let c_view = hash_peer_set(vec![c, a]);
+        assert_eq!(full_set, b_view);
+        assert_ne!(full_set, c_view);
+
+        let mut check = PeerSetCheck::new();
+        for _ in 1..MISMATCHES_BEFORE_FLAGGING {
+            assert!(!check.record_match(b, b_view == full_set));
+            assert!(!check.record_match(c, c_view == full_set));
+        }
+        // c keeps disagreeing and is flagged once
+        assert!(!check.record_match(b, b_view == full_set));
+        assert!(check.record_match(c, c_view == full_set));
+        assert!(!check.record_match(c, c_view == full_set));
+
+        // Once the gossip arrives c agrees again and could be flagged anew
+        assert!(!check.record_match(c, true));
+        assert!(!check.flagged.contains(&c));
+    }
+}
@@ -1,10 +1,15 @@
 mod frame;
+#[cfg(test)]
+mod scripted_match;
 mod spawn_manager;
 
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
     hash::{Hash, Hasher},
-    sync::Arc,
+    net::SocketAddr,
+    ops::{Range, RangeInclusive},
+    sync::{Arc, Once},
+    time::Duration,
 };
 
 use anyhow::Result;
@@ -12,20 +17,184 @@ use godot::{
     engine::utilities::{bytes_to_var, var_to_bytes},
     prelude::*,
 };
+use itertools::Itertools;
+use serde_json::json;
 use uuid::Uuid;
 
 use crate::{
     context::Context,
-    message::{Message, SentInput},
-    replay_stage::ReplayStage,
+    logging::{EventCategory, EventSeverity},
+    message::{LoggedState, Message, SentInput, StateSnapshot},
+    replay_stage::{LoggedSpawnEvents, ReplayStage},
+    stable_hasher::StableHasher,
     sync_manager::RollbackSyncManager,
     sync_stage::SyncStage,
 };
-use frame::{Frame, SpawnRecord};
+use frame::Frame;
+pub use frame::SpawnRecord;
 
 use self::spawn_manager::SpawnManager;
 
-const MAX_REWIND: u64 = 30;
+/// Number of frames a peer's input may lag behind before the simulation
+/// stalls, unless configured otherwise
+pub const DEFAULT_MAX_REWIND: u64 = 30;
+/// The first two frames are created with default inputs, so the rewind window
+/// must hold at least that many
+pub const MIN_MAX_REWIND: u64 = 2;
+/// Upper bound on a peer's rewind window when it adapts to the peer's round
+/// trip time
+const MAX_ADAPTIVE_REWIND: u64 = 60;
+const MAX_JITTER_DELAY_TICKS: u64 = 4;
+/// Upper bound on the input delay, including any added by adaptive input delay
+const MAX_INPUT_DELAY: u64 = 8;
+/// Number of ticks the rollback rate is measured over before adaptive input
+/// delay changes
+const ROLLBACK_RATE_WINDOW: usize = 60;
+/// Fraction of ticks in the window which rolled back above which adaptive
+/// input delay is raised, and below which it is lowered
+const RAISE_DELAY_ROLLBACK_RATE: f64 = 0.5;
+const LOWER_DELAY_ROLLBACK_RATE: f64 = 0.1;
+/// Ticks between comparing each peer's estimated clock offset against its
+/// baseline
+const CLOCK_DRIFT_CHECK_TICKS: u64 = 300;
+/// Checkpoints kept at once. The oldest is dropped to make room
+const MAX_CHECKPOINTS: usize = 16;
+/// Group containing the domain root of every sync manager which has one
+pub const DOMAIN_ROOT_GROUP: &str = "networked_domain_root";
+
+/// Methods the SyncManager calls on networked nodes when they are defined
+pub const NETWORKED_CALLBACKS: [&str; 6] = [
+    "networked_preprocess",
+    "networked_process",
+    "load_state",
+    "log_state",
+    "networked_spawn",
+    "networked_despawn",
+];
+
+/// How the play stage responds when a peer stops acknowledging messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisconnectPolicy {
+    /// Stall waiting for the peer's input in case it comes back
+    #[default]
+    Pause,
+    /// Drop the peer and keep simulating without it
+    Continue,
+    /// End the match
+    EndMatch,
+}
+
+impl DisconnectPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pause" => Some(DisconnectPolicy::Pause),
+            "continue" => Some(DisconnectPolicy::Continue),
+            "end_match" => Some(DisconnectPolicy::EndMatch),
+            _ => None,
+        }
+    }
+}
+
+/// How `input` predicts a peer's input for a frame it hasn't arrived for yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputPrediction {
+    /// Predict the default input, as if nothing was pressed
+    Default,
+    /// Repeat the peer's most recent input within the rewind window, falling
+    /// back to the default input
+    #[default]
+    RepeatLast,
+}
+
+impl InputPrediction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(InputPrediction::Default),
+            "repeat_last" => Some(InputPrediction::RepeatLast),
+            _ => None,
+        }
+    }
+}
+
+/// A named event scheduled by game code to fire on a future tick
+struct ScheduledEvent {
+    tick: u64,
+    name: String,
+    /// The tick being simulated when the event was scheduled. Re-simulating
+    /// that tick schedules the event again, so the old schedule is dropped
+    scheduled_on: u64,
+}
+
+/// Rolling average of the advantage over the last 100 ticks
+#[derive(Default)]
+struct RollingAdvantage {
+    sum: i64,
+    queue: VecDeque<i64>,
+}
+
+impl RollingAdvantage {
+    fn push(&mut self, advantage: i64) {
+        self.sum += advantage;
+        self.queue.push_back(advantage);
+        if self.queue.len() > 100 {
+            self.sum -= self.queue.pop_front().unwrap();
+        }
+    }
+
+    fn average(&self) -> f64 {
+        self.sum as f64 / self.queue.len() as f64
+    }
+}
+
+/// Input delay added on top of the configured delay by adaptive input delay,
+/// along with the rollbacks it adapts to
+#[derive(Default)]
+struct AdaptiveInputDelay {
+    /// Whether each of the most recent ticks rolled back, oldest first
+    recent_rollbacks: VecDeque<bool>,
+    extra: u64,
+}
+
+impl AdaptiveInputDelay {
+    /// The input delay in use with the given configured delay
+    fn delay(&self, base_delay: u64) -> u64 {
+        (base_delay + self.extra).min(MAX_INPUT_DELAY)
+    }
+
+    /// Records whether the latest tick rolled back and adjusts the extra delay
+    /// once a full window has been recorded. Returns the rollback rate if the
+    /// delay changed. Waits a full window after each change so the new delay
+    /// has a chance to take effect.
+    fn record(&mut self, rolled_back: bool, base_delay: u64) -> Option<f64> {
+        self.recent_rollbacks.push_back(rolled_back);
+        if self.recent_rollbacks.len() < ROLLBACK_RATE_WINDOW {
+            return None;
+        }
+
+        let rollback_rate = self
+            .recent_rollbacks
+            .iter()
+            .filter(|rolled_back| **rolled_back)
+            .count() as f64
+            / self.recent_rollbacks.len() as f64;
+        if rollback_rate > RAISE_DELAY_ROLLBACK_RATE && self.delay(base_delay) < MAX_INPUT_DELAY {
+            self.extra += 1;
+        } else if rollback_rate < LOWER_DELAY_ROLLBACK_RATE && self.extra > 0 {
+            self.extra -= 1;
+        } else {
+            self.recent_rollbacks.pop_front();
+            return None;
+        }
+
+        self.recent_rollbacks.clear();
+        Some(rollback_rate)
+    }
+
+    fn reset(&mut self) {
+        self.recent_rollbacks.clear();
+        self.extra = 0;
+    }
+}
 
 pub struct PlayStage {
     frames: HashMap<u64, Arc<Frame>>,
@@ -34,85 +203,797 @@ pub struct PlayStage {
     latest_frame_delivered: HashMap<Uuid, u64>,
     /// Contains the last input tick recieved from each remote peer
     latest_frame_received: HashMap<Uuid, u64>,
-    rolling_advantage_sum: i64,
-    advantage_queue: VecDeque<i64>,
+    /// The tick through which every input from each remote peer has been
+    /// received. Unlike the latest frame received this never skips a gap
+    inputs_received_through: HashMap<Uuid, u64>,
+    /// The largest advantage over any peer each tick, used for stalling
+    advantage: RollingAdvantage,
+    /// The advantage over each peer on its own
+    peer_advantages: HashMap<Uuid, RollingAdvantage>,
+    /// The latest tick at the time of the last advantage based stall
+    last_stall_tick: Option<u64>,
+    /// Number of frames a peer's input may lag behind before the simulation
+    /// stalls. Fixed for the whole match
+    max_rewind: u64,
+    /// Average clock offset to each peer when its drift baseline was taken
+    clock_baselines: HashMap<Uuid, f64>,
+    /// Sum and count of the clock offsets to each peer sampled since the
+    /// last drift check
+    clock_offset_samples: HashMap<Uuid, (f64, u32)>,
+    /// Number of outstanding pins on each frame. Pinned frames are not evicted
+    /// even once they are older than the rewind max
+    pinned_frames: HashMap<u64, usize>,
+    /// Frames before this tick have left the rewind window. Only pinned frames
+    /// before it are still kept
+    window_start: u64,
+    /// Received inputs held back until the tick they should be applied
+    jitter_buffer: Vec<(u64, Message)>,
+    /// The frame the simulation is stalled waiting on input for and the number
+    /// of consecutive ticks it has stalled so far
+    stall: Option<(u64, u64)>,
+    scheduled_events: Vec<ScheduledEvent>,
+    /// The latest tick the local input has been set for. Ahead of the latest
+    /// tick by the input delay
+    last_local_input_tick: u64,
+    /// Ticks after the first two which every peer plays with empty inputs
+    /// before input is recorded. Fixed for the whole match
+    start_offset: u64,
+    adaptive_input_delay: AdaptiveInputDelay,
+    /// The last input the input source produced and the number of ticks in a
+    /// row it has been repeated for since
+    last_local_input: Option<Variant>,
+    repeated_local_inputs: u32,
+    /// Node states received from the leader by a forced resync and the frame
+    /// they replace. Applied once the frame has been simulated locally
+    pending_resync: Option<(u64, HashMap<String, Variant>)>,
+    /// Peers reported as disconnected which haven't sent input since
+    disconnected_peers: HashSet<Uuid>,
+    /// Nodes already reported for returning states rollbacks can't restore
+    unserializable_state_paths: HashSet<String>,
+    /// Effect ids already played on each tick still held in frames. Lets
+    /// one-shot effects skip replaying when the tick is re-simulated
+    played_effects: HashMap<u64, HashSet<String>>,
+    /// Set while ticks before the latest tick are simulated again after a
+    /// rollback
+    resimulating: bool,
+    /// Number of times tick has run. Keeps counting while stalled, so it
+    /// pins down when messages were applied more precisely than frames
+    processed_ticks: u64,
+    /// Number of rollbacks this match and of ticks the simulation didn't
+    /// advance on, either waiting on input or letting peers catch up
+    rollback_count: u64,
+    stalled_ticks: u64,
+    /// Serialized node states saved every checkpoint interval frames, keyed
+    /// by tick. Rollbacks start from the nearest one when enabled
+    checkpoints: HashMap<u64, Vec<u8>>,
+    /// Set when following a match as a spectator, which never records input
+    spectating: bool,
+    /// The frame a spectator joined from. Loaded on the first tick
+    snapshot_to_load: Option<u64>,
 }
 
 impl PlayStage {
-    pub fn new(early_inputs: Vec<Message>, cx: &Context) -> Self {
-        let peers = cx.peers();
-        // Initialize the first 2 frames with default inputs to ensure no
-        // rollbacks
-        let mut frames = HashMap::new();
-        frames.insert(0, Arc::new(Frame::initial_frame(peers.iter().copied())));
-        frames.insert(1, Arc::new(Frame::initial_frame(peers.iter().copied())));
+    pub fn new(early_inputs: Vec<Message>, cx: &mut Context) -> Self {
+        let start_offset = cx.start_offset() as u64;
+        let frames = initial_frames(start_offset, &cx.peers());
+        let mut this = Self::with_frames(frames, start_offset, cx);
+        for message in early_inputs {
+            this.handle_message(message, cx)
+                .expect("Couldn't handle message");
+        }
+
+        this
+    }
+
+    /// Follows a match in progress as a spectator, starting from the frame in
+    /// the snapshot. Spectators never record input, so players never wait on
+    /// them, but they stall like players do when a player's input is missing.
+    pub fn spectate(
+        snapshot: StateSnapshot,
+        early_inputs: Vec<Message>,
+        cx: &mut Context,
+    ) -> Result<Self> {
+        let StateSnapshot {
+            frame: tick,
+            states,
+            spawns,
+            inputs,
+            ..
+        } = snapshot;
+
+        let frame = Frame::new(tick);
+        for spawn in spawns.iter() {
+            match SpawnRecord::from_log_data(spawn) {
+                Some((node_path, spawn_record)) => frame.add_spawn_record(node_path, spawn_record),
+                None => godot_warn!("Could not load spawn record from snapshot: {spawn}"),
+            }
+        }
+        let frames = HashMap::from([(tick, Arc::new(frame))]);
+
+        cx.start_at_tick(tick);
+        let mut this = Self::with_frames(frames, cx.start_offset() as u64, cx);
+        this.spectating = true;
+        this.snapshot_to_load = Some(tick);
+
+        for sent_input in inputs {
+            this.apply_message(
+                Message::Input {
+                    sent_input,
+                    last_received_frame: 0,
+                    received_through: 0,
+                },
+                cx,
+            )?;
+        }
+        for message in early_inputs {
+            this.handle_message(message, cx)?;
+        }
+
+        // Setting the states after the inputs marks the frame as simulated
+        let states = states
+            .iter()
+            .map(|(path, state)| {
+                (
+                    path.clone(),
+                    bytes_to_var(PackedByteArray::from(&state[..])),
+                )
+            })
+            .collect();
+        this.frames[&tick].set_node_states(states);
+
+        Ok(this)
+    }
 
-        let mut this = Self {
+    fn with_frames(frames: HashMap<u64, Arc<Frame>>, start_offset: u64, cx: &Context) -> Self {
+        Self {
             frames,
             spawn_manager: Arc::new(SpawnManager::new()),
             latest_frame_delivered: HashMap::new(),
             latest_frame_received: HashMap::new(),
-            rolling_advantage_sum: 0,
-            advantage_queue: VecDeque::new(),
+            inputs_received_through: HashMap::new(),
+            advantage: RollingAdvantage::default(),
+            peer_advantages: HashMap::new(),
+            last_stall_tick: None,
+            max_rewind: cx.max_rewind(),
+            clock_baselines: HashMap::new(),
+            clock_offset_samples: HashMap::new(),
+            pinned_frames: HashMap::new(),
+            window_start: 0,
+            jitter_buffer: Vec::new(),
+            stall: None,
+            scheduled_events: Vec::new(),
+            // The initial frames already have inputs
+            last_local_input_tick: start_offset + 1,
+            start_offset,
+            adaptive_input_delay: AdaptiveInputDelay::default(),
+            last_local_input: None,
+            repeated_local_inputs: 0,
+            pending_resync: None,
+            disconnected_peers: HashSet::new(),
+            unserializable_state_paths: HashSet::new(),
+            played_effects: HashMap::new(),
+            resimulating: false,
+            processed_ticks: 0,
+            rollback_count: 0,
+            stalled_ticks: 0,
+            checkpoints: HashMap::new(),
+            spectating: false,
+            snapshot_to_load: None,
+        }
+    }
+
+    pub fn input(&self, id: String, cx: &Context) -> Variant {
+        let id = Uuid::parse_str(&id).unwrap();
+        predict_input(
+            cx.input_prediction(),
+            cx.current_tick(),
+            cx.latest_tick(),
+            self.max_rewind,
+            |tick| self.frames.get(&tick)?.input(id),
+        )
+        .unwrap_or_default()
+    }
+
+    pub fn advantage(&self) -> f64 {
+        self.advantage.average()
+    }
+
+    /// The average advantage over the given peer alone, or 0 if no
+    /// advantage has been measured against it
+    pub fn advantage_for_peer(&self, id: Uuid) -> f64 {
+        self.peer_advantages
+            .get(&id)
+            .map_or(0.0, |advantage| advantage.average())
+    }
+
+    /// The state hash of the frame if it is complete and hasn't been evicted
+    pub fn frame_hash(&self, tick: u64) -> Option<u64> {
+        self.frames.get(&tick)?.state_hash()
+    }
+
+    /// Prevents the frame from being evicted until it is unpinned as many
+    /// times as it was pinned. Returns false if the frame has already been
+    /// evicted or doesn't exist yet.
+    pub fn pin_frame(&mut self, tick: u64) -> bool {
+        if !self.frames.contains_key(&tick) {
+            return false;
+        }
+
+        *self.pinned_frames.entry(tick).or_insert(0) += 1;
+        true
+    }
+
+    /// Releases a pin on the frame. Once every pin is released, a frame which
+    /// has already left the rewind window is evicted immediately, and any
+    /// other frame is evicted as usual once it leaves the window.
+    pub fn unpin_frame(&mut self, tick: u64) {
+        if release_pin(&mut self.pinned_frames, tick) && tick < self.window_start {
+            self.frames.remove(&tick);
+        }
+    }
+
+    /// Captures the frame map along with the lag and advantage bookkeeping for
+    /// attaching to bug reports.
+    pub fn dump_debug_state(&self, cx: &Context) -> serde_json::Value {
+        let frames: Vec<serde_json::Value> = self
+            .frames
+            .iter()
+            .sorted_by_key(|(tick, _)| **tick)
+            .map(|(_, frame)| frame.debug_state())
+            .collect();
+        let by_peer = |ticks: &HashMap<Uuid, u64>| {
+            ticks
+                .iter()
+                .map(|(id, tick)| (id.to_string(), json!(tick)))
+                .collect::<serde_json::Map<_, _>>()
         };
 
-        for message in early_inputs {
-            this.handle_message(message, cx)
-                .expect("Couldn't handle message");
+        json!({
+            "local_id": cx.local_id().to_string(),
+            "current_tick": cx.current_tick(),
+            "latest_tick": cx.latest_tick(),
+            "frames": frames,
+            "latest_frame_delivered": by_peer(&self.latest_frame_delivered),
+            "latest_frame_received": by_peer(&self.latest_frame_received),
+            "inputs_received_through": by_peer(&self.inputs_received_through),
+            "rolling_advantage_sum": self.advantage.sum,
+            "advantage_queue": self.advantage.queue,
+            "peer_advantages": self
+                .peer_advantages
+                .iter()
+                .map(|(id, advantage)| (id.to_string(), json!(advantage.average())))
+                .collect::<serde_json::Map<_, _>>(),
+            "last_stall_tick": self.last_stall_tick,
+            "pinned_frames": self.pinned_frames.iter().sorted().collect::<Vec<_>>(),
+            "jitter_buffer": self
+                .jitter_buffer
+                .iter()
+                .map(|(release_tick, message)| match message {
+                    Message::Input { sent_input, .. } => json!({
+                        "release_tick": release_tick,
+                        "frame": sent_input.frame,
+                        "sender": sent_input.sender.to_string(),
+                    }),
+                    _ => json!({ "release_tick": release_tick }),
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// Number of frames the peer's input may lag behind before the simulation
+    /// stalls to wait for it. With adaptive rewind the window grows with the
+    /// peer's round trip time so that distant peers cause fewer stalls at the
+    /// cost of deeper rollbacks. The window only changes when the simulation
+    /// stalls, never which inputs are applied to which frames, so peers with
+    /// different windows still simulate identically.
+    pub fn rewind_window(&self, peer: Uuid, cx: &Context) -> u64 {
+        if !cx.adaptive_rewind() || cx.is_replay() {
+            return self.max_rewind;
         }
 
-        this
+        adaptive_rewind_window(
+            self.max_rewind,
+            cx.average_response_time(peer),
+            cx.simulation_rate(),
+        )
     }
 
-    pub fn input(&self, id: String, cx: &Context) -> Variant {
-        let id = Uuid::parse_str(&id).unwrap();
-        for tick in (cx.latest_tick().saturating_sub(MAX_REWIND)..=cx.current_tick()).rev() {
-            if let Some(frame) = self.frames.get(&tick) {
-                if let Some(input) = frame.input(id) {
-                    return input;
-                }
+    /// Number of ticks between sampling the local input and applying it.
+    /// Replays apply logged inputs on the tick they were logged for, so they
+    /// never delay.
+    pub fn input_delay(&self, cx: &Context) -> u64 {
+        if cx.is_replay() {
+            return 0;
+        }
+
+        self.adaptive_input_delay.delay(cx.input_delay())
+    }
+
+    /// Repeats the last local input in place of a missing one for up to the
+    /// configured number of ticks so that a hitch in the input source doesn't
+    /// inject a neutral input into the local input stream
+    pub fn fill_missing_input(&mut self, input: Variant, cx: &Context) -> Variant {
+        // Replays play back exactly the inputs that were logged
+        let repeat_limit = if cx.is_replay() {
+            0
+        } else {
+            cx.input_repeat_limit()
+        };
+        repeat_missing_input(
+            (!input.is_nil()).then_some(input),
+            &mut self.last_local_input,
+            &mut self.repeated_local_inputs,
+            repeat_limit,
+        )
+        .unwrap_or_else(Variant::nil)
+    }
+
+    /// Tracks how often ticks roll back and adjusts the extra input delay
+    /// when adaptive input delay is enabled, logging each change
+    fn record_rollback(&mut self, rolled_back: bool, cx: &Context) -> Result<()> {
+        if !cx.adaptive_input_delay() || cx.is_replay() {
+            self.adaptive_input_delay.reset();
+            return Ok(());
+        }
+
+        let previous_delay = self.input_delay(cx);
+        let Some(rollback_rate) = self
+            .adaptive_input_delay
+            .record(rolled_back, cx.input_delay())
+        else {
+            return Ok(());
+        };
+
+        let input_delay = self.input_delay(cx);
+        cx.logger().event_for_frame(
+            cx.latest_tick(),
+            EventSeverity::Info,
+            Some(EventCategory::Network),
+            "input_delay".into(),
+            format!(
+                "Input delay changed from {previous_delay} to {input_delay} at a rollback rate of {rollback_rate:.2}"
+            ),
+            cx,
+        )
+    }
+
+    /// The ticks the local input sampled on the latest tick applies to.
+    /// Raising the input delay leaves ticks in between which reuse the same
+    /// input. Lowering it returns no ticks until the latest tick catches up
+    /// with the inputs already sent, since those can't be changed.
+    fn local_input_ticks(&mut self, latest_tick: u64, cx: &Context) -> Vec<u64> {
+        delayed_input_ticks(
+            &mut self.last_local_input_tick,
+            latest_tick,
+            self.input_delay(cx),
+        )
+    }
+
+    /// Schedules an event to fire on the given tick. Returns false if the tick
+    /// has already been simulated.
+    pub fn schedule_at(&mut self, tick: u64, name: String, cx: &Context) -> bool {
+        if tick <= cx.current_tick() {
+            return false;
+        }
+
+        self.scheduled_events.push(ScheduledEvent {
+            tick,
+            name,
+            scheduled_on: cx.current_tick(),
+        });
+        true
+    }
+
+    /// Prepares the scheduled events for simulating the tick, returning the
+    /// names of the events which fire on it. Events scheduled on or after the
+    /// tick are dropped since simulating it again will schedule them again.
+    fn scheduled_events_for(&mut self, tick: u64) -> Vec<String> {
+        events_firing_on(&mut self.scheduled_events, tick)
+    }
+
+    /// Confirms the events scheduled for a tick which can no longer be
+    /// re-simulated
+    fn confirm_scheduled_events(&mut self, tick: u64, cx: &mut Context) {
+        for name in take_events_firing_on(&mut self.scheduled_events, tick) {
+            cx.queue_signal("scheduled_event_confirmed", vec![name.to_variant()]);
+        }
+    }
+
+    /// Returns true the first time the effect id is seen on the current tick.
+    /// Re-simulations of the tick during rollbacks return false so that sounds
+    /// and particles don't fire twice.
+    pub fn should_play_once(&mut self, effect_id: String, cx: &Context) -> bool {
+        first_play(&mut self.played_effects, cx.current_tick(), effect_id)
+    }
+
+    pub fn is_resimulating(&self) -> bool {
+        self.resimulating
+    }
+
+    pub fn is_spectating(&self) -> bool {
+        self.spectating
+    }
+
+    /// Every player with input in the match. Includes the local player unless
+    /// spectating
+    fn players(&self, cx: &Context) -> Vec<Uuid> {
+        let mut players = cx.peers();
+        if !self.spectating {
+            players.push(cx.local_id());
+        }
+        players
+    }
+
+    /// Number of frames since the last input was received from the peer, or
+    /// -1 if the id isn't a peer
+    pub fn peer_input_age(&self, id: Uuid, cx: &Context) -> i64 {
+        input_age(
+            id,
+            &cx.peers(),
+            &self.latest_frame_received,
+            cx.latest_tick(),
+        )
+    }
+
+    /// Number of inputs received from the peer for ticks after the latest
+    /// tick, which will be applied without prediction. 0 if the id isn't a
+    /// peer
+    pub fn buffered_frames(&self, id: Uuid, cx: &Context) -> i64 {
+        if !cx.peers().contains(&id) {
+            return 0;
+        }
+
+        let input_ticks = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.has_input(id))
+            .map(|(tick, _)| *tick);
+        ticks_after(cx.latest_tick(), input_ticks) as i64
+    }
+
+    pub fn tick(&mut self, node: &Gd<Node>, cx: &mut Context) -> Result<Option<SyncStage>> {
+        self.release_jitter_buffer(cx)?;
+
+        record_advantages(
+            cx.latest_tick(),
+            &cx.peers(),
+            &self.latest_frame_received,
+            &self.latest_frame_delivered,
+            &mut self.peer_advantages,
+            &mut self.advantage,
+        );
+
+        self.check_clock_drift(cx)?;
+
+        if cx.stepping() {
+            // The caller stepping the simulation runs the tick itself
+            cx.request_execute_tick();
+        } else {
+            let node = (*node).clone();
+            let mut sync_manager = node.cast::<RollbackSyncManager>();
+            sync_manager.call_deferred("execute_tick".into(), &[]);
+        }
+
+        self.processed_ticks += 1;
+        Ok(None)
+    }
+
+    pub fn processed_ticks(&self) -> u64 {
+        self.processed_ticks
+    }
+
+    pub fn rollback_count(&self) -> u64 {
+        self.rollback_count
+    }
+
+    pub fn stalled_ticks(&self) -> u64 {
+        self.stalled_ticks
+    }
+
+    /// Applies the disconnect policy to a peer which stopped acknowledging
+    /// messages. Returns true if the match should end. The socket reports
+    /// every timed out packet, so repeats are ignored until the peer sends
+    /// input again.
+    pub fn peer_disconnected(&mut self, peer: Uuid, cx: &mut Context) -> Result<bool> {
+        // Nothing waits on a spectator, so it is dropped regardless of policy
+        if cx.is_spectator(peer) {
+            cx.disconnect(peer);
+            return Ok(false);
+        }
+
+        if !self.disconnected_peers.insert(peer) {
+            return Ok(false);
+        }
+
+        let policy = cx.disconnect_policy();
+        cx.logger().event(
+            EventSeverity::Warning,
+            Some(EventCategory::Network),
+            "peer_disconnected".into(),
+            format!("{peer} disconnected. Responding with {policy:?}"),
+            cx,
+        )?;
+        cx.queue_signal("peer_disconnected", vec![peer.to_string().to_variant()]);
+
+        match policy {
+            // Frames missing the peer's input already stall the simulation
+            DisconnectPolicy::Pause => Ok(false),
+            DisconnectPolicy::Continue => {
+                cx.disconnect(peer);
+                self.latest_frame_delivered.remove(&peer);
+                self.latest_frame_received.remove(&peer);
+                self.inputs_received_through.remove(&peer);
+                Ok(false)
             }
+            DisconnectPolicy::EndMatch => Ok(true),
         }
-        Default::default()
     }
 
-    pub fn advantage(&self) -> f64 {
-        self.rolling_advantage_sum as f64 / self.advantage_queue.len() as f64
+    /// Sends the node states of the latest confirmed frame to the other
+    /// peers, replacing their own states for that frame. Only the leader may
+    /// resync. Returns the resynced frame.
+    pub fn force_resync(&self, cx: &mut Context) -> Result<Option<u64>> {
+        if !cx.is_leader() || self.spectating {
+            godot_warn!("Only the leader can force a resync");
+            return Ok(None);
+        }
+
+        let Some((tick, frame)) = self.latest_confirmed_frame(cx) else {
+            godot_warn!("No frame has every player's input to resync from yet");
+            return Ok(None);
+        };
+
+        let states = frame
+            .node_states()
+            .into_iter()
+            .map(|(path, state)| (path, var_to_bytes(state).to_vec()))
+            .collect();
+        cx.logger().event_for_frame(
+            *tick,
+            EventSeverity::Warning,
+            Some(EventCategory::Desync),
+            "force_resync".into(),
+            "Sent".into(),
+            cx,
+        )?;
+        let throttled = cx.broadcast(Message::Resync {
+            frame: *tick,
+            states,
+        })?;
+        for peer in throttled {
+            godot_warn!("Resync skipped {peer} which has too many packets in flight");
+        }
+
+        Ok(Some(*tick))
+    }
+
+    /// The latest frame which has every player's input and whose states were
+    /// simulated with them, and so won't be rolled back. Frames at or after an
+    /// input received since the last simulation are left out since their
+    /// states are about to be simulated again.
+    fn latest_confirmed_frame(&self, cx: &Context) -> Option<(&u64, &Arc<Frame>)> {
+        let players = self.players(cx);
+        let tick = latest_confirmed_tick(
+            self.frames
+                .iter()
+                .filter(|(_, frame)| frame.updated())
+                .map(|(tick, _)| *tick),
+            self.frames
+                .iter()
+                .filter(|(_, frame)| frame.missing_input(&players).is_none())
+                .map(|(tick, _)| *tick),
+            cx.latest_tick(),
+        )?;
+        self.frames.get_key_value(&tick)
     }
 
-    pub fn tick(&mut self, node: &Gd<Node>, cx: &Context) -> Result<Option<SyncStage>> {
-        let mut largest_advantage: Option<i64> = None;
+    /// Lets the peer at the address watch the match. Sends it the states of
+    /// the latest frame simulated with every player's input along with every
+    /// input known since, and asks the other players to send it their inputs
+    /// as well.
+    pub fn add_spectator(&mut self, id: Uuid, address: SocketAddr, cx: &mut Context) -> Result<()> {
+        if self.spectating {
+            godot_warn!("{id} tried to join as a spectator through another spectator");
+            return Ok(());
+        }
+
+        let Some((tick, frame)) = self.latest_confirmed_frame(cx) else {
+            godot_warn!("No frame has every player's input to send to spectator {id} yet");
+            return Ok(());
+        };
+        let tick = *tick;
+
+        let states = frame
+            .node_states()
+            .into_iter()
+            .map(|(path, state)| (path, var_to_bytes(state).to_vec()))
+            .collect();
+        let spawns = frame
+            .spawned_node_paths()
+            .into_iter()
+            .filter_map(|node_path| Some(frame.spawn_record(&node_path)?.to_log_data(&node_path)))
+            .collect();
+        let peers = cx.peers();
+        let players = self.players(cx);
+        let local_id = cx.local_id();
+        let inputs = catch_up_inputs(
+            self.frames.keys().copied(),
+            tick,
+            &players,
+            |frame_tick, player| self.frames[&frame_tick].input(player),
+        )
+        .into_iter()
+        .map(|(frame, sender, input)| SentInput {
+            frame,
+            sender,
+            input: var_to_bytes(input).to_vec(),
+        })
+        .collect();
+        let player_addresses = peers
+            .iter()
+            .filter_map(|peer| Some((*peer, cx.address(*peer)?.to_string())))
+            .collect();
+
+        cx.connect_spectator(id, address);
+        cx.send_to(
+            id,
+            Message::StateSnapshot(StateSnapshot {
+                sender: local_id,
+                frame: tick,
+                states,
+                spawns,
+                inputs,
+                players: player_addresses,
+            }),
+        )?;
+        for peer in peers {
+            cx.send_to(
+                peer,
+                Message::SpectatorJoined {
+                    id,
+                    address: address.to_string(),
+                    frame: tick,
+                },
+            )?;
+        }
+        cx.logger().event_for_frame(
+            tick,
+            EventSeverity::Info,
+            Some(EventCategory::Network),
+            "spectator_joined".into(),
+            format!("{id} joined as a spectator"),
+            cx,
+        )
+    }
+
+    /// The frame to resync from if the resync received from the leader can be
+    /// applied. Replaces the frame's node states with the leader's so that
+    /// the rollback starts from them.
+    fn apply_pending_resync(&mut self, latest_tick: u64, cx: &mut Context) -> Option<u64> {
+        let (tick, _) = self.pending_resync.as_ref()?;
+        if *tick >= latest_tick {
+            // The frame hasn't been simulated locally yet, so simulating it
+            // would replace the leader's states
+            return None;
+        }
+
+        let (tick, states) = self.pending_resync.take()?;
+        let Some(frame) = self.frames.get(&tick) else {
+            godot_warn!("Frame {tick} was evicted before the resync arrived");
+            return None;
+        };
+        frame.set_node_states(states);
+        // Inputs which arrived late for earlier frames would otherwise roll
+        // back past the resynced frame and simulate over the leader's states
+        for (_, earlier_frame) in self
+            .frames
+            .iter()
+            .filter(|(frame_tick, _)| **frame_tick < tick)
+        {
+            earlier_frame.clear_updated();
+        }
+        cx.queue_signal("resynced", vec![tick.to_variant()]);
+        Some(tick)
+    }
+
+    pub fn handle_message(&mut self, message: Message, cx: &mut Context) -> Result<()> {
+        if let Message::Input { sent_input, .. } = &message {
+            if let Some(delay) = self.jitter_delay(sent_input.sender, cx) {
+                self.jitter_buffer.push((cx.latest_tick() + delay, message));
+                return Ok(());
+            }
+        }
+
+        self.apply_message(message, cx)
+    }
+
+    /// Estimates how many ticks ahead of the local clock each peer's clock is
+    /// from the latest input received and the response time, and warns about
+    /// any peer whose estimate has drifted past the threshold since its
+    /// baseline. Advantage stalls normally keep the offsets steady, so drift
+    /// means the peers' tick rates differ by more than stalling can absorb.
+    /// The baseline is retaken after each warning.
+    fn check_clock_drift(&mut self, cx: &mut Context) -> Result<()> {
+        if cx.is_replay() || cx.clock_drift_threshold() <= 0.0 {
+            return Ok(());
+        }
 
         for peer in cx.peers() {
-            let latest_frame_received =
-                self.latest_frame_received.get(&peer).copied().unwrap_or(0) as i64;
-            let remote_frame_lag = latest_frame_received
-                - self.latest_frame_delivered.get(&peer).copied().unwrap_or(0) as i64;
-            let local_frame_lag = cx.latest_tick() as i64 - latest_frame_received as i64;
-
-            largest_advantage = match largest_advantage {
-                Some(largest_advantage) => {
-                    Some(largest_advantage.max(local_frame_lag - remote_frame_lag))
-                }
-                None => Some(local_frame_lag - remote_frame_lag),
+            let Some(latest_frame_received) = self.latest_frame_received.get(&peer) else {
+                continue;
+            };
+            let Some(response_time) = cx.average_response_time(peer) else {
+                continue;
             };
+            let offset = clock_offset(
+                *latest_frame_received,
+                response_time,
+                cx.simulation_rate(),
+                cx.latest_tick(),
+            );
+            let (sum, count) = self.clock_offset_samples.entry(peer).or_default();
+            *sum += offset;
+            *count += 1;
         }
 
-        if let Some(largest_advantage) = largest_advantage {
-            self.rolling_advantage_sum += largest_advantage;
-            self.advantage_queue.push_back(largest_advantage);
-            if self.advantage_queue.len() > 100 {
-                self.rolling_advantage_sum -= self.advantage_queue.pop_front().unwrap();
-            }
+        if cx.latest_tick() % CLOCK_DRIFT_CHECK_TICKS != 0 {
+            return Ok(());
         }
 
-        let node = (*node).clone();
-        let mut sync_manager = node.cast::<RollbackSyncManager>();
-        sync_manager.call_deferred("execute_tick".into(), &[]);
-        Ok(None)
+        for (peer, drift) in clock_drifts(
+            std::mem::take(&mut self.clock_offset_samples),
+            &mut self.clock_baselines,
+            cx.clock_drift_threshold(),
+        ) {
+            cx.logger().event(
+                EventSeverity::Warning,
+                Some(EventCategory::Network),
+                "clock_drift".into(),
+                format!("{peer} drifted {drift:.1} ticks"),
+                cx,
+            )?;
+            cx.queue_signal(
+                "clock_drift",
+                vec![peer.to_string().to_variant(), drift.to_variant()],
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Number of ticks to hold an input from the peer in the jitter buffer.
+    /// Sized from the variation in response times to the peer so that inputs
+    /// which arrive in bursts are applied together. None if the input should
+    /// be applied immediately.
+    fn jitter_delay(&self, peer: Uuid, cx: &Context) -> Option<u64> {
+        if !cx.jitter_buffer() || cx.is_replay() {
+            return None;
+        }
+
+        let deviation = cx.response_time_deviation(peer)?;
+        let delay = ((deviation.as_secs_f64() * cx.simulation_rate() as f64).ceil() as u64)
+            .min(MAX_JITTER_DELAY_TICKS);
+        (delay > 0).then_some(delay)
+    }
+
+    /// Applies the inputs in the jitter buffer which have been held long
+    /// enough
+    fn release_jitter_buffer(&mut self, cx: &mut Context) -> Result<()> {
+        let (released, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.jitter_buffer)
+            .into_iter()
+            .partition(|(release_tick, _)| *release_tick <= cx.latest_tick());
+        self.jitter_buffer = held;
+
+        for (_, message) in released {
+            self.apply_message(message, cx)?;
+        }
+
+        Ok(())
     }
 
-    pub fn handle_message(&mut self, message: Message, cx: &Context) -> Result<()> {
+    fn apply_message(&mut self, message: Message, cx: &mut Context) -> Result<()> {
+        cx.logger()
+            .received_message(self.processed_ticks, &message, cx)?;
         match &message {
             Message::Input {
                 sent_input:
@@ -122,6 +1003,7 @@ impl PlayStage {
                         input,
                     },
                 last_received_frame: new_latest_frame_delivered,
+                received_through,
             } => {
                 // Store the input in the input table for the given frame and id
                 cx.logger()
@@ -131,212 +1013,1470 @@ impl PlayStage {
                     .entry(*tick)
                     .or_insert_with(|| Arc::new(Frame::new(*tick)));
                 self.latest_frame_delivered.insert(*remote_id, *tick);
-                frame.set_input(
+                self.disconnected_peers.remove(remote_id);
+                let frame = frame.clone();
+                let input = self.validate_input(
                     *remote_id,
+                    *tick,
                     bytes_to_var(PackedByteArray::from(&input[..])),
-                    cx.peers(),
-                );
+                    cx,
+                )?;
+                if frame.set_input(*remote_id, input, &self.players(cx)) {
+                    cx.queue_signal("frame_inputs_complete", vec![tick.to_variant()]);
+                }
 
                 let latest_frame_received =
                     self.latest_frame_received.entry(*remote_id).or_insert(0);
                 *latest_frame_received = (*latest_frame_received).max(*tick);
 
+                let inputs_received_through =
+                    self.inputs_received_through.entry(*remote_id).or_insert(0);
+                *inputs_received_through =
+                    advance_received_through(*inputs_received_through, |tick| {
+                        self.frames
+                            .get(&tick)
+                            .is_some_and(|frame| frame.has_input(*remote_id))
+                    });
+
                 let latest_frame_delivered =
                     self.latest_frame_delivered.entry(*remote_id).or_insert(0);
                 *latest_frame_delivered =
                     (*latest_frame_delivered).max(*new_latest_frame_delivered);
+                // The latest frame delivered may have skipped past a lost
+                // input, so resends are only canceled through the tick the
+                // peer has received every input up to
+                cx.cancel_inputs_through(*remote_id, *received_through)?;
             }
             Message::StateHash {
                 frame: tick,
                 hash: remote_hash,
             } => {
                 if let Some(frame) = self.frames.get(tick) {
-                    if let Some(local_hash) = frame.state_hash() {
-                        if *remote_hash != local_hash {
-                            panic!("Desync detected at tick {tick} {remote_hash} != {local_hash}");
-                        }
+                    if let Some(local_hash) = newly_desynced(frame, *remote_hash) {
+                        cx.logger().event_for_frame(
+                            *tick,
+                            EventSeverity::Error,
+                            Some(EventCategory::Desync),
+                            "desync".into(),
+                            format!("Remote hash {remote_hash} != local hash {local_hash}"),
+                            cx,
+                        )?;
+                        cx.logger().flush()?;
+                        // Hashes are passed as ints the same way frame_hash
+                        // returns them
+                        cx.queue_signal(
+                            "desync_detected",
+                            vec![
+                                tick.to_variant(),
+                                (local_hash as i64).to_variant(),
+                                (*remote_hash as i64).to_variant(),
+                            ],
+                        );
+                        // Share the values behind the hash so peers can
+                        // tell which of them diverged
+                        cx.broadcast(Message::DesyncStates {
+                            sender: cx.local_id(),
+                            frame: *tick,
+                            states: frame.logged_states(),
+                        })?;
                     }
                 }
             }
+            Message::DesyncStates {
+                sender,
+                frame: tick,
+                states,
+            } => {
+                // Peers which haven't hashed the frame yet compare it when
+                // this peer's hash arrives instead
+                let Some(frame) = self
+                    .frames
+                    .get(tick)
+                    .filter(|frame| frame.state_hash().is_some())
+                else {
+                    return Ok(());
+                };
+                let differences = diverged_states(&frame.logged_states(), states);
+                if differences.is_empty() {
+                    return Ok(());
+                }
+
+                // The sender may have caught the desync first, in which case
+                // it is still waiting on this peer's values. Spectators' values
+                // aren't compared by players
+                if frame.mark_desynced() && !self.spectating {
+                    cx.send_to(
+                        *sender,
+                        Message::DesyncStates {
+                            sender: cx.local_id(),
+                            frame: *tick,
+                            states: frame.logged_states(),
+                        },
+                    )?;
+                }
+                cx.queue_signal(
+                    "desync_details",
+                    vec![
+                        tick.to_variant(),
+                        sender.to_string().to_variant(),
+                        differences.to_variant(),
+                    ],
+                );
+            }
+            Message::Resync {
+                frame: tick,
+                states,
+            } => {
+                cx.logger().event_for_frame(
+                    *tick,
+                    EventSeverity::Warning,
+                    Some(EventCategory::Desync),
+                    "force_resync".into(),
+                    "Received".into(),
+                    cx,
+                )?;
+                let states = states
+                    .iter()
+                    .map(|(path, state)| {
+                        (
+                            path.clone(),
+                            bytes_to_var(PackedByteArray::from(&state[..])),
+                        )
+                    })
+                    .collect();
+                self.pending_resync = Some((*tick, states));
+            }
+            Message::SpectatorJoined {
+                id,
+                address,
+                frame: tick,
+            } => {
+                // The player the spectator joined through sent every input it
+                // had, but this peer's later inputs may not have reached it
+                cx.connect_spectator(*id, address.parse()?);
+                let local_id = cx.local_id();
+                for (frame_tick, frame) in self
+                    .frames
+                    .iter()
+                    .filter(|(frame_tick, _)| **frame_tick > *tick)
+                    .sorted_by_key(|(frame_tick, _)| **frame_tick)
+                {
+                    let Some(input) = frame.input(local_id) else {
+                        continue;
+                    };
+                    cx.send_to(
+                        *id,
+                        Message::Input {
+                            sent_input: SentInput {
+                                frame: *frame_tick,
+                                sender: local_id,
+                                input: var_to_bytes(input).to_vec(),
+                            },
+                            last_received_frame: 0,
+                            received_through: 0,
+                        },
+                    )?;
+                }
+            }
+            // Only spectators still in the lobby load snapshots
+            Message::StateSnapshot(_) => {}
             _ => panic!("Recieved lobby message during play stage"),
         }
 
         Ok(())
     }
 
+    /// Runs the input validator if one is set. Inputs the validator rejects
+    /// are replaced with the peer's last input before the tick, the same
+    /// value prediction would have used. Rejected or modified inputs are
+    /// logged as suspicious.
+    fn validate_input(
+        &self,
+        remote_id: Uuid,
+        tick: u64,
+        input: Variant,
+        cx: &Context,
+    ) -> Result<Variant> {
+        let Some(validator) = cx.input_validator() else {
+            return Ok(input);
+        };
+
+        let validated = validator.callv(varray![input.clone(), remote_id.to_string()]);
+        if validated == input {
+            return Ok(input);
+        }
+
+        let (validated, description) = if validated.is_nil() {
+            (self.predicted_input(remote_id, tick), "rejected")
+        } else {
+            (validated, "modified")
+        };
+        cx.logger().event_for_frame(
+            tick,
+            EventSeverity::Warning,
+            Some(EventCategory::Input),
+            "suspicious_input".into(),
+            format!("Input from {remote_id} {description} by validator"),
+            cx,
+        )?;
+
+        Ok(validated)
+    }
+
+    /// The most recent input from the peer before the given tick
+    fn predicted_input(&self, id: Uuid, tick: u64) -> Variant {
+        last_input_before(tick, self.max_rewind, |previous_tick| {
+            self.frames.get(&previous_tick)?.input(id)
+        })
+        .unwrap_or_default()
+    }
+
     pub fn execute_tick(mut owner: impl PlayStageOwner) {
         let peers = owner.peers();
-        let Some((oldest_updated, latest_tick)) = owner.update(|this, cx| {
-            // Remove frames that are older than the rewind max
-            let oldest_tick = (cx.latest_tick() + 1).saturating_sub(MAX_REWIND);
-            for old_tick in this
-                .frames
-                .keys()
-                .copied()
-                .filter(|tick| tick < &oldest_tick)
-                .collect::<Vec<_>>()
+        let Some((oldest_updated, latest_tick, resynced, snapshot)) = owner.update(|this, cx| {
+            // Remove frames that are older than the longest rewind window
+            // unless they have been pinned
+            let oldest_tick = match peers.as_slice() {
+                // With a single remote peer the longest window is that peer's
+                // window, so no peer can lag outside of a shorter one
+                [remote_peer] => {
+                    window_start(cx.latest_tick(), this.rewind_window(*remote_peer, cx))
+                }
+                _ => {
+                    let rewind_windows = peers
+                        .iter()
+                        .map(|peer| (*peer, this.rewind_window(*peer, cx)))
+                        .collect::<HashMap<_, _>>();
+                    let oldest_tick =
+                        longest_window_start(cx.latest_tick(), &rewind_windows, this.max_rewind);
+
+                    // Frames are only kept for the longest window, but peers
+                    // with shorter windows still stall once their input falls
+                    // outside of their own window
+                    if let Some((old_tick, missing_input_peer)) =
+                        lagging_peer(&rewind_windows, oldest_tick, cx.latest_tick(), |tick| {
+                            this.frames.get(&tick).cloned()
+                        })
+                    {
+                        this.log_stall(old_tick, missing_input_peer, cx);
+                        return None;
+                    }
+
+                    oldest_tick
+                }
+            };
+
+            // Pinned frames leaving the window go through the same checks as
+            // every other frame so that pinning only keeps them from being freed
+            for old_tick in
+                evicted_ticks(this.frames.keys().copied(), this.window_start, oldest_tick)
             {
                 let frame = this
                     .frames
                     .remove(&old_tick)
                     .expect("No frame exists for old_tick");
-                if let Some(missing_input_peer) = frame.missing_input(peers.clone()) {
+                if let Some(missing_input_peer) = frame.missing_input(&peers) {
                     // This frame is missing input from one of the peers.
                     // Log that we are stalling in order for the peer to catch up
                     // and add it back.
-                    cx.logger()
-                        .dropped_frame(cx.latest_tick() + 1, old_tick, missing_input_peer, cx)
-                        .expect("Could not log dropped frame");
+                    this.log_stall(old_tick, missing_input_peer, cx);
                     this.frames.insert(old_tick, frame);
                     return None;
                 }
+                if this.pinned_frames.contains_key(&old_tick) {
+                    this.frames.insert(old_tick, frame);
+                }
+                this.window_start = old_tick + 1;
+                this.checkpoints.remove(&old_tick);
+
+                this.confirm_scheduled_events(old_tick, cx);
+
+                // Evicted ticks can't be re-simulated, so their effects never
+                // need to be checked again
+                let frames = &this.frames;
+                this.played_effects
+                    .retain(|tick, _| frames.contains_key(tick));
 
                 // TODO: Maybe notify nodes that this tick is dead now
                 // Could be useful for when a node doesn't return the entire state
                 // and instead returns a state id
 
-                let simulation_frame_advantage = this.advantage() / 2.0;
-                if simulation_frame_advantage >= 0.75 {
-                    let period = ((MAX_REWIND / 2) as f64 - (simulation_frame_advantage + 0.5))
-                        .max(1.0) as u64
-                        * 3;
-                    if cx.latest_tick() % period == 0 {
-                        // Stall a frame to let other peers catch up
-                        return None;
-                    }
+                if advantage_stall_due(
+                    cx.advantage_stalling(),
+                    cx.latest_tick(),
+                    this.advantage() / 2.0,
+                    this.max_rewind,
+                    this.last_stall_tick,
+                    cx.min_stall_interval(),
+                ) {
+                    // Stall a frame to let other peers catch up
+                    this.last_stall_tick = Some(cx.latest_tick());
+                    this.stalled_ticks += 1;
+                    return None;
                 }
             }
 
+            this.stall = None;
             let latest_tick = cx.increment_latest_tick();
+            cx.logger().tick_time(cx).expect("Could not log tick time");
 
             this.frames
                 .entry(latest_tick)
                 .or_insert_with(|| Arc::new(Frame::new(latest_tick)));
 
             // Find the latest tick without any updates before it
-            let mut oldest_updated = latest_tick;
-            for tick in oldest_tick..latest_tick {
-                if let Some(frame) = this.frames.get(&tick) {
-                    if frame.updated() {
-                        oldest_updated = tick;
-                        break;
-                    }
-                }
+            let mut oldest_updated = oldest_updated_tick(oldest_tick..latest_tick, |tick| {
+                this.frames.get(&tick).is_some_and(|frame| frame.updated())
+            });
+
+            // The leader's states are authoritative, so simulation restarts
+            // after the resynced frame even if earlier frames were updated
+            let resynced = this.apply_pending_resync(latest_tick, cx);
+            if let Some(resync_tick) = resynced {
+                oldest_updated = resync_tick + 1;
             }
 
-            Some((oldest_updated, latest_tick))
-        }) else {
+            // A spectator starts by loading the frame it joined from, the
+            // same way a resync does
+            let loaded_snapshot = this.snapshot_to_load.take();
+            if let Some(snapshot_tick) = loaded_snapshot {
+                oldest_updated = snapshot_tick + 1;
+            }
+
+            // With checkpoints enabled a rollback starts from the nearest
+            // checkpoint instead of the states kept on the frame it would load
+            if resynced.is_none() && loaded_snapshot.is_none() && oldest_updated != latest_tick {
+                let nearest = nearest_checkpoint(
+                    &this.checkpoints,
+                    oldest_tick..=oldest_updated.saturating_sub(1),
+                );
+                if let Some(checkpoint_tick) = nearest {
+                    if let Some(frame) = this.frames.get(&checkpoint_tick) {
+                        frame.load_state(&this.checkpoints[&checkpoint_tick]);
+                        oldest_updated = checkpoint_tick + 1;
+                    }
+                }
+            }
+
+            // Every frame from the one loaded for the rollback through the
+            // latest tick is assumed to exist below
+            this.fill_frame_holes(oldest_updated.saturating_sub(1)..=latest_tick, cx);
+
+            this.record_rollback(oldest_updated != latest_tick, cx)
+                .expect("Could not log input delay");
+
+            Some((
+                oldest_updated,
+                latest_tick,
+                resynced.is_some(),
+                loaded_snapshot.is_some(),
+            ))
+        }) else {
             return;
         };
 
         // Load the frame before the oldest_updated if a rollback was necessary
-        if oldest_updated != latest_tick {
+        if oldest_updated != latest_tick || resynced || snapshot {
             let frame_to_load = oldest_updated.saturating_sub(1);
-            owner.update(|_, cx| {
+            owner.update(|this, cx| {
+                this.rollback_count += 1;
                 cx.set_current_tick(frame_to_load);
                 cx.logger()
                     .rollback(latest_tick, frame_to_load, cx)
                     .expect("Could not log rollback");
+                cx.queue_signal(
+                    "rollback_occurred",
+                    vec![latest_tick.to_variant(), frame_to_load.to_variant()],
+                );
             });
             owner.load_frame(frame_to_load);
+            if snapshot {
+                // Node states are loaded before missing nodes are spawned, so
+                // load again to give the nodes spawned from the snapshot their
+                // states
+                owner.load_frame(frame_to_load);
+            }
         }
 
-        // Dont record input on the first tick to ensure we have something
-        // to roll back to
-        if latest_tick > 1 {
+        let input_ticks = owner.update(|this, cx| {
+            if records_local_input(latest_tick, this.start_offset) && !this.spectating {
+                this.local_input_ticks(latest_tick, cx)
+            } else {
+                Vec::new()
+            }
+        });
+        if !input_ticks.is_empty() {
             let new_input = owner.fetch_local_input();
-            let (sent_input, latest_frame_received) = owner.update(|this, cx| {
-                let sent_input = SentInput {
-                    frame: latest_tick,
-                    sender: cx.local_id(),
-                    input: var_to_bytes(new_input.clone()).to_vec(),
-                };
+            let new_input = owner.update(|this, cx| this.fill_missing_input(new_input, cx));
+            let (sent_inputs, latest_frame_received, inputs_received_through) =
+                owner.update(|this, cx| {
+                    let mut sent_inputs = Vec::new();
+                    let players = this.players(cx);
+                    for input_tick in input_ticks {
+                        let sent_input = SentInput {
+                            frame: input_tick,
+                            sender: cx.local_id(),
+                            input: var_to_bytes(new_input.clone()).to_vec(),
+                        };
 
-                cx.logger()
-                    .sent_input(sent_input.clone())
-                    .expect("Couldn't log sent input");
+                        cx.logger()
+                            .sent_input(sent_input.clone())
+                            .expect("Couldn't log sent input");
+                        let frame = this
+                            .frames
+                            .entry(input_tick)
+                            .or_insert_with(|| Arc::new(Frame::new(input_tick)));
+                        if frame.set_input(cx.local_id(), new_input.clone(), &players) {
+                            cx.queue_signal("frame_inputs_complete", vec![input_tick.to_variant()]);
+                        }
+                        sent_inputs.push(sent_input);
+                    }
+                    (
+                        sent_inputs,
+                        this.latest_frame_received.clone(),
+                        this.inputs_received_through.clone(),
+                    )
+                });
+
+            for id in owner.peers() {
+                for sent_input in sent_inputs.iter() {
+                    let message = Message::Input {
+                        sent_input: sent_input.clone(),
+                        last_received_frame: latest_frame_received.get(&id).copied().unwrap_or(0),
+                        received_through: inputs_received_through.get(&id).copied().unwrap_or(0),
+                    };
+
+                    owner.send_input(id, sent_input.frame, message);
+                }
+            }
+
+            // Spectators never acknowledge inputs with their own, so their
+            // inputs aren't tracked for cancelling
+            owner.update(|_, cx| {
+                for spectator in cx.spectators() {
+                    for sent_input in sent_inputs.iter() {
+                        let message = Message::Input {
+                            sent_input: sent_input.clone(),
+                            last_received_frame: 0,
+                            received_through: 0,
+                        };
+                        cx.send_to(spectator, message)
+                            .expect("Couldn't send input to spectator");
+                    }
+                }
+            });
+        }
+
+        for tick in oldest_updated.min(latest_tick)..=latest_tick {
+            let scheduled_events = owner.update(|this, cx| {
                 let frame = this
                     .frames
-                    .get_mut(&latest_tick)
-                    .expect("Could not record input");
-                frame.set_input(cx.local_id(), new_input.clone(), cx.peers());
-                (sent_input, this.latest_frame_received.clone())
+                    .get(&tick)
+                    .expect("Could not get frame for tick");
+                if let Some(previous_frame) = this.frames.get(&tick.saturating_sub(1)) {
+                    frame.copy_spawn_data(&previous_frame);
+                }
+                frame.reset_spawn_count();
+                this.resimulating = tick < latest_tick;
+                cx.set_current_tick(tick);
+                this.scheduled_events_for(tick)
             });
 
-            for id in owner.peers() {
-                let message = Message::Input {
-                    sent_input: sent_input.clone(),
-                    last_received_frame: latest_frame_received.get(&id).copied().unwrap_or(0),
-                };
+            for scheduled_event in scheduled_events {
+                owner.emit_scheduled_event(scheduled_event);
+            }
+
+            let new_state = owner.networked_process();
+            if let Some(logged_spawn_events) = owner.logged_spawn_events(tick) {
+                PlayStage::reconcile_spawns(&mut owner, tick, logged_spawn_events);
+            }
+            let state_hash = owner.log_node_states();
+
+            owner.update(|this, cx| {
+                // Frames which already desynced stop being compared, so
+                // re-simulating them doesn't report the desync again. Players
+                // don't compare against spectators' hashes
+                let send_hash = !this.frames.get(&tick).unwrap().desynced() && !this.spectating;
+                if let Some(state_hash) = state_hash.filter(|_| send_hash) {
+                    cx.broadcast(Message::StateHash {
+                        frame: tick,
+                        hash: state_hash,
+                    })
+                    .expect("Could not broadcast state_hash");
+                }
+
+                this.check_node_states(&new_state);
+                let frame = this.frames.get(&tick).unwrap();
+                let refused_spawns = frame.refused_spawns();
+                if refused_spawns > 0 {
+                    let message = format!(
+                        "Refused {refused_spawns} spawns past the cap of {} on frame {tick}",
+                        cx.spawn_cap().unwrap_or_default()
+                    );
+                    godot_warn!("{message}");
+                    cx.logger()
+                        .event_for_frame(
+                            tick,
+                            EventSeverity::Warning,
+                            Some(EventCategory::Spawn),
+                            "spawn_cap_exceeded".into(),
+                            message,
+                            cx,
+                        )
+                        .expect("Could not log spawn cap");
+                }
+                frame.set_node_states(new_state);
+                if checkpoint_due(tick, cx.checkpoint_interval()) {
+                    insert_checkpoint(&mut this.checkpoints, tick, frame.save_state());
+                }
+                for spawned_node_path in frame.spawned_node_paths() {
+                    cx.logger()
+                        .spawned_node_alive(spawned_node_path, cx)
+                        .expect("Could not log spawned node alive");
+                }
+            });
+        }
+        owner.update(|this, _| this.resimulating = false);
+    }
+
+    /// Logs a dropped frame for a tick stalled waiting on the peer's input for
+    /// the given frame. Each entry records how many consecutive ticks the
+    /// stall has lasted, so the last entry of a stall holds its full length.
+    fn log_stall(&mut self, frame_missing_input: u64, lagger: Uuid, cx: &Context) {
+        let stall_ticks = stall_length(self.stall, frame_missing_input);
+        self.stall = Some((frame_missing_input, stall_ticks));
+        self.stalled_ticks += 1;
+
+        if stall_ticks == 1 {
+            cx.logger()
+                .event_for_frame(
+                    cx.latest_tick() + 1,
+                    EventSeverity::Warning,
+                    Some(EventCategory::Stall),
+                    "stall".into(),
+                    format!("Waiting on {lagger} for frame {frame_missing_input}"),
+                    cx,
+                )
+                .expect("Could not log stall");
+        }
+
+        cx.logger()
+            .dropped_frame(
+                cx.latest_tick() + 1,
+                frame_missing_input,
+                lagger,
+                stall_ticks,
+                cx,
+            )
+            .expect("Could not log dropped frame");
+    }
+
+    /// Reports nodes whose state contains values which don't round trip
+    /// through var_to_bytes. Objects in particular are stored by reference,
+    /// so later changes to them silently corrupt every frame holding them.
+    /// Each node is only reported once to avoid an error every tick.
+    fn check_node_states(&mut self, node_states: &HashMap<String, Variant>) {
+        for (path, variant_type) in
+            unreported_unserializable_states(node_states, &mut self.unserializable_state_paths)
+        {
+            godot_error!(
+                "networked_process of {} returned a state containing a {:?}. Rollbacks can't restore it, so return plain data instead",
+                path,
+                variant_type
+            );
+        }
+    }
+
+    /// Inserts an empty frame for any tick in the range missing from the frame
+    /// map, logging each hole found. Holes shouldn't occur, but if a frame is
+    /// evicted while still referenced, this reports it instead of panicking
+    /// part way through a tick.
+    fn fill_frame_holes(&mut self, ticks: RangeInclusive<u64>, cx: &Context) {
+        for tick in frame_holes(&self.frames, ticks) {
+            godot_warn!("Frame {tick} missing from frame map. Replacing it with an empty frame");
+            cx.logger()
+                .event_for_frame(
+                    tick,
+                    EventSeverity::Error,
+                    Some(EventCategory::Stall),
+                    "frame_hole".into(),
+                    format!("Frame {tick} was missing and replaced with an empty frame"),
+                    cx,
+                )
+                .expect("Could not log frame hole");
+            self.frames.insert(tick, Arc::new(Frame::new(tick)));
+        }
+    }
+
+    /// Spawns and despawns nodes so that the spawned nodes in the frame
+    /// match the previous frame's nodes with the logged spawns and despawns
+    /// applied. Used by faithful replays to keep the node tree identical to
+    /// the original run even if game code's spawn decisions diverge.
+    fn reconcile_spawns(
+        owner: &mut impl PlayStageOwner,
+        tick: u64,
+        logged_spawn_events: LoggedSpawnEvents,
+    ) {
+        let (frame, previous_node_paths, spawn_manager) = owner.update(|this, _| {
+            let previous_node_paths = tick
+                .checked_sub(1)
+                .and_then(|previous_tick| this.frames.get(&previous_tick))
+                .map(|previous_frame| previous_frame.spawned_node_paths())
+                .unwrap_or_default();
+            (
+                this.frames.get(&tick).unwrap().clone(),
+                previous_node_paths,
+                this.spawn_manager.clone(),
+            )
+        });
+
+        let LoggedSpawnEvents { spawned, despawned } = logged_spawn_events;
+        let expected_node_paths = logged_node_paths(
+            previous_node_paths,
+            spawned.iter().map(|(node_path, _)| node_path.clone()),
+            &despawned,
+        );
+
+        for node_path in frame.spawned_node_paths() {
+            if !expected_node_paths.contains(&node_path) {
+                spawn_manager.despawn(owner, &node_path, frame.as_ref());
+            }
+        }
+
+        for (node_path, spawn_record) in spawned {
+            if !frame.contains_spawn_record(&node_path) {
+                spawn_manager.spawn(owner, spawn_record, frame.as_ref(), true);
+            }
+        }
+    }
+
+    /// Reconciles the spawned nodes with the current frame's spawn records
+    /// after the node tree was changed outside of the sync manager, such as
+    /// by a scene change
+    pub fn resync_spawns(mut owner: impl PlayStageOwner) {
+        let (frame, spawn_manager) = owner.update(|this, cx| {
+            let frame = this.frames.get(&cx.current_tick()).unwrap();
+            (frame.clone(), this.spawn_manager.clone())
+        });
+
+        spawn_manager.resync(&mut owner, frame.as_ref());
+    }
+
+    pub fn despawn(mut owner: impl PlayStageOwner, node: &Gd<Node>) {
+        let (frame, spawn_manager) = owner.update(|this, cx| {
+            let frame = this.frames.get(&cx.current_tick()).unwrap();
+            (frame.clone(), this.spawn_manager.clone())
+        });
+
+        spawn_manager.despawn(&mut owner, &node.get_path().to_string(), frame.as_ref());
+    }
+
+    pub fn spawn(
+        mut owner: impl PlayStageOwner,
+        name: String,
+        parent: &Gd<Node>,
+        scene: Gd<PackedScene>,
+        state: Variant,
+    ) -> Option<Gd<Node>> {
+        let (frame, spawn_manager, spawn_cap) = owner.update(|this, cx| {
+            let frame = this.frames.get(&cx.current_tick()).unwrap();
+            (frame.clone(), this.spawn_manager.clone(), cx.spawn_cap())
+        });
+        if !frame.count_spawn(spawn_cap) {
+            return None;
+        }
+
+        let parent_path = parent.get_path().to_string();
+        let scene_path = scene.get_path().to_string();
+        let spawn_record = SpawnRecord {
+            name,
+            parent_path,
+            scene,
+            scene_path,
+            state,
+        };
+        Some(spawn_manager.spawn(&mut owner, spawn_record, frame.as_ref(), false))
+    }
+}
+
+/// Returns the networked nodes managed by the sync manager. A sync manager with
+/// a domain root only manages networked nodes below that root, and a sync
+/// manager without one manages every networked node not below some other
+/// manager's domain root.
+fn managed_networked_nodes(sync_manager: &Gd<RollbackSyncManager>) -> Array<Gd<Node>> {
+    let domain_root = sync_manager.bind().domain_root.clone();
+    networked_nodes_in_domain(&sync_manager.clone().upcast(), domain_root.as_ref())
+}
+
+/// Returns the networked nodes in the tree of the given node which belong to
+/// the domain root, or to no domain root if there isn't one.
+pub fn networked_nodes_in_domain(
+    node: &Gd<Node>,
+    domain_root: Option<&Gd<Node>>,
+) -> Array<Gd<Node>> {
+    node.get_tree()
+        .expect("Couldn't get tree")
+        .get_nodes_in_group("networked".into())
+        .iter_shared()
+        .filter(|networked_node| {
+            let ancestors =
+                std::iter::successors(networked_node.get_parent(), |node| node.get_parent());
+            in_domain(ancestors, domain_root, |node| {
+                node.is_in_group(DOMAIN_ROOT_GROUP.into())
+            })
+        })
+        .collect()
+}
+
+/// Whether a node with the given ancestors, nearest first, belongs to the
+/// domain root. Without a domain root, nodes below any other domain root
+/// don't belong.
+fn in_domain<N: PartialEq>(
+    mut ancestors: impl Iterator<Item = N>,
+    domain_root: Option<&N>,
+    is_domain_root: impl Fn(&N) -> bool,
+) -> bool {
+    match domain_root {
+        Some(domain_root) => ancestors.any(|ancestor| &ancestor == domain_root),
+        None => !ancestors.any(|ancestor| is_domain_root(&ancestor)),
+    }
+}
+
+/// The networked callbacks a node implements, given whether it has a method
+/// by name
+pub fn implemented_callbacks(has_method: impl Fn(&str) -> bool) -> Vec<&'static str> {
+    NETWORKED_CALLBACKS
+        .into_iter()
+        .filter(|callback| has_method(callback))
+        .collect()
+}
+
+/// Returns the type of the first value found which doesn't survive
+/// var_to_bytes and bytes_to_var, such as an object or a callable. Returns
+/// None if the whole variant round trips.
+fn unserializable_type(variant: &Variant) -> Option<VariantType> {
+    match variant.get_type() {
+        variant_type @ (VariantType::Object
+        | VariantType::Callable
+        | VariantType::Signal
+        | VariantType::Rid) => Some(variant_type),
+        VariantType::Array => variant
+            .to::<VariantArray>()
+            .iter_shared()
+            .find_map(|element| unserializable_type(&element)),
+        VariantType::Dictionary => {
+            variant
+                .to::<Dictionary>()
+                .iter_shared()
+                .find_map(|(key, value)| {
+                    unserializable_type(&key).or_else(|| unserializable_type(&value))
+                })
+        }
+        _ => None,
+    }
+}
+
+/// Finds the nodes whose state contains a value which doesn't round trip
+/// through var_to_bytes, along with the value's type, skipping and then
+/// recording those already reported. Sorted by path.
+fn unreported_unserializable_states(
+    node_states: &HashMap<String, Variant>,
+    reported_paths: &mut HashSet<String>,
+) -> Vec<(String, VariantType)> {
+    node_states
+        .iter()
+        .sorted_by_key(|(path, _)| *path)
+        .filter_map(|(path, state)| Some((path, unserializable_type(state)?)))
+        .filter(|(path, _)| reported_paths.insert(path.to_string()))
+        .map(|(path, variant_type)| (path.clone(), variant_type))
+        .collect()
+}
+
+/// Returns what the variant decodes as after var_to_bytes and bytes_to_var if
+/// that differs from the original
+fn round_trip_mismatch(variant: &Variant) -> Option<Variant> {
+    let decoded = bytes_to_var(var_to_bytes(variant.clone()));
+    (decoded != *variant).then_some(decoded)
+}
+
+/// Replaces an input returned by networked_input which can't be sent to peers
+/// with the default input, warning about the scripting bug instead of
+/// crashing the netcode.
+fn validated_input(input: Variant) -> Variant {
+    if let Some(variant_type) = unserializable_type(&input) {
+        godot_warn!(
+            "networked_input returned {} containing a {:?} which can't be sent to peers. Using the default input",
+            input.stringify(),
+            variant_type
+        );
+        return Variant::nil();
+    }
+
+    input
+}
+
+/// Rounds every float in the variant, including those in vectors, transforms,
+/// colors, packed arrays, arrays and dictionaries, to the nearest multiple of
+/// epsilon. Lets states which differ only by floating point rounding hash
+/// identically.
+fn quantize_floats(variant: &Variant, epsilon: f64) -> Variant {
+    fn quantized<T: FromGodot + ToGodot + Quantize>(variant: &Variant, epsilon: f64) -> Variant {
+        variant.to::<T>().quantize(epsilon).to_variant()
+    }
+
+    match variant.get_type() {
+        VariantType::Float => quantized::<f64>(variant, epsilon),
+        VariantType::Vector2 => quantized::<Vector2>(variant, epsilon),
+        VariantType::Vector3 => quantized::<Vector3>(variant, epsilon),
+        VariantType::Vector4 => quantized::<Vector4>(variant, epsilon),
+        VariantType::Quaternion => quantized::<Quaternion>(variant, epsilon),
+        VariantType::Rect2 => quantized::<Rect2>(variant, epsilon),
+        VariantType::Transform2D => quantized::<Transform2D>(variant, epsilon),
+        VariantType::Aabb => quantized::<Aabb>(variant, epsilon),
+        VariantType::Plane => quantized::<Plane>(variant, epsilon),
+        VariantType::Basis => quantized::<Basis>(variant, epsilon),
+        VariantType::Transform3D => quantized::<Transform3D>(variant, epsilon),
+        VariantType::Projection => quantized::<Projection>(variant, epsilon),
+        VariantType::Color => quantized::<Color>(variant, epsilon),
+        VariantType::PackedFloat32Array => quantized::<PackedFloat32Array>(variant, epsilon),
+        VariantType::PackedFloat64Array => quantized::<PackedFloat64Array>(variant, epsilon),
+        VariantType::PackedVector2Array => quantized::<PackedVector2Array>(variant, epsilon),
+        VariantType::PackedVector3Array => quantized::<PackedVector3Array>(variant, epsilon),
+        VariantType::PackedVector4Array => quantized::<PackedVector4Array>(variant, epsilon),
+        VariantType::PackedColorArray => quantized::<PackedColorArray>(variant, epsilon),
+        VariantType::Array => variant
+            .to::<VariantArray>()
+            .iter_shared()
+            .map(|element| quantize_floats(&element, epsilon))
+            .collect::<VariantArray>()
+            .to_variant(),
+        VariantType::Dictionary => {
+            let mut quantized = Dictionary::new();
+            for (key, value) in variant.to::<Dictionary>().iter_shared() {
+                quantized.set(key, quantize_floats(&value, epsilon));
+            }
+            quantized.to_variant()
+        }
+        _ => variant.clone(),
+    }
+}
+
+/// A float bearing value whose floats can each be rounded to the nearest
+/// multiple of epsilon
+trait Quantize {
+    fn quantize(self, epsilon: f64) -> Self;
+}
+
+impl Quantize for f64 {
+    fn quantize(self, epsilon: f64) -> Self {
+        // Adding zero turns negative zero into zero so both hash the same
+        (self / epsilon).round() * epsilon + 0.0
+    }
+}
+
+impl Quantize for f32 {
+    fn quantize(self, epsilon: f64) -> Self {
+        (self as f64).quantize(epsilon) as f32
+    }
+}
+
+impl Quantize for Vector2 {
+    fn quantize(self, epsilon: f64) -> Self {
+        Vector2::new(self.x.quantize(epsilon), self.y.quantize(epsilon))
+    }
+}
+
+impl Quantize for Vector3 {
+    fn quantize(self, epsilon: f64) -> Self {
+        Vector3::new(
+            self.x.quantize(epsilon),
+            self.y.quantize(epsilon),
+            self.z.quantize(epsilon),
+        )
+    }
+}
+
+impl Quantize for Vector4 {
+    fn quantize(self, epsilon: f64) -> Self {
+        Vector4::new(
+            self.x.quantize(epsilon),
+            self.y.quantize(epsilon),
+            self.z.quantize(epsilon),
+            self.w.quantize(epsilon),
+        )
+    }
+}
+
+impl Quantize for Quaternion {
+    fn quantize(self, epsilon: f64) -> Self {
+        Quaternion::new(
+            self.x.quantize(epsilon),
+            self.y.quantize(epsilon),
+            self.z.quantize(epsilon),
+            self.w.quantize(epsilon),
+        )
+    }
+}
+
+impl Quantize for Rect2 {
+    fn quantize(self, epsilon: f64) -> Self {
+        Rect2::new(self.position.quantize(epsilon), self.size.quantize(epsilon))
+    }
+}
+
+impl Quantize for Transform2D {
+    fn quantize(self, epsilon: f64) -> Self {
+        Transform2D::from_cols(
+            self.a.quantize(epsilon),
+            self.b.quantize(epsilon),
+            self.origin.quantize(epsilon),
+        )
+    }
+}
+
+impl Quantize for Aabb {
+    fn quantize(self, epsilon: f64) -> Self {
+        Aabb::new(self.position.quantize(epsilon), self.size.quantize(epsilon))
+    }
+}
+
+impl Quantize for Plane {
+    fn quantize(self, epsilon: f64) -> Self {
+        // Built from its fields because Plane::new insists on a normalized
+        // normal, which rounding may not preserve
+        Plane {
+            normal: self.normal.quantize(epsilon),
+            d: self.d.quantize(epsilon),
+        }
+    }
+}
+
+impl Quantize for Basis {
+    fn quantize(self, epsilon: f64) -> Self {
+        Basis::from_rows(
+            self.rows[0].quantize(epsilon),
+            self.rows[1].quantize(epsilon),
+            self.rows[2].quantize(epsilon),
+        )
+    }
+}
+
+impl Quantize for Transform3D {
+    fn quantize(self, epsilon: f64) -> Self {
+        Transform3D::new(self.basis.quantize(epsilon), self.origin.quantize(epsilon))
+    }
+}
+
+impl Quantize for Projection {
+    fn quantize(self, epsilon: f64) -> Self {
+        Projection {
+            cols: self.cols.map(|col| col.quantize(epsilon)),
+        }
+    }
+}
+
+impl Quantize for Color {
+    fn quantize(self, epsilon: f64) -> Self {
+        Color::from_rgba(
+            self.r.quantize(epsilon),
+            self.g.quantize(epsilon),
+            self.b.quantize(epsilon),
+            self.a.quantize(epsilon),
+        )
+    }
+}
+
+macro_rules! impl_quantize_for_packed_array {
+    ($($array:ty),*) => {
+        $(
+            impl Quantize for $array {
+                fn quantize(self, epsilon: f64) -> Self {
+                    let values = self
+                        .to_vec()
+                        .into_iter()
+                        .map(|value| value.quantize(epsilon))
+                        .collect::<Vec<_>>();
+                    <$array>::from(&values[..])
+                }
+            }
+        )*
+    };
+}
+
+impl_quantize_for_packed_array!(
+    PackedFloat32Array,
+    PackedFloat64Array,
+    PackedVector2Array,
+    PackedVector3Array,
+    PackedVector4Array,
+    PackedColorArray
+);
+
+/// Local and remote logged states paired up by path and key
+type StatePairs<'a> =
+    BTreeMap<(&'a str, &'a str), (Option<&'a LoggedState>, Option<&'a LoggedState>)>;
+
+/// Pairs up the local and remote logged states by path and key, keeping every
+/// pair whose hashes differ. Values logged by only one side are None on the
+/// other.
+fn diverged_state_pairs<'a>(local: &'a [LoggedState], remote: &'a [LoggedState]) -> StatePairs<'a> {
+    let mut pairs = StatePairs::new();
+    for state in local {
+        pairs.entry((&state.path, &state.key)).or_default().0 = Some(state);
+    }
+    for state in remote {
+        pairs.entry((&state.path, &state.key)).or_default().1 = Some(state);
+    }
+
+    pairs.retain(|_, (local, remote)| {
+        local.map(|state| state.value_hash) != remote.map(|state| state.value_hash)
+    });
+    pairs
+}
+
+/// A dictionary of path, key, local_value and remote_value for every pair of
+/// logged states whose hashes differ. Values logged by only one side are nil
+/// on the other.
+fn diverged_states(local: &[LoggedState], remote: &[LoggedState]) -> Array<Dictionary> {
+    let value = |state: Option<&LoggedState>| match state {
+        Some(state) => state.value_text.to_variant(),
+        None => Variant::nil(),
+    };
+    diverged_state_pairs(local, remote)
+        .into_iter()
+        .map(|((path, key), (local, remote))| {
+            let mut difference = Dictionary::new();
+            difference.set("path", path);
+            difference.set("key", key);
+            difference.set("local_value", value(local));
+            difference.set("remote_value", value(remote));
+            difference
+        })
+        .collect()
+}
+
+/// Whether frame states are written to the log on the tick. Only every
+/// state_log_interval'th tick is logged, though every complete frame is
+/// still hashed and compared for desyncs.
+fn logs_states_on(tick: u64, state_log_interval: u64) -> bool {
+    tick % state_log_interval.max(1) == 0
+}
+
+/// Number of the given ticks which come after the latest tick
+fn ticks_after(latest_tick: u64, ticks: impl Iterator<Item = u64>) -> usize {
+    ticks.filter(|tick| *tick > latest_tick).count()
+}
+
+/// Whether to stall this tick so slower peers can catch up. While the
+/// simulation is ahead by most of a frame, stalls come periodically, more
+/// often the further ahead it is, but never within min_stall_interval ticks
+/// of the last one. Never stalls when advantage stalling is disabled.
+fn advantage_stall_due(
+    stalling_enabled: bool,
+    latest_tick: u64,
+    simulation_frame_advantage: f64,
+    max_rewind: u64,
+    last_stall_tick: Option<u64>,
+    min_stall_interval: u64,
+) -> bool {
+    if !stalling_enabled || simulation_frame_advantage < 0.75 {
+        return false;
+    }
+
+    let period = ((max_rewind / 2) as f64 - (simulation_frame_advantage + 0.5)).max(1.0) as u64 * 3;
+    let stall_allowed = last_stall_tick.map_or(true, |last_stall_tick| {
+        latest_tick.saturating_sub(last_stall_tick) >= min_stall_interval
+    });
+    latest_tick % period == 0 && stall_allowed
+}
+
+/// The first tick in the range whose frame has been updated since it was
+/// simulated, or the end of the range if none have. Every frame keeps its
+/// states, so a rollback loads the frame just before it and re-simulates only
+/// the ticks an updated input affects.
+fn oldest_updated_tick(ticks: Range<u64>, updated: impl Fn(u64) -> bool) -> u64 {
+    let end = ticks.end;
+    ticks.into_iter().find(|tick| updated(*tick)).unwrap_or(end)
+}
+
+/// The input for the current tick if it has arrived. Otherwise the
+/// prediction's stand in for it, where None means the default input
+fn predict_input<T>(
+    prediction: InputPrediction,
+    current_tick: u64,
+    latest_tick: u64,
+    max_rewind: u64,
+    input: impl Fn(u64) -> Option<T>,
+) -> Option<T> {
+    let oldest_tick = match prediction {
+        InputPrediction::Default => current_tick,
+        InputPrediction::RepeatLast => latest_tick.saturating_sub(max_rewind),
+    };
+    (oldest_tick..=current_tick).rev().find_map(input)
+}
+
+/// The input on the latest tick before the given one which has an input, only
+/// looking back as far as the rewind window
+fn last_input_before<T>(tick: u64, max_rewind: u64, input: impl Fn(u64) -> Option<T>) -> Option<T> {
+    (tick.saturating_sub(max_rewind)..tick)
+        .rev()
+        .find_map(input)
+}
+
+/// The spawned node paths a frame held in the logged run: the previous
+/// frame's with the frame's logged despawns removed and its spawns added
+fn logged_node_paths(
+    previous_node_paths: Vec<String>,
+    spawned: impl Iterator<Item = String>,
+    despawned: &[String],
+) -> HashSet<String> {
+    previous_node_paths
+        .into_iter()
+        .filter(|node_path| !despawned.contains(node_path))
+        .chain(spawned)
+        .collect()
+}
+
+/// The ticks in the range missing from the frame map
+fn frame_holes(frames: &HashMap<u64, Arc<Frame>>, ticks: RangeInclusive<u64>) -> Vec<u64> {
+    ticks.filter(|tick| !frames.contains_key(tick)).collect()
+}
+
+/// Whether a checkpoint is saved after simulating the tick
+fn checkpoint_due(tick: u64, checkpoint_interval: u64) -> bool {
+    checkpoint_interval > 0 && tick % checkpoint_interval == 0
+}
+
+/// Stores the checkpoint for the tick, dropping the oldest checkpoints past
+/// MAX_CHECKPOINTS
+fn insert_checkpoint(checkpoints: &mut HashMap<u64, Vec<u8>>, tick: u64, snapshot: Vec<u8>) {
+    checkpoints.insert(tick, snapshot);
+    while checkpoints.len() > MAX_CHECKPOINTS {
+        let oldest = *checkpoints.keys().min().expect("Checkpoints are not empty");
+        checkpoints.remove(&oldest);
+    }
+}
+
+/// The latest tick in the range with a checkpoint
+fn nearest_checkpoint(
+    checkpoints: &HashMap<u64, Vec<u8>>,
+    ticks: RangeInclusive<u64>,
+) -> Option<u64> {
+    checkpoints
+        .keys()
+        .copied()
+        .filter(|tick| ticks.contains(tick))
+        .max()
+}
+
+/// The first 2 frames and any frames covered by the start offset, with
+/// default inputs for every peer to ensure no rollbacks
+fn initial_frames(start_offset: u64, peers: &[Uuid]) -> HashMap<u64, Arc<Frame>> {
+    (0..=start_offset + 1)
+        .map(|tick| {
+            (
+                tick,
+                Arc::new(Frame::initial_frame(tick, peers.iter().copied())),
+            )
+        })
+        .collect()
+}
+
+/// Whether local input is recorded on the latest tick. Input isn't recorded
+/// on the first tick or during the start offset to ensure there is something
+/// to roll back to
+fn records_local_input(latest_tick: u64, start_offset: u64) -> bool {
+    latest_tick > start_offset + 1
+}
+
+/// Measures the advantage over each peer on the latest tick, adding it to the
+/// peer's rolling average and the largest of them to the aggregate one
+fn record_advantages(
+    latest_tick: u64,
+    peers: &[Uuid],
+    latest_frame_received: &HashMap<Uuid, u64>,
+    latest_frame_delivered: &HashMap<Uuid, u64>,
+    peer_advantages: &mut HashMap<Uuid, RollingAdvantage>,
+    advantage: &mut RollingAdvantage,
+) {
+    let mut largest_advantage: Option<i64> = None;
+
+    for peer in peers {
+        let latest_frame_received = latest_frame_received.get(peer).copied().unwrap_or(0) as i64;
+        let remote_frame_lag =
+            latest_frame_received - latest_frame_delivered.get(peer).copied().unwrap_or(0) as i64;
+        let local_frame_lag = latest_tick as i64 - latest_frame_received;
+        let peer_advantage = local_frame_lag - remote_frame_lag;
+        peer_advantages
+            .entry(*peer)
+            .or_default()
+            .push(peer_advantage);
+
+        largest_advantage = match largest_advantage {
+            Some(largest_advantage) => Some(largest_advantage.max(peer_advantage)),
+            None => Some(peer_advantage),
+        };
+    }
+
+    if let Some(largest_advantage) = largest_advantage {
+        advantage.push(largest_advantage);
+    }
+}
+
+/// The ticks after the last tick local input was set for through the latest
+/// tick plus the input delay, moving the last tick up to match
+fn delayed_input_ticks(last_input_tick: &mut u64, latest_tick: u64, input_delay: u64) -> Vec<u64> {
+    let target_tick = latest_tick + input_delay;
+    let ticks = (*last_input_tick + 1..=target_tick).collect();
+    *last_input_tick = (*last_input_tick).max(target_tick);
+    ticks
+}
+
+/// Grows the rewind window by the peer's round trip time in ticks, up to
+/// MAX_ADAPTIVE_REWIND unless the configured window is already longer
+fn adaptive_rewind_window(
+    max_rewind: u64,
+    round_trip: Option<Duration>,
+    simulation_rate: u32,
+) -> u64 {
+    let round_trip_ticks = round_trip.map_or(0, |round_trip| {
+        (round_trip.as_secs_f64() * simulation_rate as f64).ceil() as u64
+    });
+    (max_rewind + round_trip_ticks).min(MAX_ADAPTIVE_REWIND.max(max_rewind))
+}
+
+/// The oldest tick inside a rewind window of the given length
+fn window_start(latest_tick: u64, rewind_window: u64) -> u64 {
+    (latest_tick + 1).saturating_sub(rewind_window)
+}
+
+/// Held ticks which have left the rewind window since the last eviction,
+/// oldest first
+fn evicted_ticks(
+    held_ticks: impl Iterator<Item = u64>,
+    window_start: u64,
+    oldest_tick: u64,
+) -> Vec<u64> {
+    held_ticks
+        .filter(|tick| (window_start..oldest_tick).contains(tick))
+        .sorted()
+        .collect()
+}
+
+/// The oldest tick inside the longest of the peers' rewind windows
+fn longest_window_start(
+    latest_tick: u64,
+    rewind_windows: &HashMap<Uuid, u64>,
+    max_rewind: u64,
+) -> u64 {
+    let longest_window = rewind_windows.values().copied().max().unwrap_or(max_rewind);
+    window_start(latest_tick, longest_window)
+}
+
+/// Finds the oldest frame still held which is outside of a peer's rewind
+/// window but missing that peer's input
+fn lagging_peer(
+    rewind_windows: &HashMap<Uuid, u64>,
+    oldest_tick: u64,
+    latest_tick: u64,
+    frame: impl Fn(u64) -> Option<Arc<Frame>>,
+) -> Option<(u64, Uuid)> {
+    (oldest_tick..latest_tick + 1).find_map(|tick| {
+        let frame = frame(tick)?;
+        rewind_windows
+            .iter()
+            .filter(|(_, window)| tick < window_start(latest_tick, **window))
+            .map(|(peer, _)| *peer)
+            .sorted()
+            .find(|peer| !frame.has_input(*peer))
+            .map(|peer| (tick, peer))
+    })
+}
+
+/// Drops the events scheduled on or after the tick, since simulating it again
+/// schedules them again, and returns the names of those firing on it
+fn events_firing_on(scheduled_events: &mut Vec<ScheduledEvent>, tick: u64) -> Vec<String> {
+    scheduled_events.retain(|scheduled_event| scheduled_event.scheduled_on < tick);
+    scheduled_events
+        .iter()
+        .filter(|scheduled_event| scheduled_event.tick == tick)
+        .map(|scheduled_event| scheduled_event.name.clone())
+        .collect()
+}
+
+/// Removes the events firing on the tick, returning their names
+fn take_events_firing_on(scheduled_events: &mut Vec<ScheduledEvent>, tick: u64) -> Vec<String> {
+    let (firing, pending) = std::mem::take(scheduled_events)
+        .into_iter()
+        .partition::<Vec<_>, _>(|scheduled_event| scheduled_event.tick == tick);
+    *scheduled_events = pending;
+    firing
+        .into_iter()
+        .map(|scheduled_event| scheduled_event.name)
+        .collect()
+}
+
+/// The latest complete tick before both the latest tick and the first updated
+/// tick, whose states won't be simulated again
+fn latest_confirmed_tick(
+    updated_ticks: impl Iterator<Item = u64>,
+    complete_ticks: impl Iterator<Item = u64>,
+    latest_tick: u64,
+) -> Option<u64> {
+    let first_stale_tick = updated_ticks
+        .min()
+        .map_or(latest_tick, |tick| tick.min(latest_tick));
+    complete_ticks.filter(|tick| *tick < first_stale_tick).max()
+}
+
+/// The input to play given what the input source returned, repeating the last
+/// input it returned for up to repeat_limit ticks in a row when it returns
+/// nothing
+fn repeat_missing_input<T: Clone>(
+    input: Option<T>,
+    last_input: &mut Option<T>,
+    repeated_inputs: &mut u32,
+    repeat_limit: u32,
+) -> Option<T> {
+    if let Some(input) = input {
+        *last_input = Some(input.clone());
+        *repeated_inputs = 0;
+        return Some(input);
+    }
+
+    if *repeated_inputs >= repeat_limit {
+        return None;
+    }
+
+    let repeated_input = last_input.clone()?;
+    *repeated_inputs += 1;
+    Some(repeated_input)
+}
+
+/// How many ticks ahead of the local clock the peer's clock is estimated to
+/// be, from the latest frame received from it and half the response time
+fn clock_offset(
+    latest_frame_received: u64,
+    response_time: Duration,
+    simulation_rate: u32,
+    latest_tick: u64,
+) -> f64 {
+    let one_way_ticks = response_time.as_secs_f64() / 2.0 * simulation_rate as f64;
+    latest_frame_received as f64 + one_way_ticks - latest_tick as f64
+}
+
+/// Averages the clock offsets sampled for each peer and returns the peers
+/// whose average drifted from their baseline by more than the threshold,
+/// along with the drift. A peer's first average becomes its baseline, and the
+/// baseline is retaken after each drift.
+fn clock_drifts(
+    offset_samples: HashMap<Uuid, (f64, u32)>,
+    baselines: &mut HashMap<Uuid, f64>,
+    threshold: f64,
+) -> Vec<(Uuid, f64)> {
+    let mut drifts = Vec::new();
+    for (peer, (sum, count)) in offset_samples {
+        let offset = sum / count as f64;
+        let Some(baseline) = baselines.get(&peer).copied() else {
+            baselines.insert(peer, offset);
+            continue;
+        };
+
+        let drift = offset - baseline;
+        if drift.abs() > threshold {
+            drifts.push((peer, drift));
+            baselines.insert(peer, offset);
+        }
+    }
+    drifts
+}
 
-                owner.send(id, message);
-            }
+/// Consecutive ticks stalled on the frame counting this one, given the frame
+/// and length of the stall on the previous tick if there was one
+fn stall_length(previous_stall: Option<(u64, u64)>, frame_missing_input: u64) -> u64 {
+    match previous_stall {
+        Some((stalled_frame, stall_ticks)) if stalled_frame == frame_missing_input => {
+            stall_ticks + 1
         }
+        _ => 1,
+    }
+}
 
-        for tick in oldest_updated.min(latest_tick)..=latest_tick {
-            owner.update(|this, cx| {
-                let frame = this
-                    .frames
-                    .get(&tick)
-                    .expect("Could not get frame for tick");
-                if let Some(previous_frame) = this.frames.get(&tick.saturating_sub(1)) {
-                    frame.copy_spawn_data(&previous_frame);
-                }
-                cx.set_current_tick(tick);
-            });
+/// Records the effect as played on the tick, returning true if it hadn't been
+/// played on that tick before
+fn first_play(
+    played_effects: &mut HashMap<u64, HashSet<String>>,
+    tick: u64,
+    effect_id: String,
+) -> bool {
+    played_effects.entry(tick).or_default().insert(effect_id)
+}
 
-            let new_state = owner.networked_process();
-            let state_hash = owner.log_node_states();
+/// The frame's own hash if the remote hash disagrees with it and the frame
+/// hasn't desynced before. Frames which haven't been hashed yet can't be
+/// compared
+fn newly_desynced(frame: &Frame, remote_hash: u64) -> Option<u64> {
+    let local_hash = frame.state_hash()?;
+    (remote_hash != local_hash && frame.mark_desynced()).then_some(local_hash)
+}
 
-            owner.update(|this, cx| {
-                if let Some(state_hash) = state_hash {
-                    cx.broadcast(Message::StateHash {
-                        frame: tick,
-                        hash: state_hash,
-                    })
-                    .expect("Could not broadcast state_hash");
-                }
+/// Every player input held from the snapshot's tick on, oldest tick first,
+/// for a spectator to catch up on
+fn catch_up_inputs<T>(
+    held_ticks: impl Iterator<Item = u64>,
+    snapshot_tick: u64,
+    players: &[Uuid],
+    input: impl Fn(u64, Uuid) -> Option<T>,
+) -> Vec<(u64, Uuid, T)> {
+    held_ticks
+        .filter(|tick| *tick >= snapshot_tick)
+        .sorted()
+        .flat_map(|tick| {
+            players
+                .iter()
+                .filter_map(|player| Some((tick, *player, input(tick, *player)?)))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
 
-                let frame = this.frames.get(&tick).unwrap();
-                frame.set_node_states(new_state);
-                for spawned_node_path in frame.spawned_node_paths() {
-                    cx.logger()
-                        .spawned_node_alive(spawned_node_path, cx)
-                        .expect("Could not log spawned node alive");
-                }
-            });
-        }
-    }
+/// Releases one pin on the tick, returning true if it was the last
+fn release_pin(pinned_frames: &mut HashMap<u64, usize>, tick: u64) -> bool {
+    let Entry::Occupied(mut pins) = pinned_frames.entry(tick) else {
+        return false;
+    };
 
-    pub fn despawn(mut owner: impl PlayStageOwner, node: &Gd<Node>) {
-        let (frame, spawn_manager) = owner.update(|this, cx| {
-            let frame = this.frames.get(&cx.current_tick()).unwrap();
-            (frame.clone(), this.spawn_manager.clone())
-        });
+    *pins.get_mut() -= 1;
+    if *pins.get() == 0 {
+        pins.remove();
+        return true;
+    }
+    false
+}
 
-        spawn_manager.despawn(&mut owner, &node.get_path().to_string(), frame.as_ref());
+/// Number of frames since the last input was received from the peer, or -1
+/// if the id isn't a peer
+fn input_age(
+    id: Uuid,
+    peers: &[Uuid],
+    latest_frame_received: &HashMap<Uuid, u64>,
+    latest_tick: u64,
+) -> i64 {
+    if !peers.contains(&id) {
+        return -1;
     }
 
-    pub fn spawn(
-        mut owner: impl PlayStageOwner,
-        name: String,
-        parent: &Gd<Node>,
-        scene: Gd<PackedScene>,
-        state: Variant,
-    ) -> Gd<Node> {
-        let (frame, spawn_manager) = owner.update(|this, cx| {
-            let frame = this.frames.get(&cx.current_tick()).unwrap();
-            (frame.clone(), this.spawn_manager.clone())
-        });
-        let parent_path = parent.get_path().to_string();
-        let spawn_record = SpawnRecord {
-            name,
-            parent_path,
-            scene,
-            state,
-        };
-        spawn_manager.spawn(&mut owner, spawn_record, frame.as_ref(), false)
+    let latest_frame_received = latest_frame_received.get(&id).copied().unwrap_or(0);
+    latest_tick as i64 - latest_frame_received as i64
+}
+
+/// Moves the tick through which every input has been received forward past
+/// each following tick that has input, stopping at the first gap
+fn advance_received_through(mut received_through: u64, has_input: impl Fn(u64) -> bool) -> u64 {
+    while has_input(received_through + 1) {
+        received_through += 1;
     }
+    received_through
 }
 
 // Trait implemented by the owner of the play stage. This is used in
@@ -350,16 +2490,22 @@ pub trait PlayStageOwner {
     fn load_frame(&mut self, tick: u64);
     // Fetches the local input
     fn fetch_local_input(&mut self) -> Variant;
-    // Sends a serializable message to a specific peer
-    fn send(&mut self, peer: Uuid, message: Message);
+    // Sends the local input for the given tick to a specific peer
+    fn send_input(&mut self, peer: Uuid, tick: u64, message: Message);
     // Returns the list of peers that are currently connected
     fn peers(&self) -> Vec<Uuid>;
+    // Emits a scheduled event immediately so that handlers run as part of
+    // the tick being simulated
+    fn emit_scheduled_event(&mut self, name: String);
     // Calls networked_process on all networked nodes returning their updated states
     fn networked_process(&mut self) -> HashMap<String, Variant>;
     // Calls log_state on all networked nodes and logs the result to the logger
     fn log_node_states(&mut self) -> Option<u64>;
     // Gets a node from the node tree
     fn get_node(&self, path: &str) -> Option<Gd<Node>>;
+    // Returns the spawns and despawns logged for the given tick if spawns
+    // should be driven by the log rather than by game code
+    fn logged_spawn_events(&self, tick: u64) -> Option<LoggedSpawnEvents>;
 }
 
 impl PlayStageOwner for Gd<RollbackSyncManager> {
@@ -376,10 +2522,7 @@ impl PlayStageOwner for Gd<RollbackSyncManager> {
 
     fn load_frame(&mut self, tick: u64) {
         let (networked_nodes, spawn_manager, frame) = {
-            let networked_nodes = self
-                .get_tree()
-                .expect("Couldn't get tree")
-                .get_nodes_in_group("networked".into());
+            let networked_nodes = managed_networked_nodes(self);
 
             let (spawn_manager, frame) = self.update(|this, _| {
                 (
@@ -411,16 +2554,51 @@ impl PlayStageOwner for Gd<RollbackSyncManager> {
             }
         }
 
-        let mut input_manager = self.get_node("/root/InputManager".into()).unwrap();
-        input_manager.call("networked_input".into(), &[])
+        let buffered_events = self.bind_mut().context.drain_input_buffer();
+
+        let Some(mut input_manager) = self.get_node("/root/InputManager".into()) else {
+            godot_warn!("No InputManager autoload found. Using the default input");
+            return Variant::nil();
+        };
+        if !input_manager.has_method("networked_input".into()) {
+            godot_warn!("InputManager has no networked_input method. Using the default input");
+            return Variant::nil();
+        }
+
+        let input = match buffered_events {
+            Some(events) => {
+                let events: Array<Variant> = events.into_iter().collect();
+                input_manager.call("networked_input".into(), &[Variant::from(events)])
+            }
+            None => input_manager.call("networked_input".into(), &[]),
+        };
+
+        let input = validated_input(input);
+
+        // Peers apply the decoded input, so an input which decodes to a
+        // different value desyncs the peer that produced it
+        if cfg!(debug_assertions) {
+            if let Some(decoded) = round_trip_mismatch(&input) {
+                static REPORTED_ROUND_TRIP: Once = Once::new();
+                REPORTED_ROUND_TRIP.call_once(|| {
+                    godot_warn!(
+                        "networked_input returned {} which decodes as {} after being sent to peers. This will desync",
+                        input.stringify(),
+                        decoded.stringify()
+                    );
+                });
+            }
+        }
+
+        input
     }
 
-    fn send(&mut self, peer: Uuid, message: Message) {
+    fn send_input(&mut self, peer: Uuid, tick: u64, message: Message) {
         let mut sync_manager = self.bind_mut();
         sync_manager
             .context
-            .send_to(peer, message)
-            .expect("Couldn't broadcast message");
+            .send_input(peer, tick, message)
+            .expect("Couldn't send input");
     }
 
     fn peers(&self) -> Vec<Uuid> {
@@ -428,11 +2606,12 @@ impl PlayStageOwner for Gd<RollbackSyncManager> {
         sync_manager.context.peers()
     }
 
+    fn emit_scheduled_event(&mut self, name: String) {
+        self.emit_signal("scheduled_event".into(), &[name.to_variant()]);
+    }
+
     fn networked_process(&mut self) -> HashMap<String, Variant> {
-        let networked_nodes = self
-            .get_tree()
-            .expect("Couldn't get tree")
-            .get_nodes_in_group("networked".into());
+        let networked_nodes = managed_networked_nodes(self);
 
         for mut networked_node in networked_nodes.iter_shared() {
             if networked_node.has_method("networked_preprocess".into()) {
@@ -455,20 +2634,20 @@ impl PlayStageOwner for Gd<RollbackSyncManager> {
     // If the current frame is complete, returns a hash over all of the node states
     // in the frame for desync detection purposes. Otherwise, returns None.
     fn log_node_states(&mut self) -> Option<u64> {
-        let networked_nodes = self
-            .get_tree()
-            .expect("Couldn't get tree")
-            .get_nodes_in_group("networked".into());
+        let networked_nodes = managed_networked_nodes(self);
 
-        let mut combined_hasher = self.update(|this, cx| {
+        let (mut combined_hasher, state_hash_epsilon, log_states) = self.update(|this, cx| {
             let frame = this.frames.get(&cx.current_tick()).unwrap();
-            if frame.missing_input(cx.peers()).is_none() {
-                Some(DefaultHasher::new())
+            let combined_hasher = if frame.missing_input(&cx.peers()).is_none() {
+                Some(StableHasher::new())
             } else {
                 None
-            }
+            };
+            let log_states = logs_states_on(cx.current_tick(), cx.state_log_interval());
+            (combined_hasher, cx.state_hash_epsilon(), log_states)
         });
 
+        let mut logged_states = Vec::new();
         for mut networked_node in networked_nodes.iter_shared() {
             if networked_node.has_method("log_state".into()) {
                 let path = networked_node.get_path().to_string();
@@ -478,17 +2657,27 @@ impl PlayStageOwner for Gd<RollbackSyncManager> {
                     for (key, value) in states.iter_shared() {
                         let key = key.stringify().to_string();
                         let value_text = value.stringify().to_string();
+                        let value = match state_hash_epsilon {
+                            Some(epsilon) => quantize_floats(&value, epsilon),
+                            None => value,
+                        };
                         let value_bytes = utilities::var_to_bytes(value);
                         let value_bytes = value_bytes.as_slice();
-                        let mut hasher = DefaultHasher::new();
+                        let mut hasher = StableHasher::new();
                         value_bytes.hash(&mut hasher);
+                        let value_hash = hasher.finish();
                         if let Some(combined) = combined_hasher.as_mut() {
                             value_bytes.hash(combined);
+                            logged_states.push(LoggedState {
+                                path: path.clone(),
+                                key: key.clone(),
+                                value_text: value_text.clone(),
+                                value_hash,
+                            });
                         }
 
-                        {
+                        if log_states {
                             let cx = &self.bind().context;
-                            let value_hash = hasher.finish();
                             cx.logger()
                                 .state(path.clone(), key, value_text, value_hash, cx)
                                 .unwrap();
@@ -501,10 +2690,9 @@ impl PlayStageOwner for Gd<RollbackSyncManager> {
         if let Some(hasher) = combined_hasher.as_mut() {
             let state_hash = hasher.finish();
             self.update(|this, cx| {
-                this.frames
-                    .get_mut(&cx.current_tick())
-                    .unwrap()
-                    .set_state_hash(state_hash);
+                let frame = this.frames.get_mut(&cx.current_tick()).unwrap();
+                frame.set_state_hash(state_hash);
+                frame.set_logged_states(logged_states);
             });
             Some(state_hash)
         } else {
@@ -515,4 +2703,907 @@ impl PlayStageOwner for Gd<RollbackSyncManager> {
     fn get_node(&self, path: &str) -> Option<Gd<Node>> {
         self.clone().upcast::<Node>().get_node(path.into())
     }
+
+    fn logged_spawn_events(&self, tick: u64) -> Option<LoggedSpawnEvents> {
+        let sync_manager = self.bind();
+        let SyncStage::Replay(replay_stage) = &sync_manager.stage else {
+            return None;
+        };
+        if !sync_manager.context.faithful_replay() {
+            return None;
+        }
+
+        Some(
+            replay_stage
+                .logged_spawn_events(tick, &sync_manager.context)
+                .expect("Could not read logged spawn events"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        fmt::Debug,
+        hash::{Hash, Hasher},
+        hint::black_box,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use godot::{builtin::real, prelude::*};
+    use itertools::Itertools;
+    use uuid::Uuid;
+
+    use super::{
+        adaptive_rewind_window, advance_received_through, advantage_stall_due, catch_up_inputs,
+        checkpoint_due, clock_drifts, clock_offset, delayed_input_ticks, events_firing_on,
+        evicted_ticks, first_play, frame_holes, implemented_callbacks, in_domain, initial_frames,
+        input_age, insert_checkpoint, lagging_peer, last_input_before, latest_confirmed_tick,
+        logged_node_paths, logs_states_on, longest_window_start, nearest_checkpoint,
+        newly_desynced, predict_input, record_advantages, records_local_input, release_pin,
+        repeat_missing_input, stall_length, take_events_firing_on, ticks_after, window_start,
+        AdaptiveInputDelay, Frame, InputPrediction, Quantize, ScheduledEvent, MAX_CHECKPOINTS,
+        MAX_INPUT_DELAY, ROLLBACK_RATE_WINDOW,
+    };
+    use crate::{message::LoggedState, stable_hasher::StableHasher};
+
+    const EPSILON: f64 = 0.01;
+
+    /// Hashes the quantized value's debug output, which prints every float
+    /// exactly and tells negative zero apart. Stands in for the var_to_bytes
+    /// hash of logged states, which needs the engine.
+    fn quantized_hash(value: impl Quantize + Debug) -> u64 {
+        let mut hasher = StableHasher::new();
+        format!("{:?}", value.quantize(EPSILON)).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn assert_same_hash<T: Quantize + Debug>(a: T, b: T) {
+        assert_eq!(quantized_hash(a), quantized_hash(b));
+    }
+
+    #[test]
+    fn single_peer_fast_path_keeps_the_same_frames() {
+        let remote_peer = Uuid::new_v4();
+        // Frames hold no input at all, so the general path stalls on any frame
+        // a peer has lagged out of
+        let empty_frame = |tick| Some(Arc::new(Frame::new(tick)));
+
+        for rewind_window in [1, 8, 12, 30] {
+            let rewind_windows = HashMap::from([(remote_peer, rewind_window)]);
+            for latest_tick in 0..64 {
+                let oldest_tick = longest_window_start(latest_tick, &rewind_windows, 8);
+                assert_eq!(oldest_tick, window_start(latest_tick, rewind_window));
+                assert_eq!(
+                    lagging_peer(&rewind_windows, oldest_tick, latest_tick, empty_frame),
+                    None
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn short_rewind_windows_evict_older_frames() {
+        // No remote peers, so only the configured window applies
+        let rewind_windows = HashMap::new();
+        let oldest_tick = longest_window_start(20, &rewind_windows, 4);
+        assert_eq!(oldest_tick, 17);
+        assert_eq!(
+            evicted_ticks((0..=20).rev(), 0, oldest_tick),
+            (0..17).collect::<Vec<_>>()
+        );
+
+        // Once evicted, each tick only evicts the frame leaving the window
+        let oldest_tick = longest_window_start(21, &rewind_windows, 4);
+        assert_eq!(evicted_ticks(17..=21, 17, oldest_tick), vec![17]);
+        // The default window holds all of them
+        assert_eq!(
+            evicted_ticks(0..=20, 0, longest_window_start(20, &rewind_windows, 30)),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn general_path_stalls_on_peers_with_shorter_windows() {
+        let (near_peer, far_peer) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let rewind_windows = HashMap::from([(near_peer, 4), (far_peer, 8)]);
+        let players = [far_peer];
+        let frame = |tick| {
+            Some(Arc::new(Frame::initial_frame(
+                tick,
+                players.iter().copied(),
+            )))
+        };
+
+        let oldest_tick = longest_window_start(20, &rewind_windows, 8);
+        assert_eq!(oldest_tick, 13);
+        // Ticks before 17 are outside of the near peer's window
+        assert_eq!(
+            lagging_peer(&rewind_windows, oldest_tick, 20, frame),
+            Some((13, near_peer))
+        );
+    }
+
+    #[test]
+    fn adaptive_windows_only_stall_on_the_peer_that_lags() {
+        let (near_peer, far_peer) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let near_window = adaptive_rewind_window(30, Some(Duration::from_millis(5)), 60);
+        let far_window = adaptive_rewind_window(30, Some(Duration::from_millis(250)), 60);
+        assert_eq!((near_window, far_window), (31, 45));
+        // Unmeasured peers keep the configured window, and very distant ones
+        // are capped unless the configured window is already longer
+        assert_eq!(adaptive_rewind_window(30, None, 60), 30);
+        assert_eq!(
+            adaptive_rewind_window(30, Some(Duration::from_secs(2)), 60),
+            60
+        );
+        assert_eq!(
+            adaptive_rewind_window(90, Some(Duration::from_secs(2)), 60),
+            90
+        );
+
+        // Frames are held for the far peer's longer window
+        let rewind_windows = HashMap::from([(near_peer, near_window), (far_peer, far_window)]);
+        let oldest_tick = longest_window_start(100, &rewind_windows, 30);
+        assert_eq!(oldest_tick, 56);
+        let frames_with_input_from = |player: Uuid| {
+            move |tick| Some(Arc::new(Frame::initial_frame(tick, [player].into_iter())))
+        };
+
+        // Every held frame missing the far peer's input is still inside its
+        // window
+        assert_eq!(
+            lagging_peer(
+                &rewind_windows,
+                oldest_tick,
+                100,
+                frames_with_input_from(near_peer)
+            ),
+            None
+        );
+        // The near peer lagging as far stalls on the oldest frame missing its
+        // input
+        assert_eq!(
+            lagging_peer(
+                &rewind_windows,
+                oldest_tick,
+                100,
+                frames_with_input_from(far_peer)
+            ),
+            Some((oldest_tick, near_peer))
+        );
+    }
+
+    /// Compares the per frame cost of the rewind window bookkeeping on the
+    /// single peer fast path against the general path. Run it with
+    /// `cargo test --release window_bookkeeping -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn window_bookkeeping_benchmark() {
+        const FRAMES: u64 = 1_000_000;
+        let remote_peer = Uuid::new_v4();
+        let players = [remote_peer];
+        let frames = (0..FRAMES + 1)
+            .map(|tick| {
+                let frame = Frame::initial_frame(tick, players.iter().copied());
+                (tick, Arc::new(frame))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let start = Instant::now();
+        for latest_tick in 0..FRAMES {
+            black_box(window_start(black_box(latest_tick), 8));
+        }
+        let fast_path = start.elapsed();
+
+        let start = Instant::now();
+        for latest_tick in 0..FRAMES {
+            let rewind_windows = players
+                .iter()
+                .map(|peer| (*peer, 8))
+                .collect::<HashMap<_, _>>();
+            let oldest_tick = longest_window_start(black_box(latest_tick), &rewind_windows, 8);
+            black_box(lagging_peer(
+                &rewind_windows,
+                oldest_tick,
+                latest_tick,
+                |tick| frames.get(&tick).cloned(),
+            ));
+        }
+        let general_path = start.elapsed();
+
+        println!(
+            "fast path: {:?} per frame, general path: {:?} per frame",
+            fast_path / FRAMES as u32,
+            general_path / FRAMES as u32
+        );
+    }
+
+    #[test]
+    fn received_through_stops_at_gaps() {
+        let received = HashSet::from([1, 2, 3, 5, 6]);
+        let has_input = |tick| received.contains(&tick);
+
+        // A later tick arriving doesn't move past the lost input for tick 4
+        assert_eq!(advance_received_through(0, has_input), 3);
+        assert_eq!(advance_received_through(3, has_input), 3);
+
+        let received = HashSet::from([1, 2, 3, 4, 5, 6]);
+        let has_input = |tick| received.contains(&tick);
+        assert_eq!(advance_received_through(3, has_input), 6);
+    }
+
+    #[test]
+    fn delayed_input_lands_on_future_frame() {
+        let mut last_input_tick = 3;
+
+        // The first input sampled with a delay of 2 covers the ticks up to
+        // two past the latest tick, and each one after lands two ticks ahead
+        assert_eq!(
+            delayed_input_ticks(&mut last_input_tick, 4, 2),
+            vec![4, 5, 6]
+        );
+        assert_eq!(delayed_input_ticks(&mut last_input_tick, 5, 2), vec![7]);
+        assert_eq!(delayed_input_ticks(&mut last_input_tick, 6, 2), vec![8]);
+        assert_eq!(last_input_tick, 8);
+
+        // Lowering the delay sets no input until the latest tick catches up
+        assert!(delayed_input_ticks(&mut last_input_tick, 7, 0).is_empty());
+        assert!(delayed_input_ticks(&mut last_input_tick, 8, 0).is_empty());
+        assert_eq!(delayed_input_ticks(&mut last_input_tick, 9, 0), vec![9]);
+    }
+
+    #[test]
+    fn start_offset_delays_input_recording() {
+        let peers = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        for start_offset in [0, 5] {
+            // Every frame before input is recorded already holds input from
+            // every peer, so none of them can be rolled back into
+            let frames = initial_frames(start_offset, &peers);
+            assert_eq!(
+                frames.keys().copied().sorted().collect::<Vec<_>>(),
+                (0..start_offset + 2).collect::<Vec<_>>()
+            );
+            assert!(frames
+                .values()
+                .all(|frame| frame.missing_input(&peers).is_none()));
+
+            let mut last_input_tick = start_offset + 1;
+            let recorded_ticks = (0..start_offset + 5)
+                .filter(|latest_tick| records_local_input(*latest_tick, start_offset))
+                .flat_map(|latest_tick| delayed_input_ticks(&mut last_input_tick, latest_tick, 0))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                recorded_ticks,
+                (start_offset + 2..start_offset + 5).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn adaptive_input_delay_rises_then_recovers() {
+        let base_delay = 2;
+        let mut adaptive = AdaptiveInputDelay::default();
+
+        // Rolling back every tick raises the delay by a tick each window
+        // until it reaches the cap
+        for _ in 0..ROLLBACK_RATE_WINDOW * 10 {
+            adaptive.record(true, base_delay);
+        }
+        assert_eq!(adaptive.delay(base_delay), MAX_INPUT_DELAY);
+
+        // Once rollbacks stop the delay falls back a tick at a time to the
+        // configured delay
+        let mut changes = 0;
+        for _ in 0..ROLLBACK_RATE_WINDOW * 10 {
+            if adaptive.record(false, base_delay).is_some() {
+                changes += 1;
+            }
+        }
+        assert_eq!(adaptive.delay(base_delay), base_delay);
+        assert_eq!(changes, MAX_INPUT_DELAY - base_delay);
+    }
+
+    #[test]
+    fn adaptive_input_delay_holds_at_moderate_rollback_rates() {
+        let mut adaptive = AdaptiveInputDelay::default();
+        for tick in 0..ROLLBACK_RATE_WINDOW * 10 {
+            assert_eq!(adaptive.record(tick % 4 == 0, 0), None);
+        }
+        assert_eq!(adaptive.delay(0), 0);
+    }
+
+    #[test]
+    fn states_are_logged_every_interval() {
+        let logged = |interval| {
+            (0..600)
+                .filter(|tick| logs_states_on(*tick, interval))
+                .count()
+        };
+        assert_eq!(logged(1), 600);
+        assert_eq!(logged(10), 60);
+        assert_eq!(logged(7), 86);
+        // An interval of zero is treated as logging every tick
+        assert_eq!(logged(0), 600);
+        assert!(logs_states_on(20, 10) && !logs_states_on(21, 10));
+    }
+
+    #[test]
+    fn buffered_frames_count_queued_future_inputs() {
+        // Inputs up to the latest tick have been used already, and a lost
+        // input at tick 13 doesn't stop later ones counting
+        let input_ticks = [8, 9, 10, 11, 12, 14];
+        assert_eq!(ticks_after(10, input_ticks.into_iter()), 3);
+        assert_eq!(ticks_after(14, input_ticks.into_iter()), 0);
+        assert_eq!(ticks_after(0, input_ticks.into_iter()), 6);
+    }
+
+    #[test]
+    fn sustained_advantage_stalls_no_more_than_the_minimum_interval() {
+        let min_stall_interval = 20;
+        let mut last_stall_tick = None;
+        let mut stalls = Vec::new();
+        // A large advantage makes the stall period 3 ticks on its own
+        for tick in 0..300 {
+            if advantage_stall_due(true, tick, 20.0, 30, last_stall_tick, min_stall_interval) {
+                last_stall_tick = Some(tick);
+                stalls.push(tick);
+            }
+        }
+
+        assert_eq!(stalls.len(), 15);
+        for (previous, next) in stalls.iter().zip(stalls.iter().skip(1)) {
+            assert!(next - previous >= min_stall_interval);
+        }
+    }
+
+    #[test]
+    fn stalls_follow_the_period_without_a_minimum_interval() {
+        let stalls = (0..30)
+            .filter(|tick| advantage_stall_due(true, *tick, 20.0, 30, None, 0))
+            .count();
+        assert_eq!(stalls, 10);
+        assert!(!advantage_stall_due(true, 0, 0.5, 30, None, 0));
+    }
+
+    #[test]
+    fn disabled_stalling_never_stalls_regardless_of_advantage() {
+        for advantage in [0.0, 1.0, 5.0, 20.0, 100.0] {
+            assert!((0..100).all(|tick| !advantage_stall_due(false, tick, advantage, 30, None, 0)));
+        }
+    }
+
+    #[test]
+    fn a_stall_recorded_ahead_of_the_latest_tick_does_not_underflow() {
+        // A latest tick behind the last stall never stalls rather than
+        // underflowing
+        assert!(!advantage_stall_due(true, 30, 20.0, 30, Some(40), 20));
+    }
+
+    #[test]
+    fn values_within_epsilon_quantize_to_the_same_hash() {
+        // Each pair differs by less than epsilon, and the second pair only
+        // by the sign of what rounds to zero
+        let (a, b): (real, real) = (0.998, 1.003);
+        let (c, d): (real, real) = (-0.002, 0.002);
+
+        assert_same_hash(a as f64, b as f64);
+        assert_same_hash(c as f64, d as f64);
+        assert_same_hash(a as f32, b as f32);
+        assert_same_hash(c as f32, d as f32);
+        assert_same_hash(Vector2::new(a, c), Vector2::new(b, d));
+        assert_same_hash(Vector3::new(a, c, a), Vector3::new(b, d, b));
+        assert_same_hash(Vector4::new(a, c, a, c), Vector4::new(b, d, b, d));
+        assert_same_hash(Quaternion::new(a, c, a, c), Quaternion::new(b, d, b, d));
+        assert_same_hash(
+            Rect2::new(Vector2::new(a, c), Vector2::new(c, a)),
+            Rect2::new(Vector2::new(b, d), Vector2::new(d, b)),
+        );
+        assert_same_hash(
+            Transform2D::from_cols(Vector2::new(a, c), Vector2::new(c, a), Vector2::new(a, a)),
+            Transform2D::from_cols(Vector2::new(b, d), Vector2::new(d, b), Vector2::new(b, b)),
+        );
+        assert_same_hash(
+            Aabb::new(Vector3::new(a, c, a), Vector3::new(c, a, c)),
+            Aabb::new(Vector3::new(b, d, b), Vector3::new(d, b, d)),
+        );
+        assert_same_hash(
+            Plane {
+                normal: Vector3::new(a, c, c),
+                d: a,
+            },
+            Plane {
+                normal: Vector3::new(b, d, d),
+                d: b,
+            },
+        );
+        let basis = |a, c| {
+            Basis::from_rows(
+                Vector3::new(a, c, c),
+                Vector3::new(c, a, c),
+                Vector3::new(c, c, a),
+            )
+        };
+        assert_same_hash(basis(a, c), basis(b, d));
+        assert_same_hash(
+            Transform3D::new(basis(a, c), Vector3::new(a, c, a)),
+            Transform3D::new(basis(b, d), Vector3::new(b, d, b)),
+        );
+        let projection = |a, c| Projection {
+            cols: [
+                Vector4::new(a, c, c, c),
+                Vector4::new(c, a, c, c),
+                Vector4::new(c, c, a, c),
+                Vector4::new(c, c, c, a),
+            ],
+        };
+        assert_same_hash(projection(a, c), projection(b, d));
+        // Color channels are always f32, and packed arrays quantize each
+        // element with the impls above
+        assert_same_hash(
+            Color::from_rgba(a as f32, c as f32, a as f32, c as f32),
+            Color::from_rgba(b as f32, d as f32, b as f32, d as f32),
+        );
+    }
+
+    #[test]
+    fn values_further_than_epsilon_apart_keep_distinct_hashes() {
+        assert_ne!(quantized_hash(1.0f64), quantized_hash(1.02f64));
+        assert_ne!(
+            quantized_hash(Vector3::new(1.0, 0.0, 0.0)),
+            quantized_hash(Vector3::new(1.0, 0.0, 0.02))
+        );
+    }
+
+    #[test]
+    fn checkpoints_are_saved_on_multiples_of_the_interval() {
+        assert!(checkpoint_due(8, 4));
+        assert!(!checkpoint_due(9, 4));
+        // An interval of 0 disables checkpoints
+        assert!(!checkpoint_due(0, 0));
+        assert!(!checkpoint_due(8, 0));
+    }
+
+    #[test]
+    fn only_the_latest_checkpoints_are_kept() {
+        let mut checkpoints = HashMap::new();
+        for tick in (0..=100).step_by(4) {
+            insert_checkpoint(&mut checkpoints, tick, vec![tick as u8]);
+        }
+
+        assert_eq!(checkpoints.len(), MAX_CHECKPOINTS);
+        assert_eq!(checkpoints.keys().min(), Some(&40));
+        assert_eq!(checkpoints[&100], vec![100]);
+
+        assert_eq!(nearest_checkpoint(&checkpoints, 0..=43), Some(40));
+        assert_eq!(nearest_checkpoint(&checkpoints, 0..=44), Some(44));
+        // Dropped checkpoints and ones outside the range are never used
+        assert_eq!(nearest_checkpoint(&checkpoints, 0..=39), None);
+        assert_eq!(nearest_checkpoint(&checkpoints, 41..=43), None);
+    }
+
+    #[test]
+    fn missing_intermediate_frames_are_found() {
+        let frames = [3, 4, 6]
+            .into_iter()
+            .map(|tick| (tick, Arc::new(Frame::new(tick))))
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(frame_holes(&frames, 3..=7), vec![5, 7]);
+        assert!(frame_holes(&frames, 3..=4).is_empty());
+    }
+
+    #[test]
+    fn gap_frames_are_predicted_by_the_chosen_strategy() {
+        // The peer's inputs, with a gap on tick 6 where its input hasn't
+        // arrived
+        let inputs = HashMap::from([(3, "left"), (4, "right"), (7, "jump")]);
+        let input = |tick| inputs.get(&tick).copied();
+
+        assert_eq!(
+            predict_input(InputPrediction::Default, 6, 7, 30, input),
+            None
+        );
+        assert_eq!(
+            predict_input(InputPrediction::RepeatLast, 6, 7, 30, input),
+            Some("right")
+        );
+        // Only inputs inside the rewind window are repeated
+        assert_eq!(
+            predict_input(InputPrediction::RepeatLast, 6, 7, 2, input),
+            None
+        );
+        // Input which arrived is used by both
+        for prediction in [InputPrediction::Default, InputPrediction::RepeatLast] {
+            assert_eq!(predict_input(prediction, 4, 7, 30, input), Some("right"));
+        }
+    }
+
+    #[test]
+    fn rejected_inputs_fall_back_to_the_last_input() {
+        // The peer's inputs, with gaps where its input hasn't arrived
+        let inputs = HashMap::from([(3, "left"), (4, "right"), (7, "jump")]);
+        let input = |tick| inputs.get(&tick).copied();
+
+        // An input rejected on tick 6 is replaced with the one from tick 4
+        assert_eq!(last_input_before(6, 30, input), Some("right"));
+        assert_eq!(last_input_before(8, 30, input), Some("jump"));
+        // Inputs outside of the rewind window aren't used
+        assert_eq!(last_input_before(6, 1, input), None);
+        assert_eq!(last_input_before(3, 30, input), None);
+    }
+
+    #[test]
+    fn replayed_node_set_follows_the_logged_spawns() {
+        let paths = |paths: &[&str]| {
+            paths
+                .iter()
+                .map(|path| path.to_string())
+                .collect::<Vec<_>>()
+        };
+        // Spawned and despawned paths logged on each frame, and the nodes the
+        // logged run held after each frame
+        let log = [
+            (
+                paths(&["/root/A", "/root/B"]),
+                paths(&[]),
+                paths(&["/root/A", "/root/B"]),
+            ),
+            (
+                paths(&["/root/C"]),
+                paths(&["/root/A"]),
+                paths(&["/root/B", "/root/C"]),
+            ),
+            (paths(&[]), paths(&["/root/B", "/root/C"]), paths(&[])),
+        ];
+
+        let mut node_paths = Vec::new();
+        for (spawned, despawned, logged) in log {
+            let expected = logged_node_paths(node_paths, spawned.into_iter(), &despawned);
+            assert_eq!(expected, logged.into_iter().collect::<HashSet<_>>());
+            node_paths = expected.into_iter().collect();
+        }
+    }
+
+    #[test]
+    fn input_age_counts_frames_since_the_last_input() {
+        let peers = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let latest_frame_received = HashMap::from([(peers[0], 40), (peers[1], 25)]);
+
+        assert_eq!(input_age(peers[0], &peers, &latest_frame_received, 40), 0);
+        assert_eq!(input_age(peers[1], &peers, &latest_frame_received, 40), 15);
+        // Nothing received yet counts from the start of the match
+        assert_eq!(input_age(peers[2], &peers, &latest_frame_received, 40), 40);
+        // Ids that aren't peers get the sentinel
+        assert_eq!(
+            input_age(Uuid::new_v4(), &peers, &latest_frame_received, 40),
+            -1
+        );
+    }
+
+    #[test]
+    fn desyncs_are_reported_once_per_frame() {
+        let players = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        let frame = Frame::initial_frame(10, players.iter().copied());
+        // Frames are only compared once complete and hashed
+        assert!(frame.check_complete(&players));
+        assert_eq!(newly_desynced(&frame, 5), None);
+        assert!(!frame.desynced());
+
+        frame.set_state_hash(7);
+        assert_eq!(newly_desynced(&frame, 7), None);
+        assert!(!frame.desynced());
+        assert_eq!(newly_desynced(&frame, 5), Some(7));
+        assert!(frame.desynced());
+        // Hashes arriving again after a rollback don't report it again
+        assert_eq!(newly_desynced(&frame, 5), None);
+        assert_eq!(newly_desynced(&frame, 6), None);
+    }
+
+    #[test]
+    fn spectators_catch_up_on_inputs_from_the_snapshot_on() {
+        let (player_1, player_2) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        // The second player's input for tick 7 hasn't arrived yet
+        let input = |tick, player| (player == player_1 || tick != 7).then_some(tick);
+
+        assert_eq!(
+            catch_up_inputs(
+                [8, 3, 6, 5, 7, 4].into_iter(),
+                5,
+                &[player_1, player_2],
+                input
+            ),
+            vec![
+                (5, player_1, 5),
+                (5, player_2, 5),
+                (6, player_1, 6),
+                (6, player_2, 6),
+                (7, player_1, 7),
+                (8, player_1, 8),
+                (8, player_2, 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn desync_details_pair_up_the_diverging_states() {
+        let state = |path: &str, key: &str, value: u64| LoggedState {
+            path: path.into(),
+            key: key.into(),
+            value_text: value.to_string(),
+            value_hash: value,
+        };
+        let local = [
+            state("/root/Player", "position", 1),
+            state("/root/Player", "health", 2),
+            state("/root/Bullet", "speed", 3),
+        ];
+        let remote = [
+            state("/root/Player", "position", 1),
+            state("/root/Player", "health", 5),
+            state("/root/Enemy", "target", 4),
+        ];
+
+        let pairs = diverged_state_pairs(&local, &remote);
+        assert_eq!(
+            pairs.keys().copied().collect::<Vec<_>>(),
+            vec![
+                ("/root/Bullet", "speed"),
+                ("/root/Enemy", "target"),
+                ("/root/Player", "health"),
+            ]
+        );
+        assert_eq!(
+            pairs[&("/root/Player", "health")],
+            (Some(&local[1]), Some(&remote[1]))
+        );
+        // Values only one side logged have nothing on the other
+        assert_eq!(pairs[&("/root/Bullet", "speed")], (Some(&local[2]), None));
+        assert_eq!(pairs[&("/root/Enemy", "target")], (None, Some(&remote[2])));
+        assert!(diverged_state_pairs(&local, &local).is_empty());
+    }
+
+    #[test]
+    fn laggy_peers_report_a_higher_advantage() {
+        let (near_peer, laggy_peer) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let peers = [near_peer, laggy_peer];
+        let mut peer_advantages = HashMap::new();
+        let mut advantage = RollingAdvantage::default();
+
+        for latest_tick in 10..60 {
+            // Both peers have received this peer's input through the ticks they
+            // are on, but the laggy peer is 5 ticks behind rather than 1
+            let latest_frame_received =
+                HashMap::from([(near_peer, latest_tick - 1), (laggy_peer, latest_tick - 5)]);
+            record_advantages(
+                latest_tick,
+                &peers,
+                &latest_frame_received,
+                &latest_frame_received,
+                &mut peer_advantages,
+                &mut advantage,
+            );
+        }
+
+        assert!((peer_advantages[&near_peer].average() - 1.0).abs() < EPSILON);
+        assert!((peer_advantages[&laggy_peer].average() - 5.0).abs() < EPSILON);
+        // The aggregate follows the peer this machine is furthest ahead of
+        assert!((advantage.average() - 5.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn frames_pinned_twice_are_released_by_the_second_unpin() {
+        let mut pinned_frames = HashMap::from([(5, 2)]);
+
+        assert!(!release_pin(&mut pinned_frames, 5));
+        assert!(pinned_frames.contains_key(&5));
+        assert!(release_pin(&mut pinned_frames, 5));
+        assert!(pinned_frames.is_empty());
+        // Unpinning a frame without pins does nothing
+        assert!(!release_pin(&mut pinned_frames, 5));
+    }
+
+    #[test]
+    fn nodes_belong_to_the_domain_root_above_them() {
+        let roots = ["/root/ReplayA", "/root/ReplayB"];
+        let ancestors = |path: &'static str| {
+            std::iter::successors(path.rsplit_once('/').map(|(parent, _)| parent), |path| {
+                path.rsplit_once('/').map(|(parent, _)| parent)
+            })
+            .filter(|path| !path.is_empty())
+        };
+        let is_domain_root = |path: &&str| roots.contains(path);
+
+        let player_a = "/root/ReplayA/Level/Player";
+        let player_b = "/root/ReplayB/Level/Player";
+        let hud = "/root/Hud";
+        assert!(in_domain(
+            ancestors(player_a),
+            Some(&roots[0]),
+            is_domain_root
+        ));
+        assert!(!in_domain(
+            ancestors(player_b),
+            Some(&roots[0]),
+            is_domain_root
+        ));
+        assert!(in_domain(
+            ancestors(player_b),
+            Some(&roots[1]),
+            is_domain_root
+        ));
+        // A manager without a domain root only manages nodes outside every
+        // domain root
+        assert!(!in_domain(ancestors(player_a), None, is_domain_root));
+        assert!(in_domain(ancestors(hud), None, is_domain_root));
+    }
+
+    #[test]
+    fn effects_play_once_per_tick_across_resimulations() {
+        let mut played_effects = HashMap::new();
+
+        assert!(first_play(&mut played_effects, 10, "jump_sound".into()));
+        assert!(first_play(&mut played_effects, 10, "land_dust".into()));
+        // Re-simulating tick 10 during a rollback
+        assert!(!first_play(&mut played_effects, 10, "jump_sound".into()));
+        assert!(!first_play(&mut played_effects, 10, "land_dust".into()));
+        // The same effect on a later tick is a new play
+        assert!(first_play(&mut played_effects, 11, "jump_sound".into()));
+    }
+
+    #[test]
+    fn stall_length_counts_consecutive_ticks_on_the_same_frame() {
+        let mut stall = None;
+        for expected in 1..=5 {
+            let stall_ticks = stall_length(stall, 40);
+            assert_eq!(stall_ticks, expected);
+            stall = Some((40, stall_ticks));
+        }
+
+        // Input for frame 40 arrives but the next frame is missing input too
+        assert_eq!(stall_length(stall, 41), 1);
+    }
+
+    #[test]
+    fn scheduled_events_fire_on_every_simulation_and_confirm_once() {
+        let mut scheduled_events = Vec::new();
+        let mut fired = Vec::new();
+        let mut simulate = |scheduled_events: &mut Vec<ScheduledEvent>, ticks| {
+            for tick in ticks {
+                for name in events_firing_on(scheduled_events, tick) {
+                    fired.push((tick, name));
+                }
+                // Game code schedules the explosion while simulating tick 5
+                if tick == 5 {
+                    scheduled_events.push(ScheduledEvent {
+                        tick: 10,
+                        name: "explosion".into(),
+                        scheduled_on: 5,
+                    });
+                }
+            }
+        };
+
+        simulate(&mut scheduled_events, 1..=12);
+        // A rollback re-simulating tick 10 fires it again
+        simulate(&mut scheduled_events, 8..=12);
+        // A rollback past the tick it was scheduled on schedules it again
+        // rather than keeping both schedules
+        simulate(&mut scheduled_events, 4..=12);
+        assert_eq!(scheduled_events.len(), 1);
+        assert_eq!(fired, vec![(10, "explosion".to_string()); 3]);
+
+        // Confirming tick 10 fires it once
+        assert!(take_events_firing_on(&mut scheduled_events, 9).is_empty());
+        assert_eq!(
+            take_events_firing_on(&mut scheduled_events, 10),
+            vec!["explosion".to_string()]
+        );
+        assert!(take_events_firing_on(&mut scheduled_events, 10).is_empty());
+        assert!(scheduled_events.is_empty());
+    }
+
+    #[test]
+    fn resyncs_send_the_latest_frame_which_wont_be_simulated_again() {
+        // Ticks 1 through 8 have every input, but a late input updated tick 6
+        assert_eq!(
+            latest_confirmed_tick([6, 7].into_iter(), 1..=8, 10),
+            Some(5)
+        );
+        // Without updates the latest tick itself is about to be simulated
+        assert_eq!(latest_confirmed_tick([].into_iter(), 1..=12, 10), Some(9));
+        // Incomplete ticks are skipped
+        assert_eq!(
+            latest_confirmed_tick([].into_iter(), [2, 3, 7].into_iter(), 10),
+            Some(7)
+        );
+        assert_eq!(latest_confirmed_tick([2].into_iter(), 2..=8, 10), None);
+    }
+
+    #[test]
+    fn skipped_inputs_repeat_the_last_input_up_to_the_limit() {
+        let mut last_input = None;
+        let mut repeated_inputs = 0;
+        let mut play =
+            |input| repeat_missing_input(input, &mut last_input, &mut repeated_inputs, 2);
+
+        // Nothing to repeat before the first input
+        assert_eq!(play(None), None);
+        assert_eq!(play(Some("left")), Some("left"));
+        // A hitch in the input source repeats the last input
+        assert_eq!(play(None), Some("left"));
+        assert_eq!(play(Some("right")), Some("right"));
+        // Only up to the limit in a row
+        assert_eq!(play(None), Some("right"));
+        assert_eq!(play(None), Some("right"));
+        assert_eq!(play(None), None);
+        assert_eq!(play(Some("jump")), Some("jump"));
+        assert_eq!(play(None), Some("jump"));
+
+        // A limit of 0 never repeats
+        let mut last_input = Some("left");
+        assert_eq!(repeat_missing_input(None, &mut last_input, &mut 0, 0), None);
+    }
+
+    #[test]
+    fn networked_nodes_list_the_callbacks_they_implement() {
+        let player = ["networked_process", "load_state", "_ready"];
+        assert_eq!(
+            implemented_callbacks(|method| player.contains(&method)),
+            vec!["networked_process", "load_state"]
+        );
+
+        // A typo leaves the callback out
+        let projectile = ["networked_spawn", "networked_despawn", "networked_procces"];
+        assert_eq!(
+            implemented_callbacks(|method| projectile.contains(&method)),
+            vec!["networked_spawn", "networked_despawn"]
+        );
+        assert!(implemented_callbacks(|_| false).is_empty());
+    }
+
+    #[test]
+    fn gradual_clock_drift_warns_past_the_threshold() {
+        const SIMULATION_RATE: u32 = 60;
+        const THRESHOLD: f64 = 10.0;
+
+        let peer = Uuid::new_v4();
+        let response_time = Duration::from_millis(100);
+        // Input for tick 97 arriving on tick 100 after half of 100ms, or 3
+        // ticks, in flight means the clocks are in step
+        assert!(clock_offset(97, response_time, SIMULATION_RATE, 100).abs() < 1e-9);
+
+        // The peer runs 1% fast, so its input gains three ticks on the local
+        // clock over every 300 tick check
+        let mut baselines = HashMap::new();
+        let mut warnings = Vec::new();
+        for check in 1..=9u64 {
+            let mut samples = HashMap::new();
+            for tick in (check - 1) * 300 + 1..=check * 300 {
+                let peer_tick = (tick as f64 * 1.01) as u64;
+                let offset = clock_offset(
+                    peer_tick.saturating_sub(3),
+                    response_time,
+                    SIMULATION_RATE,
+                    tick,
+                );
+                let (sum, count) = samples.entry(peer).or_insert((0.0, 0));
+                *sum += offset;
+                *count += 1;
+            }
+            for (drifted_peer, drift) in clock_drifts(samples, &mut baselines, THRESHOLD) {
+                assert_eq!(drifted_peer, peer);
+                warnings.push((check, drift));
+            }
+        }
+
+        // The first check takes the baseline, then the drift passes the
+        // threshold on the fifth check, 12 ticks later. The baseline is
+        // retaken, so it warns again four checks later
+        let checks = warnings.iter().map(|(check, _)| *check).collect::<Vec<_>>();
+        assert_eq!(checks, vec![5, 9]);
+        assert!(warnings.iter().all(|(_, drift)| *drift > THRESHOLD));
+    }
 }
@@ -1,20 +1,86 @@
 use std::{
     collections::HashMap,
-    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
-use godot::prelude::*;
+use godot::{
+    engine::{
+        utilities::{bytes_to_var, str_to_var, var_to_bytes, var_to_str},
+        Marshalls, ResourceLoader,
+    },
+    prelude::*,
+};
 use parking_lot::RwLock;
+use serde_json::{json, Map, Value};
 use uuid::Uuid;
 
+use crate::message::LoggedState;
+
+/// Encodes the variant with var_to_bytes as base64 for debug dumps
+pub fn variant_to_base64(variant: Variant) -> Value {
+    let encoded = Marshalls::singleton().raw_to_base64(var_to_bytes(variant));
+    Value::String(encoded.to_string())
+}
+
 #[derive(Clone)]
 pub struct SpawnRecord {
     pub name: String,
     pub parent_path: String,
     pub scene: Gd<PackedScene>,
+    /// Resource path of the scene. Logged with spawn events so that the scene
+    /// can be loaded again when the live resource isn't available.
+    pub scene_path: String,
     pub state: Variant,
 }
 
+impl SpawnRecord {
+    /// Serializes the record into the data logged with a spawned event. The
+    /// node path, scene path, and spawn state are separated by newlines.
+    pub fn to_log_data(&self, node_path: &str) -> String {
+        spawn_log_data(
+            node_path,
+            &self.scene_path,
+            &var_to_str(self.state.clone()).to_string(),
+        )
+    }
+
+    /// Reconstructs a spawn record from the data logged with a spawned event,
+    /// loading the scene from its resource path. Returns the spawned node path
+    /// along with the record.
+    pub fn from_log_data(data: &str) -> Option<(String, Self)> {
+        let (node_path, scene_path, state) = parse_spawn_log_data(data)?;
+        let state = str_to_var(state.into());
+        let (parent_path, name) = node_path.rsplit_once('/')?;
+        let scene = ResourceLoader::singleton()
+            .load(scene_path.into())?
+            .cast::<PackedScene>();
+
+        Some((
+            node_path.to_string(),
+            Self {
+                name: name.to_string(),
+                parent_path: parent_path.to_string(),
+                scene,
+                scene_path: scene_path.to_string(),
+                state,
+            },
+        ))
+    }
+}
+
+/// Joins the node path, scene path, and spawn state text with newlines. The
+/// state goes last since var_to_str spreads dictionaries over several lines
+fn spawn_log_data(node_path: &str, scene_path: &str, state: &str) -> String {
+    format!("{node_path}\n{scene_path}\n{state}")
+}
+
+/// Splits logged spawn data back into its node path, scene path, and spawn
+/// state text
+fn parse_spawn_log_data(data: &str) -> Option<(&str, &str, &str)> {
+    let mut lines = data.splitn(3, '\n');
+    Some((lines.next()?, lines.next()?, lines.next()?))
+}
+
 pub struct Frame {
     tick: u64,
     inputs: RwLock<HashMap<Uuid, Option<Variant>>>,
@@ -23,7 +89,17 @@ pub struct Frame {
     node_states: RwLock<HashMap<String, Variant>>,
     spawn_records: RwLock<HashMap<String, SpawnRecord>>,
     spawn_name_counters: RwLock<HashMap<String, usize>>,
+    /// Spawns made by game code during the latest simulation of the frame and
+    /// those refused for going past the spawn cap
+    spawn_count: AtomicUsize,
+    refused_spawns: AtomicUsize,
     state_hash: AtomicU64,
+    /// Set once the state hash has been computed, since 0 is a valid hash
+    state_hash_set: AtomicBool,
+    desynced: AtomicBool,
+    /// The log_state values hashed into the state hash. Kept to find which
+    /// values diverged when the frame desyncs
+    logged_states: RwLock<Vec<LoggedState>>,
 }
 
 impl Frame {
@@ -36,12 +112,17 @@ impl Frame {
             node_states: RwLock::new(HashMap::new()),
             spawn_records: RwLock::new(HashMap::new()),
             spawn_name_counters: RwLock::new(HashMap::new()),
+            spawn_count: AtomicUsize::new(0),
+            refused_spawns: AtomicUsize::new(0),
             state_hash: AtomicU64::new(0),
+            state_hash_set: AtomicBool::new(false),
+            desynced: AtomicBool::new(false),
+            logged_states: RwLock::new(Vec::new()),
         }
     }
 
-    pub fn initial_frame(peers: impl Iterator<Item = Uuid>) -> Self {
-        let frame = Self::new(0);
+    pub fn initial_frame(tick: u64, peers: impl Iterator<Item = Uuid>) -> Self {
+        let frame = Self::new(tick);
         for peer in peers {
             frame.inputs.write().insert(peer, None);
         }
@@ -56,13 +137,20 @@ impl Frame {
         self.inputs.read().get(&id).cloned().flatten()
     }
 
-    pub fn set_input(&self, id: Uuid, input: Variant, peers: Vec<Uuid>) {
+    /// Records the input for the given player. Returns true only for the call
+    /// which completes the frame with input from every player, so inputs set
+    /// again during a rollback never report completion twice.
+    pub fn set_input(&self, id: Uuid, input: Variant, players: &[Uuid]) -> bool {
         self.inputs.write().insert(id, Some(input));
         self.updated.store(true, Ordering::Relaxed);
 
-        if self.inputs.read().len() == peers.len() {
-            self.complete.store(true, Ordering::Relaxed);
-        }
+        self.check_complete(players)
+    }
+
+    /// Marks the frame complete once every player has an input. Returns true
+    /// only the first time.
+    fn check_complete(&self, players: &[Uuid]) -> bool {
+        self.missing_input(players).is_none() && !self.complete.swap(true, Ordering::Relaxed)
     }
 
     pub fn updated(&self) -> bool {
@@ -94,15 +182,71 @@ impl Frame {
         *self.spawn_name_counters.write() = frame.spawn_name_counters.read().clone();
     }
 
+    /// Clears the spawn counts before the frame is simulated again
+    pub fn reset_spawn_count(&self) {
+        self.spawn_count.store(0, Ordering::Relaxed);
+        self.refused_spawns.store(0, Ordering::Relaxed);
+    }
+
+    /// Counts a spawn made by game code while simulating the frame. Returns
+    /// false and counts the spawn as refused instead if the frame already has
+    /// as many spawns as the cap allows.
+    pub fn count_spawn(&self, spawn_cap: Option<usize>) -> bool {
+        if spawn_cap.is_some_and(|spawn_cap| self.spawn_count.load(Ordering::Relaxed) >= spawn_cap)
+        {
+            self.refused_spawns.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        self.spawn_count.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn refused_spawns(&self) -> usize {
+        self.refused_spawns.load(Ordering::Relaxed)
+    }
+
     pub fn node_state(&self, node_path: &str) -> Option<Variant> {
         self.node_states.read().get(node_path).cloned()
     }
 
+    pub fn clear_updated(&self) {
+        self.updated.store(false, Ordering::Relaxed);
+    }
+
+    pub fn node_states(&self) -> HashMap<String, Variant> {
+        self.node_states.read().clone()
+    }
+
     pub fn set_node_states(&self, node_states: HashMap<String, Variant>) {
         *self.node_states.write() = node_states;
         self.updated.store(false, Ordering::Relaxed);
     }
 
+    /// Serializes the node states into a checkpoint. The snapshot shares no
+    /// arrays or dictionaries with the states, so game code changing them
+    /// later leaves it intact
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut snapshot = Dictionary::new();
+        for (node_path, state) in self.node_states.read().iter() {
+            snapshot.set(node_path.to_variant(), state.clone());
+        }
+        var_to_bytes(snapshot.to_variant()).to_vec()
+    }
+
+    /// Replaces the node states with the ones saved in a checkpoint. Unlike
+    /// set_node_states this leaves the updated flag alone, since the frame
+    /// has not been simulated again
+    pub fn load_state(&self, snapshot: &[u8]) {
+        let snapshot = bytes_to_var(PackedByteArray::from(snapshot))
+            .try_to::<Dictionary>()
+            .unwrap_or_else(|_| Dictionary::new());
+        *self.node_states.write() = snapshot
+            .iter_shared()
+            .map(|(node_path, state)| (node_path.to::<String>(), state))
+            .collect();
+    }
+
     pub fn avoid_name_collision(&self, name: String) -> String {
         let mut counters = self.spawn_name_counters.write();
         let counter = counters.entry(name.clone()).or_insert(0);
@@ -114,7 +258,11 @@ impl Frame {
         }
     }
 
-    pub fn missing_input<'a>(&self, peers: Vec<Uuid>) -> Option<Uuid> {
+    pub fn has_input(&self, id: Uuid) -> bool {
+        self.inputs.read().contains_key(&id)
+    }
+
+    pub fn missing_input(&self, peers: &[Uuid]) -> Option<Uuid> {
         let inputs = self.inputs.read();
         peers.iter().find(|id| !inputs.contains_key(&id)).cloned()
     }
@@ -124,11 +272,8 @@ impl Frame {
     }
 
     pub fn state_hash(&self) -> Option<u64> {
-        if self.complete() {
-            let hash = self.state_hash.load(Ordering::Relaxed);
-            if hash != 0 {
-                return Some(hash);
-            }
+        if self.complete() && self.state_hash_set.load(Ordering::Relaxed) {
+            return Some(self.state_hash.load(Ordering::Relaxed));
         }
 
         None
@@ -136,5 +281,186 @@ impl Frame {
 
     pub fn set_state_hash(&self, state_hash: u64) {
         self.state_hash.store(state_hash, Ordering::Relaxed);
+        self.state_hash_set.store(true, Ordering::Relaxed);
+    }
+
+    pub fn logged_states(&self) -> Vec<LoggedState> {
+        self.logged_states.read().clone()
+    }
+
+    pub fn set_logged_states(&self, logged_states: Vec<LoggedState>) {
+        *self.logged_states.write() = logged_states;
+    }
+
+    pub fn desynced(&self) -> bool {
+        self.desynced.load(Ordering::Relaxed)
+    }
+
+    /// Marks the frame as having disagreed with a peer's state hash. Returns
+    /// true only for the call which first marks it, so a desync is reported
+    /// once per frame.
+    pub fn mark_desynced(&self) -> bool {
+        !self.desynced.swap(true, Ordering::Relaxed)
+    }
+
+    /// Captures everything stored in the frame for debug dumps. Variants are
+    /// encoded with var_to_bytes as base64.
+    pub fn debug_state(&self) -> Value {
+        let inputs: Map<String, Value> = self
+            .inputs
+            .read()
+            .iter()
+            .map(|(id, input)| {
+                let input = input.clone().map(variant_to_base64).unwrap_or(Value::Null);
+                (id.to_string(), input)
+            })
+            .collect();
+        let node_states: Map<String, Value> = self
+            .node_states
+            .read()
+            .iter()
+            .map(|(path, state)| (path.clone(), variant_to_base64(state.clone())))
+            .collect();
+        let spawn_records: Map<String, Value> = self
+            .spawn_records
+            .read()
+            .iter()
+            .map(|(path, record)| {
+                let record = json!({
+                    "name": record.name,
+                    "parent_path": record.parent_path,
+                    "scene_path": record.scene_path,
+                    "state": variant_to_base64(record.state.clone()),
+                });
+                (path.clone(), record)
+            })
+            .collect();
+
+        json!({
+            "tick": self.tick,
+            "complete": self.complete(),
+            "updated": self.updated(),
+            "state_hash": self.state_hash(),
+            "desynced": self.desynced(),
+            "inputs": inputs,
+            "node_states": node_states,
+            "spawn_records": spawn_records,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{parse_spawn_log_data, spawn_log_data, Frame};
+
+    #[test]
+    fn zero_is_a_valid_state_hash() {
+        let players = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        let frame = Frame::initial_frame(10, players.iter().copied());
+
+        frame.set_state_hash(0);
+        assert_eq!(frame.state_hash(), None);
+
+        assert!(frame.check_complete(&players));
+        assert_eq!(frame.state_hash(), Some(0));
+    }
+
+    #[test]
+    fn state_hash_is_none_until_set() {
+        let players = [Uuid::from_u128(1)];
+        let frame = Frame::initial_frame(10, players.iter().copied());
+        assert!(frame.check_complete(&players));
+
+        assert_eq!(frame.state_hash(), None);
+        frame.set_state_hash(42);
+        assert_eq!(frame.state_hash(), Some(42));
+    }
+
+    #[test]
+    fn spawns_past_the_cap_are_refused_and_reported() {
+        let frame = Frame::new(10);
+        let allowed = (0..5).filter(|_| frame.count_spawn(Some(3))).count();
+        assert_eq!(allowed, 3);
+        assert_eq!(frame.refused_spawns(), 2);
+
+        // Simulating the frame again starts counting from scratch
+        frame.reset_spawn_count();
+        assert_eq!(frame.refused_spawns(), 0);
+        assert!(frame.count_spawn(Some(1)));
+        assert!(!frame.count_spawn(Some(1)));
+        assert_eq!(frame.refused_spawns(), 1);
+
+        // Without a cap every spawn is allowed
+        frame.reset_spawn_count();
+        assert!((0..100).all(|_| frame.count_spawn(None)));
+        assert_eq!(frame.refused_spawns(), 0);
+    }
+
+    #[test]
+    fn spawn_log_data_round_trips() {
+        let state = "{\n\"health\": 3,\n\"name\": \"Bullet\"\n}";
+        let data = spawn_log_data("/root/World/Bullet", "res://bullet.tscn", state);
+
+        assert_eq!(
+            parse_spawn_log_data(&data),
+            Some(("/root/World/Bullet", "res://bullet.tscn", state))
+        );
+    }
+
+    #[test]
+    fn spawn_log_data_without_a_scene_path_is_rejected() {
+        // Spawns logged before scene paths were recorded only hold the node
+        // path
+        assert_eq!(parse_spawn_log_data("/root/World/Bullet"), None);
+        assert_eq!(
+            parse_spawn_log_data("/root/World/Bullet\nres://bullet.tscn"),
+            None
+        );
+    }
+
+    #[test]
+    fn debug_state_captures_the_frame() {
+        let players = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        let frame = Frame::initial_frame(10, players.iter().copied());
+        assert!(frame.check_complete(&players));
+        frame.set_state_hash(42);
+
+        assert_eq!(
+            frame.debug_state(),
+            json!({
+                "tick": 10,
+                "complete": true,
+                "updated": false,
+                "state_hash": 42,
+                "desynced": false,
+                "inputs": {
+                    players[0].to_string(): null,
+                    players[1].to_string(): null,
+                },
+                "node_states": {},
+                "spawn_records": {},
+            })
+        );
+    }
+
+    #[test]
+    fn frames_complete_once_when_the_last_input_arrives() {
+        let players = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        let frame = Frame::new(10);
+
+        // Stands in for set_input, whose Variant inputs need the engine
+        let receive_input = |player| {
+            frame.inputs.write().insert(player, None);
+            frame.check_complete(&players)
+        };
+        assert!(!receive_input(players[0]));
+        assert!(receive_input(players[1]));
+        // Rollbacks setting either input again don't complete it twice
+        assert!(!receive_input(players[0]));
+        assert!(!receive_input(players[1]));
+        assert!(frame.complete());
     }
 }
@@ -0,0 +1,1591 @@
+//! End to end harness which plays scripted matches through the real stages.
+//! A ScriptedPeer owns a real Context and SyncStage and stands in for the
+//! RollbackSyncManager as the PlayStageOwner. Its one networked node is a
+//! counter folded from every player's scripted input, so peers which agree
+//! on every input agree on every state hash.
+//!
+//! Inputs and node states are Variants and every Context logs to the
+//! project's user directory, so these tests need the Godot engine loaded.
+//! Matches over loopback also run in real time, so the tests are ignored by
+//! default. Run them with `cargo test scripted -- --ignored`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::Duration,
+};
+
+use godot::prelude::*;
+use itertools::Itertools;
+use udp_ext::persistent::{PersistentEvent, PersistentSocketSender};
+use uuid::Uuid;
+
+use super::{
+    round_trip_mismatch, unreported_unserializable_states, validated_input, InputPrediction,
+    PlayStage, PlayStageOwner, MIN_MAX_REWIND,
+};
+use crate::{
+    lobby_stage::LobbyStage,
+    logging::{
+        log_file_directory, DroppedFrame, Event, EventCategory, EventSeverity, LogEntry, LogReader,
+        Rollback,
+    },
+    message::{LoggedState, Message},
+    replay_stage::{LoggedSpawnEvents, ReplayStage},
+    stable_hasher::StableHasher,
+    sync_manager::{request_pause, set_paused, RollbackSyncManager},
+    sync_stage::SyncStage,
+    Context,
+};
+
+/// Path of the one networked node every peer simulates
+pub const COUNTER_PATH: &str = "/root/World/Counter";
+/// Ticks every peer must have confirmed state hashes for before the match
+/// ends
+const MATCH_TICKS: u64 = 300;
+/// Steps the harness runs before giving up on the match finishing
+const MAX_STEPS: u64 = 5000;
+/// Steps to wait after both peers connect before readying up, so that the
+/// unready messages sent while connecting arrive first
+const READY_DELAY_STEPS: u64 = 20;
+
+/// The input the peer plays on the tick. Changes every few ticks so that
+/// some predictions miss and have to be rolled back
+pub fn scripted_input(seed: u64, tick: u64) -> i64 {
+    ((tick / 7 * (seed + 1)) % 4) as i64
+}
+
+/// Folds the tick's inputs, ordered by player id, into the counter
+fn simulate(counter: i64, inputs: &[i64]) -> i64 {
+    inputs.iter().fold(counter, |counter, input| {
+        counter.wrapping_mul(31).wrapping_add(*input)
+    })
+}
+
+fn state_hash(counter: i64) -> u64 {
+    let mut hasher = StableHasher::new();
+    counter.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The state hash of the tick when simulated from scratch with every
+/// player's scripted input, without any predictions or rollbacks. Players
+/// are given as their id and seed
+fn full_replay_hash(players: &[(Uuid, u64)], through: u64) -> u64 {
+    let mut players = players.to_vec();
+    players.sort();
+    // The first ticks have no input
+    let counter = (2..=through).fold(0, |counter, tick| {
+        let inputs = players
+            .iter()
+            .map(|(_, seed)| scripted_input(*seed, tick))
+            .collect::<Vec<_>>();
+        simulate(counter, &inputs)
+    });
+    state_hash(counter)
+}
+
+/// A loopback address nothing is listening on
+fn free_loopback_address() -> SocketAddr {
+    UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|socket| socket.local_addr())
+        .expect("Could not find a free port")
+}
+
+pub struct ScriptedPeer {
+    /// Picks the scripted input
+    seed: u64,
+    /// The node the lobby starts the game on. Never added to a tree
+    node: Gd<Node>,
+    pub stage: SyncStage,
+    pub cx: Context,
+    /// The counter as of the current tick
+    counter: i64,
+    /// The hash of each tick's state the last time it was simulated with
+    /// every player's input
+    pub state_hashes: BTreeMap<u64, u64>,
+    /// The latest tick and the frame loaded of every rollback_occurred
+    /// signal queued so far
+    pub rollbacks: Vec<(u64, u64)>,
+    /// The result of every connect_finished signal queued so far
+    pub connect_results: Vec<bool>,
+    /// The tick of every frame_inputs_complete signal queued so far
+    pub completed_frames: Vec<u64>,
+    /// Events to schedule while simulating a tick, keyed by that tick, as the
+    /// tick to fire on and the event name
+    pub schedules: BTreeMap<u64, (u64, String)>,
+    /// The tick and name of every scheduled event fired so far, including
+    /// refires while re-simulating
+    pub fired_events: Vec<(u64, String)>,
+    /// The name of every scheduled_event_confirmed signal queued so far
+    pub confirmed_events: Vec<String>,
+    /// The reason given by every start_refused signal queued so far
+    pub start_refusals: Vec<String>,
+    /// The tick, local hash and remote hash of every desync_detected signal
+    /// queued so far
+    pub desyncs: Vec<(u64, i64, i64)>,
+    /// The tick, sender and diverging path and key of every difference in
+    /// the desync_details signals queued so far
+    pub desync_details: Vec<(u64, String, String, String)>,
+    /// Every tick simulated and whether the play stage reported it as
+    /// resimulating at the time
+    pub simulated_ticks: Vec<(u64, bool)>,
+    /// The latest tick each time the local input was fetched
+    pub input_fetch_ticks: Vec<u64>,
+    /// Ticks on which the local input is an object, standing in for a
+    /// networked_input which returns something that can't be sent to peers
+    pub broken_input_ticks: BTreeSet<u64>,
+    /// Set while the match is paused, standing in for the sync manager's flag
+    pub paused: bool,
+    /// A tick on which the peer adds one to the counter every time it
+    /// simulates it, standing in for game code which isn't deterministic
+    pub diverged_tick: Option<u64>,
+}
+
+impl ScriptedPeer {
+    pub fn new(seed: u64, stage: SyncStage, cx: Context) -> Self {
+        Self {
+            seed,
+            node: RollbackSyncManager::new_alloc().upcast::<Node>(),
+            stage,
+            cx,
+            counter: 0,
+            state_hashes: BTreeMap::new(),
+            rollbacks: Vec::new(),
+            connect_results: Vec::new(),
+            completed_frames: Vec::new(),
+            schedules: BTreeMap::new(),
+            fired_events: Vec::new(),
+            confirmed_events: Vec::new(),
+            start_refusals: Vec::new(),
+            desyncs: Vec::new(),
+            desync_details: Vec::new(),
+            simulated_ticks: Vec::new(),
+            input_fetch_ticks: Vec::new(),
+            broken_input_ticks: BTreeSet::new(),
+            diverged_tick: None,
+            paused: false,
+        }
+    }
+
+    /// A peer waiting in the lobby on a free loopback port. Returns the
+    /// address other peers can join it on
+    pub fn in_lobby(seed: u64) -> (Self, SocketAddr) {
+        let address = free_loopback_address();
+        let mut cx = Context::new();
+        cx.bind(address.ip(), address.port())
+            .expect("Could not bind loopback address");
+
+        let peer = Self::new(seed, SyncStage::Lobby(LobbyStage::new()), cx);
+        (peer, address)
+    }
+
+    pub fn with_lobby<T>(
+        &mut self,
+        callback: impl FnOnce(&mut LobbyStage, &mut Context) -> T,
+    ) -> T {
+        let SyncStage::Lobby(lobby) = &mut self.stage else {
+            panic!("Peer is not in the lobby");
+        };
+        callback(lobby, &mut self.cx)
+    }
+
+    pub fn play_stage(&self) -> &PlayStage {
+        match &self.stage {
+            SyncStage::Play(play_stage) => play_stage,
+            SyncStage::Replay(replay_stage) => &replay_stage.play_stage,
+            SyncStage::Lobby(_) => panic!("Peer is still in the lobby"),
+        }
+    }
+
+    /// Runs the tick pipeline once the way step_ticks does: handles received
+    /// messages, ticks the stage and executes the tick it requested. The sync
+    /// manager node stays borrowed while the stage runs, as it would in
+    /// physics_process, and queued signals are emitted once it is released
+    pub fn step(&mut self) {
+        let mut manager = self.node.clone().cast::<RollbackSyncManager>();
+        let manager_borrow = manager.bind_mut();
+        self.cx.set_stepping(true);
+        let events = self.cx.pump_socket().expect("Socket stopped receiving");
+        for (event, sender) in events {
+            if let PersistentEvent::FrameCompleted(_, mut message) = event {
+                match (message.read_serializable::<Message>(), sender) {
+                    (
+                        Some(Message::PauseRequest(paused)),
+                        PersistentSocketSender::Connected(id),
+                    ) => set_paused(&mut self.paused, paused, id, &mut self.cx),
+                    (Some(message), sender) => self
+                        .stage
+                        .handle_message(message, sender, &mut self.cx)
+                        .expect("Couldn't handle message"),
+                    (None, _) => {}
+                }
+            }
+        }
+        let in_lobby = matches!(self.stage, SyncStage::Lobby(_));
+        if !(self.paused && matches!(self.stage, SyncStage::Play(_))) {
+            self.stage
+                .tick(&mut self.node, &mut self.cx)
+                .expect("Could not tick stage");
+        }
+        self.cx.set_stepping(false);
+        if in_lobby && matches!(self.stage, SyncStage::Play(_)) {
+            // Stands in for start_game, which the lobby defers to the node
+            self.cx
+                .logger()
+                .run_info(&self.cx)
+                .expect("Could not log run info");
+        }
+
+        if self.cx.take_execute_tick_request() {
+            PlayStage::execute_tick(&mut *self);
+        }
+        drop(manager_borrow);
+
+        for (signal, args) in self.cx.take_queued_signals() {
+            match signal {
+                "rollback_occurred" => self.rollbacks.push((args[0].to(), args[1].to())),
+                "connect_finished" => self.connect_results.push(args[0].to()),
+                "start_refused" => self.start_refusals.push(args[0].to()),
+                "desync_detected" => self
+                    .desyncs
+                    .push((args[0].to(), args[1].to(), args[2].to())),
+                "desync_details" => {
+                    let tick = args[0].to::<u64>();
+                    let sender = args[1].to::<String>();
+                    for difference in args[2].to::<Array<Dictionary>>().iter_shared() {
+                        self.desync_details.push((
+                            tick,
+                            sender.clone(),
+                            difference.get_or_nil("path").to(),
+                            difference.get_or_nil("key").to(),
+                        ));
+                    }
+                }
+                "frame_inputs_complete" => self.completed_frames.push(args[0].to()),
+                "scheduled_event_confirmed" => self.confirmed_events.push(args[0].to()),
+                _ => {}
+            }
+            // Immediately rather than deferred, as nothing flushes deferred
+            // calls outside the main loop
+            self.node.emit_signal(signal.into(), &args);
+        }
+    }
+
+    /// Path of the log the peer writes during the match
+    fn log_file(&self) -> String {
+        let suffix = format!("_{}.db", self.cx.local_id());
+        std::fs::read_dir(log_file_directory().expect("Could not find log directory"))
+            .expect("Could not read log directory")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().to_string_lossy().to_string())
+            .find(|path| path.ends_with(&suffix))
+            .expect("Peer did not write a log")
+    }
+
+    /// True once the peer has confirmed state hashes for every tick of the
+    /// match
+    fn finished(&self) -> bool {
+        matches!(self.stage, SyncStage::Play(_))
+            && (2..=MATCH_TICKS).all(|tick| self.state_hashes.contains_key(&tick))
+    }
+}
+
+impl Drop for ScriptedPeer {
+    fn drop(&mut self) {
+        self.node.clone().free();
+    }
+}
+
+impl PlayStageOwner for &mut ScriptedPeer {
+    fn update<T, CB: FnOnce(&mut PlayStage, &mut Context) -> T>(&mut self, callback: CB) -> T {
+        match &mut self.stage {
+            SyncStage::Play(this) => callback(this, &mut self.cx),
+            SyncStage::Replay(replay_stage) => callback(&mut replay_stage.play_stage, &mut self.cx),
+            SyncStage::Lobby(_) => panic!("Tried to execute tick on non-play stage"),
+        }
+    }
+
+    fn load_frame(&mut self, tick: u64) {
+        let counter =
+            self.update(|this, _| this.frames.get(&tick).unwrap().node_state(COUNTER_PATH));
+        self.counter = counter.map_or(0, |counter| counter.to());
+    }
+
+    fn fetch_local_input(&mut self) -> Variant {
+        if let SyncStage::Replay(replay_stage) = &self.stage {
+            return replay_stage.local_input(&self.cx);
+        }
+
+        let tick = self.cx.latest_tick();
+        self.input_fetch_ticks.push(tick);
+        if self.broken_input_ticks.contains(&tick) {
+            return validated_input(self.node.to_variant());
+        }
+        validated_input(scripted_input(self.seed, tick).to_variant())
+    }
+
+    fn send_input(&mut self, peer: Uuid, tick: u64, message: Message) {
+        self.cx
+            .send_input(peer, tick, message)
+            .expect("Couldn't send input");
+    }
+
+    fn peers(&self) -> Vec<Uuid> {
+        self.cx.peers()
+    }
+
+    fn emit_scheduled_event(&mut self, name: String) {
+        let tick = self.cx.current_tick();
+        self.fired_events.push((tick, name));
+    }
+
+    fn networked_process(&mut self) -> HashMap<String, Variant> {
+        if let Some((tick, name)) = self.schedules.get(&self.cx.current_tick()).cloned() {
+            self.update(|this, cx| this.schedule_at(tick, name, cx));
+        }
+
+        let resimulating = self.play_stage().is_resimulating();
+        self.simulated_ticks
+            .push((self.cx.current_tick(), resimulating));
+
+        let inputs = self.update(|this, cx| {
+            let mut players = this.players(cx);
+            players.sort();
+            players
+                .into_iter()
+                .map(|player| {
+                    this.input(player.to_string(), cx)
+                        .try_to::<i64>()
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>()
+        });
+        self.counter = simulate(self.counter, &inputs);
+        if self.diverged_tick == Some(self.cx.current_tick()) {
+            self.counter += 1;
+        }
+
+        HashMap::from([(COUNTER_PATH.to_string(), self.counter.to_variant())])
+    }
+
+    fn log_node_states(&mut self) -> Option<u64> {
+        let counter = self.counter;
+        let state_hash = self.update(|this, cx| {
+            let frame = this.frames.get(&cx.current_tick()).unwrap();
+            if frame.missing_input(&cx.peers()).is_some() {
+                return None;
+            }
+
+            let state_hash = state_hash(counter);
+            cx.logger()
+                .state(
+                    COUNTER_PATH.into(),
+                    "counter".into(),
+                    counter.to_string(),
+                    state_hash,
+                    cx,
+                )
+                .expect("Could not log state");
+            frame.set_state_hash(state_hash);
+            frame.set_logged_states(vec![LoggedState {
+                path: COUNTER_PATH.into(),
+                key: "counter".into(),
+                value_text: counter.to_string(),
+                value_hash: state_hash,
+            }]);
+            Some(state_hash)
+        })?;
+
+        let tick = self.cx.current_tick();
+        self.state_hashes.insert(tick, state_hash);
+        Some(state_hash)
+    }
+
+    fn get_node(&self, _path: &str) -> Option<Gd<Node>> {
+        None
+    }
+
+    fn logged_spawn_events(&self, _tick: u64) -> Option<LoggedSpawnEvents> {
+        None
+    }
+}
+
+/// Plays a match between two peers waiting in the lobby, joining the second
+/// to the first, until both have confirmed every tick of the match
+fn play_match(peer_1: &mut ScriptedPeer, address_1: SocketAddr, peer_2: &mut ScriptedPeer) {
+    play_match_with(peer_1, address_1, peer_2, |_, _| {});
+}
+
+/// Plays a match like play_match, calling after_step with both peers once
+/// they have each stepped
+fn play_match_with(
+    peer_1: &mut ScriptedPeer,
+    address_1: SocketAddr,
+    peer_2: &mut ScriptedPeer,
+    mut after_step: impl FnMut(&mut ScriptedPeer, &mut ScriptedPeer),
+) {
+    // Stalls are only expected from missing input, which loopback should
+    // never cause
+    for peer in [&mut *peer_1, &mut *peer_2] {
+        peer.cx.set_advantage_stalling(false);
+    }
+
+    // Lobby handshake. The second peer joins the first by address
+    peer_2
+        .with_lobby(|lobby, cx| lobby.connect_and_wait(address_1.to_string(), 5.0, cx))
+        .expect("Could not join");
+
+    let mut connected_step = None;
+    let mut steps = 0;
+    while !(peer_1.finished() && peer_2.finished()) {
+        assert!(
+            steps < MAX_STEPS,
+            "Match did not finish in {MAX_STEPS} steps"
+        );
+        steps += 1;
+
+        for peer in [&mut *peer_1, &mut *peer_2] {
+            peer.step();
+        }
+        after_step(peer_1, peer_2);
+
+        if connected_step.is_none()
+            && !peer_1.cx.peers().is_empty()
+            && !peer_2.cx.peers().is_empty()
+        {
+            connected_step = Some(steps);
+        }
+        if connected_step == Some(steps.saturating_sub(READY_DELAY_STEPS)) {
+            for peer in [&mut *peer_1, &mut *peer_2] {
+                peer.with_lobby(|lobby, cx| lobby.update_ready(true, cx))
+                    .expect("Could not ready up");
+            }
+        }
+
+        sleep(Duration::from_millis(2));
+    }
+}
+
+/// Asserts both peers confirmed the same state for every tick of the match
+fn assert_peers_agree(peer_1: &ScriptedPeer, peer_2: &ScriptedPeer) {
+    for tick in 2..=MATCH_TICKS {
+        assert_eq!(
+            peer_1.state_hashes[&tick], peer_2.state_hashes[&tick],
+            "Peers disagree on tick {tick}"
+        );
+    }
+}
+
+#[test]
+#[ignore]
+fn scripted_match() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    assert_eq!(peer_1.cx.peers(), vec![peer_2.cx.local_id()]);
+    assert_eq!(peer_2.cx.peers(), vec![peer_1.cx.local_id()]);
+
+    // Both peers agree on every state once every input has arrived
+    assert_peers_agree(&peer_1, &peer_2);
+
+    let mut rollbacks = 0;
+    for peer in [&peer_1, &peer_2] {
+        let play_stage = peer.play_stage();
+        // Every rollback is counted once, and at most once per tick
+        assert_eq!(play_stage.rollback_count(), peer.rollbacks.len() as u64);
+        assert!(play_stage.rollback_count() <= peer.cx.latest_tick());
+        assert_eq!(play_stage.stalled_ticks(), 0);
+        rollbacks += play_stage.rollback_count();
+    }
+    // Inputs sent in one step arrive by the other peer's next step at the
+    // earliest, after it already predicted them
+    assert!(rollbacks > 0);
+}
+
+#[test]
+#[ignore]
+fn scripted_match_with_checkpoints() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    // Only the second peer rolls back from checkpoints, so both ways of
+    // rolling back have to agree
+    peer_2.cx.set_checkpoint_interval(4);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    assert_peers_agree(&peer_1, &peer_2);
+    assert!(peer_2.play_stage().rollback_count() > 0);
+    assert!(!peer_2.play_stage().checkpoints.is_empty());
+    assert!(peer_1.play_stage().checkpoints.is_empty());
+
+    // Resimulating from checkpoints ends on the same state as simulating
+    // every tick once with the inputs that were actually played
+    let players = [
+        (peer_1.cx.local_id(), peer_1.seed),
+        (peer_2.cx.local_id(), peer_2.seed),
+    ];
+    assert_eq!(
+        peer_2.state_hashes[&MATCH_TICKS],
+        full_replay_hash(&players, MATCH_TICKS)
+    );
+}
+
+#[test]
+#[ignore]
+fn network_replay_reproduces_the_match() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+    peer_1.cx.logger().flush().expect("Could not flush log");
+
+    let log_reader = LogReader::load_log_file(&peer_1.log_file()).expect("Could not load log");
+    let mut logged_rollbacks = log_reader
+        .log_entries()
+        .expect("Could not read log")
+        .into_iter()
+        .filter_map(|entry| match entry {
+            LogEntry::Rollback(Rollback {
+                frame,
+                rolled_back_to,
+                ..
+            }) => Some((frame, rolled_back_to)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    logged_rollbacks.sort();
+    assert!(!logged_rollbacks.is_empty());
+
+    let mut cx = Context::new();
+    cx.set_network_replay(true);
+    // Settings aren't logged, so the replay has to match the live peer's
+    cx.set_advantage_stalling(false);
+    let replay_stage = ReplayStage::new(log_reader, &mut cx).expect("Could not start replay");
+    let mut replay = ScriptedPeer::new(peer_1.seed, SyncStage::Replay(replay_stage), cx);
+    // Messages are delivered by processed tick, so running as many ticks as
+    // the live peer did delivers every message it applied
+    for _ in 0..peer_1.play_stage().processed_ticks() {
+        replay.step();
+    }
+
+    assert_eq!(replay.cx.latest_tick(), peer_1.cx.latest_tick());
+    assert_eq!(replay.state_hashes, peer_1.state_hashes);
+    // The replay rolls back exactly when and as far as the live peer did
+    let mut replayed_rollbacks = replay.rollbacks.clone();
+    replayed_rollbacks.sort();
+    assert_eq!(replayed_rollbacks, logged_rollbacks);
+    assert_eq!(
+        replay.play_stage().stalled_ticks(),
+        peer_1.play_stage().stalled_ticks()
+    );
+}
+
+#[test]
+#[ignore]
+fn ending_a_match_flushes_the_log() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    let latest_tick = peer_1.cx.latest_tick();
+    assert!(peer_1
+        .stage
+        .end_match(&mut peer_1.cx)
+        .expect("Could not end match"));
+    assert!(matches!(peer_1.stage, SyncStage::Lobby(_)));
+    assert_eq!(peer_1.cx.latest_tick(), 0);
+
+    // The log is read without waiting, so everything logged during the match
+    // has to have been written by the time end_match returns
+    let log_reader = LogReader::load_log_file(&peer_1.log_file()).expect("Could not load log");
+    let entries = log_reader.log_entries().expect("Could not read log");
+    let sent_ticks = entries
+        .iter()
+        .filter_map(|entry| match entry {
+            LogEntry::SentInput(sent_input) => Some(sent_input.frame),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    assert!((2..=latest_tick).all(|tick| sent_ticks.contains(&tick)));
+
+    // The match_ended event is the last thing logged
+    let last_event = entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            LogEntry::Event(event) => Some(event),
+            _ => None,
+        })
+        .max_by_key(|event: &Event| event.id)
+        .expect("No events logged");
+    assert_eq!(last_event.event, "match_ended");
+    assert_eq!(last_event.frame, latest_tick);
+
+    // Ending again does nothing
+    assert!(!peer_1
+        .stage
+        .end_match(&mut peer_1.cx)
+        .expect("Could not end match"));
+}
+
+#[test]
+#[ignore]
+fn connect_and_wait_finishes_on_connect_and_on_timeout() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_2
+        .with_lobby(|lobby, cx| lobby.connect_and_wait(address_1.to_string(), 1.0, cx))
+        .expect("Could not join");
+
+    // Finishes once the joined peer replies, well before the timeout
+    for _ in 0..peer_2.cx.simulation_rate() {
+        if !peer_2.connect_results.is_empty() {
+            break;
+        }
+        for peer in [&mut peer_1, &mut peer_2] {
+            peer.step();
+        }
+        sleep(Duration::from_millis(2));
+    }
+    assert_eq!(peer_2.connect_results, vec![true]);
+
+    // Nobody replies from a free port, so this join times out after three
+    // ticks
+    let (mut peer_3, _) = ScriptedPeer::in_lobby(3);
+    let unused_address = free_loopback_address();
+    let timeout_seconds = 2.5 / peer_3.cx.simulation_rate() as f64;
+    peer_3
+        .with_lobby(|lobby, cx| {
+            lobby.connect_and_wait(unused_address.to_string(), timeout_seconds, cx)
+        })
+        .expect("Could not join");
+    for _ in 0..3 {
+        peer_3.step();
+    }
+    assert!(peer_3.connect_results.is_empty());
+    peer_3.step();
+    assert_eq!(peer_3.connect_results, vec![false]);
+}
+
+#[test]
+#[ignore]
+fn signal_handlers_can_call_back_into_the_sync_manager() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+
+    // Handlers get the manager back by instance id since callables must be
+    // Send. Borrowing it would panic if the signal were emitted while the
+    // stage handling the Connect message still held it
+    let handled = Arc::new(AtomicUsize::new(0));
+    let manager_id = peer_2.node.instance_id();
+    let handler_count = handled.clone();
+    let handler = Callable::from_fn("reenter_sync_manager", move |_args| {
+        let mut manager = Gd::<RollbackSyncManager>::from_instance_id(manager_id);
+        manager.bind_mut().update_ready(false);
+        handler_count.fetch_add(1, Ordering::SeqCst);
+        Ok(Variant::nil())
+    });
+    for signal in ["connected", "connect_finished"] {
+        peer_2.node.connect(signal.into(), handler.clone());
+    }
+
+    peer_2
+        .with_lobby(|lobby, cx| lobby.connect_and_wait(address_1.to_string(), 1.0, cx))
+        .expect("Could not join");
+    for _ in 0..peer_2.cx.simulation_rate() {
+        if !peer_2.connect_results.is_empty() {
+            break;
+        }
+        for peer in [&mut peer_1, &mut peer_2] {
+            peer.step();
+        }
+        sleep(Duration::from_millis(2));
+    }
+
+    assert_eq!(peer_2.connect_results, vec![true]);
+    assert_eq!(handled.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+#[ignore]
+fn invalid_local_input_falls_back_to_the_default() {
+    let node = Node::new_alloc();
+    assert_eq!(validated_input(node.to_variant()), Variant::nil());
+    assert_eq!(
+        validated_input(varray![1, node.to_variant()].to_variant()),
+        Variant::nil()
+    );
+    let mut dictionary = Dictionary::new();
+    dictionary.set("jump", true);
+    assert_eq!(
+        validated_input(dictionary.to_variant()),
+        dictionary.to_variant()
+    );
+    node.free();
+
+    // The broken ticks are played as the default input rather than crashing,
+    // and the opponent receives the same default
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_2.broken_input_ticks = (10..MATCH_TICKS).step_by(10).collect();
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    assert_peers_agree(&peer_1, &peer_2);
+}
+
+#[test]
+#[ignore]
+fn frame_hashes_match_the_compared_hashes() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    for peer in [&peer_1, &peer_2] {
+        let hashed_ticks = peer
+            .play_stage()
+            .frames
+            .keys()
+            .copied()
+            .filter(|tick| peer.state_hashes.contains_key(tick))
+            .collect::<Vec<_>>();
+        assert!(!hashed_ticks.is_empty());
+        for tick in hashed_ticks {
+            assert_eq!(peer.stage.frame_hash(tick), Some(peer.state_hashes[&tick]));
+        }
+
+        // Evicted and not yet simulated frames have no hash
+        assert_eq!(peer.stage.frame_hash(1), None);
+        assert_eq!(peer.stage.frame_hash(MATCH_TICKS * 2), None);
+    }
+}
+
+#[test]
+#[ignore]
+fn pinned_frames_outlive_the_rewind_window_until_unpinned() {
+    const PINNED_TICK: u64 = 10;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    let mut pinned = false;
+    play_match_with(&mut peer_1, address_1, &mut peer_2, |peer_1, _| {
+        if !pinned && matches!(peer_1.stage, SyncStage::Play(_)) {
+            pinned = peer_1.stage.pin_frame(PINNED_TICK);
+        }
+    });
+    assert!(pinned);
+
+    // The match runs far past the rewind window of the pinned tick, which
+    // the unpinned opponent has long since evicted
+    assert!(peer_1.play_stage().frames.contains_key(&PINNED_TICK));
+    assert!(!peer_2.play_stage().frames.contains_key(&PINNED_TICK));
+
+    peer_1.stage.unpin_frame(PINNED_TICK);
+    assert!(!peer_1.play_stage().frames.contains_key(&PINNED_TICK));
+}
+
+#[test]
+#[ignore]
+fn concurrent_replays_reach_their_own_final_states() {
+    // Two matches with different inputs, each replayed from its first
+    // peer's log
+    let mut matches = Vec::new();
+    for seeds in [(1, 2), (3, 4)] {
+        let (mut peer_1, address_1) = ScriptedPeer::in_lobby(seeds.0);
+        let (mut peer_2, _) = ScriptedPeer::in_lobby(seeds.1);
+        play_match(&mut peer_1, address_1, &mut peer_2);
+        peer_1.cx.logger().flush().expect("Could not flush log");
+        matches.push(peer_1);
+    }
+    assert_ne!(
+        matches[0].state_hashes[&MATCH_TICKS],
+        matches[1].state_hashes[&MATCH_TICKS]
+    );
+
+    let mut replays = matches
+        .iter()
+        .map(|peer| {
+            let log_reader =
+                LogReader::load_log_file(&peer.log_file()).expect("Could not load log");
+            let mut cx = Context::new();
+            let replay_stage =
+                ReplayStage::new(log_reader, &mut cx).expect("Could not start replay");
+            ScriptedPeer::new(peer.seed, SyncStage::Replay(replay_stage), cx)
+        })
+        .collect::<Vec<_>>();
+
+    // Both replays advance side by side in the same process
+    let mut steps = 0;
+    while !replays
+        .iter()
+        .all(|replay| replay.state_hashes.contains_key(&MATCH_TICKS))
+    {
+        assert!(
+            steps < MAX_STEPS,
+            "Replays did not finish in {MAX_STEPS} steps"
+        );
+        steps += 1;
+        for replay in replays.iter_mut() {
+            replay.step();
+        }
+    }
+
+    for (replay, peer) in replays.iter().zip(&matches) {
+        assert_eq!(
+            replay.state_hashes[&MATCH_TICKS],
+            peer.state_hashes[&MATCH_TICKS]
+        );
+    }
+}
+
+#[test]
+#[ignore]
+fn debug_state_captures_the_held_frames_and_counters() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    let play_stage = peer_1.play_stage();
+    let debug_state = play_stage.dump_debug_state(&peer_1.cx);
+
+    let mut held_ticks = play_stage.frames.keys().copied().collect::<Vec<_>>();
+    held_ticks.sort();
+    let dumped_ticks = debug_state["frames"]
+        .as_array()
+        .expect("Frames are not an array")
+        .iter()
+        .map(|frame| frame["tick"].as_u64().expect("Frame has no tick"))
+        .collect::<Vec<_>>();
+    assert_eq!(dumped_ticks, held_ticks);
+    for frame in debug_state["frames"].as_array().unwrap() {
+        let tick = frame["tick"].as_u64().unwrap();
+        assert_eq!(
+            frame["state_hash"].as_u64(),
+            play_stage.frames[&tick].state_hash()
+        );
+    }
+
+    assert_eq!(debug_state["latest_tick"], peer_1.cx.latest_tick());
+    let peer_2_id = peer_2.cx.local_id().to_string();
+    assert_eq!(
+        debug_state["latest_frame_received"][&peer_2_id].as_u64(),
+        play_stage
+            .latest_frame_received
+            .get(&peer_2.cx.local_id())
+            .copied()
+    );
+}
+
+#[test]
+#[ignore]
+fn mismatched_simulation_rates_refuse_to_start() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_2
+        .with_lobby(|lobby, cx| lobby.set_simulation_rate(30, cx))
+        .expect("Could not set simulation rate");
+    peer_2
+        .with_lobby(|lobby, cx| lobby.connect_and_wait(address_1.to_string(), 5.0, cx))
+        .expect("Could not join");
+
+    let mut connected_step = None;
+    for step in 0..MAX_STEPS {
+        for peer in [&mut peer_1, &mut peer_2] {
+            peer.step();
+        }
+        if connected_step.is_none()
+            && !peer_1.cx.peers().is_empty()
+            && !peer_2.cx.peers().is_empty()
+        {
+            connected_step = Some(step);
+        }
+        if connected_step == Some(step.saturating_sub(READY_DELAY_STEPS)) {
+            for peer in [&mut peer_1, &mut peer_2] {
+                peer.with_lobby(|lobby, cx| lobby.update_ready(true, cx))
+                    .expect("Could not ready up");
+            }
+        }
+        if !peer_1.start_refusals.is_empty() && !peer_2.start_refusals.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(2));
+    }
+
+    // Both peers refuse, naming the rates they disagree on, and stay in the
+    // lobby
+    for peer in [&peer_1, &peer_2] {
+        assert!(matches!(peer.stage, SyncStage::Lobby(_)));
+        let reason = peer.start_refusals.last().expect("Start was not refused");
+        assert!(reason.contains("60") && reason.contains("30"), "{reason}");
+    }
+}
+
+#[test]
+#[ignore]
+fn frame_inputs_complete_fires_once_per_frame() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    // Rollbacks set inputs again on every peer, but each frame only
+    // completes once
+    for peer in [&peer_1, &peer_2] {
+        assert!(!peer.rollbacks.is_empty());
+        let mut completions = BTreeMap::new();
+        for tick in &peer.completed_frames {
+            *completions.entry(*tick).or_insert(0) += 1;
+        }
+        for tick in 2..=MATCH_TICKS {
+            assert_eq!(completions.get(&tick), Some(&1), "Tick {tick}");
+        }
+        assert!(completions.values().all(|count| *count == 1));
+    }
+}
+
+#[test]
+#[ignore]
+fn scripted_match_with_adaptive_rewind() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    // Only the second peer sizes its window from the round trip time, so the
+    // peers hold different windows for each other
+    peer_2.cx.set_adaptive_rewind(true);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    let peer_1_id = peer_1.cx.local_id();
+    assert!(peer_2.play_stage().rewind_window(peer_1_id, &peer_2.cx) > peer_2.cx.max_rewind());
+    assert_peers_agree(&peer_1, &peer_2);
+    let players = [
+        (peer_1_id, peer_1.seed),
+        (peer_2.cx.local_id(), peer_2.seed),
+    ];
+    assert_eq!(
+        peer_2.state_hashes[&MATCH_TICKS],
+        full_replay_hash(&players, MATCH_TICKS)
+    );
+}
+
+#[test]
+#[ignore]
+fn node_states_holding_objects_are_reported_once() {
+    let node = Node::new_alloc();
+    let mut player_state = Dictionary::new();
+    player_state.set("position", Vector2::new(1.0, 2.0));
+    player_state.set("target", node.clone());
+    let node_states = HashMap::from([
+        (COUNTER_PATH.to_string(), 3.to_variant()),
+        ("/root/World/Player".to_string(), player_state.to_variant()),
+        (
+            "/root/World/Callback".to_string(),
+            Callable::from_object_method(&node, "free").to_variant(),
+        ),
+    ]);
+
+    let mut reported_paths = HashSet::new();
+    assert_eq!(
+        unreported_unserializable_states(&node_states, &mut reported_paths),
+        vec![
+            ("/root/World/Callback".to_string(), VariantType::Callable),
+            ("/root/World/Player".to_string(), VariantType::Object),
+        ]
+    );
+    // Later ticks returning the same states aren't reported again
+    assert!(unreported_unserializable_states(&node_states, &mut reported_paths).is_empty());
+    node.free();
+}
+
+#[test]
+#[ignore]
+fn stepping_runs_exactly_the_requested_ticks() {
+    const STEPPED_TICKS: u64 = 60;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    // Each step runs the tick pipeline once the way step_ticks does, so the
+    // match advances by exactly as many ticks as were stepped
+    let ticks_before = [peer_1.cx.latest_tick(), peer_2.cx.latest_tick()];
+    for _ in 0..STEPPED_TICKS {
+        for peer in [&mut peer_1, &mut peer_2] {
+            peer.step();
+        }
+        sleep(Duration::from_millis(2));
+    }
+
+    assert_eq!(peer_1.cx.latest_tick(), ticks_before[0] + STEPPED_TICKS);
+    assert_eq!(peer_2.cx.latest_tick(), ticks_before[1] + STEPPED_TICKS);
+    assert_peers_agree(&peer_1, &peer_2);
+}
+
+#[test]
+#[ignore]
+fn dropped_frames_record_how_long_the_stall_lasted() {
+    const PAUSED_STEPS: u64 = 60;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    // The second peer stops sending input for long enough that the first
+    // runs out of rewind window and stalls, then resumes until the first
+    // peer's stall clears
+    let paused_at = peer_1.cx.latest_tick();
+    let mut stalled_steps = 0;
+    let mut step = 0;
+    loop {
+        let latest_tick = peer_1.cx.latest_tick();
+        peer_1.step();
+        if step >= PAUSED_STEPS {
+            peer_2.step();
+        }
+        step += 1;
+
+        if peer_1.cx.latest_tick() == latest_tick {
+            stalled_steps += 1;
+        } else if stalled_steps > 0 {
+            break;
+        }
+        assert!(step < MAX_STEPS, "Stall did not clear");
+        sleep(Duration::from_millis(2));
+    }
+    assert!(stalled_steps > 0);
+
+    peer_1.cx.logger().flush().expect("Could not flush log");
+    let stall_lengths = LogReader::load_log_file(&peer_1.log_file())
+        .expect("Could not load log")
+        .log_entries()
+        .expect("Could not read log")
+        .into_iter()
+        .filter_map(|entry| match entry {
+            LogEntry::DroppedFrame(DroppedFrame {
+                frame, stall_ticks, ..
+            }) if frame > paused_at => Some(stall_ticks),
+            _ => None,
+        })
+        .sorted()
+        .collect::<Vec<_>>();
+    // One entry per stalled tick, the last holding the full length
+    assert_eq!(stall_lengths, (1..=stalled_steps).collect::<Vec<_>>());
+}
+
+#[test]
+#[ignore]
+fn replay_progress_advances_to_the_end_of_the_log() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+    peer_1.cx.logger().flush().expect("Could not flush log");
+
+    let log_reader = LogReader::load_log_file(&peer_1.log_file()).expect("Could not load log");
+    let mut cx = Context::new();
+    let replay_stage = ReplayStage::new(log_reader, &mut cx).expect("Could not start replay");
+    assert!(replay_stage.length() >= MATCH_TICKS);
+    let mut replay = ScriptedPeer::new(peer_1.seed, SyncStage::Replay(replay_stage), cx);
+
+    let progress = |replay: &ScriptedPeer| {
+        let SyncStage::Replay(replay_stage) = &replay.stage else {
+            panic!("Replay ended early");
+        };
+        replay_stage.progress(&replay.cx)
+    };
+    let mut previous_progress = progress(&replay);
+    assert_eq!(previous_progress, 0.0);
+    for _ in 0..MAX_STEPS {
+        replay.step();
+        let current_progress = progress(&replay);
+        assert!(current_progress >= previous_progress);
+        previous_progress = current_progress;
+        if current_progress == 1.0 {
+            return;
+        }
+    }
+    panic!("Replay progress stopped at {previous_progress}");
+}
+
+#[test]
+#[ignore]
+fn scheduled_events_are_confirmed_once_on_their_tick() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    for peer in [&mut peer_1, &mut peer_2] {
+        peer.schedules
+            .insert(50, (MATCH_TICKS / 2, "explosion".to_string()));
+    }
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    // Rollbacks may fire the event again while re-simulating its tick, but
+    // it is only ever fired on that tick and confirmed once
+    for peer in [&peer_1, &peer_2] {
+        assert!(!peer.fired_events.is_empty());
+        assert!(peer
+            .fired_events
+            .iter()
+            .all(|event| *event == (MATCH_TICKS / 2, "explosion".to_string())));
+        assert_eq!(peer.confirmed_events, vec!["explosion".to_string()]);
+    }
+}
+
+#[test]
+#[ignore]
+fn inputs_which_decode_differently_are_caught() {
+    let mut input = Dictionary::new();
+    input.set("direction", Vector2::new(0.5, -1.0));
+    input.set("jump", true);
+    assert_eq!(round_trip_mismatch(&input.to_variant()), None);
+
+    // NaN never equals itself, so the decoded input never matches what the
+    // local peer simulated
+    input.set("aim", f64::NAN);
+    assert!(round_trip_mismatch(&input.to_variant()).is_some());
+}
+
+#[test]
+#[ignore]
+fn force_resync_brings_a_diverged_peer_back_in_line() {
+    const DIVERGED_TICK: u64 = 100;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    // The peer with the lowest id leads
+    let peer_1_leads = peer_1.cx.local_id() < peer_2.cx.local_id();
+    if peer_1_leads {
+        peer_2.diverged_tick = Some(DIVERGED_TICK);
+    } else {
+        peer_1.diverged_tick = Some(DIVERGED_TICK);
+    }
+    play_match(&mut peer_1, address_1, &mut peer_2);
+    let (leader, follower) = if peer_1_leads {
+        (&mut peer_1, &mut peer_2)
+    } else {
+        (&mut peer_2, &mut peer_1)
+    };
+    assert!(leader.cx.is_leader());
+    assert_ne!(
+        leader.state_hashes[&MATCH_TICKS],
+        follower.state_hashes[&MATCH_TICKS]
+    );
+
+    let resync_tick = leader
+        .stage
+        .force_resync(&mut leader.cx)
+        .expect("Could not force resync")
+        .expect("Leader had no frame to resync from");
+    assert!(resync_tick > DIVERGED_TICK);
+    for _ in 0..READY_DELAY_STEPS * 3 {
+        for peer in [&mut *leader, &mut *follower] {
+            peer.step();
+        }
+        sleep(Duration::from_millis(2));
+    }
+
+    // Every tick the follower confirmed after the resynced frame agrees with
+    // the leader
+    let agreed_ticks = follower
+        .state_hashes
+        .range(resync_tick + 1..)
+        .filter(|(tick, _)| leader.state_hashes.contains_key(tick))
+        .collect::<Vec<_>>();
+    assert!(!agreed_ticks.is_empty());
+    for (tick, state_hash) in agreed_ticks {
+        assert_eq!(leader.state_hashes[tick], *state_hash, "Tick {tick}");
+    }
+}
+
+#[test]
+#[ignore]
+fn internal_events_log_with_their_severity() {
+    const PAUSED_STEPS: u64 = 60;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_2.diverged_tick = Some(MATCH_TICKS / 2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    // The second peer stops sending input long enough for the first to stall
+    for step in 0..PAUSED_STEPS * 2 {
+        peer_1.step();
+        if step >= PAUSED_STEPS {
+            peer_2.step();
+        }
+        sleep(Duration::from_millis(2));
+    }
+    assert!(peer_1.play_stage().stalled_ticks() > 0);
+
+    peer_1.cx.logger().flush().expect("Could not flush log");
+    let events = LogReader::load_log_file(&peer_1.log_file())
+        .expect("Could not load log")
+        .log_entries()
+        .expect("Could not read log")
+        .into_iter()
+        .filter_map(|entry| match entry {
+            LogEntry::Event(event) => Some(event),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let logged_as = |name: &str| {
+        let event = events
+            .iter()
+            .find(|event| event.event == name)
+            .unwrap_or_else(|| panic!("No {name} event logged"));
+        (event.severity, event.category)
+    };
+    assert_eq!(
+        logged_as("desync"),
+        (EventSeverity::Error, Some(EventCategory::Desync))
+    );
+    assert_eq!(
+        logged_as("stall"),
+        (EventSeverity::Warning, Some(EventCategory::Stall))
+    );
+}
+
+#[test]
+#[ignore]
+fn seeking_to_a_marker_stops_on_its_tick() {
+    const MARKED_TICK: u64 = MATCH_TICKS / 2;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    let mut marked = false;
+    // Logs the marker the way mark does, on the tick just simulated
+    play_match_with(&mut peer_1, address_1, &mut peer_2, |peer_1, _| {
+        if !marked && peer_1.cx.current_tick() == MARKED_TICK {
+            peer_1
+                .cx
+                .logger()
+                .event(
+                    EventSeverity::Info,
+                    Some(EventCategory::Match),
+                    "marker".to_string(),
+                    "round 2 start".to_string(),
+                    &peer_1.cx,
+                )
+                .expect("Could not log marker");
+            marked = true;
+        }
+    });
+    assert!(marked);
+    peer_1.cx.logger().flush().expect("Could not flush log");
+
+    let log_reader = LogReader::load_log_file(&peer_1.log_file()).expect("Could not load log");
+    let mut cx = Context::new();
+    let replay_stage = ReplayStage::new(log_reader, &mut cx).expect("Could not start replay");
+    let mut replay = ScriptedPeer::new(peer_1.seed, SyncStage::Replay(replay_stage), cx);
+
+    let target_tick = {
+        let SyncStage::Replay(replay_stage) = &replay.stage else {
+            unreachable!();
+        };
+        assert_eq!(
+            replay_stage
+                .markers(&replay.cx)
+                .expect("Could not read markers"),
+            vec![(MARKED_TICK, "round 2 start".to_string())]
+        );
+        let marker_tick = replay_stage
+            .marker_tick("round 2 start", &replay.cx)
+            .expect("Could not read markers")
+            .expect("Marker was not logged");
+        replay_stage
+            .seek_target(marker_tick, &replay.cx)
+            .expect("Marker was already passed")
+    };
+    // Steps the way replay_seek_to_marker runs ticks
+    for _ in 0..target_tick * 2 {
+        if replay.cx.latest_tick() >= target_tick {
+            break;
+        }
+        replay.step();
+    }
+
+    assert_eq!(replay.cx.latest_tick(), MARKED_TICK);
+}
+
+#[test]
+#[ignore]
+fn skipped_local_inputs_repeat_the_previous_input() {
+    const SKIPPED_TICK: u64 = 70;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    // The default input would be 0, so repeating has to be what fills it
+    assert_ne!(scripted_input(peer_1.seed, SKIPPED_TICK - 1), 0);
+    peer_1.broken_input_ticks = BTreeSet::from([SKIPPED_TICK]);
+    peer_1.cx.set_input_repeat_limit(1);
+
+    let mut played_input = None;
+    play_match_with(&mut peer_1, address_1, &mut peer_2, |peer_1, _| {
+        if played_input.is_none() && peer_1.cx.latest_tick() > SKIPPED_TICK {
+            played_input = peer_1
+                .play_stage()
+                .frames
+                .get(&SKIPPED_TICK)
+                .and_then(|frame| frame.input(peer_1.cx.local_id()));
+        }
+    });
+
+    assert_eq!(
+        played_input.map(|input| input.to::<i64>()),
+        Some(scripted_input(peer_1.seed, SKIPPED_TICK - 1))
+    );
+    assert_peers_agree(&peer_1, &peer_2);
+}
+
+#[test]
+#[ignore]
+fn short_rewind_window_evicts_older_frames() {
+    const MAX_REWIND: u64 = 4;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_1.cx.set_max_rewind(1);
+    assert_eq!(peer_1.cx.max_rewind(), MIN_MAX_REWIND);
+    for peer in [&mut peer_1, &mut peer_2] {
+        peer.cx.set_max_rewind(MAX_REWIND);
+    }
+
+    let mut checked_steps = 0;
+    play_match_with(&mut peer_1, address_1, &mut peer_2, |peer_1, peer_2| {
+        for peer in [&*peer_1, &*peer_2] {
+            if matches!(peer.stage, SyncStage::Lobby(_)) || peer.cx.latest_tick() <= MAX_REWIND {
+                continue;
+            }
+            // The window is applied before the latest tick advances, so one
+            // frame past it may still be held
+            let oldest_held = peer.cx.latest_tick() - MAX_REWIND;
+            assert!(
+                peer.play_stage()
+                    .frames
+                    .keys()
+                    .all(|tick| *tick >= oldest_held),
+                "Frames older than {oldest_held} held on tick {}",
+                peer.cx.latest_tick()
+            );
+            checked_steps += 1;
+        }
+    });
+
+    assert!(checked_steps > 0);
+    assert_peers_agree(&peer_1, &peer_2);
+}
+
+#[test]
+#[ignore]
+fn desyncs_signal_instead_of_panicking() {
+    const DIVERGED_TICK: u64 = MATCH_TICKS / 2;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_2.diverged_tick = Some(DIVERGED_TICK);
+    // Used to panic on the first mismatched hash
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    for peer in [&peer_1, &peer_2] {
+        assert!(!peer.desyncs.is_empty());
+        let reported_ticks = peer.desyncs.iter().map(|(tick, ..)| *tick);
+        assert!(reported_ticks.clone().all_unique());
+        assert!(reported_ticks.clone().all(|tick| tick >= DIVERGED_TICK));
+        for (tick, local_hash, remote_hash) in &peer.desyncs {
+            assert_ne!(local_hash, remote_hash, "Tick {tick}");
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn start_offset_delays_input_recording() {
+    const START_OFFSET: u32 = 30;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    for peer in [&mut peer_1, &mut peer_2] {
+        peer.with_lobby(|lobby, cx| lobby.set_start_offset(START_OFFSET, cx))
+            .expect("Could not set start offset");
+    }
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    for peer in [&peer_1, &peer_2] {
+        assert_eq!(peer.cx.start_offset(), START_OFFSET);
+        assert_eq!(
+            peer.input_fetch_ticks.first(),
+            Some(&(START_OFFSET as u64 + 2))
+        );
+    }
+    assert!(peer_1.start_refusals.is_empty() && peer_2.start_refusals.is_empty());
+    assert_peers_agree(&peer_1, &peer_2);
+}
+
+#[test]
+#[ignore]
+fn peers_predicting_differently_still_agree() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_1.cx.set_input_prediction(InputPrediction::Default);
+    peer_2.cx.set_input_prediction(InputPrediction::RepeatLast);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    assert_peers_agree(&peer_1, &peer_2);
+}
+
+#[test]
+#[ignore]
+fn rollbacks_resimulate_every_tick_but_the_latest() {
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    let mut resimulated = false;
+    for peer in [&peer_1, &peer_2] {
+        // Each tick is simulated for the first time exactly once, in order
+        let first_simulations = peer
+            .simulated_ticks
+            .iter()
+            .filter(|(_, resimulating)| !resimulating)
+            .map(|(tick, _)| *tick)
+            .collect::<Vec<_>>();
+        assert!(first_simulations.iter().tuple_windows().all(|(a, b)| a < b));
+
+        // Resimulated ticks are always behind the latest tick simulated
+        let mut latest_tick = 0;
+        for (tick, resimulating) in &peer.simulated_ticks {
+            if *resimulating {
+                assert!(*tick < latest_tick, "Tick {tick}");
+                resimulated = true;
+            } else {
+                latest_tick = *tick;
+            }
+        }
+        assert_eq!(
+            peer.rollbacks.is_empty(),
+            !peer
+                .simulated_ticks
+                .iter()
+                .any(|(_, resimulating)| *resimulating)
+        );
+        assert!(!peer.play_stage().is_resimulating());
+    }
+    assert!(resimulated);
+}
+
+#[test]
+#[ignore]
+fn late_spectator_converges_with_the_players() {
+    const JOIN_TICK: u64 = MATCH_TICKS / 3;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    let (mut spectator, _) = ScriptedPeer::in_lobby(3);
+    let mut joined = false;
+    play_match_with(&mut peer_1, address_1, &mut peer_2, |peer_1, _| {
+        if !joined
+            && matches!(peer_1.stage, SyncStage::Play(_))
+            && peer_1.cx.latest_tick() >= JOIN_TICK
+        {
+            spectator
+                .with_lobby(|lobby, cx| lobby.join_as_spectator(address_1.to_string(), cx))
+                .expect("Could not join as a spectator");
+            joined = true;
+        }
+        if joined {
+            spectator.step();
+        }
+    });
+    // The spectator follows a little behind the players
+    for _ in 0..READY_DELAY_STEPS * 3 {
+        for peer in [&mut peer_1, &mut peer_2, &mut spectator] {
+            peer.step();
+        }
+        sleep(Duration::from_millis(2));
+    }
+
+    assert!(spectator.play_stage().is_spectating());
+    let spectator_id = spectator.cx.local_id();
+    for peer in [&peer_1, &peer_2] {
+        assert!(!peer.cx.peers().contains(&spectator_id));
+    }
+    // The spectator agrees with the players on every tick it simulated after
+    // loading the snapshot
+    let spectated_ticks = spectator
+        .state_hashes
+        .range(JOIN_TICK..=MATCH_TICKS)
+        .collect::<Vec<_>>();
+    assert!(spectator.state_hashes.contains_key(&MATCH_TICKS));
+    for (tick, state_hash) in spectated_ticks {
+        assert_eq!(peer_1.state_hashes[tick], *state_hash, "Tick {tick}");
+    }
+    assert_peers_agree(&peer_1, &peer_2);
+}
+
+#[test]
+#[ignore]
+fn desync_details_name_the_diverging_state() {
+    const DIVERGED_TICK: u64 = MATCH_TICKS / 2;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    peer_2.diverged_tick = Some(DIVERGED_TICK);
+    play_match(&mut peer_1, address_1, &mut peer_2);
+
+    for (peer, other) in [(&peer_1, &peer_2), (&peer_2, &peer_1)] {
+        assert!(!peer.desync_details.is_empty());
+        for (tick, sender, path, key) in &peer.desync_details {
+            assert!(*tick >= DIVERGED_TICK);
+            assert_eq!(*sender, other.cx.local_id().to_string());
+            assert_eq!((path.as_str(), key.as_str()), (COUNTER_PATH, "counter"));
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn pausing_one_peer_stops_ticks_on_both() {
+    const PAUSE_TICK: u64 = MATCH_TICKS / 2;
+    const PAUSED_STEPS: u32 = 60;
+
+    let (mut peer_1, address_1) = ScriptedPeer::in_lobby(1);
+    let (mut peer_2, _) = ScriptedPeer::in_lobby(2);
+    let mut paused_steps = None;
+    let mut paused_ticks = None;
+    play_match_with(&mut peer_1, address_1, &mut peer_2, |peer_1, peer_2| {
+        let latest_ticks = (peer_1.cx.latest_tick(), peer_2.cx.latest_tick());
+        match paused_steps.as_mut() {
+            None if matches!(peer_1.stage, SyncStage::Play(_)) && latest_ticks.0 >= PAUSE_TICK => {
+                assert!(request_pause(
+                    &mut peer_1.paused,
+                    true,
+                    &peer_1.stage,
+                    &mut peer_1.cx
+                ));
+                paused_steps = Some(0);
+            }
+            None => {}
+            Some(steps) if *steps < PAUSED_STEPS => {
+                *steps += 1;
+                // Give the request a few steps to reach the other peer
+                if *steps == PAUSED_STEPS / 2 {
+                    assert!(peer_1.paused && peer_2.paused);
+                    paused_ticks = Some(latest_ticks);
+                } else if *steps == PAUSED_STEPS {
+                    assert_eq!(paused_ticks, Some(latest_ticks));
+                    // Any peer may resume, not just the one which paused
+                    assert!(request_pause(
+                        &mut peer_2.paused,
+                        false,
+                        &peer_2.stage,
+                        &mut peer_2.cx
+                    ));
+                }
+            }
+            Some(_) => {}
+        }
+    });
+
+    assert_eq!(paused_steps, Some(PAUSED_STEPS));
+    assert!(!peer_1.paused && !peer_2.paused);
+    assert_peers_agree(&peer_1, &peer_2);
+}
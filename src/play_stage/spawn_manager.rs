@@ -1,9 +1,11 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
 
 use parking_lot::RwLock;
 
 use godot::prelude::*;
 
+use crate::logging::{EventCategory, EventSeverity};
+
 use super::{
     frame::{Frame, SpawnRecord},
     PlayStageOwner,
@@ -11,12 +13,18 @@ use super::{
 
 pub struct SpawnManager {
     spawned_nodes: RwLock<HashMap<String, Gd<Node>>>,
+    /// Resource path of the scene each spawned node was instantiated from
+    spawned_scene_paths: RwLock<HashMap<String, String>>,
+    /// Despawned nodes kept out of the tree for reuse, by scene path
+    pool: RwLock<HashMap<String, VecDeque<Gd<Node>>>>,
 }
 
 impl SpawnManager {
     pub fn new() -> Self {
         Self {
             spawned_nodes: RwLock::new(HashMap::new()),
+            spawned_scene_paths: RwLock::new(HashMap::new()),
+            pool: RwLock::new(HashMap::new()),
         }
     }
 
@@ -25,6 +33,25 @@ impl SpawnManager {
         self.spawn_missing_nodes(owner, frame);
     }
 
+    /// Rebuilds the live node mapping from the frame's spawn records and the
+    /// node tree, then spawns and despawns nodes as load_frame does. Tracked
+    /// nodes which were freed or replaced in the tree are forgotten, and
+    /// untracked nodes found at a recorded path are adopted rather than
+    /// spawned again.
+    pub fn resync(&self, owner: &mut impl PlayStageOwner, frame: &Frame) {
+        reconcile_spawned_nodes(
+            &mut self.spawned_nodes.write(),
+            frame.spawned_node_paths(),
+            |node_path| {
+                owner
+                    .get_node(node_path)
+                    .filter(|node| !node.is_queued_for_deletion())
+            },
+        );
+
+        self.load_frame(owner, frame);
+    }
+
     pub fn despawn(&self, owner: &mut impl PlayStageOwner, node_path: &str, frame: &Frame) {
         if let Some(mut node) = owner.get_node(node_path.into()) {
             if node.has_method("networked_despawn".into()) {
@@ -35,14 +62,27 @@ impl SpawnManager {
                 parent.remove_child(node.clone());
             }
 
-            node.queue_free();
+            let scene_path = self.spawned_scene_paths.write().remove(node_path);
+            let pooling = owner.update(|_, cx| cx.node_pooling());
+            if let Some(mut node) =
+                return_to_pool(&mut self.pool.write(), scene_path, pooling, node)
+            {
+                node.queue_free();
+            }
 
             self.spawned_nodes.write().remove(node_path);
             frame.remove_spawn_record(&node_path);
 
             owner.update(|_, cx| {
                 cx.logger()
-                    .event_for_frame(frame.tick(), "despawned".into(), node_path.to_string(), cx)
+                    .event_for_frame(
+                        frame.tick(),
+                        EventSeverity::Info,
+                        Some(EventCategory::Spawn),
+                        "despawned".into(),
+                        node_path.to_string(),
+                        cx,
+                    )
                     .unwrap();
             });
         }
@@ -55,7 +95,9 @@ impl SpawnManager {
         frame: &Frame,
         resurrecting: bool,
     ) -> Gd<Node> {
-        let mut spawned_node = spawn_record.scene.instantiate().unwrap();
+        let mut spawned_node = self
+            .take_pooled(&spawn_record.scene_path)
+            .unwrap_or_else(|| spawn_record.scene.instantiate().unwrap());
 
         if !resurrecting {
             spawn_record.name = frame.avoid_name_collision(spawn_record.name);
@@ -74,17 +116,35 @@ impl SpawnManager {
         self.spawned_nodes
             .write()
             .insert(node_path.clone(), spawned_node.clone());
+        self.spawned_scene_paths
+            .write()
+            .insert(node_path.clone(), spawn_record.scene_path.clone());
         frame.add_spawn_record(node_path.clone(), spawn_record.clone());
 
         owner.update(|_, cx| {
             cx.logger()
-                .event_for_frame(frame.tick(), "spawned".into(), node_path.into(), cx)
+                .event_for_frame(
+                    frame.tick(),
+                    EventSeverity::Info,
+                    Some(EventCategory::Spawn),
+                    "spawned".into(),
+                    spawn_record.to_log_data(&node_path),
+                    cx,
+                )
                 .unwrap();
         });
 
         spawned_node
     }
 
+    /// Takes a despawned node of the scene out of the pool if there is one,
+    /// skipping any which were freed by game code while pooled
+    fn take_pooled(&self, scene_path: &str) -> Option<Gd<Node>> {
+        take_from_pool(&mut self.pool.write(), scene_path, |node| {
+            node.is_instance_valid()
+        })
+    }
+
     fn remove_despawned_nodes(&self, owner: &mut impl PlayStageOwner, frame: &Frame) {
         let mut nodes_to_despawn = Vec::new();
 
@@ -127,3 +187,139 @@ impl SpawnManager {
         }
     }
 }
+
+/// Adds a despawned node to the pool for its scene if pooling is enabled and
+/// the scene has a resource path. Returns the node if it wasn't pooled
+fn return_to_pool<N>(
+    pool: &mut HashMap<String, VecDeque<N>>,
+    scene_path: Option<String>,
+    pooling: bool,
+    node: N,
+) -> Option<N> {
+    match scene_path.filter(|scene_path| pooling && !scene_path.is_empty()) {
+        Some(scene_path) => {
+            pool.entry(scene_path).or_default().push_back(node);
+            None
+        }
+        None => Some(node),
+    }
+}
+
+/// Takes the oldest pooled node of the scene which is still valid, dropping
+/// any invalid ones in front of it
+fn take_from_pool<N>(
+    pool: &mut HashMap<String, VecDeque<N>>,
+    scene_path: &str,
+    is_valid: impl Fn(&N) -> bool,
+) -> Option<N> {
+    let pooled_nodes = pool.get_mut(scene_path)?;
+    std::iter::from_fn(|| pooled_nodes.pop_front()).find(|node| is_valid(node))
+}
+
+/// Forgets tracked nodes which are no longer the live node at their path and
+/// adopts the live node at each recorded path which isn't tracked yet
+fn reconcile_spawned_nodes<N: PartialEq>(
+    spawned_nodes: &mut HashMap<String, N>,
+    recorded_paths: Vec<String>,
+    live_node: impl Fn(&str) -> Option<N>,
+) {
+    spawned_nodes.retain(|node_path, node| live_node(node_path).as_ref() == Some(&*node));
+
+    for node_path in recorded_paths {
+        if spawned_nodes.contains_key(&node_path) {
+            continue;
+        }
+
+        if let Some(node) = live_node(&node_path) {
+            spawned_nodes.insert(node_path, node);
+        }
+    }
+}
+
+impl Drop for SpawnManager {
+    fn drop(&mut self) {
+        // Pooled nodes are outside of the tree, so nothing else frees them
+        for node in self.pool.get_mut().drain().flat_map(|(_, nodes)| nodes) {
+            if node.is_instance_valid() {
+                node.free();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use super::{reconcile_spawned_nodes, return_to_pool, take_from_pool};
+
+    #[test]
+    fn resync_reconciles_the_tracked_nodes_with_the_tree() {
+        // Node ids by path as they are in the tree after a scene reload
+        let tree = HashMap::from([
+            ("/root/Level/Kept".to_string(), 1),
+            ("/root/Level/Replaced".to_string(), 5),
+            ("/root/Level/Untracked".to_string(), 3),
+        ]);
+        let mut spawned_nodes = HashMap::from([
+            ("/root/Level/Kept".to_string(), 1),
+            ("/root/Level/Replaced".to_string(), 2),
+            ("/root/Level/Freed".to_string(), 4),
+        ]);
+        let recorded_paths = vec![
+            "/root/Level/Kept".to_string(),
+            "/root/Level/Replaced".to_string(),
+            "/root/Level/Untracked".to_string(),
+            "/root/Level/Missing".to_string(),
+        ];
+
+        reconcile_spawned_nodes(&mut spawned_nodes, recorded_paths, |node_path| {
+            tree.get(node_path).copied()
+        });
+
+        assert_eq!(spawned_nodes, tree);
+    }
+
+    #[test]
+    fn pooled_nodes_are_reused_on_respawn() {
+        const BULLET: &str = "res://bullet.tscn";
+
+        let mut pool = HashMap::new();
+        let mut instantiated = 0;
+        let mut spawn = |pool: &mut HashMap<String, _>, freed: &HashSet<u32>| {
+            take_from_pool(pool, BULLET, |node| !freed.contains(node)).unwrap_or_else(|| {
+                instantiated += 1;
+                instantiated
+            })
+        };
+        let freed = HashSet::new();
+
+        let bullet = spawn(&mut pool, &freed);
+        assert_eq!(
+            return_to_pool(&mut pool, Some(BULLET.into()), true, bullet),
+            None
+        );
+        // A rollback spawns the bullet again, getting the same node back
+        assert_eq!(spawn(&mut pool, &freed), bullet);
+
+        // Nodes freed by game code while pooled are skipped
+        assert_eq!(
+            return_to_pool(&mut pool, Some(BULLET.into()), true, bullet),
+            None
+        );
+        let freed = HashSet::from([bullet]);
+        assert_ne!(spawn(&mut pool, &freed), bullet);
+
+        // Without pooling or a resource path the node is handed back to free
+        assert_eq!(
+            return_to_pool(&mut pool, Some(BULLET.into()), false, 7),
+            Some(7)
+        );
+        assert_eq!(
+            return_to_pool(&mut pool, Some(String::new()), true, 7),
+            Some(7)
+        );
+        assert_eq!(return_to_pool(&mut pool, None, true, 7), Some(7));
+        assert!(pool.values().all(|nodes| nodes.is_empty()));
+    }
+}
@@ -2,11 +2,44 @@ use anyhow::Result;
 use godot::{engine::utilities::bytes_to_var, prelude::*};
 
 use crate::{
-    logging::LogReader, message::Message, play_stage::PlayStage, sync_stage::SyncStage, Context,
+    logging::LogReader,
+    message::Message,
+    play_stage::{PlayStage, SpawnRecord},
+    sync_stage::SyncStage,
+    Context,
 };
 
+/// The spawns and despawns logged for a single frame
+pub struct LoggedSpawnEvents {
+    pub spawned: Vec<(String, SpawnRecord)>,
+    pub despawned: Vec<String>,
+}
+
+/// Fraction of a replay of the given length simulated through the latest
+/// tick, from 0 to 1. Empty replays make no progress
+fn replay_progress(latest_tick: u64, length: u64) -> f64 {
+    if length == 0 {
+        return 0.0;
+    }
+
+    (latest_tick as f64 / length as f64).min(1.0)
+}
+
+/// The tick seeking to a marker logged on the given tick stops at, or None if
+/// the replay already simulated past it. Markers past the last logged frame
+/// stop at the end of the replay
+fn marker_seek_target(marker_tick: u64, latest_tick: u64, length: u64) -> Option<u64> {
+    if marker_tick < latest_tick {
+        return None;
+    }
+
+    Some(marker_tick.min(length).max(latest_tick))
+}
+
 pub struct ReplayStage {
     log_reader: LogReader,
+    /// Number of frames with logged input. Logs without any input are empty
+    length: u64,
     pub play_stage: PlayStage,
 }
 
@@ -14,29 +47,79 @@ impl ReplayStage {
     pub fn new(log_reader: LogReader, cx: &mut Context) -> Result<Self> {
         let run_info = log_reader.run_infos()?[0].clone();
         cx.set_replay(run_info);
+        let length = log_reader.frame_count().unwrap_or(0);
+        // Input is first sent for the tick after the start offset, so the
+        // replay has to start recording input on the same tick
+        if let Some(first_input_frame) = log_reader.first_input_frame()? {
+            cx.set_start_offset(first_input_frame.saturating_sub(2) as u32);
+        }
         Ok(Self {
             log_reader,
+            length,
             play_stage: PlayStage::new(Vec::new(), cx),
         })
     }
 
-    pub fn tick(&mut self, node: &mut Gd<Node>, cx: &Context) -> Result<Option<SyncStage>> {
-        let received_inputs = self
-            .log_reader
-            .received_inputs_for_tick(cx.latest_tick() + 1)?;
-        for received_input in received_inputs {
-            self.play_stage.handle_message(
-                Message::Input {
-                    sent_input: received_input,
-                    last_received_frame: cx.latest_tick(),
-                },
-                cx,
-            )?;
+    pub fn tick(&mut self, node: &mut Gd<Node>, cx: &mut Context) -> Result<Option<SyncStage>> {
+        if cx.network_replay() {
+            // Deliver exactly what the replayed peer applied on this tick so
+            // that stalls and rollbacks happen just as they did live
+            let messages = self
+                .log_reader
+                .received_messages_for_tick(cx.local_id(), self.play_stage.processed_ticks())?;
+            for message in messages {
+                self.play_stage.handle_message(message, cx)?;
+            }
+        } else {
+            let received_inputs = self
+                .log_reader
+                .received_inputs_for_tick(cx.latest_tick() + 1)?;
+            for received_input in received_inputs {
+                self.play_stage.handle_message(
+                    Message::Input {
+                        sent_input: received_input,
+                        last_received_frame: cx.latest_tick(),
+                        received_through: 0,
+                    },
+                    cx,
+                )?;
+            }
         }
         self.play_stage.tick(node, cx)?;
         Ok(None)
     }
 
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Fraction of the replay simulated so far, from 0 to 1. Based on the
+    /// latest tick rather than the current one so that it doesn't jump back
+    /// during rollbacks.
+    pub fn progress(&self, cx: &Context) -> f64 {
+        replay_progress(cx.latest_tick(), self.length)
+    }
+
+    /// The markers logged by the replayed peer as (tick, label) pairs
+    pub fn markers(&self, cx: &Context) -> Result<Vec<(u64, String)>> {
+        self.log_reader.markers(cx.local_id())
+    }
+
+    /// The tick the marker with the given label was logged on
+    pub fn marker_tick(&self, label: &str, cx: &Context) -> Result<Option<u64>> {
+        Ok(self
+            .markers(cx)?
+            .into_iter()
+            .find(|(_, marker_label)| marker_label == label)
+            .map(|(tick, _)| tick))
+    }
+
+    /// The tick seeking to a marker logged on the given tick stops at, or
+    /// None if it has already been passed
+    pub fn seek_target(&self, marker_tick: u64, cx: &Context) -> Option<u64> {
+        marker_seek_target(marker_tick, cx.latest_tick(), self.length)
+    }
+
     pub fn input(&self, id: String, cx: &Context) -> Variant {
         self.play_stage.input(id, cx)
     }
@@ -50,7 +133,72 @@ impl ReplayStage {
         bytes_to_var(PackedByteArray::from(&input_bytes[..]))
     }
 
+    /// Reconstructs the spawn records logged for the given tick, loading each
+    /// spawned scene from its resource path. Returns the spawned node paths
+    /// along with their records.
+    pub fn logged_spawns(&self, tick: u64, cx: &Context) -> Result<Vec<(String, SpawnRecord)>> {
+        Ok(self
+            .log_reader
+            .events_for_frame(cx.local_id(), tick, "spawned")?
+            .into_iter()
+            .filter_map(|event| SpawnRecord::from_log_data(&event.data))
+            .collect())
+    }
+
+    /// Returns the node paths logged as despawned during the given tick
+    pub fn logged_despawns(&self, tick: u64, cx: &Context) -> Result<Vec<String>> {
+        Ok(self
+            .log_reader
+            .events_for_frame(cx.local_id(), tick, "despawned")?
+            .into_iter()
+            .map(|event| event.data)
+            .collect())
+    }
+
+    pub fn logged_spawn_events(&self, tick: u64, cx: &Context) -> Result<LoggedSpawnEvents> {
+        Ok(LoggedSpawnEvents {
+            spawned: self.logged_spawns(tick, cx)?,
+            despawned: self.logged_despawns(tick, cx)?,
+        })
+    }
+
     pub fn advantage(&self) -> f64 {
         self.play_stage.advantage()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{marker_seek_target, replay_progress};
+
+    #[test]
+    fn progress_advances_from_zero_to_one() {
+        let progress = (0..=250)
+            .map(|latest_tick| replay_progress(latest_tick, 200))
+            .collect::<Vec<_>>();
+
+        assert_eq!(progress[0], 0.0);
+        assert_eq!(progress[100], 0.5);
+        assert!(progress.windows(2).all(|pair| pair[0] <= pair[1]));
+        // Ticks simulated past the last logged frame stay at the end
+        assert_eq!(progress[200], 1.0);
+        assert_eq!(progress[250], 1.0);
+    }
+
+    #[test]
+    fn empty_replays_make_no_progress() {
+        assert_eq!(replay_progress(0, 0), 0.0);
+        assert_eq!(replay_progress(10, 0), 0.0);
+    }
+
+    #[test]
+    fn seeking_stops_on_the_marker_tick() {
+        assert_eq!(marker_seek_target(120, 30, 200), Some(120));
+        assert_eq!(marker_seek_target(30, 30, 200), Some(30));
+        // Replays only simulate forward
+        assert_eq!(marker_seek_target(20, 30, 200), None);
+        // Markers logged after the last frame with input stop at the end
+        assert_eq!(marker_seek_target(250, 30, 200), Some(200));
+        assert_eq!(marker_seek_target(250, 220, 200), Some(220));
+    }
+}
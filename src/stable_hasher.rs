@@ -0,0 +1,116 @@
+use std::hash::Hasher;
+
+/// FNV-1a hasher for hashes which are compared between peers. Unlike
+/// DefaultHasher its output is the same on every platform and Rust version,
+/// so peers built with different toolchains still agree on identical state.
+/// Integers are hashed as little endian bytes, and usizes as u64s, for the
+/// same reason.
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub fn new() -> Self {
+        Self(StableHasher::OFFSET_BASIS)
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(StableHasher::PRIME);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16);
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hash::{Hash, Hasher};
+
+    use super::StableHasher;
+
+    fn hash(value: impl Hash) -> u64 {
+        let mut hasher = StableHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn raw_bytes_match_reference_fnv1a() {
+        let mut hasher = StableHasher::new();
+        assert_eq!(hasher.finish(), 0xcbf2_9ce4_8422_2325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+
+        let mut hasher = StableHasher::new();
+        hasher.write(b"foobar");
+        assert_eq!(hasher.finish(), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn integers_hash_as_little_endian() {
+        assert_eq!(hash(0x0102_0304u32), 0xb345_225e_3644_edb5);
+        assert_eq!(hash(0x0102_0304_0506_0708u64), 0x0c6d_4496_e178_59d5);
+        assert_eq!(hash(-1i32), 0x994f_7665_3e2a_3951);
+        assert_eq!(hash(42usize), hash(42u64));
+        assert_eq!(hash(42usize), 0xff3a_dd6b_3789_daef);
+    }
+
+    #[test]
+    fn strings_and_slices_have_fixed_hashes() {
+        assert_eq!(hash("foobar"), 0x3452_4ba7_168a_2c15);
+        assert_eq!(hash(&[1u8, 2, 3][..]), 0x01ef_76d4_29b1_1552);
+    }
+}
@@ -1,12 +1,99 @@
-use godot::prelude::*;
+use std::net::{IpAddr, Ipv6Addr};
+
+use anyhow::anyhow;
+use godot::{
+    engine::{Engine, ProjectSettings},
+    prelude::*,
+};
 use itertools::Itertools;
-use udp_ext::persistent::PersistentEvent;
+use udp_ext::persistent::{PersistentEvent, PersistentSocketSender};
+use uuid::Uuid;
 
 use crate::{
-    lobby_stage::LobbyStage, logging::LogReader, message::Message, play_stage::PlayStage,
-    replay_stage::ReplayStage, sync_stage::SyncStage, Context,
+    lobby_stage::LobbyStage,
+    logging::{EventCategory, EventSeverity, LogConfig, LogReader},
+    message::Message,
+    peer_set_check::{PeerSetCheck, EARLY_PLAY_TICKS},
+    play_stage::{
+        implemented_callbacks, networked_nodes_in_domain, DisconnectPolicy, InputPrediction,
+        PlayStage, DOMAIN_ROOT_GROUP, MIN_MAX_REWIND,
+    },
+    replay_stage::ReplayStage,
+    sync_stage::SyncStage,
+    Context,
 };
 
+/// Combines the ip and port into an address which can be resolved. IPv6
+/// addresses need brackets to separate them from the port
+fn join_address(ip: &str, port: u32) -> String {
+    if ip.parse::<Ipv6Addr>().is_ok() {
+        format!("[{ip}]:{port}")
+    } else {
+        format!("{ip}:{port}")
+    }
+}
+
+/// Pauses or resumes the match on behalf of the given peer. Does nothing
+/// if the match is already in that state
+pub(crate) fn set_paused(paused: &mut bool, requested: bool, id: Uuid, cx: &mut Context) {
+    if *paused == requested {
+        return;
+    }
+
+    *paused = requested;
+    let name = if requested { "paused" } else { "resumed" };
+    cx.logger()
+        .event(
+            EventSeverity::Info,
+            Some(EventCategory::Match),
+            name.to_string(),
+            id.to_string(),
+            cx,
+        )
+        .expect("Could not log pause");
+    cx.queue_signal(
+        "pause_changed",
+        vec![requested.to_variant(), id.to_string().to_variant()],
+    );
+}
+
+/// Pauses or resumes the match locally and on every peer. Spectators can't
+/// pause the players, so only follow their pauses
+pub(crate) fn request_pause(
+    paused: &mut bool,
+    requested: bool,
+    stage: &SyncStage,
+    cx: &mut Context,
+) -> bool {
+    if !matches!(stage, SyncStage::Play(_)) || stage.is_spectating() {
+        return false;
+    }
+
+    set_paused(paused, requested, cx.local_id(), cx);
+    // Peers left out for having too many packets in flight would be left
+    // running, so they are sent the request anyway
+    let throttled = cx
+        .broadcast(Message::PauseRequest(requested))
+        .expect("Could not broadcast pause request");
+    for peer in throttled {
+        cx.send_to(peer, Message::PauseRequest(requested))
+            .expect("Could not send pause request");
+    }
+    true
+}
+
+/// Godot binds the sync manager for the duration of every call into it, so
+/// anything which synchronously calls back into the same `Gd<Self>` while that
+/// borrow is held panics. Signal handlers are the usual culprit since game code
+/// tends to call sync manager functions in response to them. To keep that from
+/// happening:
+///
+/// - Stages never emit signals directly. They queue them on the Context and
+///   the sync manager emits them deferred once the borrow has been released.
+/// - Work which needs to bind the sync manager itself, such as executing a
+///   tick or starting the game, is scheduled with `call_deferred`.
+/// - `#[func(gd_self)]` functions only emit signals after their
+///   `bind`/`bind_mut` scope has ended.
 #[derive(GodotClass)]
 #[class(base = Node)]
 pub struct RollbackSyncManager {
@@ -14,6 +101,16 @@ pub struct RollbackSyncManager {
 
     pub stage: SyncStage,
 
+    peer_set_check: PeerSetCheck,
+
+    /// When set, only networked nodes below this node are managed. Lets
+    /// several sync managers, such as concurrent replays, run side by side
+    pub domain_root: Option<Gd<Node>>,
+
+    /// Set while the match is paused. The socket is still pumped so
+    /// connections stay alive, but the stage isn't ticked
+    paused: bool,
+
     pub node: Base<Node>,
 }
 
@@ -25,6 +122,12 @@ impl INode for RollbackSyncManager {
 
             stage: SyncStage::Lobby(LobbyStage::new()),
 
+            peer_set_check: PeerSetCheck::new(),
+
+            domain_root: None,
+
+            paused: false,
+
             node,
         }
     }
@@ -36,25 +139,101 @@ impl INode for RollbackSyncManager {
     }
 
     fn physics_process(&mut self, _: f64) {
-        let socket_results = self.context.pump_socket().expect("Couldn't pump socket");
+        self.process_tick();
+        self.emit_queued_signals();
+    }
+}
 
-        let messages = socket_results.into_iter().filter_map(|(message, address)| {
-            if let PersistentEvent::FrameCompleted(_, mut message) = message {
-                Some((message.read_serializable()?, address))
-            } else {
-                None
-            }
+impl RollbackSyncManager {
+    /// Handles received messages and ticks the current stage. Shared by
+    /// physics_process and step_ticks.
+    fn process_tick(&mut self) {
+        let socket_results = self.context.pump_socket().unwrap_or_else(|err| {
+            godot_error!("Socket stopped receiving: {err}");
+            self.context
+                .queue_signal("socket_failed", vec![Variant::from(err.to_string())]);
+            Vec::new()
         });
 
+        let mut disconnected_peers = Vec::new();
+        let mut dropped_packets = Vec::new();
+        let messages = socket_results
+            .into_iter()
+            .filter_map(|(message, address)| match message {
+                PersistentEvent::FrameCompleted(_, mut message) => {
+                    Some((message.read_serializable()?, address))
+                }
+                PersistentEvent::PacketDropped(_) => {
+                    dropped_packets.push(address.to_string());
+                    None
+                }
+                PersistentEvent::PeerDisconnected => {
+                    if let PersistentSocketSender::Connected(id) = address {
+                        disconnected_peers.push(id);
+                    }
+                    None
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let checking_peer_set = self.checking_peer_set();
         for (message, address) in messages {
+            if let Message::PeerSetHash(hash) = message {
+                if let PersistentSocketSender::Connected(id) = address {
+                    if checking_peer_set {
+                        self.peer_set_check.record_hash(id, hash, &mut self.context);
+                    }
+                }
+                continue;
+            }
+            if let Message::PauseRequest(paused) = message {
+                if let PersistentSocketSender::Connected(id) = address {
+                    set_paused(&mut self.paused, paused, id, &mut self.context);
+                }
+                continue;
+            }
+
             self.stage
-                .handle_message(&mut self.node.to_gd(), message, address, &mut self.context)
+                .handle_message(message, address, &mut self.context)
                 .expect("Couldn't handle message");
         }
 
-        self.stage
-            .tick(&mut self.node.to_gd(), &mut self.context)
-            .expect("Could not tick stage");
+        for sender in dropped_packets {
+            self.context
+                .logger()
+                .event(
+                    EventSeverity::Warning,
+                    Some(EventCategory::Network),
+                    "packet_dropped".to_string(),
+                    sender,
+                    &self.context,
+                )
+                .expect("Could not log event");
+        }
+
+        for peer in disconnected_peers {
+            let end_match = self
+                .stage
+                .peer_disconnected(peer, &mut self.context)
+                .expect("Couldn't handle disconnect");
+            if end_match {
+                self.end_match();
+            }
+        }
+
+        let paused = self.paused && matches!(self.stage, SyncStage::Play(_));
+        if !paused {
+            self.stage
+                .tick(&mut self.node.to_gd(), &mut self.context)
+                .expect("Could not tick stage");
+        }
+
+        if checking_peer_set {
+            self.peer_set_check
+                .tick(&mut self.context)
+                .expect("Could not check peer set");
+        }
     }
 }
 
@@ -67,7 +246,62 @@ impl RollbackSyncManager {
     #[signal]
     fn connected(id: String);
     #[signal]
+    fn connect_finished(success: bool);
+    #[signal]
     fn started();
+    #[signal]
+    fn connectivity_checked(report: Dictionary);
+    #[signal]
+    fn match_ended();
+    #[signal]
+    fn peer_set_mismatch(id: String);
+    #[signal]
+    fn start_refused(reason: String);
+    #[signal]
+    fn peer_disconnected(id: String);
+    #[signal]
+    fn resynced(tick: u64);
+    #[signal]
+    fn rollback_occurred(from: u64, to: u64);
+    #[signal]
+    fn desync_detected(tick: u64, local_hash: i64, remote_hash: i64);
+    #[signal]
+    fn pause_changed(paused: bool, id: String);
+    #[signal]
+    fn desync_details(tick: u64, peer: String, differences: Array<Dictionary>);
+    #[signal]
+    fn socket_failed(error: String);
+    #[signal]
+    fn frame_inputs_complete(tick: u64);
+    #[signal]
+    fn clock_drift(id: String, ticks: f64);
+    #[signal]
+    fn scheduled_event(name: String);
+    #[signal]
+    fn scheduled_event_confirmed(name: String);
+
+    /// Peer sets are compared in the lobby and early in play, but not during
+    /// replays since no messages are exchanged.
+    fn checking_peer_set(&self) -> bool {
+        match &self.stage {
+            SyncStage::Lobby(_) => true,
+            SyncStage::Play(play_stage) => {
+                self.context.latest_tick() < EARLY_PLAY_TICKS && !play_stage.is_spectating()
+            }
+            SyncStage::Replay(_) => false,
+        }
+    }
+
+    /// Emits the signals queued on the Context. Deferred so that signal
+    /// handlers are free to call back into the sync manager.
+    fn emit_queued_signals(&mut self) {
+        for (signal, args) in self.context.take_queued_signals() {
+            let mut call_args = vec![Variant::from(signal)];
+            call_args.extend(args);
+            self.base_mut()
+                .call_deferred("emit_signal".into(), &call_args);
+        }
+    }
 
     // LOBBY APIS
 
@@ -75,9 +309,117 @@ impl RollbackSyncManager {
     pub fn update_ready(&mut self, value: bool) {
         if let SyncStage::Lobby(lobby) = &mut self.stage {
             lobby
-                .update_ready(&mut self.node.to_gd(), value, &mut self.context)
+                .update_ready(value, &mut self.context)
                 .expect("Couldn't update ready");
         }
+        self.emit_queued_signals();
+    }
+
+    #[func]
+    pub fn check_connectivity(&mut self) {
+        if let SyncStage::Lobby(lobby) = &mut self.stage {
+            lobby
+                .check_connectivity(&mut self.context)
+                .expect("Couldn't check connectivity");
+        }
+        self.emit_queued_signals();
+    }
+
+    #[func]
+    pub fn connectivity_report(&mut self) -> Dictionary {
+        if let SyncStage::Lobby(lobby) = &self.stage {
+            lobby.connectivity_report()
+        } else {
+            Dictionary::new()
+        }
+    }
+
+    /// Approximate fraction of packets to the peer which were lost, from 0 to
+    /// 1. Always 0 during a replay or for unknown peers
+    #[func]
+    pub fn packet_loss(&mut self, peer: String) -> f32 {
+        let Ok(peer) = Uuid::parse_str(&peer) else {
+            godot_warn!("{peer} is not a valid peer id");
+            return 0.0;
+        };
+
+        self.context.packet_loss(peer)
+    }
+
+    /// Standard deviation of the response times to the peer in milliseconds.
+    /// -1 if there are no measurements yet or during a replay
+    #[func]
+    pub fn response_jitter(&mut self, peer: String) -> f64 {
+        let Ok(peer) = Uuid::parse_str(&peer) else {
+            godot_warn!("{peer} is not a valid peer id");
+            return -1.0;
+        };
+        if self.context.is_replay() {
+            return -1.0;
+        }
+
+        self.context
+            .response_time_deviation(peer)
+            .map(|deviation| deviation.as_secs_f64() * 1000.0)
+            .unwrap_or(-1.0)
+    }
+
+    #[func]
+    pub fn set_lobby_setting(&mut self, key: String, value: Variant) {
+        if let SyncStage::Lobby(lobby) = &mut self.stage {
+            lobby
+                .set_setting(key, value, &mut self.context)
+                .expect("Couldn't set lobby setting");
+        }
+    }
+
+    #[func]
+    pub fn set_simulation_rate(&mut self, rate: u32) {
+        if rate == 0 {
+            godot_warn!("The simulation rate must be at least one tick per second");
+            return;
+        }
+
+        if let SyncStage::Lobby(lobby) = &mut self.stage {
+            lobby
+                .set_simulation_rate(rate, &mut self.context)
+                .expect("Couldn't set simulation rate");
+            Engine::singleton().set_physics_ticks_per_second(rate as i32);
+        } else {
+            godot_warn!("The simulation rate can only be changed in the lobby");
+        }
+        self.emit_queued_signals();
+    }
+
+    #[func]
+    pub fn simulation_rate(&mut self) -> u32 {
+        self.context.simulation_rate()
+    }
+
+    #[func]
+    pub fn set_start_offset(&mut self, ticks: u32) {
+        if let SyncStage::Lobby(lobby) = &mut self.stage {
+            lobby
+                .set_start_offset(ticks, &mut self.context)
+                .expect("Couldn't set start offset");
+        } else {
+            godot_warn!("The start offset can only be changed in the lobby");
+        }
+        self.emit_queued_signals();
+    }
+
+    #[func]
+    pub fn start_offset(&mut self) -> u32 {
+        self.context.start_offset()
+    }
+
+    #[func]
+    pub fn lobby_settings(&mut self) -> Dictionary {
+        if let SyncStage::Lobby(lobby) = &self.stage {
+            lobby.settings()
+        } else {
+            Dictionary::new()
+        }
     }
 
     #[func(gd_self)]
@@ -94,23 +436,196 @@ impl RollbackSyncManager {
         this.emit_signal("started".into(), &[]);
     }
 
+    /// The markers logged in the current replay as dictionaries with the
+    /// marker's label and tick, ordered by tick. Empty outside of a replay
+    #[func]
+    pub fn replay_markers(&mut self) -> Array<Dictionary> {
+        let SyncStage::Replay(replay_stage) = &self.stage else {
+            return Array::new();
+        };
+
+        replay_stage
+            .markers(&self.context)
+            .expect("Could not read replay markers")
+            .into_iter()
+            .map(|(tick, label)| {
+                let mut marker = Dictionary::new();
+                marker.set("label", label);
+                marker.set("tick", tick as i64);
+                marker
+            })
+            .collect()
+    }
+
+    /// Simulates the replay up to the tick the marker with the given label
+    /// was logged on. Replays can only be simulated forward, so returns false
+    /// if the marker doesn't exist or has already been passed.
+    #[func(gd_self)]
+    pub fn replay_seek_to_marker(mut this: Gd<Self>, label: String) -> bool {
+        let target_tick = {
+            let sync_manager = this.bind();
+            let SyncStage::Replay(replay_stage) = &sync_manager.stage else {
+                godot_warn!("Can only seek to markers during a replay");
+                return false;
+            };
+            let Some(marker_tick) = replay_stage
+                .marker_tick(&label, &sync_manager.context)
+                .expect("Could not read replay markers")
+            else {
+                godot_warn!("No marker named {label} in the replay");
+                return false;
+            };
+            replay_stage.seek_target(marker_tick, &sync_manager.context)
+        };
+
+        let Some(target_tick) = target_tick else {
+            godot_warn!("Marker {label} has already been passed");
+            return false;
+        };
+
+        // Stalled ticks don't advance the latest tick, so allow some extra
+        // ticks without looping forever on a replay that never gets there
+        let tick_budget = (target_tick - this.bind().context.latest_tick()) * 2;
+        for _ in 0..tick_budget {
+            if this.bind().context.latest_tick() >= target_tick {
+                break;
+            }
+            Self::run_tick(&this);
+        }
+
+        this.bind_mut().emit_queued_signals();
+        true
+    }
+
+    /// Number of frames in the current replay, or -1 outside of a replay
+    #[func]
+    pub fn replay_length(&mut self) -> i64 {
+        match &self.stage {
+            SyncStage::Replay(replay_stage) => replay_stage.length() as i64,
+            _ => -1,
+        }
+    }
+
+    #[func]
+    pub fn replay_progress(&mut self) -> f64 {
+        match &self.stage {
+            SyncStage::Replay(replay_stage) => replay_stage.progress(&self.context),
+            _ => 0.0,
+        }
+    }
+
+    #[func]
+    pub fn set_domain_root(&mut self, mut domain_root: Gd<Node>) {
+        if let Some(mut previous_root) = self.domain_root.take() {
+            if previous_root.is_instance_valid() {
+                previous_root.remove_from_group(DOMAIN_ROOT_GROUP.into());
+            }
+        }
+
+        domain_root.add_to_group(DOMAIN_ROOT_GROUP.into());
+        self.domain_root = Some(domain_root);
+    }
+
+    /// Lists the networked nodes this SyncManager manages along with which of
+    /// the networked callbacks each of them implements
+    #[func]
+    pub fn networked_nodes(&mut self) -> Array<Dictionary> {
+        networked_nodes_in_domain(&self.node.to_gd(), self.domain_root.as_ref())
+            .iter_shared()
+            .map(|node| {
+                let callbacks: PackedStringArray =
+                    implemented_callbacks(|method| node.has_method(method.into()))
+                        .into_iter()
+                        .map(GString::from)
+                        .collect();
+                let mut row = Dictionary::new();
+                row.set("path", node.get_path());
+                row.set("callbacks", callbacks);
+                row
+            })
+            .collect()
+    }
+
+    #[func]
+    pub fn set_faithful_replay(&mut self, faithful_replay: bool) {
+        self.context.set_faithful_replay(faithful_replay);
+    }
+
+    #[func]
+    pub fn set_network_replay(&mut self, network_replay: bool) {
+        self.context.set_network_replay(network_replay);
+    }
+
     #[func]
     fn host(&mut self, port: u16) {
         godot_print!("Hosting on port {}", port);
         self.context.set_port(port).expect("Could not set port");
     }
 
+    /// Hosts like `host`, but only listens on the local interface with the
+    /// given address
+    #[func]
+    fn host_on(&mut self, address: String, port: u16) {
+        let Ok(ip) = address.parse::<IpAddr>() else {
+            godot_warn!("{} is not a valid IP address", address);
+            return;
+        };
+
+        godot_print!("Hosting on {}:{}", ip, port);
+        self.context.bind(ip, port).expect("Could not bind address");
+    }
+
     #[func]
     fn join(&mut self, ip: String, port: u32) {
         godot_print!("Connecting to {}:{}", ip, port);
         self.context
             .send_to_address(
-                format!("{}:{}", ip, port),
+                join_address(&ip, port),
                 Message::Connect(self.context.local_id()),
             )
             .expect("Could not send message");
     }
 
+    /// Joins like `join`, but returns a signal which can be awaited for the
+    /// result. The signal passes true once the peer replies or false if the
+    /// timeout runs out first.
+    #[func]
+    fn connect_and_wait(&mut self, ip: String, port: u32, timeout: f64) -> Signal {
+        godot_print!("Connecting to {}:{}", ip, port);
+        let result = if let SyncStage::Lobby(lobby) = &mut self.stage {
+            lobby.connect_and_wait(join_address(&ip, port), timeout, &mut self.context)
+        } else {
+            Err(anyhow!("Can only connect from the lobby"))
+        };
+
+        if let Err(error) = result {
+            godot_warn!("Could not connect: {error}");
+            self.context
+                .queue_signal("connect_finished", vec![Variant::from(false)]);
+        }
+        // Emitted deferred, so the caller has a chance to await the signal
+        // first
+        self.emit_queued_signals();
+
+        Signal::from_object_signal(&self.node.to_gd(), "connect_finished")
+    }
+
+    /// Asks the peer at the address to let this peer watch the match it is
+    /// playing. The `started` signal is emitted once the peer replies.
+    #[func]
+    fn join_as_spectator(&mut self, ip: String, port: u32) {
+        godot_print!("Spectating {}:{}", ip, port);
+        let result = if let SyncStage::Lobby(lobby) = &mut self.stage {
+            lobby.join_as_spectator(join_address(&ip, port), &mut self.context)
+        } else {
+            Err(anyhow!("Can only join as a spectator from the lobby"))
+        };
+
+        if let Err(error) = result {
+            godot_warn!("Could not join as a spectator: {error}");
+        }
+    }
+
     #[func(gd_self)]
     fn start_game(mut this: Gd<Self>) {
         {
@@ -140,12 +655,15 @@ impl RollbackSyncManager {
             .collect()
     }
 
+    /// The ids of every player, which doesn't include the local id while
+    /// spectating
     #[func]
     pub fn ids(&mut self) -> Array<Variant> {
+        let local_id = (!self.stage.is_spectating()).then(|| self.context.local_id());
         self.context
             .peers()
             .into_iter()
-            .chain(std::iter::once(self.context.local_id()))
+            .chain(local_id)
             .sorted()
             .map(|id| Variant::from(id.to_string()))
             .collect()
@@ -156,6 +674,14 @@ impl RollbackSyncManager {
         self.context.is_leader()
     }
 
+    #[func]
+    pub fn force_resync(&mut self) -> i64 {
+        self.stage
+            .force_resync(&mut self.context)
+            .expect("Couldn't force resync")
+            .map_or(-1, |tick| tick as i64)
+    }
+
     #[func]
     pub fn input(&mut self, id: String) -> Variant {
         self.stage.input(id, &self.context)
@@ -166,11 +692,307 @@ impl RollbackSyncManager {
         self.stage.advantage()
     }
 
+    #[func]
+    pub fn advantage_for_peer(&mut self, id: String) -> f64 {
+        let Ok(id) = Uuid::parse_str(&id) else {
+            return 0.0;
+        };
+        self.stage.advantage_for_peer(id)
+    }
+
+    #[func]
+    pub fn frame_hash(&mut self, tick: i64) -> i64 {
+        u64::try_from(tick)
+            .ok()
+            .and_then(|tick| self.stage.frame_hash(tick))
+            .map(|hash| hash as i64)
+            .unwrap_or(0)
+    }
+
+    #[func]
+    pub fn pin_frame(&mut self, tick: i64) -> bool {
+        u64::try_from(tick)
+            .map(|tick| self.stage.pin_frame(tick))
+            .unwrap_or(false)
+    }
+
+    #[func]
+    pub fn unpin_frame(&mut self, tick: i64) {
+        if let Ok(tick) = u64::try_from(tick) {
+            self.stage.unpin_frame(tick);
+        }
+    }
+
+    #[func]
+    pub fn dump_debug_state(&mut self, path: String) -> bool {
+        let Some(debug_state) = self.stage.dump_debug_state(&self.context) else {
+            godot_warn!("Debug state can only be dumped during play or replay");
+            return false;
+        };
+
+        let path: String = ProjectSettings::singleton()
+            .globalize_path(path.into())
+            .into();
+        let result = serde_json::to_string_pretty(&debug_state)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(std::fs::write(&path, json)?));
+        if let Err(error) = result {
+            godot_warn!("Could not dump debug state to {path}: {error}");
+            return false;
+        }
+
+        true
+    }
+
+    #[func]
+    pub fn peer_input_age(&mut self, id: String) -> i64 {
+        let Ok(id) = Uuid::parse_str(&id) else {
+            return -1;
+        };
+        self.stage.peer_input_age(id, &self.context)
+    }
+
+    #[func]
+    pub fn buffered_frames(&mut self, id: String) -> i64 {
+        let Ok(id) = Uuid::parse_str(&id) else {
+            return 0;
+        };
+        self.stage.buffered_frames(id, &self.context)
+    }
+
+    #[func]
+    pub fn rollback_count(&mut self) -> u64 {
+        self.stage.rollback_count()
+    }
+
+    #[func]
+    pub fn stalled_ticks(&mut self) -> u64 {
+        self.stage.stalled_ticks()
+    }
+
+    #[func]
+    pub fn schedule_at(&mut self, tick: i64, name: String) -> bool {
+        u64::try_from(tick)
+            .map(|tick| self.stage.schedule_at(tick, name, &self.context))
+            .unwrap_or(false)
+    }
+
+    #[func]
+    pub fn should_play_once(&mut self, effect_id: String) -> bool {
+        self.stage.should_play_once(effect_id, &self.context)
+    }
+
+    #[func]
+    pub fn is_resimulating(&mut self) -> bool {
+        self.stage.is_resimulating()
+    }
+
+    #[func]
+    pub fn is_spectating(&mut self) -> bool {
+        self.stage.is_spectating()
+    }
+
+    #[func]
+    pub fn pause(&mut self) -> bool {
+        request_pause(&mut self.paused, true, &self.stage, &mut self.context)
+    }
+
+    #[func]
+    pub fn resume(&mut self) -> bool {
+        request_pause(&mut self.paused, false, &self.stage, &mut self.context)
+    }
+
+    #[func]
+    pub fn is_paused(&mut self) -> bool {
+        self.paused
+    }
+
+    #[func]
+    pub fn set_buffered_input(&mut self, buffered_input: bool) {
+        self.context.set_buffered_input(buffered_input);
+    }
+
+    #[func]
+    pub fn buffer_input(&mut self, event: Variant) {
+        // Events buffered outside of play would be delivered in a stale batch
+        // on the first tick
+        if let SyncStage::Play(_) = self.stage {
+            self.context.buffer_input(event);
+        }
+    }
+
+    #[func]
+    pub fn set_jitter_buffer(&mut self, jitter_buffer: bool) {
+        self.context.set_jitter_buffer(jitter_buffer);
+    }
+
+    /// Sets how many frames a peer's input may lag behind before the
+    /// simulation stalls. Takes effect from the next match
+    #[func]
+    pub fn set_max_rewind(&mut self, frames: u32) {
+        if (frames as u64) < MIN_MAX_REWIND {
+            godot_warn!("A rewind window of {frames} frames is too short. Using {MIN_MAX_REWIND}");
+        }
+        self.context.set_max_rewind(frames as u64);
+    }
+
+    #[func]
+    pub fn set_adaptive_rewind(&mut self, adaptive_rewind: bool) {
+        self.context.set_adaptive_rewind(adaptive_rewind);
+    }
+
+    #[func]
+    pub fn set_node_pooling(&mut self, node_pooling: bool) {
+        self.context.set_node_pooling(node_pooling);
+    }
+
+    #[func]
+    pub fn set_disconnect_policy(&mut self, policy: String) {
+        match DisconnectPolicy::from_name(&policy) {
+            Some(policy) => self.context.set_disconnect_policy(policy),
+            None => godot_warn!(
+                "Unknown disconnect policy {policy}. Expected pause, continue or end_match"
+            ),
+        }
+    }
+
+    #[func]
+    pub fn set_input_prediction(&mut self, prediction: String) {
+        match InputPrediction::from_name(&prediction) {
+            Some(prediction) => self.context.set_input_prediction(prediction),
+            None => godot_warn!(
+                "Unknown input prediction {prediction}. Expected default or repeat_last"
+            ),
+        }
+    }
+
+    #[func]
+    pub fn set_input_delay(&mut self, ticks: u32) {
+        self.context.set_input_delay(ticks as u64);
+    }
+
+    #[func]
+    pub fn set_adaptive_input_delay(&mut self, adaptive_input_delay: bool) {
+        self.context.set_adaptive_input_delay(adaptive_input_delay);
+    }
+
+    /// Repeats the last local input for up to the given number of ticks in
+    /// a row when the input source returns nothing. 0 disables repeating
+    #[func]
+    pub fn set_input_repeat_limit(&mut self, ticks: u32) {
+        self.context.set_input_repeat_limit(ticks);
+    }
+
+    /// Warns when a peer's clock drifts by more than the given number of
+    /// ticks. 0 disables the check
+    #[func]
+    pub fn set_clock_drift_threshold(&mut self, ticks: f64) {
+        self.context.set_clock_drift_threshold(ticks.max(0.0));
+    }
+
+    #[func]
+    pub fn input_delay(&mut self) -> u32 {
+        self.stage.input_delay(&self.context) as u32
+    }
+
+    /// Only writes logged states to the log on frames which are a multiple of
+    /// the interval. States are still hashed every frame. 0 logs every frame
+    /// like the default of 1
+    #[func]
+    pub fn set_state_log_interval(&mut self, frames: u32) {
+        self.context.set_state_log_interval(frames as u64);
+    }
+
+    /// Saves a serialized snapshot of the node states every `frames` frames.
+    /// Rollbacks start from the nearest snapshot instead of the states kept
+    /// on the frame. 0 disables checkpoints, the default
+    #[func]
+    pub fn set_checkpoint_interval(&mut self, frames: u32) {
+        self.context.set_checkpoint_interval(frames as u64);
+    }
+
+    /// Limits how many nodes game code may spawn while simulating a single
+    /// frame. Spawns past the cap return null and are logged as a
+    /// spawn_cap_exceeded warning for the frame. 0 or less, the default,
+    /// allows any number of spawns
+    #[func]
+    pub fn set_spawn_cap(&mut self, spawns: i64) {
+        self.context
+            .set_spawn_cap(usize::try_from(spawns).ok().filter(|cap| *cap > 0));
+    }
+
+    /// Rounds floats in logged states to a multiple of epsilon before they
+    /// are hashed for desync detection. 0 or less hashes states exactly
+    #[func]
+    pub fn set_state_hash_epsilon(&mut self, epsilon: f64) {
+        self.context
+            .set_state_hash_epsilon((epsilon > 0.0).then_some(epsilon));
+    }
+
+    #[func]
+    pub fn set_min_stall_interval(&mut self, frames: u32) {
+        self.context.set_min_stall_interval(frames as u64);
+    }
+
+    #[func]
+    pub fn set_advantage_stalling(&mut self, advantage_stalling: bool) {
+        self.context.set_advantage_stalling(advantage_stalling);
+    }
+
+    #[func]
+    pub fn set_input_validator(&mut self, validator: Callable) {
+        self.context
+            .set_input_validator(validator.is_valid().then_some(validator));
+    }
+
     #[func(gd_self)]
     fn execute_tick(this: Gd<Self>) {
         PlayStage::execute_tick(this);
     }
 
+    /// Synchronously runs the full tick pipeline the given number of times
+    /// rather than waiting on physics frames. Only available in debug builds
+    /// since stepping lets this peer run ahead of real time.
+    #[func(gd_self)]
+    fn step_ticks(mut this: Gd<Self>, ticks: u32) {
+        if !cfg!(debug_assertions) {
+            godot_warn!("step_ticks is only available in debug builds");
+            return;
+        }
+
+        for _ in 0..ticks {
+            Self::run_tick(&this);
+        }
+
+        this.bind_mut().emit_queued_signals();
+    }
+
+    /// Runs the full tick pipeline once, including execute_tick if the stage
+    /// requested it
+    fn run_tick(this: &Gd<Self>) {
+        let execute_tick = {
+            let mut this = this.clone();
+            let mut sync_manager = this.bind_mut();
+            sync_manager.context.set_stepping(true);
+            sync_manager.process_tick();
+            sync_manager.context.set_stepping(false);
+            sync_manager.context.take_execute_tick_request()
+        };
+
+        if execute_tick {
+            PlayStage::execute_tick(this.clone());
+        }
+    }
+
+    #[func(gd_self)]
+    fn resync_spawns(this: Gd<Self>) {
+        if matches!(this.bind().stage, SyncStage::Lobby(_)) {
+            return;
+        }
+
+        PlayStage::resync_spawns(this);
+    }
+
     #[func(gd_self)]
     fn despawn(this: Gd<Self>, node: Gd<Node>) {
         PlayStage::despawn(this, &node);
@@ -183,16 +1005,163 @@ impl RollbackSyncManager {
         parent: Gd<Node>,
         scene: Gd<PackedScene>,
         data: Dictionary,
-    ) -> Gd<Node> {
+    ) -> Option<Gd<Node>> {
         let data = Variant::from(data);
         PlayStage::spawn(this, name, &parent, scene, data)
     }
 
+    /// Ends the current match or replay. Deferred so that a tick in progress
+    /// finishes before the stage is torn down.
+    #[func]
+    fn end_match(&mut self) {
+        self.base_mut().call_deferred("finish_match".into(), &[]);
+    }
+
+    #[func(gd_self)]
+    fn finish_match(mut this: Gd<Self>) {
+        {
+            let mut this = this.bind_mut();
+            let this = &mut *this;
+            let ended = this
+                .stage
+                .end_match(&mut this.context)
+                .expect("Could not end match");
+            if !ended {
+                return;
+            }
+            this.paused = false;
+        }
+        this.emit_signal("match_ended".into(), &[]);
+    }
+
+    /// Logs a marker with the given label on the current tick. Replays of
+    /// the log can seek straight to it with replay_seek_to_marker
+    #[func]
+    fn mark(&mut self, label: String) {
+        self.context
+            .logger()
+            .event(
+                EventSeverity::Info,
+                Some(EventCategory::Match),
+                "marker".to_string(),
+                label,
+                &self.context,
+            )
+            .expect("Could not log marker");
+    }
+
     #[func]
     fn log(&mut self, event: String) {
         self.context
             .logger()
-            .event("GODOT".to_string(), event, &self.context)
+            .event(
+                EventSeverity::Info,
+                None,
+                "GODOT".to_string(),
+                event,
+                &self.context,
+            )
             .expect("Could not log event");
     }
+
+    /// Logs an event with a severity of info, warning or error and an
+    /// optional category the log viewer can filter and color by
+    #[func]
+    fn log_event(&mut self, event: String, severity: String, category: String) {
+        let Some(severity) = EventSeverity::from_name(&severity) else {
+            godot_warn!("Unknown event severity {severity}. Expected info, warning or error");
+            return;
+        };
+        let category = if category.is_empty() {
+            None
+        } else {
+            let Some(category) = EventCategory::from_name(&category) else {
+                godot_warn!("Unknown event category {category}");
+                return;
+            };
+            Some(category)
+        };
+
+        self.context
+            .logger()
+            .event(
+                severity,
+                category,
+                "GODOT".to_string(),
+                event,
+                &self.context,
+            )
+            .expect("Could not log event");
+    }
+
+    #[func]
+    fn set_log_compression(&mut self, compress: bool) {
+        let logger = self.context.logger();
+        logger.set_config(LogConfig {
+            compress_states: compress,
+            ..logger.config()
+        });
+    }
+
+    #[func]
+    fn set_max_log_backlog(&mut self, max_backlog: i64) {
+        let logger = self.context.logger();
+        logger.set_config(LogConfig {
+            max_backlog: usize::try_from(max_backlog).ok().filter(|max| *max > 0),
+            ..logger.config()
+        });
+    }
+
+    #[func]
+    fn log_backlog(&mut self) -> u64 {
+        self.context.logger().backlog() as u64
+    }
+
+    #[func]
+    fn dropped_log_states(&mut self) -> u64 {
+        self.context.logger().dropped_states() as u64
+    }
+
+    #[func]
+    fn log_journal_mode(&mut self) -> String {
+        self.context
+            .logger()
+            .journal_mode()
+            .map(|journal_mode| journal_mode.to_string())
+            .unwrap_or_default()
+    }
+
+    #[func]
+    fn set_packet_tracing(&mut self, capacity: i64) {
+        self.context.set_packet_tracing(
+            usize::try_from(capacity)
+                .ok()
+                .filter(|capacity| *capacity > 0),
+        );
+    }
+
+    /// Skips peers with at least the given number of unacknowledged packets
+    /// when broadcasting. 0 disables throttling
+    #[func]
+    fn set_max_in_flight(&mut self, packets: i64) {
+        self.context
+            .set_max_in_flight(usize::try_from(packets).ok().filter(|packets| *packets > 0));
+    }
+
+    #[func]
+    fn packet_trace(&mut self) -> Array<Dictionary> {
+        self.context
+            .packet_trace()
+            .into_iter()
+            .map(|event| {
+                let mut row = Dictionary::new();
+                row.set("packet_id", format!("{:?}", event.packet_id));
+                row.set("size", event.size as i64);
+                row.set("direction", format!("{:?}", event.direction));
+                row.set("age_msec", event.time.elapsed().as_millis() as i64);
+                row.set("peer", event.peer.to_string());
+                row
+            })
+            .collect()
+    }
 }
@@ -4,7 +4,11 @@ use udp_ext::persistent::PersistentSocketSender;
 use uuid::Uuid;
 
 use crate::{
-    lobby_stage::LobbyStage, message::Message, play_stage::PlayStage, replay_stage::ReplayStage,
+    lobby_stage::LobbyStage,
+    logging::{EventCategory, EventSeverity},
+    message::Message,
+    play_stage::PlayStage,
+    replay_stage::ReplayStage,
     Context,
 };
 
@@ -29,16 +33,66 @@ impl SyncStage {
         Ok(())
     }
 
+    /// Logs a final match_ended event, flushes the log and returns to the
+    /// lobby. Returns false if there was no match to end
+    pub fn end_match(&mut self, cx: &mut Context) -> Result<bool> {
+        if let SyncStage::Lobby(_) = self {
+            return Ok(false);
+        }
+
+        let summary = format!(
+            "Ended after {} frames with {} peers",
+            cx.latest_tick(),
+            cx.peers().len()
+        );
+        cx.logger().event(
+            EventSeverity::Info,
+            Some(EventCategory::Match),
+            "match_ended".into(),
+            summary,
+            cx,
+        )?;
+        cx.logger().flush()?;
+
+        if let SyncStage::Replay(_) = self {
+            cx.clear_replay();
+        }
+        cx.reset_ticks();
+        *self = SyncStage::Lobby(LobbyStage::new());
+        Ok(true)
+    }
+
+    pub fn force_resync(&mut self, cx: &mut Context) -> Result<Option<u64>> {
+        match self {
+            SyncStage::Play(play_stage) => play_stage.force_resync(cx),
+            SyncStage::Lobby(_) | SyncStage::Replay(_) => Ok(None),
+        }
+    }
+
+    /// Returns true if the match should end in response
+    pub fn peer_disconnected(&mut self, peer: Uuid, cx: &mut Context) -> Result<bool> {
+        match self {
+            SyncStage::Play(play_stage) => play_stage.peer_disconnected(peer, cx),
+            SyncStage::Lobby(_) | SyncStage::Replay(_) => Ok(false),
+        }
+    }
+
     pub fn handle_message(
         &mut self,
-        node: &mut Gd<Node>,
         message: Message,
         address: PersistentSocketSender<Uuid>,
         cx: &mut Context,
     ) -> Result<()> {
         match self {
-            SyncStage::Lobby(lobby_stage) => lobby_stage.handle_message(node, message, address, cx),
-            SyncStage::Play(play_stage) => play_stage.handle_message(message, cx),
+            SyncStage::Lobby(lobby_stage) => lobby_stage.handle_message(message, address, cx),
+            SyncStage::Play(play_stage) => match (message, address) {
+                (Message::JoinAsSpectator(id), PersistentSocketSender::Unconnected(address)) => {
+                    play_stage.add_spectator(id, address, cx)
+                }
+                // Already connected peers are already players or spectators
+                (Message::JoinAsSpectator(_), PersistentSocketSender::Connected(_)) => Ok(()),
+                (message, _) => play_stage.handle_message(message, cx),
+            },
             SyncStage::Replay(_) => {
                 // Noop. During a replay messages are thrown out.
                 Ok(())
@@ -54,6 +108,109 @@ impl SyncStage {
         }
     }
 
+    pub fn frame_hash(&self, tick: u64) -> Option<u64> {
+        match self {
+            SyncStage::Lobby(_) => None,
+            SyncStage::Play(play_stage) => play_stage.frame_hash(tick),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.frame_hash(tick),
+        }
+    }
+
+    pub fn dump_debug_state(&self, cx: &Context) -> Option<serde_json::Value> {
+        match self {
+            SyncStage::Lobby(_) => None,
+            SyncStage::Play(play_stage) => Some(play_stage.dump_debug_state(cx)),
+            SyncStage::Replay(replay_stage) => Some(replay_stage.play_stage.dump_debug_state(cx)),
+        }
+    }
+
+    pub fn pin_frame(&mut self, tick: u64) -> bool {
+        match self {
+            SyncStage::Lobby(_) => false,
+            SyncStage::Play(play_stage) => play_stage.pin_frame(tick),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.pin_frame(tick),
+        }
+    }
+
+    pub fn unpin_frame(&mut self, tick: u64) {
+        match self {
+            SyncStage::Lobby(_) => {}
+            SyncStage::Play(play_stage) => play_stage.unpin_frame(tick),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.unpin_frame(tick),
+        }
+    }
+
+    pub fn schedule_at(&mut self, tick: u64, name: String, cx: &Context) -> bool {
+        match self {
+            SyncStage::Lobby(_) => false,
+            SyncStage::Play(play_stage) => play_stage.schedule_at(tick, name, cx),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.schedule_at(tick, name, cx),
+        }
+    }
+
+    pub fn should_play_once(&mut self, effect_id: String, cx: &Context) -> bool {
+        match self {
+            SyncStage::Lobby(_) => true,
+            SyncStage::Play(play_stage) => play_stage.should_play_once(effect_id, cx),
+            SyncStage::Replay(replay_stage) => {
+                replay_stage.play_stage.should_play_once(effect_id, cx)
+            }
+        }
+    }
+
+    pub fn is_resimulating(&self) -> bool {
+        match self {
+            SyncStage::Lobby(_) => false,
+            SyncStage::Play(play_stage) => play_stage.is_resimulating(),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.is_resimulating(),
+        }
+    }
+
+    pub fn is_spectating(&self) -> bool {
+        match self {
+            SyncStage::Play(play_stage) => play_stage.is_spectating(),
+            SyncStage::Lobby(_) | SyncStage::Replay(_) => false,
+        }
+    }
+
+    /// Returns -1 outside of play since no inputs are being received
+    pub fn peer_input_age(&self, id: Uuid, cx: &Context) -> i64 {
+        match self {
+            SyncStage::Play(play_stage) => play_stage.peer_input_age(id, cx),
+            SyncStage::Lobby(_) | SyncStage::Replay(_) => -1,
+        }
+    }
+
+    pub fn buffered_frames(&self, id: Uuid, cx: &Context) -> i64 {
+        match self {
+            SyncStage::Play(play_stage) => play_stage.buffered_frames(id, cx),
+            SyncStage::Lobby(_) | SyncStage::Replay(_) => 0,
+        }
+    }
+
+    pub fn rollback_count(&self) -> u64 {
+        match self {
+            SyncStage::Lobby(_) => 0,
+            SyncStage::Play(play_stage) => play_stage.rollback_count(),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.rollback_count(),
+        }
+    }
+
+    pub fn stalled_ticks(&self) -> u64 {
+        match self {
+            SyncStage::Lobby(_) => 0,
+            SyncStage::Play(play_stage) => play_stage.stalled_ticks(),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.stalled_ticks(),
+        }
+    }
+
+    pub fn input_delay(&self, cx: &Context) -> u64 {
+        match self {
+            SyncStage::Lobby(_) | SyncStage::Replay(_) => 0,
+            SyncStage::Play(play_stage) => play_stage.input_delay(cx),
+        }
+    }
+
     pub fn advantage(&self) -> f64 {
         match self {
             SyncStage::Lobby(_) => 0.0,
@@ -61,4 +218,12 @@ impl SyncStage {
             SyncStage::Replay(replay_stage) => replay_stage.advantage(),
         }
     }
+
+    pub fn advantage_for_peer(&self, id: Uuid) -> f64 {
+        match self {
+            SyncStage::Lobby(_) => 0.0,
+            SyncStage::Play(play_stage) => play_stage.advantage_for_peer(id),
+            SyncStage::Replay(replay_stage) => replay_stage.play_stage.advantage_for_peer(id),
+        }
+    }
 }
@@ -1,11 +1,13 @@
 use std::collections::*;
-use std::io::Error;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 
 use super::messages::*;
 use super::reliable::*;
+use super::trace::PacketTraceEvent;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
 pub struct FrameId(pub usize);
@@ -32,6 +34,35 @@ pub enum FrameEvent {
     FrameComponentRecieved(ComponentPosition),
     FrameCompleted(FrameId, IncomingMessage),
     FrameComponentSent(PacketId),
+    /// A component of a frame was never acknowledged and has been given up on
+    PacketDropped(PacketId),
+    /// The reassembled frame didn't match its checksum and was dropped. Only
+    /// emitted with the checksum feature
+    FrameCorrupted(FrameId),
+    /// The frame was thrown away before it was complete, either because no
+    /// component of it arrived for longer than the partial frame timeout or
+    /// because one of its components was malformed
+    FrameDiscarded(FrameId),
+}
+
+/// Appends a crc32 of the payload so that corruption in transit can be
+/// detected once the frame is reassembled.
+#[cfg(feature = "checksum")]
+fn append_checksum(mut message: OutgoingMessage) -> OutgoingMessage {
+    let checksum = crc32fast::hash(&message.data);
+    message.write_data(checksum.to_le_bytes());
+    message
+}
+
+/// Strips the checksum from a reassembled payload. Returns None if the
+/// payload doesn't match it.
+#[cfg(feature = "checksum")]
+fn verify_checksum(message: IncomingMessage) -> Option<IncomingMessage> {
+    let mut data = message.read_rest();
+    let payload_length = data.len().checked_sub(std::mem::size_of::<u32>())?;
+    let checksum = u32::from_le_bytes(data[payload_length..].try_into().ok()?);
+    data.truncate(payload_length);
+    (crc32fast::hash(&data) == checksum).then(|| IncomingMessage::new(data))
 }
 
 enum AddComponentResult {
@@ -41,14 +72,19 @@ enum AddComponentResult {
 
 struct PartialFrame {
     pub frame_components: HashMap<usize, IncomingMessage>,
+    pub component_count: usize,
     pub remaining_components: usize,
+    /// When the most recent component arrived
+    pub last_received: Instant,
 }
 
 impl PartialFrame {
     pub fn new(component_count: usize) -> PartialFrame {
         PartialFrame {
             frame_components: HashMap::with_capacity(component_count),
+            component_count,
             remaining_components: component_count,
+            last_received: Instant::now(),
         }
     }
 
@@ -73,32 +109,146 @@ impl PartialFrame {
         let component_position = component
             .read_usize()
             .ok_or(anyhow!("Component doesn't have size"))?;
+        if component_position >= self.component_count {
+            return Err(anyhow!(
+                "Component {component_position} is past the end of a frame with {} components",
+                self.component_count
+            ));
+        }
 
+        if self.frame_components.contains_key(&component_position) {
+            return Ok(AddComponentResult::Unfinished(self));
+        }
         self.frame_components.insert(component_position, component);
         self.remaining_components -= 1;
+        self.last_received = Instant::now();
 
         Ok(self.complete_frame_if_done())
     }
 }
 
+/// Completed frames from a single source which arrived before a frame with a
+/// lower id, along with when each arrived
+#[derive(Default)]
+struct OrderedFrames {
+    next_frame_id: usize,
+    held_frames: BTreeMap<usize, (IncomingMessage, Instant)>,
+}
+
+impl OrderedFrames {
+    /// Holds the frame and returns every frame which is now next in line. Frames
+    /// older than the next expected one have already been given up on and
+    /// are returned as Err
+    fn add(
+        &mut self,
+        frame_id: FrameId,
+        message: IncomingMessage,
+    ) -> Result<Vec<(FrameId, IncomingMessage)>, FrameId> {
+        if frame_id.0 < self.next_frame_id {
+            return Err(frame_id);
+        }
+
+        self.held_frames.insert(frame_id.0, (message, Instant::now()));
+        Ok(self.release())
+    }
+
+    /// Gives up on the missing frames before any frame which has been held
+    /// longer than the timeout, and returns the frames which are then next in
+    /// line
+    fn skip_stale(&mut self, timeout: Duration) -> Vec<(FrameId, IncomingMessage)> {
+        let mut released = Vec::new();
+        while self
+            .held_frames
+            .values()
+            .any(|(_, arrived)| arrived.elapsed() > timeout)
+        {
+            // Held frames are never next in line, so the first one comes
+            // after a gap
+            self.next_frame_id = *self.held_frames.keys().next().unwrap();
+            released.extend(self.release());
+        }
+        released
+    }
+
+    fn release(&mut self) -> Vec<(FrameId, IncomingMessage)> {
+        let mut released = Vec::new();
+        while let Some((message, _)) = self.held_frames.remove(&self.next_frame_id) {
+            released.push((FrameId(self.next_frame_id), message));
+            self.next_frame_id += 1;
+        }
+        released
+    }
+}
+
 pub struct FrameSocket {
     reliable: ReliableSocket,
     frame_id_counter: usize,
-    packets_to_send: VecDeque<(OutgoingMessage, SocketAddr)>,
+    /// Whether frames from each source are delivered in the order they were
+    /// sent. Frame ids then count up separately for each destination so that
+    /// receivers can tell when a frame is missing
+    ordered: bool,
+    destination_frame_ids: HashMap<SocketAddr, usize>,
+    ordered_frames: HashMap<SocketAddr, OrderedFrames>,
+    packets_to_send: VecDeque<(OutgoingMessage, SocketAddr, FrameId)>,
     partial_frames: HashMap<(SocketAddr, FrameId), PartialFrame>,
+    unacked_frame_packets: HashMap<(SocketAddr, FrameId), Vec<PacketId>>,
+    frame_by_packet: HashMap<PacketId, (SocketAddr, FrameId)>,
+    /// How long a partially received frame is kept without any new
+    /// components before it is discarded
+    partial_frame_timeout: Duration,
 }
 
 impl FrameSocket {
     pub const MAX_FRAME_PACKET_DATA_SIZE: usize = ReliableSocket::MAX_RELIABLE_PACKET_SIZE - 24;
+    /// Largest payload a frame may carry. Frames claiming to be split into
+    /// more components than this needs are dropped without being allocated
+    pub const MAX_FRAME_SIZE: usize = 1 << 20;
+    pub const MAX_COMPONENT_COUNT: usize =
+        FrameSocket::MAX_FRAME_SIZE.div_ceil(FrameSocket::MAX_FRAME_PACKET_DATA_SIZE);
+    pub const DEFAULT_PARTIAL_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
 
     pub fn bind(port: u16) -> Result<FrameSocket> {
-        let reliable = ReliableSocket::bind(port)?;
+        FrameSocket::bind_to(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+    }
+
+    pub fn bind_to(address: IpAddr, port: u16) -> Result<FrameSocket> {
+        FrameSocket::bind_addr(SocketAddr::new(address, port))
+    }
+
+    /// Binds to the given local socket address, which may be IPv4 or IPv6
+    pub fn bind_addr(address: SocketAddr) -> Result<FrameSocket> {
+        FrameSocket::bind_with_ordering(address, false)
+    }
+
+    /// Binds to every interface, delivering the frames from each source in
+    /// the order they were sent. Frames which complete early are held until
+    /// every frame before them has been delivered, or until they have been
+    /// held for longer than the partial frame timeout in which case the
+    /// missing frames are given up on. Every peer must use ordered sockets.
+    pub fn bind_ordered(port: u16) -> Result<FrameSocket> {
+        FrameSocket::bind_ordered_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+    }
+
+    /// Binds to the given local socket address, delivering the frames from
+    /// each source in the order they were sent like `bind_ordered`
+    pub fn bind_ordered_addr(address: SocketAddr) -> Result<FrameSocket> {
+        FrameSocket::bind_with_ordering(address, true)
+    }
+
+    fn bind_with_ordering(address: SocketAddr, ordered: bool) -> Result<FrameSocket> {
+        let reliable = ReliableSocket::bind_addr(address)?;
 
         Ok(FrameSocket {
             reliable,
             frame_id_counter: 0,
+            ordered,
+            destination_frame_ids: HashMap::new(),
+            ordered_frames: HashMap::new(),
             packets_to_send: VecDeque::new(),
             partial_frames: HashMap::new(),
+            unacked_frame_packets: HashMap::new(),
+            frame_by_packet: HashMap::new(),
+            partial_frame_timeout: FrameSocket::DEFAULT_PARTIAL_FRAME_TIMEOUT,
         })
     }
 
@@ -108,10 +258,27 @@ impl FrameSocket {
         destination: impl ToSocketAddrs,
     ) -> Result<FrameId, Error> {
         let destination = destination.to_socket_addrs()?.next().unwrap();
+        #[cfg(feature = "checksum")]
+        let message = append_checksum(message);
         let data_length = message.data.len();
+        if data_length > FrameSocket::MAX_FRAME_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Frame of {data_length} bytes is larger than the maximum of {}",
+                    FrameSocket::MAX_FRAME_SIZE
+                ),
+            ));
+        }
         let mut readable_message = message.into_incoming();
-        let frame_id = self.frame_id_counter;
-        self.frame_id_counter += 1;
+        let frame_id = if self.ordered {
+            let frame_id = self.destination_frame_ids.entry(destination).or_insert(0);
+            *frame_id += 1;
+            *frame_id - 1
+        } else {
+            self.frame_id_counter += 1;
+            self.frame_id_counter - 1
+        };
         let component_count =
             (data_length as f64 / FrameSocket::MAX_FRAME_PACKET_DATA_SIZE as f64).ceil() as usize;
         for i in 0..component_count {
@@ -124,7 +291,7 @@ impl FrameSocket {
             wrapped_message.write_data(next_component_data);
 
             self.packets_to_send
-                .push_back((wrapped_message, destination));
+                .push_back((wrapped_message, destination, FrameId(frame_id)));
         }
 
         Ok(FrameId(frame_id))
@@ -133,32 +300,44 @@ impl FrameSocket {
     pub fn pump(&mut self) -> Result<Vec<(FrameEvent, SocketAddr)>> {
         let mut results = Vec::new();
 
-        for (message, destination) in self.packets_to_send.drain(..) {
+        for (message, destination, frame_id) in self.packets_to_send.drain(..) {
             let packet_id = self.reliable.send_to(message, destination)?;
+            self.unacked_frame_packets
+                .entry((destination, frame_id))
+                .or_default()
+                .push(packet_id);
+            self.frame_by_packet.insert(packet_id, (destination, frame_id));
             results.push((FrameEvent::FrameComponentSent(packet_id), destination));
         }
 
         for reliable_event in self.reliable.pump()? {
             match reliable_event {
                 (ReliableEvent::PacketRecieved(mut message), remote_address) => {
-                    let frame_id = FrameId(
-                        message
-                            .read_usize()
-                            .ok_or(anyhow!("Frame message doesn't have frame id"))?,
-                    );
-                    let component_count = message
+                    // Packets come straight off the network, so malformed ones
+                    // are dropped rather than failing the whole pump
+                    let Some(frame_id) = message.read_usize().map(FrameId) else {
+                        continue;
+                    };
+                    let Some(component_count) = message
                         .read_usize()
-                        .ok_or(anyhow!("Frame message doesn't have component count"))?;
+                        .filter(|count| (1..=FrameSocket::MAX_COMPONENT_COUNT).contains(count))
+                    else {
+                        results.push((FrameEvent::FrameDiscarded(frame_id), remote_address));
+                        continue;
+                    };
                     let frame_key = (remote_address, frame_id);
 
                     let add_result = self
                         .partial_frames
                         .remove(&frame_key)
                         .unwrap_or_else(|| PartialFrame::new(component_count))
-                        .add_component(message)?;
+                        .add_component(message);
 
                     match add_result {
-                        AddComponentResult::Unfinished(partial) => {
+                        Err(_) => {
+                            results.push((FrameEvent::FrameDiscarded(frame_id), remote_address));
+                        }
+                        Ok(AddComponentResult::Unfinished(partial)) => {
                             results.push((
                                 FrameEvent::FrameComponentRecieved(ComponentPosition::new(
                                     frame_id,
@@ -168,27 +347,167 @@ impl FrameSocket {
                             ));
                             self.partial_frames.insert(frame_key, partial);
                         }
-                        AddComponentResult::Done(finished_message) => results.push((
-                            FrameEvent::FrameCompleted(frame_id, finished_message),
-                            remote_address,
-                        )),
+                        Ok(AddComponentResult::Done(finished_message)) => {
+                            #[cfg(feature = "checksum")]
+                            let Some(finished_message) = verify_checksum(finished_message) else {
+                                results.push((FrameEvent::FrameCorrupted(frame_id), remote_address));
+                                continue;
+                            };
+
+                            if !self.ordered {
+                                results.push((
+                                    FrameEvent::FrameCompleted(frame_id, finished_message),
+                                    remote_address,
+                                ));
+                                continue;
+                            }
+
+                            let ordered_frames =
+                                self.ordered_frames.entry(remote_address).or_default();
+                            match ordered_frames.add(frame_id, finished_message) {
+                                Ok(released) => {
+                                    for (frame_id, message) in released {
+                                        results.push((
+                                            FrameEvent::FrameCompleted(frame_id, message),
+                                            remote_address,
+                                        ));
+                                    }
+                                }
+                                Err(frame_id) => {
+                                    results.push((FrameEvent::FrameDiscarded(frame_id), remote_address));
+                                }
+                            }
+                        }
                     }
                 }
                 (ReliableEvent::PacketAcknowledged(packet_id), remote_address) => {
+                    self.forget_packet(packet_id);
                     results.push((FrameEvent::PacketAcknowledged(packet_id), remote_address));
                 }
                 (ReliableEvent::PacketResent(packet_id), remote_address) => {
                     results.push((FrameEvent::PacketResent(packet_id), remote_address));
                 }
+                (ReliableEvent::PacketDropped(packet_id), remote_address) => {
+                    self.forget_packet(packet_id);
+                    results.push((FrameEvent::PacketDropped(packet_id), remote_address));
+                }
             }
         }
 
+        // Drop frames from peers which stopped sending part way through so
+        // they don't hold on to memory forever
+        let partial_frame_timeout = self.partial_frame_timeout;
+        self.partial_frames
+            .retain(|(remote_address, frame_id), partial_frame| {
+                if partial_frame.last_received.elapsed() <= partial_frame_timeout {
+                    return true;
+                }
+                results.push((FrameEvent::FrameDiscarded(*frame_id), *remote_address));
+                false
+            });
+        for (remote_address, ordered_frames) in self.ordered_frames.iter_mut() {
+            results.extend(
+                ordered_frames
+                    .skip_stale(partial_frame_timeout)
+                    .into_iter()
+                    .map(|(frame_id, message)| {
+                        (FrameEvent::FrameCompleted(frame_id, message), *remote_address)
+                    }),
+            );
+        }
+
         Ok(results)
     }
 
+    /// Sets how long a partially received frame is kept without receiving any
+    /// more of its components. Defaults to 10 seconds.
+    pub fn set_partial_frame_timeout(&mut self, timeout: Duration) {
+        self.partial_frame_timeout = timeout;
+    }
+
+    /// Stops sending or resending any components of the frame which haven't
+    /// been acknowledged yet. Useful when a newer frame supersedes the frame's
+    /// contents. Returns the ids of the canceled packets which had already
+    /// been sent.
+    pub fn cancel_frame(&mut self, frame_id: FrameId, destination: SocketAddr) -> Vec<PacketId> {
+        self.packets_to_send
+            .retain(|(_, queued_destination, queued_frame_id)| {
+                *queued_frame_id != frame_id || *queued_destination != destination
+            });
+
+        let packet_ids = self
+            .unacked_frame_packets
+            .remove(&(destination, frame_id))
+            .unwrap_or_default();
+        for packet_id in packet_ids.iter() {
+            self.frame_by_packet.remove(packet_id);
+            self.reliable.cancel(*packet_id);
+        }
+        packet_ids
+    }
+
+    pub fn set_rtt_estimate(&mut self, destination: SocketAddr, rtt: Duration) {
+        self.reliable.set_rtt_estimate(destination, rtt);
+    }
+
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.reliable.set_max_retries(max_retries);
+    }
+
+    /// Forgets the frame ids sent to and received from the address so that
+    /// both directions count from frame 0 again. Call when a peer reconnects,
+    /// since a restarted peer numbers its frames from 0.
+    pub fn reset_ordering(&mut self, address: SocketAddr) {
+        self.destination_frame_ids.remove(&address);
+        self.ordered_frames.remove(&address);
+    }
+
+    /// Stops sending and resending every frame to the destination. Returns
+    /// the ids of the canceled packets which had already been sent.
+    pub fn cancel_destination(&mut self, destination: SocketAddr) -> Vec<PacketId> {
+        self.packets_to_send
+            .retain(|(_, queued_destination, _)| *queued_destination != destination);
+
+        let frame_ids = self
+            .unacked_frame_packets
+            .keys()
+            .filter(|(unacked_destination, _)| *unacked_destination == destination)
+            .map(|(_, frame_id)| *frame_id)
+            .collect::<Vec<_>>();
+        frame_ids
+            .into_iter()
+            .flat_map(|frame_id| self.cancel_frame(frame_id, destination))
+            .collect()
+    }
+
     pub fn local_addr(&self) -> Result<SocketAddr> {
         Ok(self.reliable.local_addr()?)
     }
+
+    /// Records the most recent `capacity` packet events of the underlying
+    /// reliable socket
+    pub fn enable_tracing(&mut self, capacity: usize) {
+        self.reliable.enable_tracing(capacity);
+    }
+
+    pub fn disable_tracing(&mut self) {
+        self.reliable.disable_tracing();
+    }
+
+    pub fn trace(&self) -> Vec<PacketTraceEvent> {
+        self.reliable.trace()
+    }
+
+    fn forget_packet(&mut self, packet_id: PacketId) {
+        if let Some(frame_key) = self.frame_by_packet.remove(&packet_id) {
+            if let Some(packet_ids) = self.unacked_frame_packets.get_mut(&frame_key) {
+                packet_ids.retain(|unacked_id| *unacked_id != packet_id);
+                if packet_ids.is_empty() {
+                    self.unacked_frame_packets.remove(&frame_key);
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -200,6 +519,16 @@ mod test {
 
     use super::*;
 
+    /// The data of a single component frame holding the string, with its
+    /// checksum appended when the checksum feature is enabled
+    fn frame_data(contents: &str) -> Vec<u8> {
+        let mut message = OutgoingMessage::new();
+        message.write_string(contents);
+        #[cfg(feature = "checksum")]
+        let message = append_checksum(message);
+        message.data
+    }
+
     #[test]
     fn frame_socket_reconstructs_large_packets() -> Result<()> {
         let mut frame_socket = FrameSocket::bind(0)?;
@@ -245,4 +574,272 @@ mod test {
 
         Ok(())
     }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn corrupted_frames_are_rejected() -> Result<()> {
+        let mut frame_socket = FrameSocket::bind(0)?;
+        let frame_address = format!("127.0.0.1:{}", frame_socket.local_addr()?.port());
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+
+        let mut payload = OutgoingMessage::new();
+        payload.write_string("This payload gets corrupted in transit.");
+        let mut corrupted = append_checksum(payload).data;
+        corrupted[3] ^= 0b0000_0100;
+
+        let mut packet = OutgoingMessage::new();
+        packet.write_bool(true); // Message Type (content)
+        packet.write_usize(0); // Ack Id
+        packet.write_usize(0); // Frame Id
+        packet.write_usize(1); // Component Count
+        packet.write_usize(0); // Component Index
+        packet.write_data(corrupted);
+        remote.send_to(&packet.data, frame_address)?;
+
+        sleep(Duration::from_millis(100));
+        let events = frame_socket.pump()?;
+        assert!(events
+            .iter()
+            .any(|(event, _)| matches!(event, FrameEvent::FrameCorrupted(FrameId(0)))));
+        assert!(!events
+            .iter()
+            .any(|(event, _)| matches!(event, FrameEvent::FrameCompleted(_, _))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_frames_are_discarded() -> Result<()> {
+        let mut frame_socket = FrameSocket::bind(0)?;
+        frame_socket.set_partial_frame_timeout(Duration::from_millis(50));
+        let frame_address = format!("127.0.0.1:{}", frame_socket.local_addr()?.port());
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+
+        let mut packet = OutgoingMessage::new();
+        packet.write_bool(true); // Message Type (content)
+        packet.write_usize(0); // Ack Id
+        packet.write_usize(0); // Frame Id
+        packet.write_usize(2); // Component Count
+        packet.write_usize(0); // Component Index
+        packet.write_string("The second half never arrives");
+        remote.send_to(&packet.data, frame_address)?;
+
+        sleep(Duration::from_millis(20));
+        let events = frame_socket.pump()?;
+        assert!(events
+            .iter()
+            .any(|(event, _)| matches!(event, FrameEvent::FrameComponentRecieved(_))));
+        assert_eq!(frame_socket.partial_frames.len(), 1);
+
+        sleep(Duration::from_millis(100));
+        let events = frame_socket.pump()?;
+        assert!(events
+            .iter()
+            .any(|(event, _)| matches!(event, FrameEvent::FrameDiscarded(FrameId(0)))));
+        assert!(frame_socket.partial_frames.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bogus_component_counts_are_dropped() -> Result<()> {
+        let mut frame_socket = FrameSocket::bind(0)?;
+        let frame_address = format!("127.0.0.1:{}", frame_socket.local_addr()?.port());
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+
+        let bogus_packets = [
+            // Frame id, component count, component index
+            (0, usize::MAX, 0),
+            (1, FrameSocket::MAX_COMPONENT_COUNT + 1, 0),
+            (2, 0, 0),
+            (3, 2, 5),
+        ];
+        for (ack_id, (frame_id, component_count, component_index)) in
+            bogus_packets.into_iter().enumerate()
+        {
+            let mut packet = OutgoingMessage::new();
+            packet.write_bool(true); // Message Type (content)
+            packet.write_usize(ack_id); // Ack Id
+            packet.write_usize(frame_id);
+            packet.write_usize(component_count);
+            packet.write_usize(component_index);
+            packet.write_string("Bogus");
+            remote.send_to(&packet.data, &frame_address)?;
+        }
+
+        sleep(Duration::from_millis(100));
+        let mut discarded = frame_socket
+            .pump()?
+            .into_iter()
+            .filter_map(|(event, _)| match event {
+                FrameEvent::FrameDiscarded(FrameId(frame_id)) => Some(frame_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        discarded.sort();
+        assert_eq!(discarded, vec![0, 1, 2, 3]);
+        assert!(frame_socket.partial_frames.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_frames_are_delivered_in_order() -> Result<()> {
+        let mut frame_socket = FrameSocket::bind_ordered(0)?;
+        let frame_address = format!("127.0.0.1:{}", frame_socket.local_addr()?.port());
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let send_frame = |frame_id: usize, contents: &str| -> Result<()> {
+            let mut packet = OutgoingMessage::new();
+            packet.write_bool(true); // Message Type (content)
+            packet.write_usize(frame_id); // Ack Id
+            packet.write_usize(frame_id); // Frame Id
+            packet.write_usize(1); // Component Count
+            packet.write_usize(0); // Component Index
+            packet.write_data(frame_data(contents));
+            remote.send_to(&packet.data, &frame_address)?;
+            Ok(())
+        };
+        let completed_frames = |events: Vec<(FrameEvent, SocketAddr)>| {
+            events
+                .into_iter()
+                .filter_map(|(event, _)| match event {
+                    FrameEvent::FrameCompleted(FrameId(frame_id), mut message) => {
+                        Some((frame_id, message.read_string().unwrap()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        send_frame(1, "Second")?;
+        sleep(Duration::from_millis(50));
+        assert!(completed_frames(frame_socket.pump()?).is_empty());
+
+        send_frame(0, "First")?;
+        sleep(Duration::from_millis(50));
+        assert_eq!(
+            completed_frames(frame_socket.pump()?),
+            vec![(0, "First".to_string()), (1, "Second".to_string())]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_frames_skip_missing_frames_after_timeout() -> Result<()> {
+        let mut frame_socket = FrameSocket::bind_ordered(0)?;
+        frame_socket.set_partial_frame_timeout(Duration::from_millis(50));
+        let frame_address = format!("127.0.0.1:{}", frame_socket.local_addr()?.port());
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+
+        let mut packet = OutgoingMessage::new();
+        packet.write_bool(true); // Message Type (content)
+        packet.write_usize(0); // Ack Id
+        packet.write_usize(1); // Frame Id
+        packet.write_usize(1); // Component Count
+        packet.write_usize(0); // Component Index
+        packet.write_data(frame_data("Frame zero never arrives"));
+        remote.send_to(&packet.data, &frame_address)?;
+
+        sleep(Duration::from_millis(20));
+        assert!(!frame_socket
+            .pump()?
+            .iter()
+            .any(|(event, _)| matches!(event, FrameEvent::FrameCompleted(_, _))));
+
+        sleep(Duration::from_millis(100));
+        assert!(frame_socket
+            .pump()?
+            .iter()
+            .any(|(event, _)| matches!(event, FrameEvent::FrameCompleted(FrameId(1), _))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_ordering_restarts_frame_ids() -> Result<()> {
+        let mut frame_socket =
+            FrameSocket::bind_ordered_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))?;
+        let frame_address = frame_socket.local_addr()?;
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let remote_address = remote.local_addr()?;
+        let send_frame = |ack_id: usize, frame_id: usize| -> Result<()> {
+            let mut packet = OutgoingMessage::new();
+            packet.write_bool(true); // Message Type (content)
+            packet.write_usize(ack_id); // Ack Id
+            packet.write_usize(frame_id); // Frame Id
+            packet.write_usize(1); // Component Count
+            packet.write_usize(0); // Component Index
+            packet.write_data(frame_data("Frame"));
+            remote.send_to(&packet.data, frame_address)?;
+            Ok(())
+        };
+        let completed_frames = |events: Vec<(FrameEvent, SocketAddr)>| {
+            events
+                .into_iter()
+                .filter_map(|(event, _)| match event {
+                    FrameEvent::FrameCompleted(FrameId(frame_id), _) => Some(frame_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        send_frame(0, 0)?;
+        send_frame(1, 1)?;
+        sleep(Duration::from_millis(50));
+        assert_eq!(completed_frames(frame_socket.pump()?), vec![0, 1]);
+        assert_eq!(frame_socket.send_to(OutgoingMessage::new(), remote_address)?, FrameId(0));
+        assert_eq!(frame_socket.send_to(OutgoingMessage::new(), remote_address)?, FrameId(1));
+
+        // The remote restarted and numbers its frames from 0 again
+        frame_socket.reset_ordering(remote_address);
+        send_frame(2, 0)?;
+        sleep(Duration::from_millis(50));
+        assert_eq!(completed_frames(frame_socket.pump()?), vec![0]);
+        assert_eq!(frame_socket.send_to(OutgoingMessage::new(), remote_address)?, FrameId(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn canceled_frames_are_not_resent() -> Result<()> {
+        let mut frame_socket = FrameSocket::bind(0)?;
+        // Never acknowledges anything, so every packet would be resent
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let remote_address = remote.local_addr()?;
+
+        let mut superseded = OutgoingMessage::new();
+        superseded.write_usize(1);
+        let superseded_id = frame_socket.send_to(superseded, remote_address)?;
+        let mut current = OutgoingMessage::new();
+        current.write_usize(2);
+        frame_socket.send_to(current, remote_address)?;
+        let sent_packets: Vec<PacketId> = frame_socket
+            .pump()?
+            .into_iter()
+            .filter_map(|(event, _)| match event {
+                FrameEvent::FrameComponentSent(packet_id) => Some(packet_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent_packets.len(), 2);
+
+        assert_eq!(
+            frame_socket.cancel_frame(superseded_id, remote_address),
+            vec![sent_packets[0]]
+        );
+
+        sleep(Duration::from_millis(50));
+        let resent_packets: Vec<PacketId> = frame_socket
+            .pump()?
+            .into_iter()
+            .filter_map(|(event, _)| match event {
+                FrameEvent::PacketResent(packet_id) => Some(packet_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(resent_packets, vec![sent_packets[1]]);
+
+        Ok(())
+    }
 }
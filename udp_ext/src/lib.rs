@@ -2,4 +2,5 @@ pub mod frame;
 pub mod messages;
 pub mod persistent;
 pub mod reliable;
+pub mod trace;
 pub mod util;
@@ -1,7 +1,7 @@
 use std::iter::FromIterator;
 use std::iter::Iterator;
-use std::mem::transmute;
 
+use anyhow::Result;
 use core::convert::*;
 use serde::{de::DeserializeOwned, ser::Serialize};
 
@@ -28,11 +28,7 @@ impl OutgoingMessage {
     }
 
     pub fn write_u16(&mut self, value: u16) {
-        unsafe {
-            let bytes = transmute::<u16, [u8; 2]>(value);
-            self.write_u8(bytes[0]);
-            self.write_u8(bytes[1]);
-        }
+        self.write_data(value.to_le_bytes());
     }
 
     pub fn write_i16(&mut self, value: i16) {
@@ -40,11 +36,7 @@ impl OutgoingMessage {
     }
 
     pub fn write_u32(&mut self, value: u32) {
-        unsafe {
-            let shorts = transmute::<u32, [u16; 2]>(value);
-            self.write_u16(shorts[0]);
-            self.write_u16(shorts[1]);
-        }
+        self.write_data(value.to_le_bytes());
     }
 
     pub fn write_i32(&mut self, value: i32) {
@@ -52,11 +44,7 @@ impl OutgoingMessage {
     }
 
     pub fn write_u64(&mut self, value: u64) {
-        unsafe {
-            let ints = transmute::<u64, [u32; 2]>(value);
-            self.write_u32(ints[0]);
-            self.write_u32(ints[1]);
-        }
+        self.write_data(value.to_le_bytes());
     }
 
     pub fn write_i64(&mut self, value: i64) {
@@ -64,15 +52,11 @@ impl OutgoingMessage {
     }
 
     pub fn write_f32(&mut self, value: f32) {
-        unsafe {
-            self.write_u32(transmute::<f32, u32>(value));
-        }
+        self.write_u32(value.to_bits());
     }
 
     pub fn write_f64(&mut self, value: f64) {
-        unsafe {
-            self.write_u64(transmute::<f64, u64>(value));
-        }
+        self.write_u64(value.to_bits());
     }
 
     pub fn write_usize(&mut self, value: usize) {
@@ -83,13 +67,23 @@ impl OutgoingMessage {
         self.write_i64(value as i64);
     }
 
+    /// Writes the value as a LEB128 varint, taking a single byte for values
+    /// under 128 and at most ten bytes for the largest values.
+    pub fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.write_u8((value as u8 & 0x7F) | 0x80);
+            value >>= 7;
+        }
+        self.write_u8(value as u8);
+    }
+
     pub fn write_u8s<T>(&mut self, values: T)
     where
         T: AsRef<[u8]>,
     {
         let bytes = values.as_ref();
 
-        self.write_usize(bytes.len());
+        self.write_varint(bytes.len() as u64);
         for byte in bytes {
             self.write_u8(*byte);
         }
@@ -110,9 +104,26 @@ impl OutgoingMessage {
         self.write_u8s(value.as_bytes());
     }
 
-    pub fn write_serializable<T: Serialize>(&mut self, value: T) {
-        let binary = bincode::serialize(&value).unwrap();
+    /// Writes the bincode serialized value. Nothing is written if the value
+    /// fails to serialize.
+    pub fn write_serializable<T: Serialize>(&mut self, value: T) -> Result<()> {
+        let binary = bincode::serialize(&value)?;
         self.write_u8s(binary);
+        Ok(())
+    }
+
+    /// Writes a presence flag followed by the bincode serialized value if
+    /// there is one. Nothing is written if the value fails to serialize.
+    pub fn write_option<T: Serialize>(&mut self, value: Option<T>) -> Result<()> {
+        let start = self.data.len();
+        self.write_bool(value.is_some());
+        if let Some(value) = value {
+            if let Err(error) = self.write_serializable(value) {
+                self.data.truncate(start);
+                return Err(error);
+            }
+        }
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
@@ -125,20 +136,20 @@ impl OutgoingMessage {
 }
 
 pub trait IntoOutgoingMessage {
-    fn into(self) -> OutgoingMessage;
+    fn into(self) -> Result<OutgoingMessage>;
 }
 
 impl IntoOutgoingMessage for OutgoingMessage {
-    fn into(self) -> OutgoingMessage {
-        self
+    fn into(self) -> Result<OutgoingMessage> {
+        Ok(self)
     }
 }
 
 impl<T: Serialize> IntoOutgoingMessage for T {
-    fn into(self) -> OutgoingMessage {
+    fn into(self) -> Result<OutgoingMessage> {
         let mut message = OutgoingMessage::new();
-        message.write_serializable(&self);
-        message
+        message.write_serializable(&self)?;
+        Ok(message)
     }
 }
 
@@ -153,12 +164,48 @@ impl IncomingMessage {
         IncomingMessage { data, cursor: 0 }
     }
 
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.cursor
+    }
+
+    /// Moves the cursor to the given byte offset, clamped to the end of the
+    /// message. Useful for re-reading a field after branching on it.
+    pub fn seek(&mut self, cursor: usize) {
+        self.cursor = cursor.min(self.data.len());
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Returns the next byte without consuming it
+    pub fn peek_u8(&self) -> Option<u8> {
+        self.data.get(self.cursor).copied()
+    }
+
     pub fn read_u8(&mut self) -> Option<u8> {
         let result = self.data.get(self.cursor)?;
         self.cursor += 1;
         Some(*result)
     }
 
+    /// Reads the next N bytes. Multi-byte values are always little endian on
+    /// the wire regardless of the host's byte order.
+    fn read_array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.data.get(self.cursor..self.cursor + N)?;
+        self.cursor += N;
+        bytes.try_into().ok()
+    }
+
     pub fn read_bool(&mut self) -> Option<bool> {
         Some(self.read_u8()? != 0)
     }
@@ -168,10 +215,7 @@ impl IncomingMessage {
     }
 
     pub fn read_u16(&mut self) -> Option<u16> {
-        unsafe {
-            let bytes = [self.read_u8()?, self.read_u8()?];
-            Some(transmute::<[u8; 2], u16>(bytes))
-        }
+        Some(u16::from_le_bytes(self.read_array()?))
     }
 
     pub fn read_i16(&mut self) -> Option<i16> {
@@ -179,10 +223,7 @@ impl IncomingMessage {
     }
 
     pub fn read_u32(&mut self) -> Option<u32> {
-        unsafe {
-            let shorts = [self.read_u16()?, self.read_u16()?];
-            Some(transmute::<[u16; 2], u32>(shorts))
-        }
+        Some(u32::from_le_bytes(self.read_array()?))
     }
 
     pub fn read_i32(&mut self) -> Option<i32> {
@@ -190,10 +231,7 @@ impl IncomingMessage {
     }
 
     pub fn read_u64(&mut self) -> Option<u64> {
-        unsafe {
-            let ints = [self.read_u32()?, self.read_u32()?];
-            Some(transmute::<[u32; 2], u64>(ints))
-        }
+        Some(u64::from_le_bytes(self.read_array()?))
     }
 
     pub fn read_i64(&mut self) -> Option<i64> {
@@ -201,11 +239,11 @@ impl IncomingMessage {
     }
 
     pub fn read_f32(&mut self) -> Option<f32> {
-        unsafe { Some(transmute::<u32, f32>(self.read_u32()?)) }
+        Some(f32::from_bits(self.read_u32()?))
     }
 
     pub fn read_f64(&mut self) -> Option<f64> {
-        unsafe { Some(transmute::<u64, f64>(self.read_u64()?)) }
+        Some(f64::from_bits(self.read_u64()?))
     }
 
     pub fn read_usize(&mut self) -> Option<usize> {
@@ -216,6 +254,24 @@ impl IncomingMessage {
         Some(self.read_u64()? as isize)
     }
 
+    pub fn read_varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            // The tenth byte only has room for the top bit of a u64
+            if shift == 63 && byte > 1 {
+                return None;
+            }
+
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
     pub fn read_n_u8s(&mut self, n: usize) -> Option<Vec<u8>> {
         let mut bytes = Vec::new();
         for _ in 0..n {
@@ -225,12 +281,12 @@ impl IncomingMessage {
     }
 
     pub fn read_at_most_n_u8s(&mut self, n: usize) -> Vec<u8> {
-        let length = std::cmp::min(n, self.data.len() - self.cursor);
+        let length = std::cmp::min(n, self.remaining());
         self.read_n_u8s(length).unwrap_or(Vec::new())
     }
 
     pub fn read_u8s(&mut self) -> Option<Vec<u8>> {
-        let length = self.read_usize()?;
+        let length = usize::try_from(self.read_varint()?).ok()?;
         Some(self.read_n_u8s(length)?)
     }
 
@@ -246,6 +302,19 @@ impl IncomingMessage {
         Some(bincode::deserialize_from(&bytes[..]).ok()?)
     }
 
+    /// Reads a value written by write_option. The outer option is None if
+    /// the message couldn't be decoded.
+    pub fn read_option<T>(&mut self) -> Option<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        if self.read_bool()? {
+            Some(Some(self.read_serializable()?))
+        } else {
+            Some(None)
+        }
+    }
+
     pub fn read_rest(self) -> Vec<u8> {
         Vec::from_iter(self.data.into_iter().skip(self.cursor))
     }
@@ -293,6 +362,67 @@ mod test {
     test_read_write!(string, "Hello world!");
     test_read_write!(u8s, vec![3u8, 1u8, 4u8, 1u8, 5u8]);
 
+    #[test]
+    fn integers_are_read_little_endian() {
+        let mut incoming = IncomingMessage::new(vec![
+            0x34, 0x12, // u16
+            0x78, 0x56, 0x34, 0x12, // u32
+            0xEF, 0xCD, 0xAB, 0x90, 0x78, 0x56, 0x34, 0x12, // u64
+            0x00, 0x00, 0x80, 0x3F, // f32 1.0
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xF0, 0xBF, // f64 -1.0
+        ]);
+
+        assert_eq!(incoming.read_u16().unwrap(), 0x1234);
+        assert_eq!(incoming.read_u32().unwrap(), 0x12345678);
+        assert_eq!(incoming.read_u64().unwrap(), 0x1234567890ABCDEF);
+        assert_eq!(incoming.read_f32().unwrap(), 1.0);
+        assert_eq!(incoming.read_f64().unwrap(), -1.0);
+        assert!(incoming.at_end());
+    }
+
+    #[test]
+    fn integers_are_written_little_endian() {
+        let mut outgoing = OutgoingMessage::new();
+        outgoing.write_u16(0x1234);
+        outgoing.write_u32(0x12345678);
+
+        assert_eq!(outgoing.data, vec![0x34, 0x12, 0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn written_varint_equals_read_varint() {
+        for (value, length) in [
+            (0u64, 1),
+            (127, 1),
+            (128, 2),
+            (16383, 2),
+            (16384, 3),
+            (u64::MAX, 10),
+        ] {
+            let mut outgoing = OutgoingMessage::new();
+            outgoing.write_varint(value);
+            assert_eq!(outgoing.len(), length);
+
+            let mut incoming = IncomingMessage::new(outgoing.data);
+            assert_eq!(incoming.read_varint().unwrap(), value);
+            assert!(incoming.at_end());
+        }
+    }
+
+    #[test]
+    fn overlong_varint_is_rejected() {
+        let mut incoming = IncomingMessage::new(vec![0xFF; 10]);
+        assert!(incoming.read_varint().is_none());
+    }
+
+    #[test]
+    fn short_string_uses_single_byte_length() {
+        let mut outgoing = OutgoingMessage::new();
+        outgoing.write_string("Hello");
+
+        assert_eq!(outgoing.len(), 6);
+    }
+
     #[derive(Serialize, Deserialize, Debug, PartialEq)]
     struct TestSerializable {
         foo: usize,
@@ -303,11 +433,13 @@ mod test {
     #[test]
     fn written_serializable_equals_read_serializable() {
         let mut outgoing = OutgoingMessage::new();
-        outgoing.write_serializable(TestSerializable {
-            foo: 42,
-            bar: "bar".to_owned(),
-            baz: false,
-        });
+        outgoing
+            .write_serializable(TestSerializable {
+                foo: 42,
+                bar: "bar".to_owned(),
+                baz: false,
+            })
+            .unwrap();
 
         let mut incoming = IncomingMessage::new(outgoing.data);
         assert_eq!(
@@ -321,6 +453,90 @@ mod test {
         assert!(incoming.at_end());
     }
 
+    #[test]
+    fn written_option_equals_read_option() {
+        let mut outgoing = OutgoingMessage::new();
+        outgoing
+            .write_option(Some(TestSerializable {
+                foo: 42,
+                bar: "bar".to_owned(),
+                baz: true,
+            }))
+            .unwrap();
+        outgoing.write_option::<TestSerializable>(None).unwrap();
+
+        let mut incoming = IncomingMessage::new(outgoing.data);
+        assert_eq!(
+            incoming.read_option::<TestSerializable>().unwrap(),
+            Some(TestSerializable {
+                foo: 42,
+                bar: "bar".to_owned(),
+                baz: true
+            })
+        );
+        assert_eq!(incoming.read_option::<TestSerializable>().unwrap(), None);
+        assert!(incoming.at_end());
+    }
+
+    #[test]
+    fn truncated_option_fails_to_read() {
+        let mut incoming = IncomingMessage::new(vec![1u8]);
+        assert!(incoming.read_option::<TestSerializable>().is_none());
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> std::result::Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("Unserializable can't be serialized"))
+        }
+    }
+
+    #[test]
+    fn unserializable_value_returns_error() {
+        let mut outgoing = OutgoingMessage::new();
+        outgoing.write_u8(7);
+
+        assert!(outgoing.write_serializable(Unserializable).is_err());
+        assert_eq!(outgoing.data, vec![7u8]);
+        assert!(outgoing.write_option(Some(Unserializable)).is_err());
+        assert_eq!(outgoing.data, vec![7u8]);
+        assert!(IntoOutgoingMessage::into(Unserializable).is_err());
+    }
+
+    #[test]
+    fn peek_does_not_move_cursor() {
+        let mut incoming = IncomingMessage::new(vec![3u8, 1u8]);
+
+        assert_eq!(incoming.peek_u8(), Some(3));
+        assert_eq!(incoming.peek_u8(), Some(3));
+        assert_eq!(incoming.remaining(), 2);
+        assert_eq!(incoming.read_u8(), Some(3));
+        assert_eq!(incoming.peek_u8(), Some(1));
+        assert_eq!(incoming.read_u8(), Some(1));
+        assert_eq!(incoming.peek_u8(), None);
+        assert_eq!(incoming.remaining(), 0);
+    }
+
+    #[test]
+    fn seek_backward_rereads_field() {
+        let mut outgoing = OutgoingMessage::new();
+        outgoing.write_u8(1);
+        outgoing.write_string("Hello");
+        let mut incoming = outgoing.into_incoming();
+
+        let _ = incoming.read_u8();
+        let string_start = incoming.cursor();
+        assert_eq!(incoming.read_string().unwrap(), "Hello");
+        assert!(incoming.at_end());
+
+        incoming.seek(string_start);
+        assert_eq!(incoming.read_string().unwrap(), "Hello");
+
+        incoming.seek(100);
+        assert!(incoming.at_end());
+    }
+
     #[test]
     fn message_read_rest_works() {
         let mut incoming = IncomingMessage::new(vec![3u8, 1u8, 4u8, 1u8, 5u8]);
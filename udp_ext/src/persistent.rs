@@ -1,7 +1,7 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::hash::Hash;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
@@ -9,6 +9,7 @@ use anyhow::{anyhow, Result};
 use super::frame::*;
 use super::messages::*;
 use super::reliable::PacketId;
+use super::trace::PacketTraceEvent;
 
 #[derive(Debug, PartialEq)]
 pub enum PersistentEvent {
@@ -17,6 +18,12 @@ pub enum PersistentEvent {
     FrameComponentRecieved(ComponentPosition),
     FrameCompleted(FrameId, IncomingMessage),
     FrameComponentSent(PacketId),
+    FrameCorrupted(FrameId),
+    /// Only part of the frame arrived before it timed out
+    FrameDiscarded(FrameId),
+    /// A packet was resent the maximum number of times without being
+    /// acknowledged and has been given up on
+    PacketDropped(PacketId),
     PeerDisconnected,
 }
 
@@ -41,6 +48,41 @@ where
     }
 }
 
+/// The frame a broadcast was sent in for each peer, along with the peers it
+/// skipped for having too many packets in flight
+#[derive(Debug, Default)]
+pub struct Broadcast<ID> {
+    pub frame_ids: HashMap<ID, FrameId>,
+    pub throttled: Vec<ID>,
+}
+
+/// Whether each of the most recent transmissions to a peer was a resend,
+/// oldest first. Every resend means at least one transmission was lost.
+#[derive(Default)]
+struct LossStats {
+    resends: VecDeque<bool>,
+}
+
+impl LossStats {
+    const WINDOW: usize = 200;
+
+    fn record(&mut self, resent: bool) {
+        self.resends.push_back(resent);
+        if self.resends.len() > LossStats::WINDOW {
+            self.resends.pop_front();
+        }
+    }
+
+    fn loss(&self) -> f32 {
+        if self.resends.is_empty() {
+            return 0.0;
+        }
+
+        let resent = self.resends.iter().filter(|resent| **resent).count();
+        resent as f32 / self.resends.len() as f32
+    }
+}
+
 /// Wrapper over frame sockets which tracks average reply times and disconnects.
 pub struct PersistentSocket<ID>
 where
@@ -48,9 +90,23 @@ where
 {
     frame: FrameSocket,
     sent_times: HashMap<(PacketId, SocketAddr), Instant>,
+    /// Packets in sent_times which have been resent. Their acknowledgements
+    /// can't be matched to a particular send, so they aren't sampled for the
+    /// round trip time (Karn's algorithm)
+    resent_packets: HashSet<(PacketId, SocketAddr)>,
     ping_times: HashMap<ID, VecDeque<Duration>>,
+    loss_stats: HashMap<ID, LossStats>,
+    /// Exponentially weighted moving average of the round trip time to each
+    /// address. Sizes the reliable layer's resend timeout
+    smoothed_rtts: HashMap<SocketAddr, Duration>,
     addresses_by_id: HashMap<ID, SocketAddr>,
     id_by_address: HashMap<SocketAddr, ID>,
+    /// Whether peers which time out are disconnected automatically. Off when
+    /// the layer above decides whether to forget them
+    auto_disconnect: bool,
+    /// Number of unacknowledged packets a peer may have before broadcasts
+    /// skip it. None never skips
+    max_in_flight: Option<usize>,
 }
 
 impl<ID> PersistentSocket<ID>
@@ -62,16 +118,32 @@ where
     // hasn't recieved a message from a peer in this amount of time
     pub const PING_MILLIS: u64 = 500;
     pub const PING_ROLLING_AVERAGE_SIZE: usize = 100;
+    /// Weight of each new round trip sample in the smoothed round trip time
+    pub const RTT_SMOOTHING: f64 = 0.125;
 
     pub fn bind(port: u16) -> Result<PersistentSocket<ID>> {
-        let frame = FrameSocket::bind(port)?;
+        PersistentSocket::bind_to(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+    }
+
+    pub fn bind_to(address: IpAddr, port: u16) -> Result<PersistentSocket<ID>> {
+        PersistentSocket::bind_addr(SocketAddr::new(address, port))
+    }
+
+    /// Binds to the given local socket address, which may be IPv4 or IPv6
+    pub fn bind_addr(address: SocketAddr) -> Result<PersistentSocket<ID>> {
+        let frame = FrameSocket::bind_addr(address)?;
 
         Ok(PersistentSocket {
             frame,
             sent_times: HashMap::new(),
+            resent_packets: HashSet::new(),
             ping_times: HashMap::new(),
+            loss_stats: HashMap::new(),
+            smoothed_rtts: HashMap::new(),
             addresses_by_id: HashMap::new(),
             id_by_address: HashMap::new(),
+            auto_disconnect: true,
+            max_in_flight: None,
         })
     }
 
@@ -80,7 +152,7 @@ where
             .addresses_by_id
             .get(&id)
             .ok_or(anyhow!("No address found for this id"))?;
-        let message = message.into();
+        let message = message.into()?;
         Ok(self.frame.send_to(message, remote_address)?)
     }
 
@@ -90,26 +162,108 @@ where
         message: impl IntoOutgoingMessage,
     ) -> Result<FrameId> {
         let remote_address = remote_address.to_socket_addrs()?.next().unwrap();
-        let message = message.into();
+        let message = message.into()?;
         Ok(self.frame.send_to(message, remote_address)?)
     }
 
-    pub fn broadcast(&mut self, message: impl IntoOutgoingMessage) -> Result<HashMap<ID, FrameId>> {
-        let message = message.into();
-        let mut results = HashMap::new();
+    /// Sends the message to every connected peer except those with at least
+    /// the maximum number of packets in flight, so that a slow peer's backlog
+    /// doesn't keep growing
+    pub fn broadcast(&mut self, message: impl IntoOutgoingMessage) -> Result<Broadcast<ID>> {
+        let message = message.into()?;
+        let mut broadcast = Broadcast {
+            frame_ids: HashMap::new(),
+            throttled: Vec::new(),
+        };
         for (remote_address, id) in self.id_by_address.iter() {
+            let throttled = self
+                .max_in_flight
+                .is_some_and(|max_in_flight| self.in_flight_to(*remote_address) >= max_in_flight);
+            if throttled {
+                broadcast.throttled.push(*id);
+                continue;
+            }
+
             let frame_id = self.frame.send_to(message.clone(), remote_address)?;
-            results.insert(*id, frame_id);
+            broadcast.frame_ids.insert(*id, frame_id);
         }
-        Ok(results)
+        Ok(broadcast)
+    }
+
+    /// Number of packets sent to the peer which haven't been acknowledged yet
+    pub fn in_flight(&self, id: ID) -> usize {
+        self.addresses_by_id
+            .get(&id)
+            .map_or(0, |remote_address| self.in_flight_to(*remote_address))
+    }
+
+    fn in_flight_to(&self, remote_address: SocketAddr) -> usize {
+        self.sent_times
+            .keys()
+            .filter(|(_, sent_address)| *sent_address == remote_address)
+            .count()
+    }
+
+    /// Sets how many unacknowledged packets a peer may have before broadcasts
+    /// skip it. Messages sent directly to the peer are never skipped. None,
+    /// the default, never skips.
+    pub fn set_max_in_flight(&mut self, max_in_flight: Option<usize>) {
+        self.max_in_flight = max_in_flight;
+    }
+
+    /// Stops resending a frame previously sent to the peer. Use when the peer
+    /// no longer needs the frame because a newer one superseded it.
+    pub fn cancel(&mut self, id: ID, frame_id: FrameId) -> Result<()> {
+        let remote_address = *self
+            .addresses_by_id
+            .get(&id)
+            .ok_or(anyhow!("No address found for this id"))?;
+        for packet_id in self.frame.cancel_frame(frame_id, remote_address) {
+            // Canceled packets will never be acknowledged, so they must not
+            // count towards disconnect detection
+            self.sent_times.remove(&(packet_id, remote_address));
+            self.resent_packets.remove(&(packet_id, remote_address));
+        }
+        Ok(())
     }
 
     pub fn connect(&mut self, id: ID, address: SocketAddr) {
+        // A reconnecting peer numbers its frames from the start again
+        if self.addresses_by_id.get(&id) != Some(&address) {
+            self.frame.reset_ordering(address);
+        }
         self.ping_times.insert(id.clone(), VecDeque::new());
         self.addresses_by_id.insert(id.clone(), address);
         self.id_by_address.insert(address, id);
     }
 
+    /// Forgets the peer and stops resending anything to it. Messages from the
+    /// peer's address are treated as unconnected afterwards.
+    pub fn disconnect(&mut self, id: ID) {
+        self.ping_times.remove(&id);
+        self.loss_stats.remove(&id);
+        let Some(remote_address) = self.addresses_by_id.remove(&id) else {
+            return;
+        };
+        self.id_by_address.remove(&remote_address);
+        self.smoothed_rtts.remove(&remote_address);
+
+        self.frame.cancel_destination(remote_address);
+        self.frame.reset_ordering(remote_address);
+        self.sent_times
+            .retain(|(_, sent_address), _| *sent_address != remote_address);
+        self.resent_packets
+            .retain(|(_, sent_address)| *sent_address != remote_address);
+    }
+
+    /// Sets whether peers which time out are disconnected as soon as the
+    /// PeerDisconnected event is returned. On by default. When off the peer
+    /// stays connected until `disconnect` is called and PeerDisconnected is
+    /// returned again for every later message that times out.
+    pub fn set_auto_disconnect(&mut self, auto_disconnect: bool) {
+        self.auto_disconnect = auto_disconnect;
+    }
+
     pub fn peers(&self) -> Vec<ID> {
         self.addresses_by_id.keys().copied().collect()
     }
@@ -129,6 +283,8 @@ where
                     self.record_acknowledgement(packet_id, remote_address);
                 }
                 FrameEvent::PacketResent(packet_id) => {
+                    self.record_transmission(&sender, true);
+                    self.resent_packets.insert((packet_id, remote_address));
                     results.push((PersistentEvent::PacketResent(packet_id), sender));
                 }
                 FrameEvent::FrameComponentRecieved(component_position) => {
@@ -143,25 +299,47 @@ where
                         sender,
                     ));
                 }
+                FrameEvent::FrameCorrupted(frame_id) => {
+                    results.push((PersistentEvent::FrameCorrupted(frame_id), sender));
+                }
+                FrameEvent::FrameDiscarded(frame_id) => {
+                    results.push((PersistentEvent::FrameDiscarded(frame_id), sender));
+                }
+                FrameEvent::PacketDropped(packet_id) => {
+                    results.push((PersistentEvent::PacketDropped(packet_id), sender));
+                }
                 FrameEvent::FrameComponentSent(packet_id) => {
+                    self.record_transmission(&sender, false);
                     results.push((PersistentEvent::FrameComponentSent(packet_id), sender));
                     self.record_send(packet_id, remote_address);
                 }
             }
         }
 
-        let mut disconnects = Vec::new();
-        for ((ack_id, remote_address), sent_time) in self.sent_times.iter() {
-            let sender = self.to_sender(*remote_address);
-            if sent_time.elapsed()
-                > Duration::from_millis(PersistentSocket::<ID>::DISCONNECT_MILLIS)
-            {
-                results.push((PersistentEvent::PeerDisconnected, sender));
-                disconnects.push((*ack_id, *remote_address));
+        let disconnect_timeout = Duration::from_millis(PersistentSocket::<ID>::DISCONNECT_MILLIS);
+        let mut timed_out_addresses = Vec::new();
+        self.sent_times.retain(|(_, remote_address), sent_time| {
+            if sent_time.elapsed() <= disconnect_timeout {
+                return true;
             }
-        }
-        for disconnect in disconnects {
-            self.sent_times.remove(&disconnect);
+            if !timed_out_addresses.contains(remote_address) {
+                timed_out_addresses.push(*remote_address);
+            }
+            false
+        });
+        let sent_times = &self.sent_times;
+        self.resent_packets
+            .retain(|packet| sent_times.contains_key(packet));
+
+        // Only reported once per peer no matter how many messages timed out
+        for remote_address in timed_out_addresses {
+            let sender = self.to_sender(remote_address);
+            if self.auto_disconnect {
+                if let PersistentSocketSender::Connected(id) = sender {
+                    self.disconnect(id);
+                }
+            }
+            results.push((PersistentEvent::PeerDisconnected, sender));
         }
 
         Ok(results)
@@ -171,6 +349,28 @@ where
         self.frame.local_addr()
     }
 
+    pub fn enable_tracing(&mut self, capacity: usize) {
+        self.frame.enable_tracing(capacity);
+    }
+
+    pub fn disable_tracing(&mut self) {
+        self.frame.disable_tracing();
+    }
+
+    pub fn trace(&self) -> Vec<PacketTraceEvent> {
+        self.frame.trace()
+    }
+
+    pub fn set_partial_frame_timeout(&mut self, timeout: Duration) {
+        self.frame.set_partial_frame_timeout(timeout);
+    }
+
+    /// Sets how many times packets sent from now on are resent before
+    /// they are given up on with a PacketDropped event
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.frame.set_max_retries(max_retries);
+    }
+
     pub fn average_response_time(&self, id: ID) -> Option<Duration> {
         self.ping_times
             .get(&id)
@@ -178,6 +378,30 @@ where
             .map(|times| times.iter().sum::<Duration>() / times.len() as u32)
     }
 
+    /// Standard deviation of the response times to the peer. Measures how much
+    /// the timing of messages to and from the peer varies.
+    pub fn response_time_deviation(&self, id: ID) -> Option<Duration> {
+        let times = self.ping_times.get(&id).filter(|times| !times.is_empty())?;
+        let count = times.len() as f64;
+        let average = times.iter().map(Duration::as_secs_f64).sum::<f64>() / count;
+        let variance = times
+            .iter()
+            .map(|time| (time.as_secs_f64() - average).powi(2))
+            .sum::<f64>()
+            / count;
+        Some(Duration::from_secs_f64(variance.sqrt()))
+    }
+
+    /// Fraction of the most recent transmissions to the peer which were
+    /// resends, from 0 to 1. Each resend means an earlier transmission or its
+    /// acknowledgement was lost, so this approximates the packet loss on the
+    /// connection. 0 if nothing has been sent to the peer yet.
+    pub fn packet_loss(&self, id: ID) -> f32 {
+        self.loss_stats
+            .get(&id)
+            .map_or(0.0, |loss_stats| loss_stats.loss())
+    }
+
     pub fn average_lobby_response_time(&self) -> Duration {
         if self.ping_times.len() == 0 {
             Duration::from_secs(0)
@@ -193,19 +417,37 @@ where
     }
 
     fn record_acknowledgement(&mut self, packet_id: PacketId, remote_address: SocketAddr) {
+        let resent = self.resent_packets.remove(&(packet_id, remote_address));
         if let Some(sent_time) = self
             .sent_times
-            .remove(&(packet_id, remote_address)) {
+            .remove(&(packet_id, remote_address))
+            .filter(|_| !resent) {
+            let rtt = sent_time.elapsed();
+            let smoothed_rtt = match self.smoothed_rtts.get(&remote_address) {
+                Some(smoothed_rtt) => smoothed_rtt
+                    .mul_f64(1.0 - PersistentSocket::<ID>::RTT_SMOOTHING)
+                    + rtt.mul_f64(PersistentSocket::<ID>::RTT_SMOOTHING),
+                None => rtt,
+            };
+            self.smoothed_rtts.insert(remote_address, smoothed_rtt);
+            self.frame.set_rtt_estimate(remote_address, smoothed_rtt);
+
             if let Some(id) = self.id_by_address.get(&remote_address) {
-                let ping_times = self.ping_times.get_mut(&id).unwrap();
-                ping_times.push_front(sent_time.elapsed());
-                if ping_times.len() > PersistentSocket::<ID>::PING_ROLLING_AVERAGE_SIZE {
-                    ping_times.pop_back();
-                }
+                record_ping_time(
+                    self.ping_times.get_mut(&id).unwrap(),
+                    rtt,
+                    PersistentSocket::<ID>::PING_ROLLING_AVERAGE_SIZE,
+                );
             }
         }
     }
 
+    fn record_transmission(&mut self, sender: &PersistentSocketSender<ID>, resent: bool) {
+        if let PersistentSocketSender::Connected(id) = sender {
+            self.loss_stats.entry(*id).or_default().record(resent);
+        }
+    }
+
     fn to_sender(&self, remote_address: SocketAddr) -> PersistentSocketSender<ID> {
         if let Some(id) = self.id_by_address.get(&remote_address) {
             PersistentSocketSender::Connected(id.clone())
@@ -215,16 +457,28 @@ where
     }
 }
 
+/// Adds the newest response time to the front of the rolling window, dropping
+/// the oldest once the window is full
+fn record_ping_time(ping_times: &mut VecDeque<Duration>, rtt: Duration, window: usize) {
+    ping_times.push_front(rtt);
+    if ping_times.len() > window {
+        ping_times.pop_back();
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use anyhow::Result;
     use std::{
+        collections::VecDeque,
+        net::{IpAddr, Ipv6Addr, SocketAddr},
         thread::{sleep, spawn},
         time::Duration,
     };
 
     use crate::{
-        messages::OutgoingMessage,
-        persistent::{PersistentEvent, PersistentSocket},
+        messages::{IncomingMessage, OutgoingMessage},
+        persistent::{record_ping_time, PersistentEvent, PersistentSocket, PersistentSocketSender},
     };
 
     #[ignore]
@@ -275,4 +529,251 @@ mod test {
 
         assert_eq!(incoming_messages.len(), 500);
     }
+
+    #[test]
+    fn disconnected_peers_are_forgotten() -> Result<()> {
+        let mut persistent = PersistentSocket::<usize>::bind(0)?;
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let remote_address = remote.local_addr()?;
+        persistent.connect(1, remote_address);
+
+        let mut message = OutgoingMessage::new();
+        message.write_usize(7);
+        persistent.send_to(1, message)?;
+        persistent.pump()?;
+        assert!(!persistent.sent_times.is_empty());
+
+        persistent.disconnect(1);
+        assert!(persistent.peers().is_empty());
+        assert!(persistent.address(1).is_none());
+        assert!(persistent.sent_times.is_empty());
+
+        // Nothing is left to resend or time out
+        sleep(Duration::from_millis(100));
+        assert!(persistent.pump()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn exchanges_messages_over_ipv6() -> Result<()> {
+        let loopback = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0);
+        let mut persistent_1 = PersistentSocket::<usize>::bind_addr(loopback)?;
+        let mut persistent_2 = PersistentSocket::<usize>::bind_addr(loopback)?;
+        let address_1 = persistent_1.local_addr()?;
+        let address_2 = persistent_2.local_addr()?;
+        assert!(address_1.is_ipv6());
+        persistent_1.connect(2, address_2);
+        persistent_2.connect(1, address_1);
+
+        let mut message = OutgoingMessage::new();
+        message.write_string("Over IPv6");
+        persistent_1.send_to(2, message)?;
+        persistent_1.pump()?;
+
+        sleep(Duration::from_millis(50));
+        let mut received = persistent_2
+            .pump()?
+            .into_iter()
+            .filter_map(|(event, sender)| match event {
+                PersistentEvent::FrameCompleted(_, message) => Some((message, sender)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(received.len(), 1);
+        let (mut message, sender) = received.pop().unwrap();
+        assert_eq!(message.read_string().as_deref(), Some("Over IPv6"));
+        assert_eq!(sender, PersistentSocketSender::Connected(1));
+
+        // The acknowledgement makes it back as well
+        sleep(Duration::from_millis(50));
+        assert!(persistent_1
+            .pump()?
+            .into_iter()
+            .any(|(event, _)| matches!(event, PersistentEvent::PacketAcknowledged(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn timed_out_peers_are_disconnected() -> Result<()> {
+        let mut persistent = PersistentSocket::<usize>::bind(0)?;
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        persistent.connect(1, remote.local_addr()?);
+
+        for value in 0..2 {
+            let mut message = OutgoingMessage::new();
+            message.write_usize(value);
+            persistent.send_to(1, message)?;
+        }
+        persistent.pump()?;
+        assert_eq!(persistent.sent_times.len(), 2);
+
+        // Pretend the messages were sent long enough ago to time out
+        let timeout = Duration::from_millis(PersistentSocket::<usize>::DISCONNECT_MILLIS + 1);
+        for sent_time in persistent.sent_times.values_mut() {
+            *sent_time -= timeout;
+        }
+
+        let disconnects = persistent
+            .pump()?
+            .into_iter()
+            .filter(|(event, _)| *event == PersistentEvent::PeerDisconnected)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            disconnects,
+            vec![(
+                PersistentEvent::PeerDisconnected,
+                PersistentSocketSender::Connected(1)
+            )]
+        );
+        assert!(!persistent.peers().contains(&1));
+        assert!(persistent.ping_times.is_empty());
+        assert!(persistent.sent_times.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_throttles_saturated_peers() -> Result<()> {
+        let mut persistent = PersistentSocket::<usize>::bind_to("127.0.0.1".parse()?, 0)?;
+        let mut responsive = PersistentSocket::<usize>::bind_to("127.0.0.1".parse()?, 0)?;
+        // Never acknowledges anything, so every packet sent to it stays in
+        // flight
+        let saturated = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        persistent.connect(1, responsive.local_addr()?);
+        persistent.connect(2, saturated.local_addr()?);
+        persistent.set_max_in_flight(Some(3));
+
+        for value in 0..5 {
+            let mut message = OutgoingMessage::new();
+            message.write_usize(value);
+            let broadcast = persistent.broadcast(message)?;
+            persistent.pump()?;
+
+            if value < 3 {
+                assert!(broadcast.throttled.is_empty());
+            } else {
+                assert_eq!(broadcast.throttled, vec![2]);
+                assert!(!broadcast.frame_ids.contains_key(&2));
+            }
+            assert!(broadcast.frame_ids.contains_key(&1));
+
+            // Let the responsive peer acknowledge before the next broadcast
+            sleep(Duration::from_millis(20));
+            responsive.pump()?;
+            sleep(Duration::from_millis(20));
+            persistent.pump()?;
+        }
+
+        assert_eq!(persistent.in_flight(1), 0);
+        assert_eq!(persistent.in_flight(2), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn withheld_acknowledgements_count_as_loss() -> Result<()> {
+        let mut persistent = PersistentSocket::<usize>::bind(0)?;
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        persistent.connect(1, remote.local_addr()?);
+        assert_eq!(persistent.packet_loss(1), 0.0);
+
+        let mut message = OutgoingMessage::new();
+        message.write_string("Never acknowledged");
+        persistent.send_to(1, message)?;
+        persistent.pump()?;
+        assert_eq!(persistent.packet_loss(1), 0.0);
+
+        // The remote never acknowledges, so the packet is resent
+        sleep(Duration::from_millis(100));
+        let events = persistent.pump()?;
+        assert!(events
+            .iter()
+            .any(|(event, _)| matches!(event, PersistentEvent::PacketResent(_))));
+        assert!(persistent.packet_loss(1) > 0.0);
+        assert_eq!(persistent.packet_loss(2), 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resent_packets_are_not_sampled_for_rtt() -> Result<()> {
+        let mut persistent = PersistentSocket::<usize>::bind_to("127.0.0.1".parse()?, 0)?;
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        persistent.connect(1, remote.local_addr()?);
+
+        let mut message = OutgoingMessage::new();
+        message.write_string("Acknowledged late");
+        persistent.send_to(1, message)?;
+        persistent.pump()?;
+
+        // Hold the acknowledgement back until the packet has been resent
+        sleep(Duration::from_millis(100));
+        assert!(persistent
+            .pump()?
+            .iter()
+            .any(|(event, _)| matches!(event, PersistentEvent::PacketResent(_))));
+
+        let mut buf = [0u8; 1024];
+        let (byte_count, _) = remote.recv_from(&mut buf)?;
+        let mut received = IncomingMessage::new(buf[..byte_count].to_vec());
+        assert_eq!(received.read_bool(), Some(true));
+        let mut ack = OutgoingMessage::new();
+        ack.write_bool(false);
+        ack.write_usize(received.read_usize().unwrap());
+        remote.send_to(&ack.data, persistent.local_addr()?)?;
+        sleep(Duration::from_millis(20));
+
+        assert!(persistent
+            .pump()?
+            .iter()
+            .any(|(event, _)| matches!(event, PersistentEvent::PacketAcknowledged(_))));
+        // The acknowledgement could be for either send, so it isn't sampled
+        assert!(persistent.smoothed_rtts.is_empty());
+        assert!(persistent.ping_times[&1].is_empty());
+        assert!(persistent.resent_packets.is_empty());
+        assert!(persistent.sent_times.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn response_time_deviation() {
+        let mut persistent = PersistentSocket::<usize>::bind(0).unwrap();
+        assert_eq!(persistent.response_time_deviation(1), None);
+
+        persistent.ping_times.insert(
+            1,
+            VecDeque::from(vec![
+                Duration::from_millis(10),
+                Duration::from_millis(30),
+                Duration::from_millis(10),
+                Duration::from_millis(30),
+            ]),
+        );
+        let deviation = persistent.response_time_deviation(1).unwrap();
+        assert!((deviation.as_secs_f64() - 0.010).abs() < 1e-9);
+
+        persistent.ping_times.insert(2, VecDeque::from(vec![Duration::from_millis(20); 4]));
+        assert_eq!(persistent.response_time_deviation(2), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn alternating_replies_measure_jitter() {
+        let window = PersistentSocket::<usize>::PING_ROLLING_AVERAGE_SIZE;
+        let mut ping_times = VecDeque::new();
+        // Replies alternate between 10ms and 90ms for longer than the window
+        for reply in 0..window * 3 / 2 {
+            let rtt = Duration::from_millis(if reply % 2 == 0 { 10 } else { 90 });
+            record_ping_time(&mut ping_times, rtt, window);
+        }
+        assert_eq!(ping_times.len(), window);
+
+        let mut persistent = PersistentSocket::<usize>::bind(0).unwrap();
+        persistent.ping_times.insert(1, ping_times);
+        assert_eq!(persistent.average_response_time(1), Some(Duration::from_millis(50)));
+        let jitter = persistent.response_time_deviation(1).unwrap();
+        assert!((jitter.as_secs_f64() - 0.040).abs() < 1e-9);
+    }
 }
@@ -1,13 +1,15 @@
 use std::collections::*;
+use std::fmt;
 use std::io::{Error, ErrorKind};
 use std::net::UdpSocket;
-use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
-use std::sync::mpsc::{channel, Receiver};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
+use crate::trace::{PacketDirection, PacketTrace, PacketTraceEvent};
 use crate::util::DropTracker;
 
 use super::messages::*;
@@ -15,11 +17,32 @@ use super::messages::*;
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
 pub struct PacketId(usize);
 
+/// The socket error which stopped the receiver thread. Unlike other errors
+/// returned from pump, nothing more will be received until the socket is bound
+/// again.
+#[derive(Debug)]
+pub struct ReceiveError(pub Error);
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reliable socket stopped receiving: {}", self.0)
+    }
+}
+
+impl std::error::Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ReliableEvent {
     PacketAcknowledged(PacketId),
     PacketResent(PacketId),
     PacketRecieved(IncomingMessage),
+    /// The packet was resent the maximum number of times without being
+    /// acknowledged and has been given up on
+    PacketDropped(PacketId),
 }
 
 struct UnackedMessage {
@@ -27,27 +50,39 @@ struct UnackedMessage {
     pub message: OutgoingMessage,
     pub destination: SocketAddr,
     pub last_sent: Option<Instant>,
+    pub resends: usize,
+    pub max_retries: usize,
 }
 
 impl UnackedMessage {
+    /// Resend timeout used until a round trip time estimate is available
     pub const RESEND_MILLIS: u64 = 32;
+    pub const MIN_RESEND_MILLIS: u64 = 16;
+    pub const MAX_RESEND_MILLIS: u64 = 500;
+    pub const DEFAULT_MAX_RETRIES: usize = 20;
+    /// Longest a resend is backed off to while a peer doesn't acknowledge
+    pub const MAX_BACKOFF_MILLIS: u64 = 1000;
 
     pub fn new(
         packet_id: PacketId,
         message: OutgoingMessage,
         destination: SocketAddr,
+        max_retries: usize,
     ) -> UnackedMessage {
         UnackedMessage {
             packet_id,
             message,
             destination,
             last_sent: None,
+            resends: 0,
+            max_retries,
         }
     }
 
     pub fn send_if_needed(
         &mut self,
         socket: &UdpSocket,
+        resend_interval: Duration,
     ) -> Result<Option<(ReliableEvent, SocketAddr)>, Error> {
         if self.last_sent.is_none() {
             socket.send_to(&self.message.data, self.destination)?;
@@ -57,9 +92,17 @@ impl UnackedMessage {
 
         let time_since_last_sent = self.last_sent.unwrap().elapsed();
 
-        if time_since_last_sent > Duration::from_millis(UnackedMessage::RESEND_MILLIS) {
+        if time_since_last_sent > self.backoff(resend_interval) {
+            if self.resends >= self.max_retries {
+                return Ok(Some((
+                    ReliableEvent::PacketDropped(self.packet_id),
+                    self.destination,
+                )));
+            }
+
             socket.send_to(&self.message.data, self.destination)?;
             self.last_sent = Some(Instant::now());
+            self.resends += 1;
 
             Ok(Some((
                 ReliableEvent::PacketResent(self.packet_id),
@@ -69,23 +112,127 @@ impl UnackedMessage {
             Ok(None)
         }
     }
+
+    /// Doubles the resend interval for every resend so far, up to the maximum
+    /// backoff. The first resend still happens after a single interval so an
+    /// isolated loss recovers quickly, while a peer which has gone quiet isn't
+    /// flooded with resends.
+    fn backoff(&self, resend_interval: Duration) -> Duration {
+        let max_backoff = Duration::from_millis(UnackedMessage::MAX_BACKOFF_MILLIS);
+        let doublings = self.resends.min(u32::BITS as usize - 1) as u32;
+        resend_interval
+            .saturating_mul(1 << doublings)
+            .min(max_backoff.max(resend_interval))
+    }
+}
+
+/// Appends a crc32 of the datagram so the receiver can drop packets which were
+/// corrupted but still delivered
+fn append_checksum(message: &mut OutgoingMessage) {
+    let checksum = crc32fast::hash(&message.data);
+    message.write_data(checksum.to_le_bytes());
+}
+
+/// Strips the trailing crc32 from a received datagram. Returns None if the
+/// datagram doesn't match it.
+fn verify_checksum(mut data: Vec<u8>) -> Option<Vec<u8>> {
+    let payload_length = data.len().checked_sub(std::mem::size_of::<u32>())?;
+    let checksum = u32::from_le_bytes(data[payload_length..].try_into().ok()?);
+    data.truncate(payload_length);
+    (crc32fast::hash(&data) == checksum).then_some(data)
+}
+
+/// One and a half round trips, so that acknowledgements which are only a little
+/// late don't cause a resend
+fn resend_interval(rtt_estimate: Option<&Duration>) -> Duration {
+    let Some(rtt) = rtt_estimate else {
+        return Duration::from_millis(UnackedMessage::RESEND_MILLIS);
+    };
+
+    rtt.mul_f64(1.5).clamp(
+        Duration::from_millis(UnackedMessage::MIN_RESEND_MILLIS),
+        Duration::from_millis(UnackedMessage::MAX_RESEND_MILLIS),
+    )
+}
+
+/// The most recently received packet ids from a single peer. Evicts in the
+/// order packets arrived rather than by id so that ids which wrap around past
+/// usize::MAX are remembered like any other.
+#[derive(Default)]
+struct SeenPackets {
+    ids: HashSet<PacketId>,
+    order: VecDeque<PacketId>,
+}
+
+impl SeenPackets {
+    const CAPACITY: usize = 1000;
+
+    /// Records the packet id. Returns false if it was already seen.
+    fn insert(&mut self, packet_id: PacketId) -> bool {
+        if !self.ids.insert(packet_id) {
+            return false;
+        }
+
+        self.order.push_back(packet_id);
+        while self.order.len() > SeenPackets::CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
 }
 
 pub struct ReliableSocket {
     socket: Arc<UdpSocket>,
+    /// Whether every datagram carries a crc32. Both ends must agree
+    checksum: bool,
     _drop_tracker: DropTracker,
 
-    incoming_messages: Receiver<(IncomingMessage, SocketAddr)>,
+    /// Datagrams from the receiver thread, or the socket error that stopped it
+    incoming_messages: Receiver<Result<(IncomingMessage, SocketAddr), Error>>,
+    /// Wraps back to zero after usize::MAX. Peers only remember recently
+    /// seen ids, so reused ids are delivered once the originals are long gone
     packet_id_counter: usize,
     unacked_messages: HashMap<PacketId, UnackedMessage>,
-    seen_acks: HashMap<SocketAddr, BTreeSet<PacketId>>,
+    seen_acks: HashMap<SocketAddr, SeenPackets>,
+    /// Smoothed round trip time to each destination, fed by the layers above
+    rtt_estimates: HashMap<SocketAddr, Duration>,
+    /// Number of times a packet is resent before it is dropped
+    max_retries: usize,
+    trace: Option<PacketTrace>,
 }
 
 impl ReliableSocket {
     pub const MAX_RELIABLE_PACKET_SIZE: usize = 500;
 
     pub fn bind(port: u16) -> Result<ReliableSocket> {
-        let socket = Arc::new(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?);
+        ReliableSocket::bind_to(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+    }
+
+    /// Binds to the given local address instead of every interface. Useful on
+    /// machines with several interfaces, such as a VPN alongside a LAN.
+    pub fn bind_to(address: IpAddr, port: u16) -> Result<ReliableSocket> {
+        ReliableSocket::bind_addr(SocketAddr::new(address, port))
+    }
+
+    /// Binds to the given local socket address, which may be IPv4 or IPv6
+    pub fn bind_addr(address: SocketAddr) -> Result<ReliableSocket> {
+        ReliableSocket::bind_with_checksum(address, false)
+    }
+
+    /// Binds to every interface, optionally appending a crc32 to every
+    /// datagram. Datagrams which fail the check are dropped as if they were
+    /// lost so that they get resent. Every peer must use the same setting.
+    pub fn with_checksum(port: u16, checksum: bool) -> Result<ReliableSocket> {
+        ReliableSocket::bind_with_checksum(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+            checksum,
+        )
+    }
+
+    fn bind_with_checksum(address: SocketAddr, checksum: bool) -> Result<ReliableSocket> {
+        let socket = Arc::new(UdpSocket::bind(address)?);
         // socket.set_nonblocking(true)?;
         let drop_tracker = DropTracker::new();
         let (incoming_message_sender, incoming_messages) = channel();
@@ -98,17 +245,30 @@ impl ReliableSocket {
                     let mut buf = [0u8; ReliableSocket::MAX_RELIABLE_PACKET_SIZE + 32];
                     match socket.recv_from(&mut buf) {
                         Ok((byte_count, remote_address)) => {
-                            let incoming_message = IncomingMessage::new(buf[..byte_count].to_vec());
-                            if let Err(err) =
-                                incoming_message_sender.send((incoming_message, remote_address))
+                            let mut data = buf[..byte_count].to_vec();
+                            if checksum {
+                                let Some(verified) = verify_checksum(data) else {
+                                    continue;
+                                };
+                                data = verified;
+                            }
+                            let incoming_message = IncomingMessage::new(data);
+                            if incoming_message_sender
+                                .send(Ok((incoming_message, remote_address)))
+                                .is_err()
                             {
-                                panic!("Send message error: {err}");
+                                // The socket was dropped
+                                break;
                             }
                         }
                         Err(e) if e.kind() == ErrorKind::WouldBlock => {
                             // Continue. This is expected
                         }
-                        Err(e) => panic!("Recv message error: {e}"),
+                        Err(e) => {
+                            // Hand the error to pump rather than dying silently
+                            incoming_message_sender.send(Err(e)).ok();
+                            break;
+                        }
                     }
                 }
             }
@@ -116,23 +276,48 @@ impl ReliableSocket {
 
         Ok(ReliableSocket {
             socket,
+            checksum,
             _drop_tracker: drop_tracker,
             incoming_messages,
             packet_id_counter: 0,
             unacked_messages: HashMap::new(),
             seen_acks: HashMap::new(),
+            rtt_estimates: HashMap::new(),
+            max_retries: UnackedMessage::DEFAULT_MAX_RETRIES,
+            trace: None,
         })
     }
 
     fn resend_unacked_messages(&mut self) -> Result<Vec<(ReliableEvent, SocketAddr)>> {
         let mut results = Vec::new();
+        let mut dropped = Vec::new();
+
+        for (packet_id, unacked_message) in self.unacked_messages.iter_mut() {
+            let resend_interval =
+                resend_interval(self.rtt_estimates.get(&unacked_message.destination));
+            if let Some(event) = unacked_message.send_if_needed(&self.socket, resend_interval)? {
+                if let (ReliableEvent::PacketDropped(_), _) = event {
+                    dropped.push(*packet_id);
+                    results.push(event);
+                    continue;
+                }
 
-        for (_, unacked_message) in self.unacked_messages.iter_mut() {
-            if let Some(event) = unacked_message.send_if_needed(&self.socket)? {
+                if let Some(trace) = &mut self.trace {
+                    trace.record(
+                        unacked_message.packet_id,
+                        unacked_message.message.len(),
+                        PacketDirection::Resent,
+                        unacked_message.destination,
+                    );
+                }
                 results.push(event);
             }
         }
 
+        for packet_id in dropped {
+            self.unacked_messages.remove(&packet_id);
+        }
+
         Ok(results)
     }
 
@@ -140,8 +325,14 @@ impl ReliableSocket {
         let mut ack_message = OutgoingMessage::new();
         ack_message.write_bool(false);
         ack_message.write_usize(packet_id.0);
+        if self.checksum {
+            append_checksum(&mut ack_message);
+        }
 
         self.socket.send_to(&ack_message.data, destination)?;
+        if let Some(trace) = &mut self.trace {
+            trace.record(packet_id, ack_message.len(), PacketDirection::AckSent, destination);
+        }
         Ok(())
     }
 
@@ -156,52 +347,102 @@ impl ReliableSocket {
         }
 
         let packet_id = PacketId(self.packet_id_counter);
-        self.packet_id_counter += 1;
+        self.packet_id_counter = self.packet_id_counter.wrapping_add(1);
         let mut wrapped_message = OutgoingMessage::new();
         wrapped_message.write_bool(true);
         wrapped_message.write_usize(packet_id.0);
 
         wrapped_message.write_data(message.data);
+        if self.checksum {
+            append_checksum(&mut wrapped_message);
+        }
 
-        let mut unacked_message = UnackedMessage::new(packet_id, wrapped_message, destination);
-        unacked_message.send_if_needed(&self.socket)?;
+        let mut unacked_message =
+            UnackedMessage::new(packet_id, wrapped_message, destination, self.max_retries);
+        unacked_message.send_if_needed(&self.socket, self.resend_interval(destination))?;
+        if let Some(trace) = &mut self.trace {
+            trace.record(
+                packet_id,
+                unacked_message.message.len(),
+                PacketDirection::Sent,
+                destination,
+            );
+        }
         self.unacked_messages.insert(packet_id, unacked_message);
         Ok(packet_id)
     }
 
+    /// Sets the round trip time the resend timeout to the destination is sized
+    /// from
+    pub fn set_rtt_estimate(&mut self, destination: SocketAddr, rtt: Duration) {
+        self.rtt_estimates.insert(destination, rtt);
+    }
+
+    /// How long to wait for an acknowledgement from the destination before
+    /// resending
+    pub fn resend_interval(&self, destination: SocketAddr) -> Duration {
+        resend_interval(self.rtt_estimates.get(&destination))
+    }
+
+    /// Sets how many times packets sent from now on are resent before they
+    /// are dropped and a PacketDropped event is returned
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = max_retries;
+    }
+
+    /// Stops resending the packet if it hasn't been acknowledged yet. Returns
+    /// true if the packet was still waiting on an acknowledgement.
+    pub fn cancel(&mut self, packet_id: PacketId) -> bool {
+        self.unacked_messages.remove(&packet_id).is_some()
+    }
+
     pub fn pump(&mut self) -> Result<Vec<(ReliableEvent, SocketAddr)>> {
         let mut results = self.resend_unacked_messages()?;
 
-        while let Ok((mut incoming_message, remote_address)) = self.incoming_messages.try_recv() {
-            let is_data = incoming_message
-                .read_bool()
-                .ok_or(anyhow!("Reliable message is not data."))?;
-            let packet_id = PacketId(
-                incoming_message
-                    .read_usize()
-                    .ok_or(anyhow!("Reliable message does not have ack."))?,
-            );
+        loop {
+            let (mut incoming_message, remote_address) = match self.incoming_messages.try_recv() {
+                Ok(received) => received.map_err(ReceiveError)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return Err(ReceiveError(Error::new(
+                        ErrorKind::BrokenPipe,
+                        "Receiver thread stopped",
+                    ))
+                    .into())
+                }
+            };
+            let size = incoming_message.len();
+            // Anyone can send a datagram to the socket, so one without a
+            // reliable header is dropped rather than failing the whole pump
+            let (Some(is_data), Some(packet_id)) =
+                (incoming_message.read_bool(), incoming_message.read_usize())
+            else {
+                if let Some(trace) = &mut self.trace {
+                    trace.record(PacketId(0), size, PacketDirection::Malformed, remote_address);
+                }
+                continue;
+            };
+            let packet_id = PacketId(packet_id);
             if is_data {
+                if let Some(trace) = &mut self.trace {
+                    trace.record(packet_id, size, PacketDirection::Received, remote_address);
+                }
                 self.send_ack(packet_id, remote_address)?;
                 if self
                     .seen_acks
-                    .get(&remote_address)
-                    .map_or(true, |seen_acks| !seen_acks.contains(&packet_id))
+                    .entry(remote_address)
+                    .or_default()
+                    .insert(packet_id)
                 {
                     results.push((
                         ReliableEvent::PacketRecieved(incoming_message),
                         remote_address,
                     ));
-                    let seen_acks = self
-                        .seen_acks
-                        .entry(remote_address)
-                        .or_insert_with(|| BTreeSet::new());
-                    seen_acks.insert(packet_id);
-                    while seen_acks.len() > 1000 {
-                        seen_acks.pop_first();
-                    }
                 }
             } else if let Some(_) = self.unacked_messages.remove(&packet_id) {
+                if let Some(trace) = &mut self.trace {
+                    trace.record(packet_id, size, PacketDirection::AckReceived, remote_address);
+                }
                 results.push((ReliableEvent::PacketAcknowledged(packet_id), remote_address));
             }
         }
@@ -212,6 +453,25 @@ impl ReliableSocket {
     pub fn local_addr(&self) -> Result<SocketAddr, Error> {
         self.socket.local_addr()
     }
+
+    /// Starts recording the most recent `capacity` packet events. Replaces any
+    /// trace already being recorded.
+    pub fn enable_tracing(&mut self, capacity: usize) {
+        self.trace = Some(PacketTrace::new(capacity));
+    }
+
+    pub fn disable_tracing(&mut self) {
+        self.trace = None;
+    }
+
+    /// Returns the recorded packet events from oldest to newest. Empty when
+    /// tracing is disabled.
+    pub fn trace(&self) -> Vec<PacketTraceEvent> {
+        self.trace
+            .iter()
+            .flat_map(|trace| trace.events().cloned())
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -303,4 +563,314 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn reliable_socket_traces_packets() -> Result<()> {
+        let mut sender = ReliableSocket::bind_to(IpAddr::from(Ipv4Addr::LOCALHOST), 0)?;
+        let mut receiver = ReliableSocket::bind_to(IpAddr::from(Ipv4Addr::LOCALHOST), 0)?;
+        let sender_address = sender.local_addr()?;
+        let receiver_address = receiver.local_addr()?;
+        sender.enable_tracing(16);
+        receiver.enable_tracing(16);
+
+        let mut message = OutgoingMessage::new();
+        message.write_string("Traced");
+        let packet_id = sender.send_to(message, receiver_address)?;
+
+        sleep(Duration::from_millis(10));
+        receiver.pump()?;
+        sleep(Duration::from_millis(10));
+        sender.pump()?;
+
+        let sender_trace = sender
+            .trace()
+            .into_iter()
+            .map(|event| (event.packet_id, event.direction, event.peer))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sender_trace,
+            vec![
+                (packet_id, PacketDirection::Sent, receiver_address),
+                (packet_id, PacketDirection::AckReceived, receiver_address),
+            ]
+        );
+
+        let receiver_trace = receiver
+            .trace()
+            .into_iter()
+            .map(|event| (event.packet_id, event.direction, event.peer))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            receiver_trace,
+            vec![
+                (packet_id, PacketDirection::Received, sender_address),
+                (packet_id, PacketDirection::AckSent, sender_address),
+            ]
+        );
+
+        receiver.disable_tracing();
+        assert!(receiver.trace().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_packets_are_dropped() -> Result<()> {
+        let mut reliable = ReliableSocket::with_checksum(0, true)?;
+        let reliable_address = format!("127.0.0.1:{}", reliable.local_addr()?.port());
+        let test = UdpSocket::bind("127.0.0.1:0")?;
+
+        let mut message = OutgoingMessage::new();
+        message.write_bool(true);
+        message.write_usize(42);
+        message.write_string("This is a test.");
+        append_checksum(&mut message);
+
+        let mut corrupted = message.clone();
+        corrupted.data[12] ^= 0xFF;
+        test.send_to(&corrupted.data, &reliable_address)?;
+        sleep(Duration::from_millis(100));
+        assert!(reliable.pump()?.is_empty());
+
+        test.send_to(&message.data, &reliable_address)?;
+        sleep(Duration::from_millis(100));
+        assert!(matches!(
+            reliable.pump()?.pop(),
+            Some((ReliableEvent::PacketRecieved(_), _))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn resend_interval_follows_rtt() -> Result<()> {
+        let mut reliable = ReliableSocket::bind(0)?;
+        let test = UdpSocket::bind("127.0.0.1:0")?;
+        let test_address = test.local_addr()?;
+        assert_eq!(
+            reliable.resend_interval(test_address),
+            Duration::from_millis(UnackedMessage::RESEND_MILLIS)
+        );
+
+        reliable.set_rtt_estimate(test_address, Duration::from_millis(200));
+        assert_eq!(reliable.resend_interval(test_address), Duration::from_millis(300));
+
+        let mut message = OutgoingMessage::new();
+        message.write_string("Slow link");
+        reliable.send_to(message, test_address)?;
+
+        // Well past the default timeout but short of the adapted one
+        sleep(Duration::from_millis(100));
+        assert!(reliable.pump()?.is_empty());
+
+        reliable.set_rtt_estimate(test_address, Duration::from_secs(10));
+        assert_eq!(
+            reliable.resend_interval(test_address),
+            Duration::from_millis(UnackedMessage::MAX_RESEND_MILLIS)
+        );
+        reliable.set_rtt_estimate(test_address, Duration::from_millis(1));
+        assert_eq!(
+            reliable.resend_interval(test_address),
+            Duration::from_millis(UnackedMessage::MIN_RESEND_MILLIS)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unacknowledged_packets_are_dropped() -> Result<()> {
+        let mut reliable = ReliableSocket::bind(0)?;
+        let test = UdpSocket::bind("127.0.0.1:0")?;
+        let test_address = test.local_addr()?;
+        reliable.set_max_retries(3);
+        reliable.set_rtt_estimate(test_address, Duration::from_millis(1));
+
+        let mut message = OutgoingMessage::new();
+        message.write_string("Never acknowledged");
+        let packet_id = reliable.send_to(message, test_address)?;
+
+        // The waits between resends double from 16ms, so the packet is
+        // dropped 128ms after the third resend
+        let mut events = Vec::new();
+        for _ in 0..20 {
+            sleep(Duration::from_millis(30));
+            events.extend(reliable.pump()?.into_iter().map(|(event, _)| event));
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                ReliableEvent::PacketResent(packet_id),
+                ReliableEvent::PacketResent(packet_id),
+                ReliableEvent::PacketResent(packet_id),
+                ReliableEvent::PacketDropped(packet_id),
+            ]
+        );
+        assert!(!reliable.cancel(packet_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn resends_back_off() -> Result<()> {
+        let mut reliable = ReliableSocket::bind(0)?;
+        let test = UdpSocket::bind("127.0.0.1:0")?;
+        let test_address = test.local_addr()?;
+        reliable.set_rtt_estimate(test_address, Duration::from_millis(1));
+
+        let mut message = OutgoingMessage::new();
+        message.write_string("Peer went quiet");
+        reliable.send_to(message, test_address)?;
+        let sent = Instant::now();
+
+        let mut resend_times = Vec::new();
+        while resend_times.len() < 4 {
+            sleep(Duration::from_millis(1));
+            for (event, _) in reliable.pump()? {
+                if let ReliableEvent::PacketResent(_) = event {
+                    resend_times.push(sent.elapsed());
+                }
+            }
+        }
+
+        let intervals: Vec<Duration> = std::iter::once(resend_times[0])
+            .chain(resend_times.windows(2).map(|times| times[1] - times[0]))
+            .collect();
+        for (previous, next) in intervals.iter().zip(intervals.iter().skip(1)) {
+            assert!(next > previous, "{intervals:?} didn't grow");
+        }
+        assert!(intervals[3] >= Duration::from_millis(128));
+
+        Ok(())
+    }
+
+    #[test]
+    fn packet_ids_wrap_around() -> Result<()> {
+        let mut sender = ReliableSocket::bind_to(IpAddr::from(Ipv4Addr::LOCALHOST), 0)?;
+        let mut receiver = ReliableSocket::bind_to(IpAddr::from(Ipv4Addr::LOCALHOST), 0)?;
+        let sender_address = sender.local_addr()?;
+        let receiver_address = receiver.local_addr()?;
+
+        // The receiver has already seen a full window of ids right below the
+        // wrap point
+        let seen_packets = receiver.seen_acks.entry(sender_address).or_default();
+        for id in usize::MAX - SeenPackets::CAPACITY..usize::MAX {
+            assert!(seen_packets.insert(PacketId(id)));
+        }
+
+        sender.packet_id_counter = usize::MAX;
+        let mut sent_ids = Vec::new();
+        for text in ["Last", "First again"] {
+            let mut message = OutgoingMessage::new();
+            message.write_string(text);
+            sent_ids.push(sender.send_to(message, receiver_address)?);
+        }
+        assert_eq!(sent_ids, vec![PacketId(usize::MAX), PacketId(0)]);
+
+        sleep(Duration::from_millis(50));
+        let received = receiver
+            .pump()?
+            .into_iter()
+            .filter_map(|(event, _)| match event {
+                ReliableEvent::PacketRecieved(mut message) => message.read_string(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec!["Last".to_string(), "First again".to_string()]);
+
+        // A resend of the newest id is still recognized as a duplicate
+        let mut duplicate = OutgoingMessage::new();
+        duplicate.write_bool(true);
+        duplicate.write_usize(0);
+        duplicate.write_string("First again");
+        sender.socket.send_to(&duplicate.data, receiver_address)?;
+        sleep(Duration::from_millis(50));
+        assert!(!receiver
+            .pump()?
+            .into_iter()
+            .any(|(event, _)| matches!(event, ReliableEvent::PacketRecieved(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn socket_errors_are_returned_from_pump() -> Result<()> {
+        let mut reliable = ReliableSocket::bind(0)?;
+
+        // Stand in for the receiver thread hitting a fatal error, such as the
+        // socket being closed underneath it
+        let (incoming_message_sender, incoming_messages) = channel();
+        reliable.incoming_messages = incoming_messages;
+        incoming_message_sender.send(Err(Error::new(ErrorKind::NotConnected, "Closed")))?;
+        drop(incoming_message_sender);
+
+        let error = reliable.pump().unwrap_err();
+        assert_eq!(
+            error
+                .downcast_ref::<ReceiveError>()
+                .map(|error| error.0.kind()),
+            Some(ErrorKind::NotConnected)
+        );
+
+        // Once the thread is gone every pump reports it
+        assert!(reliable.pump().unwrap_err().is::<ReceiveError>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_datagrams_are_dropped() -> Result<()> {
+        let mut reliable = ReliableSocket::bind_to(IpAddr::from(Ipv4Addr::LOCALHOST), 0)?;
+        reliable.enable_tracing(16);
+        let reliable_address = reliable.local_addr()?;
+        let test = UdpSocket::bind("127.0.0.1:0")?;
+        let test_address = test.local_addr()?;
+
+        // Too short to hold the packet id
+        test.send_to(&[1, 2, 3], reliable_address)?;
+        let mut message = OutgoingMessage::new();
+        message.write_bool(true);
+        message.write_usize(42);
+        message.write_string("Still received");
+        test.send_to(&message.data, reliable_address)?;
+        sleep(Duration::from_millis(50));
+
+        let events = reliable.pump()?;
+        assert!(matches!(
+            events.as_slice(),
+            [(ReliableEvent::PacketRecieved(_), address)] if *address == test_address
+        ));
+        assert_eq!(reliable.trace()[0].direction, PacketDirection::Malformed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reliable_socket_binds_to_address() -> Result<()> {
+        let loopback = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let mut reliable = ReliableSocket::bind_to(loopback, 0)?;
+        let reliable_address = reliable.local_addr()?;
+        assert_eq!(reliable_address.ip(), loopback);
+
+        let test = UdpSocket::bind("127.0.0.1:0")?;
+        let mut message = OutgoingMessage::new();
+        message.write_bool(true);
+        message.write_usize(7);
+        message.write_string("Loopback only");
+        test.send_to(&message.data, reliable_address)?;
+
+        sleep(Duration::from_millis(100));
+
+        let mut events = reliable.pump()?;
+        if let (ReliableEvent::PacketRecieved(mut incoming_message), address) =
+            events.pop().expect("Recieved Event")
+        {
+            assert_eq!(incoming_message.read_string().unwrap(), "Loopback only");
+            assert_eq!(address.ip(), loopback);
+        } else {
+            panic!("reliable socket did not recieve loopback message.")
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::reliable::PacketId;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketDirection {
+    Sent,
+    Resent,
+    Received,
+    AckSent,
+    AckReceived,
+    /// A datagram without a reliable header, which was dropped. It has no
+    /// packet id so is recorded with id 0
+    Malformed,
+}
+
+#[derive(Debug, Clone)]
+pub struct PacketTraceEvent {
+    pub packet_id: PacketId,
+    /// Size in bytes of the datagram including the reliable header
+    pub size: usize,
+    pub direction: PacketDirection,
+    pub time: Instant,
+    pub peer: SocketAddr,
+}
+
+/// Ring buffer of the most recent packet events on a socket. Only allocated
+/// while tracing is enabled so that it costs nothing otherwise.
+pub struct PacketTrace {
+    capacity: usize,
+    events: VecDeque<PacketTraceEvent>,
+}
+
+impl PacketTrace {
+    pub fn new(capacity: usize) -> PacketTrace {
+        PacketTrace {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        packet_id: PacketId,
+        size: usize,
+        direction: PacketDirection,
+        peer: SocketAddr,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        while self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(PacketTraceEvent {
+            packet_id,
+            size,
+            direction,
+            time: Instant::now(),
+            peer,
+        });
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &PacketTraceEvent> {
+        self.events.iter()
+    }
+}